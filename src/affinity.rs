@@ -0,0 +1,153 @@
+//! Pinning of threads to CPU cores.
+
+use std::io;
+
+/// Restricts the calling thread to the cores numbered in `cores`.
+///
+/// Cores the system cannot represent are left out, and the call fails if
+/// none is left.
+#[cfg(target_os = "linux")]
+pub(crate) fn pin_current(cores: &[usize]) -> io::Result<()> {
+    let size = libc::CPU_SETSIZE as usize;
+    if !cores.iter().any(|&core| core < size) {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    // SAFETY: the set is plain data, and only cores within its size are set
+    let ret = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for &core in cores.iter().filter(|&&core| core < size) {
+            libc::CPU_SET(core, &mut set);
+        }
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Restricts the calling thread to the cores numbered in `cores`.
+///
+/// Only the cores of the processor group of the thread can be set, and the
+/// call fails if none of `cores` is among them.
+#[cfg(windows)]
+pub(crate) fn pin_current(cores: &[usize]) -> io::Result<()> {
+    use windows_sys::Win32::System::Threading::{GetCurrentThread, SetThreadAffinityMask};
+
+    let mask = cores
+        .iter()
+        .filter(|&&core| core < usize::BITS as usize)
+        .fold(0usize, |mask, &core| mask | 1 << core);
+    if mask == 0 {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    // SAFETY: the pseudo handle of the current thread is always valid
+    if unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// NUMA nodes of the system that have cores, by increasing number, as
+/// listed in sysfs. Empty if they cannot be read.
+#[cfg(target_os = "linux")]
+pub(crate) fn numa_nodes() -> Vec<(usize, Vec<usize>)> {
+    let dir = match std::fs::read_dir("/sys/devices/system/node") {
+        Ok(dir) => dir,
+        Err(_) => return Vec::new(),
+    };
+    let mut nodes: Vec<_> = dir
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let id = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()?;
+            let cores = std::fs::read_to_string(entry.path().join("cpulist")).ok()?;
+            Some((id, parse_cpu_list(cores.trim())?))
+        })
+        // nodes with memory only
+        .filter(|(_, cores)| !cores.is_empty())
+        .collect();
+    nodes.sort_unstable();
+    nodes
+}
+
+/// Parses a list of cores in the format of sysfs, e.g. `0-3,8,10-11`
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (first.parse::<usize>().ok()?, last.parse().ok()?);
+                cores.extend(first..=last);
+            }
+            None => cores.push(range.parse().ok()?),
+        }
+    }
+    Some(cores)
+}
+
+/// NUMA nodes of the system, which this system does not tell
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn numa_nodes() -> Vec<(usize, Vec<usize>)> {
+    Vec::new()
+}
+
+/// Restricts the calling thread to the cores numbered in `cores`, which this
+/// system does not support.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn pin_current(_cores: &[usize]) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::thread;
+
+    use super::{numa_nodes, parse_cpu_list, pin_current};
+
+    /// Cores the calling thread may run on
+    fn allowed() -> Vec<usize> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(
+                0,
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            );
+            (0..libc::CPU_SETSIZE as usize)
+                .filter(|&core| libc::CPU_ISSET(core, &set))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn pin() {
+        let core = *allowed().last().unwrap();
+        thread::spawn(move || {
+            pin_current(&[core]).unwrap();
+            assert_eq!(vec![core], allowed());
+        })
+        .join()
+        .unwrap();
+        assert!(pin_current(&[usize::MAX]).is_err());
+    }
+
+    #[test]
+    fn cpu_list() {
+        assert_eq!(
+            Some(vec![0, 1, 2, 3, 8, 10, 11]),
+            parse_cpu_list("0-3,8,10-11")
+        );
+        assert_eq!(Some(vec![]), parse_cpu_list(""));
+        assert_eq!(None, parse_cpu_list("0-x"));
+        // every node listed has cores
+        assert!(numa_nodes().iter().all(|(_, cores)| !cores.is_empty()));
+    }
+}
@@ -0,0 +1,159 @@
+//! Cooperative cancellation of blocking waits.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sync::{self, Arc, Mutex};
+
+/// Callback run when a token is cancelled, with its lifetime erased
+type Listener = &'static (dyn Fn() + Sync);
+
+struct Inner {
+    cancelled: AtomicBool,
+    /// Callbacks of the threads blocked on the token, with their id
+    listeners: Mutex<Vec<(u64, Listener)>>,
+    next_id: Mutex<u64>,
+}
+
+/// Token used to abort blocking waits from another thread
+///
+/// Clones share the same state: cancelling any of them cancels them all.
+/// Once cancelled, a token stays cancelled, and every wait given the token
+/// fails right away with [`AcquireError::Cancelled`](crate::AcquireError).
+///
+/// # Examples
+///
+/// ```
+/// # use esync::{AcquireError, CancellationToken, Semaphore};
+/// # use std::thread;
+/// let sem = Semaphore::new(0);
+/// let token = CancellationToken::new();
+/// thread::scope(|s| {
+///     let waiter = s.spawn(|| sem.wait_cancellable(&token));
+///     token.cancel();
+///     assert_eq!(Err(AcquireError::Cancelled), waiter.join().unwrap());
+/// });
+/// ```
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+/// Keeps a callback registered on a token until dropped
+pub(crate) struct Registration<'a> {
+    token: &'a CancellationToken,
+    id: u64,
+}
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                cancelled: AtomicBool::new(false),
+                listeners: Mutex::new(Vec::new()),
+                next_id: Mutex::new(0),
+            }),
+        }
+    }
+
+    /// Cancels the token, waking up all the waits it was given to.
+    pub fn cancel(&self) {
+        if self.inner.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // holding the lock keeps the callbacks registered, and alive, while
+        // they run
+        let listeners = sync::lock(&self.inner.listeners);
+        for (_, listener) in listeners.iter() {
+            listener();
+        }
+    }
+
+    /// Returns `true` if the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Registers `listener` to be called if the token gets cancelled, until
+    /// the returned registration is dropped. The listener may run on the
+    /// cancelling thread, before or during this call.
+    ///
+    /// # Safety
+    ///
+    /// The registration must be dropped, not leaked: dropping it is what
+    /// guarantees that `listener` is not called past its lifetime.
+    pub(crate) unsafe fn register<'a>(
+        &'a self,
+        listener: &'a (dyn Fn() + Sync),
+    ) -> Registration<'a> {
+        // SAFETY: the registration removes the listener, under the lock
+        // `cancel` holds while calling it, before `'a` ends
+        let listener: Listener = unsafe { core::mem::transmute(listener) };
+        let id = {
+            let mut next_id = sync::lock(&self.inner.next_id);
+            *next_id += 1;
+            *next_id
+        };
+        sync::lock(&self.inner.listeners).push((id, listener));
+        Registration { token: self, id }
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut listeners = sync::lock(&self.token.inner.listeners);
+        if let Some(pos) = listeners.iter().position(|(id, _)| *id == self.id) {
+            listeners.swap_remove(pos);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::CancellationToken;
+
+    #[test]
+    fn cancel() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        assert!(!clone.is_cancelled());
+        token.cancel();
+        assert!(clone.is_cancelled());
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn listeners() {
+        let token = CancellationToken::new();
+        let calls = AtomicUsize::new(0);
+        let listener = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+        };
+        let registration = unsafe { token.register(&listener) };
+        let other = || panic!("unregistered listener called");
+        drop(unsafe { token.register(&other) });
+        token.cancel();
+        token.cancel();
+        drop(registration);
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+    }
+}
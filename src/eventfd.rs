@@ -0,0 +1,253 @@
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use crate::AcquireError;
+
+/// Semaphore whose resource counter is an `eventfd(2)` file descriptor
+///
+/// The descriptor is readable whenever a resource is available, so the
+/// semaphore can be registered with `poll`, `epoll` or an event loop such as
+/// `mio`, next to sockets and timers. Readiness only means that a resource
+/// was available at the time: it is not reserved, and the event loop still
+/// has to take it with [`try_wait`](Self::try_wait), which may fail if
+/// another consumer was faster.
+///
+/// The blocking operations park in `poll(2)` as well. There is no fairness
+/// guarantee, and only single resource acquisitions.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::eventfd::EventFdSemaphore;
+/// # use std::os::unix::io::AsRawFd;
+/// let sem = EventFdSemaphore::new(1).unwrap();
+/// // register sem.as_raw_fd() for readability with the event loop
+/// let _fd = sem.as_raw_fd();
+/// assert!(sem.try_wait());
+/// assert!(!sem.try_wait());
+/// sem.release();
+/// ```
+pub struct EventFdSemaphore {
+    fd: OwnedFd,
+}
+
+impl EventFdSemaphore {
+    /// Instanties a semaphore with a given initial value
+    ///
+    /// Fails if the system cannot create the file descriptor.
+    pub fn new(initial_value: u32) -> io::Result<Self> {
+        // SAFETY: plain system call; a non-blocking descriptor lets
+        // `try_wait` never block and event loops use it as is
+        let fd = unsafe {
+            libc::eventfd(
+                initial_value,
+                libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK | libc::EFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` was just opened and nothing else owns it
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    /// Acquires the semaphore or waits in order to do so until another consumer
+    /// releases the resource.
+    pub fn wait(&self) {
+        while !self.try_wait() {
+            self.poll(None);
+        }
+    }
+
+    /// Attempts to acquire the semaphore without blocking.
+    pub fn try_wait(&self) -> bool {
+        let mut value = 0u64;
+        loop {
+            // SAFETY: reads 8 bytes into `value`, from a descriptor open
+            // until drop. In semaphore mode this takes a single resource.
+            let r = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    &mut value as *mut u64 as *mut c_void,
+                    8,
+                )
+            };
+            if r == 8 {
+                return true;
+            }
+            if io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN) {
+                return false;
+            }
+            check_interrupted("read");
+        }
+    }
+
+    /// Acquires the semaphore, waiting at most `timeout` for a resource to
+    /// become available.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => {
+                self.wait();
+                Ok(())
+            }
+        }
+    }
+
+    /// Acquires the semaphore, waiting until `deadline` at most for a
+    /// resource to become available.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        loop {
+            if self.try_wait() {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(AcquireError::TimedOut);
+            }
+            self.poll(Some(deadline - now));
+        }
+    }
+
+    /// Releases once the semaphore
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// Releases `n` resources at once
+    pub fn release_many(&self, n: u32) {
+        let value = u64::from(n);
+        loop {
+            // SAFETY: writes the 8 bytes of `value` to a descriptor open
+            // until drop
+            let r = unsafe {
+                libc::write(
+                    self.fd.as_raw_fd(),
+                    &value as *const u64 as *const c_void,
+                    8,
+                )
+            };
+            if r == 8 {
+                return;
+            }
+            // EAGAIN would take nearly 2^64 resources and cannot happen
+            check_interrupted("write");
+        }
+    }
+
+    /// Waits for the descriptor to become readable, for at most `timeout`.
+    /// Wakeups may be spurious.
+    fn poll(&self, timeout: Option<Duration>) {
+        let ms = timeout.map_or(-1, |t| {
+            // round up, not to wake up just before the deadline
+            let ms = (t.as_nanos() + 999_999) / 1_000_000;
+            c_int::try_from(ms).unwrap_or(c_int::MAX)
+        });
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: `pfd` is a single valid entry for the duration of the call
+        if unsafe { libc::poll(&mut pfd, 1, ms) } < 0 {
+            check_interrupted("poll");
+        }
+    }
+}
+
+impl AsRawFd for EventFdSemaphore {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl AsFd for EventFdSemaphore {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Lets the caller retry on EINTR; any other error means the descriptor is
+/// invalid, which cannot happen through the safe API
+fn check_interrupted(call: &str) {
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EINTR) {
+        panic!("{} failed: {}", call, err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::AsRawFd;
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::EventFdSemaphore;
+    use crate::AcquireError;
+
+    fn readable(sem: &EventFdSemaphore) -> bool {
+        let mut pfd = libc::pollfd {
+            fd: sem.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn wait_and_release() {
+        let sem = EventFdSemaphore::new(2).unwrap();
+        assert!(readable(&sem));
+        sem.wait();
+        assert!(sem.try_wait());
+        assert!(!readable(&sem));
+        assert!(!sem.try_wait());
+        sem.release_many(2);
+        assert!(readable(&sem));
+        assert!(sem.try_wait());
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let sem = EventFdSemaphore::new(0).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(100))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sem.release();
+            });
+            assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        });
+    }
+
+    #[test]
+    fn stress() {
+        let sem = EventFdSemaphore::new(2).unwrap();
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        sem.wait();
+                        sem.release();
+                    }
+                });
+            }
+        });
+        assert!(sem.try_wait());
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+    }
+}
@@ -0,0 +1,288 @@
+//! Minimal semaphore built directly on `futex(2)`, for Linux.
+//!
+//! [`FutexSemaphore`] is a type of its own, not a backend of
+//! [`Semaphore`](crate::Semaphore): the standard `Mutex` and `Condvar` the
+//! semaphore is built on already park on futexes on Linux, so what is left
+//! to save is the lock itself, and the features of the semaphore need it.
+//! Code needing only single resource acquisitions, waits with a timeout and
+//! releases can switch to it; the rest keeps using `Semaphore`.
+use std::os::raw::c_int;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::AcquireError;
+
+/// Semaphore parking its waiters on the resource counter itself, with
+/// `futex(2)`
+///
+/// This is a Linux only alternative to [`Semaphore`](crate::Semaphore) for
+/// latency sensitive code: there is no lock and no condition variable, an
+/// acquire or a release is a single atomic operation plus, only when threads
+/// are parked, one system call. In exchange it offers no fairness guarantee
+/// and only single resource acquisitions: none of the FIFO order, closing,
+/// cancellation, async acquisition, statistics or maximum value of
+/// `Semaphore`, which does not use it.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::futex::FutexSemaphore;
+/// let sem = FutexSemaphore::new(1);
+/// sem.wait();
+/// assert_eq!(0, sem.get_current_value());
+/// sem.release();
+/// ```
+#[repr(C)]
+pub struct FutexSemaphore {
+    /// Number of available resources, also the futex word
+    _permits: AtomicU32,
+    /// Number of threads parked, or about to park, on the futex
+    _waiters: AtomicU32,
+    /// Whether the semaphore lives in memory shared between processes
+    _shared: bool,
+}
+
+impl FutexSemaphore {
+    /// Instanties a semaphore with a given initial value
+    pub const fn new(initial_value: u32) -> Self {
+        Self {
+            _permits: AtomicU32::new(initial_value),
+            _waiters: AtomicU32::new(0),
+            _shared: false,
+        }
+    }
+
+    /// Instanties a semaphore that can be placed in memory shared between
+    /// processes
+    pub(crate) const fn new_shared(initial_value: u32) -> Self {
+        Self {
+            _shared: true,
+            ..Self::new(initial_value)
+        }
+    }
+
+    /// Acquires the semaphore or waits in order to do so until another consumer
+    /// releases the resource.
+    pub fn wait(&self) {
+        self.acquire(None);
+    }
+
+    /// Attempts to acquire the semaphore without blocking.
+    pub fn try_wait(&self) -> bool {
+        let mut current = self._permits.load(Ordering::Relaxed);
+        while current > 0 {
+            match self._permits.compare_exchange_weak(
+                current,
+                current - 1,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+        false
+    }
+
+    /// Acquires the semaphore, waiting at most `timeout` for a resource to
+    /// become available.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => {
+                self.wait();
+                Ok(())
+            }
+        }
+    }
+
+    /// Acquires the semaphore, waiting until `deadline` at most for a
+    /// resource to become available.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        if self.acquire(Some(deadline)) {
+            Ok(())
+        } else {
+            Err(AcquireError::TimedOut)
+        }
+    }
+
+    /// Releases once the semaphore
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// Releases `n` resources at once
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value of the semaphore would overflow.
+    pub fn release_many(&self, n: u32) {
+        let mut current = self._permits.load(Ordering::Relaxed);
+        loop {
+            let new = current
+                .checked_add(n)
+                .expect("semaphore value overflow on release");
+            match self._permits.compare_exchange_weak(
+                current,
+                new,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // A waiter registers itself before the kernel checks the counter, so
+        // either it saw the released resources or it is visible here.
+        if self._waiters.load(Ordering::SeqCst) > 0 {
+            futex_wake(&self._permits, n, self._shared);
+        }
+    }
+
+    /// Get the current value of the semaphore.
+    pub fn get_current_value(&self) -> u32 {
+        self._permits.load(Ordering::SeqCst)
+    }
+
+    /// Takes a resource, parking on the counter while it is zero. Returns
+    /// `false` if `deadline` passed first.
+    fn acquire(&self, deadline: Option<Instant>) -> bool {
+        loop {
+            if self.try_wait() {
+                return true;
+            }
+            let timeout = match deadline {
+                None => None,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    Some(deadline - now)
+                }
+            };
+            self._waiters.fetch_add(1, Ordering::SeqCst);
+            futex_wait(&self._permits, 0, timeout, self._shared);
+            self._waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Futex operation flags, for a word private to the process or not
+fn futex_op(op: c_int, shared: bool) -> c_int {
+    if shared {
+        op
+    } else {
+        op | libc::FUTEX_PRIVATE_FLAG
+    }
+}
+
+/// Parks the calling thread as long as `word` holds `expected`, for at most
+/// `timeout`. Wakeups may be spurious.
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>, shared: bool) {
+    let ts = timeout.map(|t| libc::timespec {
+        tv_sec: t.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: t.subsec_nanos() as _,
+    });
+    let ts_ptr = ts
+        .as_ref()
+        .map_or(ptr::null(), |ts| ts as *const libc::timespec);
+    // SAFETY: `word` is a valid, aligned u32 for the duration of the call.
+    // EINTR, EAGAIN and ETIMEDOUT are all handled by the caller re-checking
+    // the counter.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            futex_op(libc::FUTEX_WAIT, shared),
+            expected,
+            ts_ptr,
+        );
+    }
+}
+
+/// Wakes up to `n` threads parked on `word`
+fn futex_wake(word: &AtomicU32, n: u32, shared: bool) {
+    let n = i32::try_from(n).unwrap_or(i32::MAX);
+    // SAFETY: `word` is a valid, aligned u32 for the duration of the call.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            futex_op(libc::FUTEX_WAKE, shared),
+            n,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::FutexSemaphore;
+    use crate::AcquireError;
+
+    #[test]
+    fn wait_and_release() {
+        let sem = FutexSemaphore::new(1);
+        sem.wait();
+        assert!(!sem.try_wait());
+        sem.release();
+        assert!(sem.try_wait());
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn release_while_wait() {
+        let sem = FutexSemaphore::new(0);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(waiter.is_finished());
+        });
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let sem = FutexSemaphore::new(0);
+        let start = Instant::now();
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(100))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sem.release();
+            });
+            assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        });
+    }
+
+    #[test]
+    fn stress() {
+        let sem = FutexSemaphore::new(2);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..10000 {
+                        sem.wait();
+                        assert!(sem.get_current_value() < 2);
+                        sem.release();
+                    }
+                });
+            }
+        });
+        assert_eq!(2, sem.get_current_value());
+    }
+}
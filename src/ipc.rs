@@ -0,0 +1,222 @@
+//! Primitives shared between processes.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::AcquireError;
+
+#[cfg(target_os = "linux")]
+#[path = "ipc/posix.rs"]
+mod imp;
+#[cfg(windows)]
+#[path = "ipc/windows.rs"]
+mod imp;
+
+#[cfg(target_os = "linux")]
+mod shm;
+#[cfg(target_os = "linux")]
+pub use shm::{ShmError, ShmSemaphore, MAX_HOLDERS};
+
+/// Semaphore identified by a system-wide name, that several processes can
+/// open and use together
+///
+/// The semaphore offers the same wait/release operations as the in-process
+/// [`Semaphore`](crate::Semaphore). On Linux it wraps a POSIX named
+/// semaphore, and names follow the `sem_open(3)` rules: a leading slash
+/// followed by up to 250 characters, none of which a slash. On Windows it
+/// wraps a kernel semaphore object, for which such names are valid as well,
+/// so the same name can be used on both systems.
+///
+/// The process that creates the semaphore owns its name and removes it when
+/// the semaphore is dropped. Processes which already opened it keep using it
+/// until they drop it too. On Windows the name lives as long as any process
+/// has the semaphore open.
+///
+/// The system does not tell which process holds the resources of a named
+/// semaphore, so resources held by a process that dies are lost. Use a
+/// [`ShmSemaphore`] where they have to be reclaimed.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::ipc::NamedSemaphore;
+/// let name = format!("/esync-doc-{}", std::process::id());
+/// let sem = NamedSemaphore::create(&name, 1).unwrap();
+/// // usually done by another process
+/// let other = NamedSemaphore::open(&name).unwrap();
+/// sem.wait();
+/// assert!(!other.try_wait());
+/// other.release();
+/// assert_eq!(1, sem.get_current_value());
+/// ```
+pub struct NamedSemaphore {
+    inner: imp::NamedSemaphore,
+    name: String,
+    owner: bool,
+}
+
+impl NamedSemaphore {
+    /// Creates a new semaphore named `name`, with the given initial value.
+    ///
+    /// Fails if a semaphore with the same name already exists.
+    pub fn create(name: &str, initial_value: u32) -> io::Result<Self> {
+        Ok(Self {
+            inner: imp::NamedSemaphore::create(name, initial_value)?,
+            name: name.to_owned(),
+            owner: true,
+        })
+    }
+
+    /// Opens the existing semaphore named `name`.
+    pub fn open(name: &str) -> io::Result<Self> {
+        Ok(Self {
+            inner: imp::NamedSemaphore::open(name)?,
+            name: name.to_owned(),
+            owner: false,
+        })
+    }
+
+    /// Removes the name of a semaphore from the system, e.g. one left behind
+    /// by a creator process that crashed.
+    ///
+    /// Processes which have the semaphore open can keep using it.
+    pub fn unlink(name: &str) -> io::Result<()> {
+        imp::NamedSemaphore::unlink(name)
+    }
+
+    /// Get the name of the semaphore
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Acquires the semaphore or waits in order to do so until another consumer
+    /// releases the resource.
+    pub fn wait(&self) {
+        self.inner.wait()
+    }
+
+    /// Attempts to acquire the semaphore without blocking.
+    pub fn try_wait(&self) -> bool {
+        self.inner.try_wait()
+    }
+
+    /// Acquires the semaphore, waiting at most `timeout` for a resource to
+    /// become available.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => {
+                self.wait();
+                Ok(())
+            }
+        }
+    }
+
+    /// Acquires the semaphore, waiting until `deadline` at most for a
+    /// resource to become available.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        self.inner.wait_deadline(deadline)
+    }
+
+    /// Releases once the semaphore
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value of the semaphore would exceed the system limit.
+    pub fn release(&self) {
+        self.inner.release()
+    }
+
+    /// Get the current value of the semaphore.
+    pub fn get_current_value(&self) -> u32 {
+        self.inner.get_current_value()
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        if self.owner {
+            // nothing to do if someone else removed it already
+            let _ = Self::unlink(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        process::{Command, Stdio},
+        time::{Duration, Instant},
+    };
+
+    use super::NamedSemaphore;
+    use crate::AcquireError;
+
+    /// Environment variable telling the test binary it runs as the child
+    const CHILD_ENV: &str = "ESYNC_IPC_CHILD";
+
+    fn unique_name(tag: &str) -> String {
+        format!("/esync-test-{}-{}", tag, std::process::id())
+    }
+
+    #[test]
+    fn create_open_unlink() {
+        let name = unique_name("create");
+        let sem = NamedSemaphore::create(&name, 2).unwrap();
+        assert_eq!(name, sem.name());
+        assert!(NamedSemaphore::create(&name, 2).is_err());
+        let other = NamedSemaphore::open(&name).unwrap();
+        assert!(other.try_wait());
+        assert_eq!(1, sem.get_current_value());
+        other.release();
+        drop(sem);
+        // the creator removed the name, but the opened handle still works
+        #[cfg(target_os = "linux")]
+        assert!(NamedSemaphore::open(&name).is_err());
+        assert!(other.try_wait());
+        assert!(NamedSemaphore::open("/esync-test-missing").is_err());
+        assert!(NamedSemaphore::open("/nul\0byte").is_err());
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let name = unique_name("timeout");
+        let sem = NamedSemaphore::create(&name, 0).unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(100))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        sem.release();
+        assert_eq!(Ok(()), sem.wait_timeout(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn child_process() {
+        if let Ok(name) = env::var(CHILD_ENV) {
+            // running as the child: signal the parent, then wait for it
+            let ready = NamedSemaphore::open(&format!("{}-ready", name)).unwrap();
+            let go = NamedSemaphore::open(&format!("{}-go", name)).unwrap();
+            ready.release();
+            go.wait_timeout(Duration::from_secs(10)).unwrap();
+            return;
+        }
+
+        let name = unique_name("child");
+        let ready = NamedSemaphore::create(&format!("{}-ready", name), 0).unwrap();
+        let go = NamedSemaphore::create(&format!("{}-go", name), 0).unwrap();
+        let mut child = Command::new(env::current_exe().unwrap())
+            .args(["--exact", "ipc::test::child_process"])
+            .env(CHILD_ENV, &name)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        ready.wait_timeout(Duration::from_secs(10)).unwrap();
+        go.release();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(0, ready.get_current_value());
+        assert_eq!(0, go.get_current_value());
+    }
+}
@@ -0,0 +1,135 @@
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_int, c_uint};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::AcquireError;
+
+/// POSIX named semaphore, from `sem_open(3)`
+pub(super) struct NamedSemaphore {
+    sem: *mut libc::sem_t,
+}
+
+// SAFETY: POSIX semaphores are meant to be used from several threads (and
+// processes) at once.
+unsafe impl Send for NamedSemaphore {}
+unsafe impl Sync for NamedSemaphore {}
+
+fn c_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+impl NamedSemaphore {
+    pub(super) fn create(name: &str, initial_value: u32) -> io::Result<Self> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is a valid C string; the variadic mode and value are
+        // passed with the types sem_open expects.
+        let sem = unsafe {
+            libc::sem_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL,
+                0o600 as c_uint,
+                initial_value as c_uint,
+            )
+        };
+        Self::from_raw(sem)
+    }
+
+    pub(super) fn open(name: &str) -> io::Result<Self> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is a valid C string
+        let sem = unsafe { libc::sem_open(name.as_ptr(), 0) };
+        Self::from_raw(sem)
+    }
+
+    pub(super) fn unlink(name: &str) -> io::Result<()> {
+        let name = c_name(name)?;
+        // SAFETY: `name` is a valid C string
+        if unsafe { libc::sem_unlink(name.as_ptr()) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn from_raw(sem: *mut libc::sem_t) -> io::Result<Self> {
+        if sem == libc::SEM_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Self { sem })
+        }
+    }
+
+    pub(super) fn wait(&self) {
+        // SAFETY: `self.sem` stays open until drop
+        while unsafe { libc::sem_wait(self.sem) } != 0 {
+            check_interrupted("sem_wait");
+        }
+    }
+
+    pub(super) fn try_wait(&self) -> bool {
+        loop {
+            // SAFETY: `self.sem` stays open until drop
+            if unsafe { libc::sem_trywait(self.sem) } == 0 {
+                return true;
+            }
+            if io::Error::last_os_error().raw_os_error() == Some(libc::EAGAIN) {
+                return false;
+            }
+            check_interrupted("sem_trywait");
+        }
+    }
+
+    pub(super) fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        // sem_timedwait measures the deadline against the realtime clock
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let abs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            + remaining;
+        let ts = libc::timespec {
+            tv_sec: abs.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+            tv_nsec: abs.subsec_nanos() as _,
+        };
+        loop {
+            // SAFETY: `self.sem` stays open until drop
+            if unsafe { libc::sem_timedwait(self.sem, &ts) } == 0 {
+                return Ok(());
+            }
+            if io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT) {
+                return Err(AcquireError::TimedOut);
+            }
+            check_interrupted("sem_timedwait");
+        }
+    }
+
+    pub(super) fn release(&self) {
+        // SAFETY: `self.sem` stays open until drop
+        if unsafe { libc::sem_post(self.sem) } != 0 {
+            panic!("sem_post failed: {}", io::Error::last_os_error());
+        }
+    }
+
+    pub(super) fn get_current_value(&self) -> u32 {
+        let mut value: c_int = 0;
+        // SAFETY: `self.sem` stays open until drop
+        unsafe { libc::sem_getvalue(self.sem, &mut value) };
+        value.max(0) as u32
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        // SAFETY: `self.sem` was opened by sem_open and is closed only here
+        unsafe { libc::sem_close(self.sem) };
+    }
+}
+
+/// Lets the caller retry on EINTR; any other error means the semaphore handle
+/// is invalid, which cannot happen through the safe API
+fn check_interrupted(call: &str) {
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EINTR) {
+        panic!("{} failed: {}", call, err);
+    }
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::futex::FutexSemaphore;
+use crate::AcquireError;
+
+/// Marks a region holding an initialized semaphore ("esem")
+const MAGIC: u32 = 0x6573_656d;
+
+/// Number of processes whose resources can be reclaimed if they die
+pub const MAX_HOLDERS: usize = 64;
+
+/// How long a waiter parks before checking for dead holders
+const RECLAIM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Resources held by a process
+#[repr(C)]
+struct Holder {
+    /// Process id, 0 for a free slot
+    pid: AtomicU32,
+    /// Number of resources held
+    count: AtomicU32,
+}
+
+/// What lives at the start of the shared region
+#[repr(C)]
+struct Layout {
+    magic: AtomicU32,
+    sem: FutexSemaphore,
+    holders: [Holder; MAX_HOLDERS],
+}
+
+/// Error returned when a memory region cannot host a [`ShmSemaphore`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShmError {
+    /// The region is smaller than [`ShmSemaphore::SIZE`]
+    TooSmall,
+    /// The region is not aligned to [`ShmSemaphore::ALIGN`]
+    Misaligned,
+    /// No semaphore has been initialized in the region yet
+    NotInitialized,
+}
+
+impl fmt::Display for ShmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShmError::TooSmall => write!(f, "shared memory region too small"),
+            ShmError::Misaligned => write!(f, "shared memory region misaligned"),
+            ShmError::NotInitialized => write!(f, "no semaphore in the shared memory region"),
+        }
+    }
+}
+
+impl std::error::Error for ShmError {}
+
+/// Semaphore placed in a memory region shared between processes, e.g. a
+/// `MAP_SHARED` mapping
+///
+/// One process initializes the semaphore in the region with
+/// [`init`](Self::init), then any process mapping the same region attaches
+/// to it with [`attach`](Self::attach). Attaching before the initialization
+/// completed fails with [`ShmError::NotInitialized`], and can be retried.
+/// Waiters park on a process-shared futex.
+///
+/// The semaphore keeps track of how many resources every process holds, for
+/// up to [`MAX_HOLDERS`] processes. If a process dies without releasing its
+/// resources, blocked waiters notice it and reclaim them instead of
+/// deadlocking; [`reclaim_dead`](Self::reclaim_dead) does it on demand.
+/// Resources are tracked per process, not per thread, and a process dying in
+/// the middle of an acquire or a release may still leak the resource.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::ipc::ShmSemaphore;
+/// // stands for a region mapped by several processes
+/// let mut region = vec![0u64; ShmSemaphore::SIZE / 8 + 1];
+/// let ptr = region.as_mut_ptr() as *mut u8;
+/// let len = region.len() * 8;
+/// let sem = unsafe { ShmSemaphore::init(ptr, len, 1) }.unwrap();
+/// let other = unsafe { ShmSemaphore::attach(ptr, len) }.unwrap();
+/// sem.wait();
+/// assert!(!other.try_wait());
+/// other.release();
+/// ```
+pub struct ShmSemaphore<'a> {
+    layout: &'a Layout,
+}
+
+impl<'a> ShmSemaphore<'a> {
+    /// Number of bytes the semaphore takes at the start of the region
+    pub const SIZE: usize = mem::size_of::<Layout>();
+    /// Alignment the region must have
+    pub const ALIGN: usize = mem::align_of::<Layout>();
+
+    /// Initializes a semaphore with the given value at the start of the
+    /// region at `ptr`, spanning `len` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes of `len` bytes for `'a`, and
+    /// the first [`SIZE`](Self::SIZE) bytes must only be accessed through
+    /// [`ShmSemaphore`] for the same duration. No other process may be using
+    /// a semaphore previously placed in the region.
+    pub unsafe fn init(ptr: *mut u8, len: usize, initial_value: u32) -> Result<Self, ShmError> {
+        Self::check(ptr, len)?;
+        let layout = ptr as *mut Layout;
+        // SAFETY: checked for size and alignment, valid per the contract
+        unsafe {
+            (*layout).magic.store(0, Ordering::Relaxed);
+            (*layout).sem = FutexSemaphore::new_shared(initial_value);
+            for holder in &(*layout).holders {
+                holder.pid.store(0, Ordering::Relaxed);
+                holder.count.store(0, Ordering::Relaxed);
+            }
+            // publish the semaphore only once it is complete
+            (*layout).magic.store(MAGIC, Ordering::Release);
+            Ok(Self { layout: &*layout })
+        }
+    }
+
+    /// Attaches to the semaphore a process initialized at the start of the
+    /// region at `ptr`, spanning `len` bytes.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`init`](Self::init), except that the region is expected to
+    /// hold a semaphore already, possibly one still being initialized.
+    pub unsafe fn attach(ptr: *mut u8, len: usize) -> Result<Self, ShmError> {
+        Self::check(ptr, len)?;
+        // SAFETY: checked for size and alignment, valid per the contract;
+        // the magic word is only ever accessed atomically
+        let layout = unsafe { &*(ptr as *const Layout) };
+        if layout.magic.load(Ordering::Acquire) != MAGIC {
+            return Err(ShmError::NotInitialized);
+        }
+        Ok(Self { layout })
+    }
+
+    /// Acquires the semaphore or waits in order to do so until another consumer
+    /// releases the resource, or dies holding it.
+    pub fn wait(&self) {
+        loop {
+            if self.wait_timeout(RECLAIM_INTERVAL).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Attempts to acquire the semaphore without blocking.
+    pub fn try_wait(&self) -> bool {
+        if self.layout.sem.try_wait() {
+            self.track(1);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Acquires the semaphore, waiting at most `timeout` for a resource to
+    /// become available.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => {
+                self.wait();
+                Ok(())
+            }
+        }
+    }
+
+    /// Acquires the semaphore, waiting until `deadline` at most for a
+    /// resource to become available.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        loop {
+            let slice = Instant::now()
+                .checked_add(RECLAIM_INTERVAL)
+                .map_or(deadline, |d| d.min(deadline));
+            if self.layout.sem.wait_deadline(slice).is_ok() {
+                self.track(1);
+                return Ok(());
+            }
+            if self.reclaim_dead() == 0 && Instant::now() >= deadline {
+                return Err(AcquireError::TimedOut);
+            }
+        }
+    }
+
+    /// Releases once the semaphore
+    pub fn release(&self) {
+        // untracking first: dying in between leaks the resource, instead of
+        // letting it be reclaimed twice
+        self.untrack(1);
+        self.layout.sem.release();
+    }
+
+    /// Get the current value of the semaphore.
+    pub fn get_current_value(&self) -> u32 {
+        self.layout.sem.get_current_value()
+    }
+
+    /// Gives back the resources held by processes that no longer exist, and
+    /// returns how many were reclaimed.
+    pub fn reclaim_dead(&self) -> u32 {
+        let mut reclaimed = 0;
+        for holder in &self.layout.holders {
+            let pid = holder.pid.load(Ordering::Acquire);
+            if pid == 0 || is_alive(pid) {
+                continue;
+            }
+            // only one process gets to free the slot and its resources
+            if holder
+                .pid
+                .compare_exchange(pid, 0, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                reclaimed += holder.count.swap(0, Ordering::AcqRel);
+            }
+        }
+        if reclaimed > 0 {
+            self.layout.sem.release_many(reclaimed);
+        }
+        reclaimed
+    }
+
+    /// Records that the calling process took `n` resources. Untracked if all
+    /// the slots are in use.
+    fn track(&self, n: u32) {
+        let me = std::process::id();
+        for holder in &self.layout.holders {
+            let pid = holder.pid.load(Ordering::Acquire);
+            if pid == me
+                || (pid == 0
+                    && holder
+                        .pid
+                        .compare_exchange(0, me, Ordering::AcqRel, Ordering::Relaxed)
+                        .is_ok())
+            {
+                holder.count.fetch_add(n, Ordering::AcqRel);
+                return;
+            }
+        }
+    }
+
+    /// Records that the calling process gave `n` resources back
+    fn untrack(&self, n: u32) {
+        let me = std::process::id();
+        if let Some(holder) = self
+            .layout
+            .holders
+            .iter()
+            .find(|h| h.pid.load(Ordering::Acquire) == me)
+        {
+            let _ = holder
+                .count
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| {
+                    Some(c.saturating_sub(n))
+                });
+        }
+    }
+
+    fn check(ptr: *mut u8, len: usize) -> Result<(), ShmError> {
+        if len < Self::SIZE {
+            Err(ShmError::TooSmall)
+        } else if ptr as usize % Self::ALIGN != 0 {
+            Err(ShmError::Misaligned)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Whether the process `pid` exists
+fn is_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks for the existence of the process
+    let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    r == 0 || std::io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{ptr, thread, time::Duration};
+
+    use super::{ShmError, ShmSemaphore};
+
+    /// Maps an anonymous region that survives fork as shared memory
+    fn map_shared(len: usize) -> *mut u8 {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(libc::MAP_FAILED, ptr);
+        ptr as *mut u8
+    }
+
+    /// Forks a child running `f`, which must only touch the semaphore, and
+    /// returns its pid
+    fn fork(f: impl FnOnce() -> bool) -> libc::pid_t {
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let status = if f() { 0 } else { 1 };
+            unsafe { libc::_exit(status) };
+        }
+        pid
+    }
+
+    fn join(pid: libc::pid_t) -> bool {
+        let mut status = 0;
+        assert_eq!(pid, unsafe { libc::waitpid(pid, &mut status, 0) });
+        libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0
+    }
+
+    #[test]
+    fn init_and_attach() {
+        let len = ShmSemaphore::SIZE + 8;
+        let mut region = vec![0u64; len / 8];
+        let ptr = region.as_mut_ptr() as *mut u8;
+        assert_eq!(Some(ShmError::NotInitialized), unsafe {
+            ShmSemaphore::attach(ptr, len).err()
+        });
+        assert_eq!(Some(ShmError::TooSmall), unsafe {
+            ShmSemaphore::init(ptr, 2, 1).err()
+        });
+        assert_eq!(Some(ShmError::Misaligned), unsafe {
+            ShmSemaphore::init(ptr.add(1), len - 1, 1).err()
+        });
+        let sem = unsafe { ShmSemaphore::init(ptr, len, 1) }.unwrap();
+        let other = unsafe { ShmSemaphore::attach(ptr, len) }.unwrap();
+        thread::scope(|s| {
+            sem.wait();
+            let waiter = s.spawn(|| other.wait());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            sem.release();
+        });
+        assert_eq!(0, other.get_current_value());
+        // nobody died holding anything
+        assert_eq!(0, sem.reclaim_dead());
+    }
+
+    #[test]
+    fn across_fork() {
+        let len = ShmSemaphore::SIZE;
+        let ptr = map_shared(len);
+        let sem = unsafe { ShmSemaphore::init(ptr, len, 0) }.unwrap();
+        let child = fork(|| match unsafe { ShmSemaphore::attach(ptr, len) } {
+            Ok(sem) => {
+                sem.release();
+                true
+            }
+            Err(_) => false,
+        });
+        assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        assert!(join(child));
+        unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+    }
+
+    #[test]
+    fn reclaim_from_dead_process() {
+        let len = ShmSemaphore::SIZE;
+        let ptr = map_shared(len);
+        let sem = unsafe { ShmSemaphore::init(ptr, len, 2) }.unwrap();
+        // the child takes both resources and dies without releasing them
+        let child = fork(|| match unsafe { ShmSemaphore::attach(ptr, len) } {
+            Ok(sem) => sem.try_wait() && sem.try_wait(),
+            Err(_) => false,
+        });
+        assert!(join(child));
+        assert_eq!(0, sem.get_current_value());
+        // a waiter notices the dead holder and takes over its resources
+        assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        assert_eq!(1, sem.get_current_value());
+        assert_eq!(0, sem.reclaim_dead());
+        sem.release();
+        assert_eq!(2, sem.get_current_value());
+        unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+    }
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::ptr;
+use std::time::Instant;
+
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, ERROR_ALREADY_EXISTS, HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT,
+};
+use windows_sys::Win32::System::Threading::{
+    CreateSemaphoreW, OpenSemaphoreW, ReleaseSemaphore, WaitForSingleObject, INFINITE,
+    SEMAPHORE_ALL_ACCESS,
+};
+
+use crate::AcquireError;
+
+/// Windows named semaphore, from `CreateSemaphoreW`
+pub(super) struct NamedSemaphore {
+    handle: HANDLE,
+}
+
+// SAFETY: semaphore handles are meant to be used from several threads (and
+// processes) at once.
+unsafe impl Send for NamedSemaphore {}
+unsafe impl Sync for NamedSemaphore {}
+
+fn wide_name(name: &str) -> io::Result<Vec<u16>> {
+    if name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "semaphore name contains a nul character",
+        ));
+    }
+    Ok(name.encode_utf16().chain(Some(0)).collect())
+}
+
+impl NamedSemaphore {
+    pub(super) fn create(name: &str, initial_value: u32) -> io::Result<Self> {
+        let name = wide_name(name)?;
+        let initial = i32::try_from(initial_value)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // SAFETY: `name` is a nul terminated wide string
+        let handle = unsafe { CreateSemaphoreW(ptr::null(), initial, i32::MAX, name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let sem = Self { handle };
+        // SAFETY: no call since CreateSemaphoreW
+        if unsafe { GetLastError() } == ERROR_ALREADY_EXISTS {
+            // we were given the existing semaphore, drop it like sem_open
+            // does with O_EXCL
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        Ok(sem)
+    }
+
+    pub(super) fn open(name: &str) -> io::Result<Self> {
+        let name = wide_name(name)?;
+        // SAFETY: `name` is a nul terminated wide string
+        let handle = unsafe { OpenSemaphoreW(SEMAPHORE_ALL_ACCESS, 0, name.as_ptr()) };
+        if handle.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Self { handle })
+        }
+    }
+
+    /// Windows removes the name along with the last handle to the semaphore,
+    /// so there is nothing to do.
+    pub(super) fn unlink(name: &str) -> io::Result<()> {
+        wide_name(name).map(|_| ())
+    }
+
+    pub(super) fn wait(&self) {
+        // SAFETY: `self.handle` stays open until drop
+        let r = unsafe { WaitForSingleObject(self.handle, INFINITE) };
+        if r != WAIT_OBJECT_0 {
+            panic!("WaitForSingleObject failed: {}", io::Error::last_os_error());
+        }
+    }
+
+    pub(super) fn try_wait(&self) -> bool {
+        self.wait_ms(0)
+    }
+
+    pub(super) fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // round up so that we never give up before the deadline, and stay
+            // clear of INFINITE
+            let ms = (remaining.as_nanos() + 999_999) / 1_000_000;
+            let ms = u32::try_from(ms).unwrap_or(INFINITE - 1).min(INFINITE - 1);
+            if self.wait_ms(ms) {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(AcquireError::TimedOut);
+            }
+        }
+    }
+
+    fn wait_ms(&self, ms: u32) -> bool {
+        // SAFETY: `self.handle` stays open until drop
+        match unsafe { WaitForSingleObject(self.handle, ms) } {
+            WAIT_OBJECT_0 => true,
+            WAIT_TIMEOUT => false,
+            _ => panic!("WaitForSingleObject failed: {}", io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn release(&self) {
+        // SAFETY: `self.handle` stays open until drop
+        if unsafe { ReleaseSemaphore(self.handle, 1, ptr::null_mut()) } == 0 {
+            panic!("ReleaseSemaphore failed: {}", io::Error::last_os_error());
+        }
+    }
+
+    /// Windows has no call to read the value of a semaphore: take a
+    /// resource, if any, and read the previous value while giving it back.
+    pub(super) fn get_current_value(&self) -> u32 {
+        if !self.wait_ms(0) {
+            return 0;
+        }
+        let mut previous = 0;
+        // SAFETY: `self.handle` stays open until drop
+        unsafe { ReleaseSemaphore(self.handle, 1, &mut previous) };
+        previous as u32 + 1
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        // SAFETY: `self.handle` was opened by us and is closed only here
+        unsafe { CloseHandle(self.handle) };
+    }
+}
@@ -0,0 +1,93 @@
+//! Parallel adapters of iterators.
+
+use std::vec;
+
+use crate::worker_threads::{filter_map, for_each, process};
+
+/// Adapters running the steps of an iterator chain on worker threads
+///
+/// Every adapter processes the items as the function of
+/// [`worker_threads`](crate::worker_threads) it is named after does: it
+/// spawns `workers` threads, zero meaning one per CPU, and returns once all
+/// the items are processed. The results are collected in the order of the
+/// input and handed back as an iterator, so that the chain goes on from
+/// there, and the closures may borrow what outlives the call.
+///
+/// # Examples
+///
+/// ```
+/// use esync::iter::ParallelExt;
+///
+/// let words = ["alpha", "beta", "gamma", "delta"];
+/// let long = words
+///     .iter()
+///     .par_map(|w| w.to_uppercase(), 2)
+///     .par_filter(|w| w.len() > 4, 2)
+///     .collect::<Vec<_>>();
+/// assert_eq!(vec!["ALPHA", "GAMMA", "DELTA"], long);
+/// ```
+pub trait ParallelExt: Iterator + Sized {
+    /// Maps every item with `f`, as [`process`] does.
+    fn par_map<F, R>(self, f: F, workers: usize) -> vec::IntoIter<R>
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) -> R + Sync,
+        R: Send,
+    {
+        process(self, f, workers).into_iter()
+    }
+
+    /// Keeps the items `f` returns `true` for, as [`filter_map`] does.
+    fn par_filter<F>(self, f: F, workers: usize) -> vec::IntoIter<Self::Item>
+    where
+        Self::Item: Send,
+        F: Fn(&Self::Item) -> bool + Sync,
+    {
+        filter_map(
+            self,
+            |item| if f(&item) { Some(item) } else { None },
+            workers,
+        )
+        .into_iter()
+    }
+
+    /// Calls `f` on every item, as [`for_each`] does, ending the chain.
+    fn par_for_each<F>(self, f: F, workers: usize)
+    where
+        Self::Item: Send,
+        F: Fn(Self::Item) + Sync,
+    {
+        for_each(self, f, workers)
+    }
+}
+
+impl<I: Iterator> ParallelExt for I {}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::ParallelExt;
+
+    #[test]
+    fn chain() {
+        let total = AtomicUsize::new(0);
+        (0..100)
+            .par_map(|i| i * 3, 4)
+            .par_filter(|i| i % 2 == 0, 4)
+            .map(|i| i + 1)
+            .par_for_each(
+                |i| {
+                    total.fetch_add(i, Ordering::Relaxed);
+                },
+                4,
+            );
+        let expected = (0..100)
+            .map(|i| i * 3)
+            .filter(|i| i % 2 == 0)
+            .map(|i| i + 1);
+        assert_eq!(expected.sum::<usize>(), total.into_inner());
+        let kept = (0..10).par_filter(|i| i % 3 == 0, 2).collect::<Vec<_>>();
+        assert_eq!(vec![0, 3, 6, 9], kept);
+    }
+}
@@ -1,4 +1,64 @@
+//! Synchronization primitives.
+//!
+//! With the default `std` feature the crate builds on the standard library.
+//! Without it the crate is `no_std`, only needs `alloc`, and offers the
+//! untimed operations of [`Semaphore`], [`mutex::Mutex`] and
+//! [`CancellationToken`]: waiters then spin, and the internal locks are taken
+//! in a [critical section](https://docs.rs/critical-section), so that
+//! interrupt handlers may release resources.
+//!
+//! With the `tracing` feature, every task of a [`pool::ThreadPool`] and every
+//! item of a [`worker_threads`] processing runs in an `esync` span of the
+//! [`tracing`](https://docs.rs/tracing) crate, child of the span it was
+//! submitted in. Its fields are the `kind` of work, `task` or `item`, the
+//! `id` of the task or the position of the item, the `thread` running it,
+//! the time it waited to start as `wait_us`, and the time it ran as
+//! `busy_us`, recorded once it completes.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod sync;
+
 pub mod semaphore;
-pub use semaphore::Semaphore;
+#[cfg(feature = "async")]
+pub use semaphore::{Acquire, OwnedPermit, Permits};
+pub use semaphore::{
+    AcquireError, FairnessPolicy, ReleaseError, Semaphore, SpinConfig, TryAcquireError,
+};
+#[cfg(feature = "std")]
+pub use semaphore::{Lease, SemaphoreStats, WaitSet};
+
+pub mod cancel;
+pub use cancel::CancellationToken;
+
+pub mod mutex;
+
+#[cfg(all(feature = "std", target_os = "linux", not(loom)))]
+pub mod futex;
 
+#[cfg(all(feature = "std", target_os = "linux", not(loom)))]
+pub mod eventfd;
+
+#[cfg(all(feature = "std", any(target_os = "linux", windows), not(loom)))]
+pub mod ipc;
+
+#[cfg(all(feature = "std", not(loom)))]
+pub mod pool;
+
+#[cfg(all(feature = "std", not(loom)))]
 pub mod worker_threads;
+
+#[cfg(all(feature = "std", not(loom)))]
+pub mod iter;
+
+#[cfg(all(feature = "std", not(loom)))]
+pub mod slice;
+
+#[cfg(all(feature = "std", not(loom)))]
+mod affinity;
+
+#[cfg(all(feature = "std", not(loom)))]
+mod trace;
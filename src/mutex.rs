@@ -0,0 +1,212 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use crate::Semaphore;
+
+/// Mutual exclusion lock built on a binary [`Semaphore`]
+///
+/// Unlike `std::sync::Mutex`, the guard is not tied to the thread that took
+/// the lock: it can be sent to another thread, which then unlocks by
+/// dropping it. Waiters are served in arrival order. The lock is never
+/// poisoned.
+///
+/// # Examples
+///
+/// Lock on one thread and hand the guard over to another one, which unlocks.
+/// ```
+/// # use esync::mutex::Mutex;
+/// # use std::thread;
+/// let m = Mutex::new(0);
+/// let mut guard = m.lock();
+/// *guard += 1;
+/// thread::scope(|s| {
+///     s.spawn(move || {
+///         *guard += 1;
+///     });
+/// });
+/// assert_eq!(2, *m.lock());
+/// ```
+pub struct Mutex<T: ?Sized> {
+    sem: Semaphore,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: the semaphore grants access to the data to a single guard at a
+// time, so sharing the mutex only requires the data to be sendable.
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// RAII guard giving access to the data of a locked [`Mutex`]
+///
+/// The lock is released when the guard is dropped, on whatever thread that
+/// happens.
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+// SAFETY: the guard is an exclusive handle to the data, so moving it to
+// another thread is moving `&mut T`, and sharing it is sharing `&T`.
+unsafe impl<T: ?Sized + Send> Send for MutexGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    const_fn! {
+        /// Creates an unlocked mutex holding `data`
+        pub fn new(data: T) -> Self {
+            Self {
+                sem: Semaphore::with_max(1, 1),
+                data: UnsafeCell::new(data),
+            }
+        }
+    }
+
+    /// Consumes the mutex and returns the data it holds
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the lock, waiting for the current holder to release it.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        // the semaphore is private to the mutex and never closed
+        self.sem.wait().unwrap();
+        MutexGuard { mutex: self }
+    }
+
+    /// Attempts to acquire the lock without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.sem.try_wait() {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    /// Acquires the lock, waiting at most `timeout` for it.
+    #[cfg(feature = "std")]
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+        self.sem
+            .wait_timeout(timeout)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    /// Acquires the lock, waiting until `deadline` at most for it.
+    #[cfg(feature = "std")]
+    pub fn lock_deadline(&self, deadline: Instant) -> Option<MutexGuard<'_, T>> {
+        self.sem
+            .wait_deadline(deadline)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.sem.get_current_value() == 0
+    }
+
+    /// Gives mutable access to the data, which the exclusive borrow
+    /// guarantees no guard is holding
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the only permit of the semaphore
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the only permit of the semaphore
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.sem.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Mutex, MutexGuard};
+
+    #[test]
+    fn lock_and_unlock() {
+        let m = Mutex::new(vec![1]);
+        m.lock().push(2);
+        assert!(!m.is_locked());
+        let guard = m.lock();
+        assert!(m.is_locked());
+        assert!(m.try_lock().is_none());
+        drop(guard);
+        assert_eq!(vec![1, 2], *m.try_lock().unwrap());
+        assert_eq!(vec![1, 2], m.into_inner());
+    }
+
+    #[test]
+    fn timed_lock() {
+        let m = Mutex::new(());
+        let _guard = m.lock();
+        let start = Instant::now();
+        assert!(m.lock_timeout(Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(m
+            .lock_deadline(Instant::now() + Duration::from_millis(10))
+            .is_none());
+    }
+
+    #[test]
+    fn unlock_on_another_thread() {
+        let m = Mutex::new(0);
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut guard: MutexGuard<i32> = rx.recv().unwrap();
+                thread::sleep(Duration::from_millis(50));
+                *guard += 1;
+            });
+            tx.send(m.lock()).unwrap();
+            // the guard now lives on the other thread
+            assert_eq!(1, *m.lock());
+        });
+    }
+
+    #[test]
+    fn counter() {
+        let m = Mutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(8000, *m.lock());
+    }
+}
@@ -0,0 +1,2317 @@
+//! Long-lived pool of worker threads.
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::sync::{self, Mutex};
+use crate::trace::{self, Parent, Stamp};
+use crate::worker_threads::{worker_count, Affinity, ThreadConfig};
+use crate::{CancellationToken, Semaphore, WaitSet};
+
+mod broadcast;
+mod child;
+mod global;
+mod graph;
+mod metrics;
+#[cfg(feature = "rayon")]
+mod rayon;
+mod scope;
+mod shutdown;
+mod timer;
+pub use child::ChildPool;
+pub use global::{global, AlreadyBuilt};
+pub use graph::{NodeId, TaskGraph, SKIPPED};
+pub use metrics::PoolMetrics;
+use metrics::{run_task, Counters};
+pub use scope::{Scope, ScopedTaskHandle};
+use timer::Timers;
+
+/// Closure queued for a worker
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Jobs queued for a worker
+type Deque = Mutex<Jobs>;
+
+/// Jobs by priority, each priority in submission order
+#[derive(Default)]
+struct Jobs {
+    levels: BTreeMap<i32, VecDeque<(Instant, Job)>>,
+    /// Number of jobs taken to run, and the time they waited in all
+    waits: u64,
+    waited: Duration,
+}
+
+/// State shared between the pool and its workers
+struct Shared {
+    /// Queue of every worker slot, grown along with the pool and replaced
+    /// as a whole then, so that workers scan a snapshot of it
+    deques: Mutex<Arc<Vec<Arc<Deque>>>>,
+    /// Slots whose worker retired, taken by the next workers spawned
+    free: Mutex<Vec<usize>>,
+    /// Slot the next task submitted from outside the pool goes to
+    next: AtomicUsize,
+    /// Number of jobs queued and not claimed by a worker yet
+    queued: AtomicUsize,
+    /// Number of workers to retire, all of them once the pool is dropped or
+    /// shut down
+    retiring: AtomicUsize,
+    /// Number of workers to wake up to keep track of a job scheduled ahead
+    /// of the others
+    wakeups: AtomicUsize,
+    /// Number of queued jobs plus workers to retire or wake up: a worker
+    /// wakes up to claim one or the other
+    ready: Semaphore,
+    /// Room left in a bounded queue, given back as jobs are claimed
+    room: Option<Semaphore>,
+    /// Number of jobs queued over the capacity of a bounded queue, whose
+    /// claims give back no room
+    over: AtomicUsize,
+    /// Delayed jobs, queued by the idle workers once due
+    timers: Mutex<Timers>,
+    /// Taken by a broadcast while it runs, since two of them at once could
+    /// share the workers out and wait for each other
+    broadcasting: Semaphore,
+    overflow: Overflow,
+    /// Time after which a queued job gains a priority level
+    aging: Option<Duration>,
+    /// Jobs waiting for the previous one of their key to complete, by hash
+    /// of the key. A key is there while one of its jobs is queued or
+    /// running.
+    keyed: Mutex<HashMap<u64, VecDeque<Job>>>,
+    counters: Counters,
+    /// Set once the pool is shut down, and takes no job anymore
+    shut: AtomicBool,
+    /// Cancelled by an immediate shutdown
+    token: CancellationToken,
+    /// Number of worker threads running
+    live: AtomicUsize,
+    /// Released once the last worker stopped after a shutdown
+    terminated: Semaphore,
+    panic_policy: PanicPolicy,
+    /// Workers spawned to replace those whose task panicked, joined along
+    /// with the others
+    respawned: Mutex<Vec<(usize, JoinHandle<()>)>>,
+    /// Number of workers waiting for a job
+    idle: AtomicUsize,
+    /// Number of workers spawned over the size of the pool under load, the
+    /// first ones idle for `keep_alive` stopping
+    extra: AtomicUsize,
+    keep_alive: Option<Duration>,
+}
+
+/// Job running the jobs of a key one after the other, as they come
+struct Runner {
+    shared: Arc<Shared>,
+    key: u64,
+    /// Next job to run
+    job: Option<Job>,
+}
+
+thread_local! {
+    /// Pool and slot of the worker running on this thread, so that the
+    /// tasks it submits go to its own queue
+    static WORKER: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+    /// Set once a task panicked on this worker, which is then to be
+    /// replaced
+    static PANICKED: Cell<bool> = const { Cell::new(false) };
+    /// Id and abort flag of the task running on this thread, if any, read
+    /// by [`task_id`] and [`is_aborted`]
+    static CURRENT: Cell<Option<(usize, *const AtomicBool)>> = const { Cell::new(None) };
+    /// State the [`worker_state`](PoolBuilder::worker_state) hook made for
+    /// the worker running on this thread, taken out while a task uses it
+    static STATE: RefCell<Option<Box<dyn Any>>> = const { RefCell::new(None) };
+}
+
+/// States of a [`Task`]
+const QUEUED: usize = 0;
+const STARTED: usize = 1;
+const ABORTED: usize = 2;
+
+/// Outcome of a task, filled in by the worker that ran it
+struct Task<R> {
+    result: Mutex<Option<Result<R, TaskError>>>,
+    done: Semaphore,
+    /// [`QUEUED`] until a worker starts the task or its handle aborts it
+    /// first
+    state: AtomicUsize,
+    /// Set once the handle asked to abort the task
+    aborted: AtomicBool,
+    id: usize,
+}
+
+/// Releases its semaphore when dropped, as a worker is ready for tasks or
+/// fails to start
+struct Started(Option<Arc<Semaphore>>);
+
+/// Completes a task when dropped, as dropped from the queue unless a result
+/// was set
+struct Completion<R>(Arc<Task<R>>);
+
+/// What submitting to a full bounded queue does, see
+/// [`ThreadPool::bounded`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for a worker to take a task off the queue
+    Block,
+    /// Fail the submission, giving the closure back
+    Reject,
+    /// Drop the oldest of the lowest priority tasks queued for the worker
+    /// the new one goes to, or for another worker if it has none, to make
+    /// room. Its handle reports
+    /// it as [dropped](TaskError::Dropped).
+    DropOldest,
+}
+
+/// What a pool does when one of its tasks panics, see
+/// [`PoolBuilder::panic_policy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Report the panic to the joiner of the task, and keep the worker
+    #[default]
+    Propagate,
+    /// Report the panic to the joiner of the task, and replace the worker
+    /// with a new thread, whose thread-local state the panic did not leave
+    /// half updated
+    Restart,
+    /// Abort the process, for a panicking task leaves things in a state the
+    /// application may not go on from
+    Abort,
+}
+
+/// Panic payload reported for a node of a [`TaskGraph`] or a run of a
+/// [broadcast](ThreadPool::broadcast) dropped before it ran: from a full
+/// queue with [`Overflow::DropOldest`], or by the
+/// [shutdown](ThreadPool::shutdown) of its pool
+pub const DROPPED: &str = "task dropped before it ran";
+
+/// Reason a task submitted to a [`ThreadPool`] produced no result, reported
+/// by [`TaskHandle::join`]
+pub enum TaskError {
+    /// The task panicked, with this payload
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The task was [aborted](TaskHandle::abort) before it started
+    Aborted,
+    /// The task was dropped before it ran: from a full queue with
+    /// [`Overflow::DropOldest`], or by the [shutdown](ThreadPool::shutdown)
+    /// of its pool
+    Dropped,
+}
+
+/// Error of a submission to a full queue with [`Overflow::Reject`], holding
+/// the closure that was not queued
+pub struct Full<F>(pub F);
+
+/// Configuration of a [`ThreadPool`], created with [`ThreadPool::builder`]
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::{Overflow, ThreadPool};
+/// let pool = ThreadPool::builder()
+///     .workers(2)
+///     .bounded(64, Overflow::Block)
+///     .thread_name("esync-worker")
+///     .stack_size(256 * 1024)
+///     .build();
+/// let name = pool.submit(|| std::thread::current().name().map(String::from));
+/// assert!(name.join().unwrap().unwrap().starts_with("esync-worker-"));
+/// ```
+#[derive(Clone)]
+pub struct PoolBuilder {
+    workers: usize,
+    /// Capacity of a bounded queue, and what overflowing it does
+    queue: Option<(usize, Overflow)>,
+    threads: ThreadConfig,
+    on_start: Option<WorkerHook>,
+    on_stop: Option<WorkerHook>,
+    /// Makes and tears down the state of every worker
+    state: Option<(StateInit, StateTeardown)>,
+    aging: Option<Duration>,
+    panic_policy: PanicPolicy,
+    /// Whether spawning workers waits for them to be ready for tasks
+    prestart: bool,
+    /// Number of workers the pool may grow to under load, and how long the
+    /// extra ones stay idle before they stop
+    max_workers: Option<(usize, Duration)>,
+}
+
+/// Callback run on a worker thread as it starts or stops, given the number
+/// of the worker
+type WorkerHook = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// Makes the state of a worker as it starts, given the number of the worker
+type StateInit = Arc<dyn Fn(usize) -> Box<dyn Any> + Send + Sync>;
+
+/// Tears down the state of a worker as it stops
+type StateTeardown = Arc<dyn Fn(usize, Box<dyn Any>) + Send + Sync>;
+
+/// Set of worker threads running submitted closures
+///
+/// The threads are spawned when the pool is created, and every one has its
+/// own queue of closures. The closures submitted from outside the pool are
+/// spread across the queues in turn, or by the hash of a key with
+/// [`submit_hashed`](ThreadPool::submit_hashed), and those submitted by a
+/// closure running on the pool go to the queue of its thread. A thread runs the
+/// closures of its queue in submission order, and steals those of the
+/// others once it runs out, so that a long closure does not hold up the
+/// ones queued behind it while other threads are idle.
+///
+/// The pool may be [resized](ThreadPool::resize) on the fly. Dropping the
+/// pool waits for all the submitted closures to complete, then stops the
+/// threads; [shutting it down](ThreadPool::shutdown) does not wait, and may
+/// drop the queued closures.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::ThreadPool;
+/// let pool = ThreadPool::new(4);
+/// let handles = (0..8).map(|i| pool.submit(move || i * i)).collect::<Vec<_>>();
+/// let squares = handles.into_iter().map(|h| h.join().unwrap());
+/// assert_eq!(140, squares.sum::<i32>());
+/// ```
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    /// Configuration of the workers spawned
+    config: PoolBuilder,
+    workers: Mutex<Workers>,
+}
+
+/// Worker threads of a pool
+struct Workers {
+    /// Threads spawned, with their number, some of which may have been
+    /// retired already
+    threads: Vec<(usize, JoinHandle<()>)>,
+    /// Number of workers the pool is to run, once the retired ones are gone
+    target: usize,
+}
+
+/// Handle on the result of a closure submitted to a [`ThreadPool`]
+///
+/// Dropping the handle does not cancel the closure, only discards its
+/// result; [`abort`](TaskHandle::abort) does.
+#[must_use = "dropping the handle discards the result of the task"]
+pub struct TaskHandle<R> {
+    task: Arc<Task<R>>,
+}
+
+impl ThreadPool {
+    /// Spawns a pool of `workers` threads, zero meaning one per available
+    /// CPU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread cannot be spawned.
+    pub fn new(workers: usize) -> Self {
+        Self::builder().workers(workers).build()
+    }
+
+    /// Spawns a pool of `workers` threads, queueing at most `capacity` tasks
+    /// waiting for them. Submitting while the queue is full does what
+    /// `overflow` says.
+    ///
+    /// A bounded queue makes an overloaded pool push back on its submitters,
+    /// rather than piling up tasks until memory runs out.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero, or if a thread cannot be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::{Overflow, ThreadPool};
+    /// let pool = ThreadPool::bounded(2, 16, Overflow::Block);
+    /// // the submitter waits whenever 16 tasks are already queued
+    /// let handles = (0..100).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+    /// let sum = handles.into_iter().map(|h| h.join().unwrap()).sum::<i32>();
+    /// assert_eq!(4950, sum);
+    /// ```
+    pub fn bounded(workers: usize, capacity: usize, overflow: Overflow) -> Self {
+        Self::builder()
+            .workers(workers)
+            .bounded(capacity, overflow)
+            .build()
+    }
+
+    /// Starts configuring a pool, with one worker per available CPU and an
+    /// unbounded queue.
+    pub fn builder() -> PoolBuilder {
+        PoolBuilder {
+            workers: 0,
+            queue: None,
+            threads: ThreadConfig::default(),
+            on_start: None,
+            on_stop: None,
+            state: None,
+            aging: None,
+            panic_policy: PanicPolicy::Propagate,
+            prestart: false,
+            max_workers: None,
+        }
+    }
+
+    /// Number of worker threads of the pool, not counting the ones being
+    /// retired nor the extra ones spawned under
+    /// [load](PoolBuilder::max_workers)
+    pub fn workers(&self) -> usize {
+        sync::lock(&self.workers).target
+    }
+
+    /// Changes the number of worker threads to `workers`, zero meaning one
+    /// per available CPU.
+    ///
+    /// Growing the pool spawns the new threads right away. Shrinking it
+    /// retires threads as they become idle, once the queue is empty: the
+    /// tasks already running are not interrupted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread cannot be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// let pool = ThreadPool::new(2);
+    /// pool.resize(8);
+    /// assert_eq!(8, pool.workers());
+    /// pool.resize(1);
+    /// assert_eq!(Ok(4), pool.submit(|| 4).join().map_err(|_| ()));
+    /// ```
+    pub fn resize(&self, workers: usize) {
+        let workers = worker_count(workers);
+        let mut w = sync::lock(&self.workers);
+        if self.shared.is_shut_down() {
+            return;
+        }
+        if workers > w.target {
+            let started = self.config.started();
+            for _ in w.target..workers {
+                let n = w.number(&self.shared);
+                let t = self.config.spawn_worker(&self.shared, n, started.clone());
+                w.threads.push((n, t));
+            }
+            self.config.wait_started(started, workers - w.target);
+        } else {
+            // a worker leaves on a wakeup that finds no job to claim
+            self.shared.retire(w.target - workers);
+        }
+        w.target = workers;
+    }
+
+    /// Queues `f` to run on one of the workers, and returns a handle on its
+    /// result.
+    ///
+    /// A panic in `f` does not take the worker down: it is caught and
+    /// reported by [`TaskHandle::join`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::{TaskError, ThreadPool};
+    /// let pool = ThreadPool::new(2);
+    /// let handle = pool.submit(|| 6 * 7);
+    /// assert_eq!(42, handle.join().unwrap());
+    /// let failed = pool.submit(|| panic!("oops"));
+    /// assert!(matches!(failed.join(), Err(TaskError::Panicked(_))));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue is full and the pool rejects tasks on overflow;
+    /// [`try_submit`](Self::try_submit) lets the caller handle that.
+    pub fn submit<F, R>(&self, f: F) -> TaskHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.submit_with_priority(0, f)
+    }
+
+    /// Queues `f` to run on one of the workers, as
+    /// [`submit`](Self::submit) does, but gives it back if the queue is full
+    /// and the pool rejects tasks on overflow.
+    pub fn try_submit<F, R>(&self, f: F) -> Result<TaskHandle<R>, Full<F>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.try_submit_with_priority(0, f)
+    }
+
+    /// Queues `f` to run on one of the workers, as
+    /// [`submit`](Self::submit) does, given the state of that worker.
+    ///
+    /// The state is the one made by the
+    /// [`worker_state`](PoolBuilder::worker_state) hook of the pool. The
+    /// task fails with [`TaskError::Panicked`] if the workers hold no state
+    /// of type `S`, or if it runs while a task of the same worker uses the
+    /// state, i.e. within a [`join`](TaskHandle::join) of such a task.
+    pub fn submit_with_state<S, F, R>(&self, f: F) -> TaskHandle<R>
+    where
+        S: 'static,
+        F: FnOnce(&mut S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.submit(move || with_state(f))
+    }
+
+    /// Queues `f` to run on one of the workers ahead of the tasks with a
+    /// lower `priority`, as [`submit`](Self::submit) does.
+    ///
+    /// [`submit`](Self::submit) uses priority 0. An idle worker takes the
+    /// task with the highest priority among those queued for it and for the
+    /// other workers, preferring its own on a tie. The tasks of a priority
+    /// run in submission order. Unless the pool was built with
+    /// [`aging`](PoolBuilder::aging), low priority tasks wait for as long as
+    /// higher ones keep coming.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// let pool = ThreadPool::new(1);
+    /// let batch = pool.submit_with_priority(-10, || "batch");
+    /// let request = pool.submit_with_priority(10, || "request");
+    /// assert_eq!("request", request.join().unwrap());
+    /// assert_eq!("batch", batch.join().unwrap());
+    /// ```
+    pub fn submit_with_priority<F, R>(&self, priority: i32, f: F) -> TaskHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.try_submit_with_priority(priority, f) {
+            Ok(handle) => handle,
+            Err(_) => panic!("the queue of the thread pool is full"),
+        }
+    }
+
+    /// Queues `f` to run on one of the workers ahead of the tasks with a
+    /// lower `priority`, as
+    /// [`submit_with_priority`](Self::submit_with_priority) does, but gives
+    /// it back if the queue is full and the pool rejects tasks on overflow.
+    pub fn try_submit_with_priority<F, R>(
+        &self,
+        priority: i32,
+        f: F,
+    ) -> Result<TaskHandle<R>, Full<F>>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut handle = None;
+        let queued = self.shared.submit(priority, f, |f| {
+            let (job, h) = job(f);
+            handle = Some(h);
+            job
+        });
+        match queued {
+            Ok(()) => {
+                self.grow();
+                Ok(handle.expect("queued tasks have a handle"))
+            }
+            Err(f) => Err(Full(f)),
+        }
+    }
+
+    /// Spawns an extra worker if jobs are queued with no idle worker to
+    /// take them, and the pool may grow
+    fn grow(&self) {
+        let max = match self.config.max_workers {
+            Some((max, _)) => max,
+            None => return,
+        };
+        let shared = &self.shared;
+        if shared.queued.load(Ordering::SeqCst) <= shared.idle.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut w = sync::lock(&self.workers);
+        if shared.is_shut_down() || w.target + shared.extra.load(Ordering::SeqCst) >= max {
+            return;
+        }
+        shared.extra.fetch_add(1, Ordering::SeqCst);
+        let n = w.number(shared);
+        let t = self.config.spawn_worker(shared, n, None);
+        w.threads.push((n, t));
+    }
+
+    /// Queues `f` to run on the worker `key` hashes to, as
+    /// [`submit`](Self::submit) does.
+    ///
+    /// Tasks submitted with the same key go to the queue of the same worker,
+    /// rather than to the queues of the workers in turn, so that tasks on the
+    /// same data run where that data is in cache. They still run in parallel
+    /// and in no particular order, unlike those of
+    /// [`submit_keyed`](Self::submit_keyed): the other workers steal them
+    /// once out of tasks of their own. Keys map to another worker once the
+    /// pool is [resized](Self::resize).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue is full and the pool rejects tasks on overflow.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// let pool = ThreadPool::new(4);
+    /// let shards = (0..8u32)
+    ///     .map(|shard| pool.submit_hashed(shard % 4, move || shard * 10))
+    ///     .collect::<Vec<_>>();
+    /// let total = shards.into_iter().map(|h| h.join().unwrap()).sum::<u32>();
+    /// assert_eq!(280, total);
+    /// ```
+    pub fn submit_hashed<K, F, R>(&self, key: K, f: F) -> TaskHandle<R>
+    where
+        K: Hash,
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let mut handle = None;
+        let queued = self.shared.submit_at(Some(hash_key(key)), 0, f, |f| {
+            let (job, h) = job(f);
+            handle = Some(h);
+            job
+        });
+        if queued.is_err() {
+            panic!("the queue of the thread pool is full");
+        }
+        self.grow();
+        handle.expect("queued tasks have a handle")
+    }
+
+    /// Queues `f` to run on one of the workers once the tasks submitted
+    /// before with the same `key` completed, as [`submit`](Self::submit)
+    /// does.
+    ///
+    /// The tasks of a key run one after the other, in submission order, e.g.
+    /// the events of a user; tasks with different keys run in parallel.
+    /// Keys are told apart by their hash, so that in the unlikely event of
+    /// two keys hashing alike their tasks run one after the other too.
+    ///
+    /// A single task of a key is queued at a time: the next ones wait apart,
+    /// and do not count in the capacity of a bounded queue. They run on the
+    /// worker that ran the previous one, right after it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue is full and the pool rejects tasks on overflow.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::sync::{Arc, Mutex};
+    /// let pool = ThreadPool::new(4);
+    /// let log = Arc::new(Mutex::new(vec![]));
+    /// let handles = (0..10)
+    ///     .map(|i| {
+    ///         let log = log.clone();
+    ///         pool.submit_keyed("user-1", move || log.lock().unwrap().push(i))
+    ///     })
+    ///     .collect::<Vec<_>>();
+    /// handles.into_iter().for_each(|h| h.join().unwrap());
+    /// assert_eq!((0..10).collect::<Vec<_>>(), *log.lock().unwrap());
+    /// ```
+    pub fn submit_keyed<K, F, R>(&self, key: K, f: F) -> TaskHandle<R>
+    where
+        K: Hash,
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let key = hash_key(key);
+        let (job, handle) = job(f);
+        if self.shared.is_shut_down() {
+            return handle;
+        }
+        {
+            let mut keyed = sync::lock(&self.shared.keyed);
+            if let Some(waiting) = keyed.get_mut(&key) {
+                waiting.push_back(job);
+                return handle;
+            }
+            keyed.insert(key, VecDeque::new());
+        }
+        let runner = Runner {
+            shared: self.shared.clone(),
+            key,
+            job: Some(job),
+        };
+        if self.shared.submit(0, runner, Runner::job).is_err() {
+            panic!("the queue of the thread pool is full");
+        }
+        self.grow();
+        handle
+    }
+}
+
+fn hash_key<K: Hash>(key: K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Job running `f`, and a handle on its result
+fn job<'a, F, R>(f: F) -> (Box<dyn FnOnce() + Send + 'a>, TaskHandle<R>)
+where
+    F: FnOnce() -> R + Send + 'a,
+    R: Send + 'a,
+{
+    let task = Arc::new(Task {
+        result: Mutex::new(None),
+        done: Semaphore::new(0),
+        state: AtomicUsize::new(QUEUED),
+        aborted: AtomicBool::new(false),
+        id: trace::next_id(),
+    });
+    let completion = Completion(task.clone());
+    let (parent, queued, id) = (Parent::current(), Stamp::now(), task.id);
+    let job = Box::new(move || {
+        let task = &completion.0;
+        let claimed =
+            task.state
+                .compare_exchange(QUEUED, STARTED, Ordering::SeqCst, Ordering::SeqCst);
+        if claimed.is_err() {
+            // aborted before it started
+            return;
+        }
+        // a task helping while it waits runs others on this thread
+        let outer = CURRENT.with(|c| c.replace(Some((id, &task.aborted))));
+        let result = run_task(|| parent.run("task", id, &queued, f));
+        CURRENT.with(|c| c.set(outer));
+        *sync::lock(&task.result) = Some(result.map_err(TaskError::Panicked));
+    });
+    (job, TaskHandle { task })
+}
+
+impl PoolBuilder {
+    /// Sets the number of worker threads, zero meaning one per available
+    /// CPU.
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Bounds the queue to `capacity` tasks, see [`ThreadPool::bounded`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn bounded(mut self, capacity: usize, overflow: Overflow) -> Self {
+        assert!(capacity > 0, "a bounded queue needs room for a task");
+        self.queue = Some((capacity, overflow));
+        self
+    }
+
+    /// Names the worker threads `name-0`, `name-1`, and so on.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.threads.name = Some(name.into());
+        self
+    }
+
+    /// Sets the stack size of the worker threads, in bytes, instead of the
+    /// default of [`std::thread`].
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.threads.stack_size = Some(size);
+        self
+    }
+
+    /// Pins the worker threads to CPU cores, see [`Affinity`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `affinity` holds no core.
+    pub fn affinity(mut self, affinity: Affinity) -> Self {
+        affinity.check();
+        self.threads.affinity = Some(affinity);
+        self
+    }
+
+    /// Runs `f` on every worker thread as it starts, before it takes any
+    /// task, e.g. to name or register the thread. State for the tasks is
+    /// better kept with [`worker_state`](Self::worker_state).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::sync::atomic::{AtomicUsize, Ordering};
+    /// static STARTED: AtomicUsize = AtomicUsize::new(0);
+    ///
+    /// let pool = ThreadPool::builder()
+    ///     .workers(2)
+    ///     .prestart(true)
+    ///     .on_start(|_| {
+    ///         STARTED.fetch_add(1, Ordering::SeqCst);
+    ///     })
+    ///     .build();
+    /// assert_eq!(2, STARTED.load(Ordering::SeqCst));
+    /// ```
+    pub fn on_start<F>(mut self, f: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_start = Some(Arc::new(f));
+        self
+    }
+
+    /// Runs `f` on every worker thread as it stops, once the pool is dropped
+    /// and the queue drained, e.g. to tear down what
+    /// [`on_start`](Self::on_start) set up.
+    pub fn on_stop<F>(mut self, f: F) -> Self
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.on_stop = Some(Arc::new(f));
+        self
+    }
+
+    /// Gives every worker a state of its own, made by `init` as the worker
+    /// starts, after [`on_start`](Self::on_start), and handed to
+    /// `teardown` as it stops, before [`on_stop`](Self::on_stop). Both are
+    /// given the number of the worker.
+    ///
+    /// The tasks submitted with
+    /// [`submit_with_state`](ThreadPool::submit_with_state) borrow the
+    /// state of the worker running them. A worker replaced under
+    /// [`PanicPolicy::Restart`] tears its state down and its replacement
+    /// makes a new one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// let pool = ThreadPool::builder()
+    ///     .workers(2)
+    ///     .worker_state(|_| Vec::<u8>::with_capacity(4096), |_, _| ())
+    ///     .build();
+    /// let len = pool.submit_with_state(|buffer: &mut Vec<u8>| {
+    ///     buffer.clear();
+    ///     buffer.extend_from_slice(b"reused");
+    ///     buffer.len()
+    /// });
+    /// assert_eq!(6, len.join().unwrap());
+    /// ```
+    pub fn worker_state<S, I, T>(mut self, init: I, teardown: T) -> Self
+    where
+        S: 'static,
+        I: Fn(usize) -> S + Send + Sync + 'static,
+        T: Fn(usize, S) + Send + Sync + 'static,
+    {
+        let init: StateInit = Arc::new(move |w| Box::new(init(w)));
+        let teardown: StateTeardown = Arc::new(move |w, state: Box<dyn Any>| {
+            if let Ok(state) = state.downcast::<S>() {
+                teardown(w, *state);
+            }
+        });
+        self.state = Some((init, teardown));
+        self
+    }
+
+    /// Makes the queued tasks gain a priority level every `period` they
+    /// wait, so that low priority tasks eventually run even while higher
+    /// ones keep coming, see
+    /// [`submit_with_priority`](ThreadPool::submit_with_priority).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::time::Duration;
+    /// // a batch job queued for a second is on par with a request 100
+    /// // levels above it
+    /// let pool = ThreadPool::builder()
+    ///     .aging(Duration::from_millis(10))
+    ///     .build();
+    /// assert_eq!(Ok(1), pool.submit_with_priority(-100, || 1).join().map_err(|_| ()));
+    /// ```
+    pub fn aging(mut self, period: Duration) -> Self {
+        assert!(period > Duration::ZERO, "tasks must age over some time");
+        self.aging = Some(period);
+        self
+    }
+
+    /// Sets what the pool does when a task panics, reporting the panic to
+    /// the joiner of the task by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::{PanicPolicy, ThreadPool};
+    /// let pool = ThreadPool::builder()
+    ///     .workers(1)
+    ///     .panic_policy(PanicPolicy::Restart)
+    ///     .build();
+    /// let first = pool.submit(|| panic!("corrupted state")).join();
+    /// assert!(first.is_err());
+    /// // the next task runs on a new thread
+    /// assert_eq!(Ok(1), pool.submit(|| 1).join().map_err(|_| ()));
+    /// ```
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// Lets the pool spawn extra workers while tasks are queued with no idle
+    /// worker to take them, up to `max` workers in all, zero meaning one per
+    /// available CPU. The extra workers stop once idle for `keep_alive`, so
+    /// that a bursty load does not keep threads parked between the bursts.
+    ///
+    /// The pool grows as tasks are [submitted](ThreadPool::submit) with
+    /// none of its workers idle, not as running tasks spawn others. A worker
+    /// spawned takes the lowest number that no live worker has, so the
+    /// numbers given to the hooks are reused across the bursts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `keep_alive` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::time::Duration;
+    /// // 4 workers at all times, up to 64 during a burst
+    /// let pool = ThreadPool::builder()
+    ///     .workers(4)
+    ///     .max_workers(64, Duration::from_secs(30))
+    ///     .build();
+    /// let burst = (0..100).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+    /// assert_eq!(4950, burst.into_iter().map(|h| h.join().unwrap()).sum::<i32>());
+    /// ```
+    pub fn max_workers(mut self, max: usize, keep_alive: Duration) -> Self {
+        assert!(
+            keep_alive > Duration::ZERO,
+            "extra workers must stay some time"
+        );
+        self.max_workers = Some((worker_count(max), keep_alive));
+        self
+    }
+
+    /// Makes [`build`](Self::build) return only once every worker thread
+    /// started, ran its [`on_start`](Self::on_start) hook and waits for
+    /// tasks, and so does growing the pool with
+    /// [`resize`](ThreadPool::resize), so that the first tasks submitted do
+    /// not wait for the threads to start.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::sync::atomic::{AtomicUsize, Ordering};
+    /// # use std::sync::Arc;
+    /// let started = Arc::new(AtomicUsize::new(0));
+    /// let s = started.clone();
+    /// let pool = ThreadPool::builder()
+    ///     .workers(4)
+    ///     .on_start(move |_| {
+    ///         s.fetch_add(1, Ordering::SeqCst);
+    ///     })
+    ///     .prestart(true)
+    ///     .build();
+    /// assert_eq!(4, started.load(Ordering::SeqCst));
+    /// ```
+    pub fn prestart(mut self, prestart: bool) -> Self {
+        self.prestart = prestart;
+        self
+    }
+
+    /// Spawns the pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread cannot be spawned.
+    pub fn build(self) -> ThreadPool {
+        let (room, overflow) = match self.queue {
+            Some((capacity, overflow)) => (Some(Semaphore::new(capacity)), overflow),
+            None => (None, Overflow::Block),
+        };
+        let shared = Arc::new(Shared {
+            deques: Mutex::new(Arc::new(Vec::new())),
+            free: Mutex::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            retiring: AtomicUsize::new(0),
+            wakeups: AtomicUsize::new(0),
+            ready: Semaphore::new(0),
+            room,
+            over: AtomicUsize::new(0),
+            timers: Mutex::new(Timers::default()),
+            broadcasting: Semaphore::new(1),
+            overflow,
+            aging: self.aging,
+            keyed: Mutex::new(HashMap::new()),
+            counters: Counters::default(),
+            shut: AtomicBool::new(false),
+            token: CancellationToken::new(),
+            live: AtomicUsize::new(0),
+            terminated: Semaphore::new(0),
+            panic_policy: self.panic_policy,
+            respawned: Mutex::new(Vec::new()),
+            idle: AtomicUsize::new(0),
+            extra: AtomicUsize::new(0),
+            keep_alive: self.max_workers.map(|(_, keep_alive)| keep_alive),
+        });
+        let target = worker_count(self.workers);
+        let started = self.started();
+        let threads = (0..target)
+            .map(|w| (w, self.spawn_worker(&shared, w, started.clone())))
+            .collect();
+        self.wait_started(started, target);
+        ThreadPool {
+            shared,
+            config: self,
+            workers: Mutex::new(Workers { threads, target }),
+        }
+    }
+
+    /// Semaphore the workers spawned next release once ready for tasks, if
+    /// they are to be waited for
+    fn started(&self) -> Option<Arc<Semaphore>> {
+        if self.prestart {
+            Some(Arc::new(Semaphore::new(0)))
+        } else {
+            None
+        }
+    }
+
+    /// Waits for `workers` workers to release `started`
+    fn wait_started(&self, started: Option<Arc<Semaphore>>, workers: usize) {
+        if let Some(started) = started {
+            // the semaphore is private to the spawning and never closed
+            started.acquire_many(workers).unwrap();
+        }
+    }
+
+    /// Spawns the worker `w` of a pool, which releases `started` once ready
+    /// for tasks
+    fn spawn_worker(
+        &self,
+        shared: &Arc<Shared>,
+        w: usize,
+        started: Option<Arc<Semaphore>>,
+    ) -> JoinHandle<()> {
+        let shared = shared.clone();
+        let slot = shared.take_slot();
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        let config = self.clone();
+        self.threads
+            .builder(w)
+            .spawn(self.threads.pinned(w, move || {
+                // released even if the hook panics, not to block the spawning
+                let started = Started(started);
+                if let Some(f) = &config.on_start {
+                    f(w);
+                }
+                if let Some((init, _)) = &config.state {
+                    let state = init(w);
+                    STATE.with(|s| *s.borrow_mut() = Some(state));
+                }
+                WORKER.with(|worker| worker.set(Some((Arc::as_ptr(&shared), slot))));
+                drop(started);
+                let restart = shared.work(slot);
+                WORKER.with(|worker| worker.set(None));
+                sync::lock(&shared.free).push(slot);
+                if let Some((_, teardown)) = &config.state {
+                    if let Some(state) = STATE.with(|s| s.borrow_mut().take()) {
+                        teardown(w, state);
+                    }
+                }
+                if let Some(f) = &config.on_stop {
+                    f(w);
+                }
+                if restart {
+                    // counted live before this one stops
+                    let t = config.spawn_worker(&shared, w, None);
+                    sync::lock(&shared.respawned).push((w, t));
+                }
+                shared.stopped();
+            }))
+            .expect("failed to spawn thread")
+    }
+}
+
+impl Shared {
+    /// Queues the job `job` makes of `f` with `priority`, as the overflow
+    /// policy says, or gives `f` back if the queue is full and the pool
+    /// rejects jobs on overflow
+    fn submit<T>(&self, priority: i32, f: T, job: impl FnOnce(T) -> Job) -> Result<(), T> {
+        self.submit_at(None, priority, f, job)
+    }
+
+    /// Queues the job `job` makes of `f`, as [`submit`](Self::submit) does,
+    /// on the deque `hash` maps to if any
+    fn submit_at<T>(
+        &self,
+        hash: Option<u64>,
+        priority: i32,
+        f: T,
+        job: impl FnOnce(T) -> Job,
+    ) -> Result<(), T> {
+        if self.is_shut_down() {
+            drop(job(f));
+            return Ok(());
+        }
+        let queued = self.enqueue(hash, priority, f, job);
+        if queued.is_ok() && self.is_shut_down() && self.live.load(Ordering::SeqCst) == 0 {
+            // queued as the last worker stopped, after a shutdown
+            self.drop_queued();
+        }
+        queued
+    }
+
+    /// Queues the job `job` makes of `f`, as [`submit_at`](Self::submit_at)
+    /// does before a shutdown
+    fn enqueue<T>(
+        &self,
+        hash: Option<u64>,
+        priority: i32,
+        f: T,
+        job: impl FnOnce(T) -> Job,
+    ) -> Result<(), T> {
+        let deques = self.snapshot();
+        let slot = match hash {
+            Some(hash) => (hash % deques.len() as u64) as usize,
+            None => self.slot_for_submit(deques.len()),
+        };
+        let room = match &self.room {
+            Some(room) => room,
+            None => {
+                sync::lock(&deques[slot]).push(priority, job(f));
+                self.ready();
+                return Ok(());
+            }
+        };
+        if self.overflow == Overflow::Block {
+            // the semaphore is private to the pool and never closed
+            room.wait().unwrap();
+            sync::lock(&deques[slot]).push(priority, job(f));
+            self.ready();
+            return Ok(());
+        }
+
+        loop {
+            if room.try_wait() {
+                sync::lock(&deques[slot]).push(priority, job(f));
+                self.ready();
+                return Ok(());
+            }
+            if self.overflow == Overflow::Reject {
+                return Err(f);
+            }
+            if take_one(&self.queued) {
+                // the new job takes the room, and the wakeup, of the oldest
+                // one
+                let oldest = Self::find_lowest(&deques, slot);
+                sync::lock(&deques[slot]).push(priority, job(f));
+                self.queued.fetch_add(1, Ordering::SeqCst);
+                drop(oldest);
+                return Ok(());
+            }
+            // the queue emptied meanwhile, and its room is coming back
+            thread::yield_now();
+        }
+    }
+
+    /// Takes the next job waiting for the previous one of `key`, or forgets
+    /// about the key if there is none
+    fn next_keyed(&self, key: u64) -> Option<Job> {
+        let mut keyed = sync::lock(&self.keyed);
+        let job = keyed.get_mut(&key).and_then(VecDeque::pop_front);
+        if job.is_none() {
+            keyed.remove(&key);
+        }
+        job
+    }
+
+    /// Runs jobs, those of `slot` first, until told to retire, which only
+    /// happens once no job is left to claim. Meanwhile, queues the delayed
+    /// jobs as they fall due. Returns `true` if a job panicked, and the
+    /// worker is to be replaced.
+    fn work(&self, slot: usize) -> bool {
+        let mut expiry = self
+            .keep_alive
+            .map(|keep_alive| Instant::now() + keep_alive);
+        loop {
+            let due = match (self.fire_timers(slot), expiry) {
+                (Some(due), Some(expiry)) => Some(due.min(expiry)),
+                (due, expiry) => due.or(expiry),
+            };
+            self.idle.fetch_add(1, Ordering::SeqCst);
+            let woken = match due {
+                Some(due) => self.ready.wait_deadline(due).is_ok(),
+                // the semaphore is private to the pool and never closed
+                None => self.ready.wait().is_ok(),
+            };
+            self.idle.fetch_sub(1, Ordering::SeqCst);
+            if !woken {
+                let expired = expiry.map_or(false, |expiry| Instant::now() >= expiry);
+                if expired {
+                    // an extra worker stops, and any other one waits again
+                    if take_one(&self.extra) {
+                        return false;
+                    }
+                    expiry = self
+                        .keep_alive
+                        .map(|keep_alive| Instant::now() + keep_alive);
+                }
+                continue;
+            }
+            loop {
+                if self.claim() {
+                    let job = self.find(&self.snapshot(), slot);
+                    self.counters.active(job);
+                    if PANICKED.with(Cell::get) {
+                        return true;
+                    }
+                    expiry = self
+                        .keep_alive
+                        .map(|keep_alive| Instant::now() + keep_alive);
+                    break;
+                }
+                if take_one(&self.retiring) {
+                    return false;
+                }
+                if take_one(&self.wakeups) {
+                    break;
+                }
+                // the wakeup of a job dropped from a full queue, whose
+                // replacement is about to be queued
+                thread::yield_now();
+            }
+        }
+    }
+
+    /// Runs queued jobs, those of `slot` first, until `done` can be taken
+    fn help(&self, slot: usize, done: &Semaphore) {
+        let mut set = WaitSet::new();
+        let task = set.add(done);
+        set.add(&self.ready);
+        loop {
+            // the semaphores are private to the pool and the task, and never
+            // closed
+            if set.wait_any().unwrap() == task {
+                return;
+            }
+            if self.claim() {
+                let job = self.find(&self.snapshot(), slot);
+                self.counters.active(job);
+                continue;
+            }
+            // the wakeup of a worker to retire or to keep track of a delayed
+            // job, or of a job dropped from a full queue, given back to the
+            // workers
+            self.ready.release();
+            if done.wait_timeout(Duration::from_millis(1)).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Claims a queued job, giving back its room in a bounded queue.
+    /// Returns `false` if there is none left to claim.
+    fn claim(&self) -> bool {
+        if !take_one(&self.queued) {
+            return false;
+        }
+        if let Some(room) = &self.room {
+            if !take_one(&self.over) {
+                room.release();
+            }
+        }
+        true
+    }
+
+    /// Takes a job claimed already: the one with the highest priority,
+    /// from the deque of `slot` unless another one holds a higher priority
+    fn find(&self, deques: &[Arc<Deque>], slot: usize) -> Job {
+        loop {
+            let now = Instant::now();
+            let mut best = None;
+            for k in 0..deques.len() {
+                let i = (slot + k) % deques.len();
+                if let Some(top) = sync::lock(&deques[i]).top(self.aging, now) {
+                    if best.map_or(true, |(b, _)| top > b) {
+                        best = Some((top, i));
+                    }
+                }
+            }
+            if let Some(((_, level), i)) = best {
+                if let Some(job) = sync::lock(&deques[i]).take(level) {
+                    return job;
+                }
+            }
+            // missed while the jobs were taken by other workers
+        }
+    }
+
+    /// Takes a job claimed already, to drop it: the oldest of the lowest
+    /// priority, from the deque of `slot` first
+    fn find_lowest(deques: &[Arc<Deque>], slot: usize) -> Job {
+        loop {
+            for k in 0..deques.len() {
+                let deque = &deques[(slot + k) % deques.len()];
+                if let Some(job) = sync::lock(deque).pop_lowest() {
+                    return job;
+                }
+            }
+            // missed while the jobs were taken from deques already scanned
+        }
+    }
+
+    /// Queues `jobs` with `priority` on the deque of `slot`, even over the
+    /// capacity of a bounded queue
+    fn queue_over(&self, slot: usize, priority: i32, jobs: Vec<Job>) {
+        let deques = self.snapshot();
+        let n = jobs.len();
+        let mut deque = sync::lock(&deques[slot]);
+        for job in jobs {
+            deque.push(priority, job);
+        }
+        drop(deque);
+        if self.room.is_some() {
+            // queued without taking room, which their claims must not give
+            // back
+            self.over.fetch_add(n, Ordering::SeqCst);
+        }
+        self.queued.fetch_add(n, Ordering::SeqCst);
+        self.ready.release_many(n);
+    }
+
+    /// Counts a job just queued, and wakes up a worker to run it
+    fn ready(&self) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.ready.release();
+    }
+
+    /// Tells `n` workers to retire once there is no job left to claim
+    fn retire(&self, n: usize) {
+        self.retiring.fetch_add(n, Ordering::SeqCst);
+        self.ready.release_many(n);
+    }
+
+    /// Retires the `target` workers of the pool along with the extra ones
+    fn retire_all(&self, target: usize) {
+        self.retire(target + self.extra.swap(0, Ordering::SeqCst));
+    }
+
+    fn snapshot(&self) -> Arc<Vec<Arc<Deque>>> {
+        sync::lock(&self.deques).clone()
+    }
+
+    /// Slot of the deque a task submitted from this thread goes to: that of
+    /// the worker submitting it, or the next one in turn
+    fn slot_for_submit(&self, slots: usize) -> usize {
+        let own = WORKER.with(Cell::get).and_then(|(shared, slot)| {
+            if std::ptr::eq(shared, self) {
+                Some(slot)
+            } else {
+                None
+            }
+        });
+        own.unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed) % slots)
+    }
+
+    /// Slot for a new worker: one left by a retired worker, or a new one
+    fn take_slot(&self) -> usize {
+        if let Some(slot) = sync::lock(&self.free).pop() {
+            return slot;
+        }
+        let mut deques = sync::lock(&self.deques);
+        let mut grown = Vec::clone(&deques);
+        grown.push(Arc::new(Mutex::new(Jobs::default())));
+        *deques = Arc::new(grown);
+        deques.len() - 1
+    }
+}
+
+impl Jobs {
+    fn push(&mut self, priority: i32, job: Job) {
+        let level = self.levels.entry(priority).or_default();
+        level.push_back((Instant::now(), job));
+    }
+
+    /// Priority of the next job, raised by its wait if jobs age every
+    /// `aging`, along with its level
+    fn top(&self, aging: Option<Duration>, now: Instant) -> Option<(i64, i32)> {
+        let aging = match aging {
+            Some(aging) => aging,
+            None => return self.levels.keys().next_back().map(|&l| (l.into(), l)),
+        };
+        self.levels
+            .iter()
+            .filter_map(|(&level, jobs)| {
+                let (queued, _) = jobs.front()?;
+                let age = now.saturating_duration_since(*queued).as_nanos() / aging.as_nanos();
+                let boost = i64::try_from(age).unwrap_or(i64::MAX);
+                Some((i64::from(level).saturating_add(boost), level))
+            })
+            .max()
+    }
+
+    /// Takes the oldest job of priority `level`
+    fn pop(&mut self, level: i32) -> Option<Job> {
+        let jobs = self.levels.get_mut(&level)?;
+        let (_, job) = jobs.pop_front()?;
+        if jobs.is_empty() {
+            self.levels.remove(&level);
+        }
+        Some(job)
+    }
+
+    /// Takes the oldest job of priority `level` to run it, counting its
+    /// wait
+    fn take(&mut self, level: i32) -> Option<Job> {
+        let jobs = self.levels.get_mut(&level)?;
+        let queued = jobs.front()?.0;
+        self.waits += 1;
+        self.waited += queued.elapsed();
+        self.pop(level)
+    }
+
+    /// Takes the oldest job of the lowest priority
+    fn pop_lowest(&mut self) -> Option<Job> {
+        let level = *self.levels.keys().next()?;
+        self.pop(level)
+    }
+}
+
+impl Runner {
+    fn job(self) -> Job {
+        Box::new(move || self.run())
+    }
+
+    fn run(mut self) {
+        while let Some(job) = self.job.take() {
+            // jobs catch their panics
+            job();
+            self.job = self.shared.next_keyed(self.key);
+        }
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        // dropped from a full queue along with its job: the next jobs of the
+        // key get a runner of their own
+        if self.job.take().is_some() {
+            if let Some(job) = self.shared.next_keyed(self.key) {
+                let runner = Runner {
+                    shared: self.shared.clone(),
+                    key: self.key,
+                    job: Some(job),
+                };
+                let _ = self.shared.submit(0, runner, Runner::job);
+            }
+        }
+    }
+}
+
+/// Queues `f` to run on the pool the calling thread is a worker of, as
+/// [`ThreadPool::submit`] does, and returns a handle on its result.
+///
+/// Tasks spawn subtasks this way, without a reference to their pool, and
+/// may [wait](TaskHandle::join) for them: the waiting worker runs the queued
+/// tasks meanwhile, so that recursive tasks do not deadlock the pool.
+/// Returns `None` if the calling thread is not a worker of a pool.
+///
+/// # Panics
+///
+/// Panics if the queue is full and the pool rejects tasks on overflow.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::{self, ThreadPool};
+/// fn sum(v: Vec<u64>) -> u64 {
+///     if v.len() <= 4 {
+///         return v.iter().sum();
+///     }
+///     let mut left = v;
+///     let right = left.split_off(left.len() / 2);
+///     let right = pool::spawn(move || sum(right)).unwrap();
+///     sum(left) + right.join().unwrap()
+/// }
+///
+/// let pool = ThreadPool::new(2);
+/// let total = pool.submit(|| sum((1..=100).collect()));
+/// assert_eq!(5050, total.join().unwrap());
+/// assert!(pool::spawn(|| ()).is_none());
+/// ```
+pub fn spawn<F, R>(f: F) -> Option<TaskHandle<R>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    with_worker(|shared, _| {
+        let mut handle = None;
+        let queued = shared.submit(0, f, |f| {
+            let (job, h) = job(f);
+            handle = Some(h);
+            job
+        });
+        match queued {
+            Ok(()) => handle.expect("queued tasks have a handle"),
+            Err(_) => panic!("the queue of the thread pool is full"),
+        }
+    })
+}
+
+/// Calls `f` with the pool the calling thread is a worker of and the slot of
+/// the worker, if it is one
+fn with_worker<T>(f: impl FnOnce(&Shared, usize) -> T) -> Option<T> {
+    let (shared, slot) = WORKER.with(Cell::get)?;
+    // SAFETY: the worker is set while the thread runs the jobs of the pool,
+    // which keeps it alive meanwhile
+    Some(f(unsafe { &*shared }, slot))
+}
+
+/// Calls `f` with the state of the worker running on this thread, which
+/// is put back once `f` returns or panics
+fn with_state<S: 'static, R>(f: impl FnOnce(&mut S) -> R) -> R {
+    struct Restore(Option<Box<dyn Any>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let state = self.0.take();
+            STATE.with(|s| *s.borrow_mut() = state);
+        }
+    }
+    let mut state = match STATE.with(|s| s.borrow_mut().take()) {
+        Some(state) => Restore(Some(state)),
+        None => panic!("the worker has no state, or a task it waits for uses it"),
+    };
+    match state.0.as_mut().and_then(|s| s.downcast_mut::<S>()) {
+        Some(s) => f(s),
+        None => panic!(
+            "the state of the worker is not a {}",
+            std::any::type_name::<S>()
+        ),
+    }
+}
+
+/// Decrements `count` if it is not zero, returning whether it did
+fn take_one(count: &AtomicUsize) -> bool {
+    count
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+impl Workers {
+    /// Forgets the threads gone for good, and returns the lowest number no
+    /// live worker has, for the worker spawned next
+    fn number(&mut self, shared: &Shared) -> usize {
+        self.threads.retain(|(_, t)| !t.is_finished());
+        let mut respawned = sync::lock(&shared.respawned);
+        respawned.retain(|(_, t)| !t.is_finished());
+        let live = || self.threads.iter().chain(respawned.iter());
+        (0..)
+            .find(|n| live().all(|(w, _)| w != n))
+            .expect("more workers than numbers")
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // every worker retires once the queues are drained; the retired ones
+        // are gone already
+        let mut w = sync::lock(&self.workers);
+        self.shared.retire_all(w.target);
+        for (_, t) in w.threads.drain(..) {
+            // jobs catch their panics, so workers do not panic
+            let _ = t.join();
+        }
+        // the replacements of the workers joined are known by then
+        loop {
+            let respawned = mem::take(&mut *sync::lock(&self.shared.respawned));
+            if respawned.is_empty() {
+                break;
+            }
+            for (_, t) in respawned {
+                let _ = t.join();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PoolBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoolBuilder")
+            .field("workers", &self.workers)
+            .field("queue", &self.queue)
+            .field("threads", &self.threads)
+            .field("on_start", &self.on_start.is_some())
+            .field("on_stop", &self.on_stop.is_some())
+            .field("worker_state", &self.state.is_some())
+            .field("aging", &self.aging)
+            .field("panic_policy", &self.panic_policy)
+            .field("prestart", &self.prestart)
+            .field("max_workers", &self.max_workers)
+            .finish()
+    }
+}
+
+impl Drop for Started {
+    fn drop(&mut self) {
+        if let Some(started) = &self.0 {
+            started.release();
+        }
+    }
+}
+
+impl<R> Drop for Completion<R> {
+    fn drop(&mut self) {
+        let claimed =
+            self.0
+                .state
+                .compare_exchange(QUEUED, STARTED, Ordering::SeqCst, Ordering::SeqCst);
+        if claimed == Err(ABORTED) {
+            // the handle completed the task already
+            return;
+        }
+        let mut result = sync::lock(&self.0.result);
+        if result.is_none() {
+            *result = Some(Err(TaskError::Dropped));
+        }
+        drop(result);
+        self.0.done.release();
+    }
+}
+
+impl<F> Full<F> {
+    /// Gives back the closure that was not queued
+    pub fn into_inner(self) -> F {
+        self.0
+    }
+}
+
+impl<F> fmt::Debug for Full<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Full(..)")
+    }
+}
+
+impl<F> fmt::Display for Full<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the queue of the thread pool is full")
+    }
+}
+
+impl<F> std::error::Error for Full<F> {}
+
+impl fmt::Debug for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::Panicked(payload) => match panic_message(&**payload) {
+                Some(message) => f.debug_tuple("Panicked").field(&message).finish(),
+                None => f.write_str("Panicked(..)"),
+            },
+            TaskError::Aborted => f.write_str("Aborted"),
+            TaskError::Dropped => f.write_str("Dropped"),
+        }
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::Panicked(payload) => match panic_message(&**payload) {
+                Some(message) => write!(f, "the task panicked: {}", message),
+                None => f.write_str("the task panicked"),
+            },
+            TaskError::Aborted => f.write_str("the task was aborted"),
+            TaskError::Dropped => f.write_str("the task was dropped before it ran"),
+        }
+    }
+}
+
+impl std::error::Error for TaskError {}
+
+/// Message of a panic raised with a string, as most are
+fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => Some(message),
+        None => payload.downcast_ref::<String>().map(String::as_str),
+    }
+}
+
+impl<R> TaskHandle<R> {
+    /// Waits for the task to complete and returns its result, or why it has
+    /// none: the payload of its panic, like
+    /// [`std::thread::JoinHandle::join`], or that it was aborted or dropped
+    /// before it ran.
+    ///
+    /// Called from a task running on a pool, it runs the other tasks queued
+    /// on the pool while waiting, so that tasks waiting for the tasks they
+    /// [spawned](spawn) do not leave the pool without a worker to run them.
+    pub fn join(self) -> Result<R, TaskError> {
+        let helped = with_worker(|shared, slot| shared.help(slot, &self.task.done));
+        if helped.is_none() {
+            // the semaphore is private to the task and never closed
+            self.task.done.wait().unwrap();
+        }
+        sync::lock(&self.task.result)
+            .take()
+            .expect("completed tasks have a result")
+    }
+
+    /// Aborts the task, on a best-effort basis.
+    ///
+    /// A task still queued does not run at all, and its handle reports it as
+    /// [aborted](TaskError::Aborted) at once. A task already running is only
+    /// asked to stop: it sees [`is_aborted`] return `true`, and is up to
+    /// check it and return early, its result reported as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::{is_aborted, TaskError, ThreadPool};
+    /// # use std::sync::mpsc;
+    /// let pool = ThreadPool::new(1);
+    /// let (started_tx, started) = mpsc::channel();
+    /// let running = pool.submit(move || {
+    ///     started_tx.send(()).unwrap();
+    ///     while !is_aborted() {
+    ///         std::thread::yield_now();
+    ///     }
+    ///     "stopped"
+    /// });
+    /// started.recv().unwrap();
+    /// let queued = pool.submit(|| "never runs");
+    /// queued.abort();
+    /// assert!(queued.is_finished());
+    /// assert!(matches!(queued.join(), Err(TaskError::Aborted)));
+    /// running.abort();
+    /// assert_eq!("stopped", running.join().unwrap());
+    /// ```
+    pub fn abort(&self) {
+        let task = &self.task;
+        task.aborted.store(true, Ordering::SeqCst);
+        let claimed =
+            task.state
+                .compare_exchange(QUEUED, ABORTED, Ordering::SeqCst, Ordering::SeqCst);
+        if claimed.is_ok() {
+            *sync::lock(&task.result) = Some(Err(TaskError::Aborted));
+            task.done.release();
+        }
+    }
+
+    /// Returns whether the task completed, or was aborted or dropped, so
+    /// that [`join`](Self::join) returns without waiting.
+    pub fn is_finished(&self) -> bool {
+        sync::lock(&self.task.result).is_some()
+    }
+
+    /// Id of the task, as [`task_id`] returns it to the task, and as its
+    /// span records it with the `tracing` feature.
+    ///
+    /// Ids are unique within the process, and increase with the submissions.
+    pub fn id(&self) -> usize {
+        self.task.id
+    }
+}
+
+/// Returns whether the handle of the task running on this thread asked to
+/// [abort](TaskHandle::abort) it, for tasks to stop early once it did.
+///
+/// Returns `false` outside of a task submitted to a [`ThreadPool`].
+pub fn is_aborted() -> bool {
+    match CURRENT.with(Cell::get) {
+        // SAFETY: the flag is set while the task runs, whose closure holds
+        // the task and keeps it alive meanwhile
+        Some((_, aborted)) => unsafe { &*aborted }.load(Ordering::SeqCst),
+        None => false,
+    }
+}
+
+/// Returns the [id](TaskHandle::id) of the task running on this thread, e.g.
+/// to tell it apart in the logs, or `None` outside of a task submitted to a
+/// [`ThreadPool`].
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::{task_id, ThreadPool};
+/// let pool = ThreadPool::new(1);
+/// let handle = pool.submit(task_id);
+/// let id = handle.id();
+/// assert_eq!(Some(id), handle.join().unwrap());
+/// assert_eq!(None, task_id());
+/// ```
+pub fn task_id() -> Option<usize> {
+    CURRENT.with(Cell::get).map(|(id, _)| id)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Barrier, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{is_aborted, spawn, with_state, Overflow, PanicPolicy, TaskError, ThreadPool};
+    #[cfg(target_os = "linux")]
+    use crate::worker_threads::Affinity;
+
+    /// Keeps the single worker of `pool` busy until the returned closure is
+    /// called
+    fn occupy(pool: &ThreadPool) -> impl FnOnce() {
+        let (started_tx, started) = mpsc::channel();
+        let (release, rx) = mpsc::channel::<()>();
+        drop(pool.submit(move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        }));
+        started.recv().unwrap();
+        move || drop(release)
+    }
+
+    #[test]
+    fn submit_and_join() {
+        let pool = ThreadPool::new(2);
+        assert_eq!(2, pool.workers());
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let handles = (0..100)
+            .map(|i| {
+                let threads = threads.clone();
+                pool.submit(move || {
+                    threads.lock().unwrap().insert(thread::current().id());
+                    i + 1
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles.into_iter().map(|h| h.join().unwrap());
+        assert_eq!((1..=100).collect::<Vec<_>>(), results.collect::<Vec<_>>());
+        assert!(threads.lock().unwrap().len() <= 2);
+    }
+
+    #[test]
+    fn idle_workers_steal() {
+        let pool = ThreadPool::new(2);
+        let (release, rx) = mpsc::channel::<()>();
+        let long = pool.submit(move || {
+            let _ = rx.recv();
+        });
+        // half of them are queued behind the long task, and complete anyway
+        let handles = (0..20).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+        let results = handles.into_iter().map(|h| h.join().unwrap());
+        assert_eq!(190, results.sum::<i32>());
+        drop(release);
+        long.join().unwrap();
+
+        // a task submitted by a task goes to the queue of its worker, and
+        // the other one steals it while the first is busy
+        let pool = Arc::new(pool);
+        let p = pool.clone();
+        let outer = pool.submit(move || {
+            let (tx, rx) = mpsc::channel();
+            drop(p.submit(move || tx.send(thread::current().id()).unwrap()));
+            (thread::current().id(), rx.recv().unwrap())
+        });
+        let (outer, nested) = outer.join().unwrap();
+        assert_ne!(outer, nested);
+    }
+
+    #[test]
+    fn priorities() {
+        let run = |pool: ThreadPool, wait: Duration| {
+            let order = Arc::new(Mutex::new(vec![]));
+            let release = occupy(&pool);
+            let record = |i| {
+                let order = order.clone();
+                move || order.lock().unwrap().push(i)
+            };
+            let mut handles = vec![pool.submit_with_priority(-5, record(0))];
+            thread::sleep(wait);
+            handles.push(pool.submit(record(1)));
+            handles.push(pool.submit_with_priority(5, record(2)));
+            handles.push(pool.submit(record(3)));
+            release();
+            handles.into_iter().for_each(|h| h.join().unwrap());
+            let order = order.lock().unwrap().clone();
+            order
+        };
+        assert_eq!(vec![2, 1, 3, 0], run(ThreadPool::new(1), Duration::ZERO));
+        // the first task waited long enough to gain more than 10 levels
+        let aging = ThreadPool::builder()
+            .workers(1)
+            .aging(Duration::from_millis(1))
+            .build();
+        assert_eq!(0, run(aging, Duration::from_millis(50))[0]);
+    }
+
+    #[test]
+    fn keyed() {
+        let pool = ThreadPool::new(4);
+        let logs = Arc::new(Mutex::new(vec![vec![], vec![], vec![]]));
+        let running = Arc::new([
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        ]);
+        let handles = (0..90)
+            .map(|i| {
+                let (logs, running) = (logs.clone(), running.clone());
+                let key = i % 3;
+                pool.submit_keyed(key, move || {
+                    assert_eq!(0, running[key].fetch_add(1, Ordering::SeqCst));
+                    thread::sleep(Duration::from_millis(1));
+                    logs.lock().unwrap()[key].push(i);
+                    running[key].fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        for (key, log) in logs.lock().unwrap().iter().enumerate() {
+            assert_eq!((key..90).step_by(3).collect::<Vec<_>>(), *log);
+        }
+    }
+
+    #[test]
+    fn fork_join() {
+        fn fib(n: u64) -> u64 {
+            if n < 2 {
+                return n;
+            }
+            let a = super::spawn(move || fib(n - 1)).unwrap();
+            let b = super::spawn(move || fib(n - 2)).unwrap();
+            a.join().unwrap() + b.join().unwrap()
+        }
+        // every task waits for its subtasks, even on a single worker, which
+        // runs the tasks in turn on top of those waiting, deeper than the
+        // default stack of a debug build allows once traced
+        for workers in [1, 3] {
+            let pool = ThreadPool::builder()
+                .workers(workers)
+                .stack_size(8 << 20)
+                .build();
+            assert_eq!(Ok(610), pool.submit(|| fib(15)).join().map_err(|_| ()));
+        }
+        assert!(super::spawn(|| ()).is_none());
+    }
+
+    #[test]
+    fn panics_are_reported() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.submit(|| panic!("task failure"));
+        let error = failed.join().unwrap_err();
+        assert_eq!("the task panicked: task failure", error.to_string());
+        match error {
+            TaskError::Panicked(payload) => {
+                assert_eq!(Some(&"task failure"), payload.downcast_ref::<&str>());
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        // the worker survived
+        assert_eq!(Ok(3), pool.submit(|| 3).join().map_err(|_| ()));
+    }
+
+    #[test]
+    fn abort() {
+        let pool = ThreadPool::new(1);
+        let release = occupy(&pool);
+        let queued = pool.submit(|| panic!("aborted tasks do not run"));
+        assert!(!queued.is_finished());
+        queued.abort();
+        assert!(queued.is_finished());
+        // aborting twice, or after completion, changes nothing
+        queued.abort();
+        assert!(matches!(queued.join(), Err(TaskError::Aborted)));
+        release();
+
+        let (started_tx, started) = mpsc::channel();
+        let running = pool.submit(move || {
+            started_tx.send(()).unwrap();
+            while !is_aborted() {
+                thread::yield_now();
+            }
+            is_aborted()
+        });
+        started.recv().unwrap();
+        running.abort();
+        assert!(running.join().unwrap());
+        let done = pool.submit(|| 1);
+        while !done.is_finished() {
+            thread::yield_now();
+        }
+        done.abort();
+        assert_eq!(1, done.join().unwrap());
+        assert!(!is_aborted());
+    }
+
+    #[test]
+    fn task_ids() {
+        let pool = ThreadPool::new(2);
+        let handles = (0..10).map(|_| pool.submit(super::task_id));
+        let handles = handles.collect::<Vec<_>>();
+        let ids = handles.iter().map(|h| h.id()).collect::<Vec<_>>();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        for (id, h) in ids.into_iter().zip(handles) {
+            assert_eq!(Some(id), h.join().unwrap());
+        }
+        // a task sees its own id again once a nested one completes
+        let outer = pool.submit(|| {
+            let inner = super::spawn(super::task_id).unwrap();
+            let inner_id = inner.id();
+            assert_eq!(Some(inner_id), inner.join().unwrap());
+            super::task_id()
+        });
+        let id = outer.id();
+        assert_eq!(Some(id), outer.join().unwrap());
+    }
+
+    #[test]
+    fn drop_runs_queued_tasks() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = ThreadPool::new(2);
+        for _ in 0..10 {
+            let count = count.clone();
+            // the handles are dropped right away
+            drop(pool.submit(move || {
+                thread::sleep(Duration::from_millis(5));
+                count.fetch_add(1, Ordering::SeqCst);
+            }));
+        }
+        drop(pool);
+        assert_eq!(10, count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn bounded_reject() {
+        let pool = ThreadPool::bounded(1, 2, Overflow::Reject);
+        let release = occupy(&pool);
+        let queued = (0..2).map(|i| pool.try_submit(move || i).unwrap());
+        let queued = queued.collect::<Vec<_>>();
+        let full = match pool.try_submit(|| 7) {
+            Ok(_) => panic!("queued in a full queue"),
+            Err(full) => full,
+        };
+        assert_eq!(7, full.into_inner()());
+        release();
+        let results = queued.into_iter().map(|h| h.join().unwrap());
+        assert_eq!(vec![0, 1], results.collect::<Vec<_>>());
+        // room is given back as the tasks are taken
+        assert_eq!(Ok(3), pool.try_submit(|| 3).unwrap().join().map_err(|_| ()));
+    }
+
+    #[test]
+    fn bounded_drop_oldest() {
+        let pool = ThreadPool::bounded(1, 2, Overflow::DropOldest);
+        let release = occupy(&pool);
+        let handles = (0..4).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+        release();
+        let results = handles
+            .into_iter()
+            .map(|h| h.join().map_err(|e| e.to_string()))
+            .collect::<Vec<_>>();
+        let dropped = Err(TaskError::Dropped.to_string());
+        assert_eq!(vec![dropped.clone(), dropped, Ok(2), Ok(3)], results);
+    }
+
+    #[test]
+    fn bounded_block() {
+        let pool = ThreadPool::bounded(1, 1, Overflow::Block);
+        let release = occupy(&pool);
+        let first = pool.submit(|| 1);
+        thread::scope(|s| {
+            let blocked = s.spawn(|| pool.submit(|| 2).join().unwrap());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!blocked.is_finished());
+            release();
+            assert_eq!(2, blocked.join().unwrap());
+        });
+        assert_eq!(1, first.join().unwrap());
+    }
+
+    #[test]
+    fn restart_on_panic() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (s, t) = (started.clone(), stopped.clone());
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .panic_policy(PanicPolicy::Restart)
+            .on_start(move |_| {
+                s.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_stop(move |_| {
+                t.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        let first = pool.submit(|| thread::current().id()).join().unwrap();
+        let failed = pool.submit(|| panic!("task failure"));
+        assert!(failed.join().is_err());
+        // the next task runs on the replacement
+        let next = pool.submit(|| thread::current().id()).join().unwrap();
+        assert_ne!(first, next);
+        assert_eq!(1, pool.workers());
+        assert_eq!(2, started.load(Ordering::SeqCst));
+        drop(pool);
+        assert_eq!(2, stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn worker_hooks() {
+        let started = Arc::new(Mutex::new(HashSet::new()));
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let (s, t) = (started.clone(), stopped.clone());
+        let pool = ThreadPool::builder()
+            .workers(3)
+            .on_start(move |w| assert!(s.lock().unwrap().insert(w)))
+            .on_stop(move |_| {
+                t.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        assert_eq!(Ok(1), pool.submit(|| 1).join().map_err(|_| ()));
+        assert_eq!(0, stopped.load(Ordering::SeqCst));
+        drop(pool);
+        assert_eq!(3, started.lock().unwrap().len());
+        assert_eq!(3, stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn worker_state() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let r = runs.clone();
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .worker_state(
+                |w| (w, 0),
+                move |w, (v, n): (usize, usize)| {
+                    assert_eq!(w, v);
+                    r.fetch_add(n, Ordering::SeqCst);
+                },
+            )
+            .build();
+        let tasks: Vec<_> = (0..20)
+            .map(|_| pool.submit_with_state(|(_, n): &mut (usize, usize)| *n += 1))
+            .collect();
+        for t in tasks {
+            t.join().unwrap();
+        }
+        let wrong = pool.submit_with_state(|_: &mut String| ());
+        assert!(matches!(wrong.join(), Err(TaskError::Panicked(_))));
+        // the state is put back after a failure
+        pool.submit_with_state(|(_, n): &mut (usize, usize)| *n += 1)
+            .join()
+            .unwrap();
+        drop(pool);
+        assert_eq!(21, runs.load(Ordering::SeqCst));
+
+        let bare = ThreadPool::new(1);
+        let none = bare.submit_with_state(|_: &mut u8| ());
+        assert!(matches!(none.join(), Err(TaskError::Panicked(_))));
+    }
+
+    #[test]
+    fn worker_state_in_use() {
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .worker_state(|_| 0u8, |_, _| ())
+            .build();
+        // the one worker runs the inner task as it waits, and the outer task
+        // still holds the state
+        let outer = pool.submit_with_state(|_: &mut u8| {
+            let inner = spawn(|| with_state(|_: &mut u8| ())).unwrap();
+            matches!(inner.join(), Err(TaskError::Panicked(_)))
+        });
+        assert!(outer.join().unwrap());
+        assert!(pool.submit_with_state(|_: &mut u8| ()).join().is_ok());
+    }
+
+    #[test]
+    fn prestart() {
+        let started = Arc::new(AtomicUsize::new(0));
+        let s = started.clone();
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .on_start(move |_| {
+                thread::sleep(Duration::from_millis(20));
+                s.fetch_add(1, Ordering::SeqCst);
+            })
+            .prestart(true)
+            .build();
+        assert_eq!(2, started.load(Ordering::SeqCst));
+        pool.resize(5);
+        assert_eq!(5, started.load(Ordering::SeqCst));
+        assert_eq!(Ok(1), pool.submit(|| 1).join().map_err(|_| ()));
+    }
+
+    #[test]
+    fn submit_hashed() {
+        let pool = ThreadPool::new(2);
+        let (started_tx, started) = mpsc::channel();
+        let (release, rx) = mpsc::channel::<()>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..2 {
+            let (started_tx, rx) = (started_tx.clone(), rx.clone());
+            drop(pool.submit(move || {
+                started_tx.send(()).unwrap();
+                let _ = rx.lock().unwrap().recv();
+            }));
+        }
+        started.recv().unwrap();
+        started.recv().unwrap();
+        let handles = (0..5)
+            .map(|i| pool.submit_hashed("shard", move || i))
+            .collect::<Vec<_>>();
+        let queued = pool
+            .shared
+            .snapshot()
+            .iter()
+            .map(|deque| {
+                crate::sync::lock(deque)
+                    .levels
+                    .values()
+                    .map(|l| l.len())
+                    .sum()
+            })
+            .collect::<Vec<usize>>();
+        assert!(queued == [5, 0] || queued == [0, 5], "{:?}", queued);
+        drop(release);
+        let results = handles.into_iter().map(|h| h.join().unwrap());
+        assert_eq!((0..5).sum::<i32>(), results.sum());
+    }
+
+    #[test]
+    fn max_workers() {
+        let stopped = Arc::new(AtomicUsize::new(0));
+        let t = stopped.clone();
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .max_workers(3, Duration::from_millis(50))
+            .on_stop(move |_| {
+                t.fetch_add(1, Ordering::SeqCst);
+            })
+            .build();
+        // the tasks only complete once all three run at once
+        let barrier = Arc::new(Barrier::new(3));
+        let handles = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                pool.submit(move || {
+                    barrier.wait();
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert_eq!(1, pool.workers());
+        let started = Instant::now();
+        while stopped.load(Ordering::SeqCst) < 2 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Ok(1), pool.submit(|| 1).join().map_err(|_| ()));
+        drop(pool);
+        assert_eq!(3, stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn max_workers_bursts() {
+        let highest = Arc::new(AtomicUsize::new(0));
+        let h = highest.clone();
+        let pool = ThreadPool::builder()
+            .workers(1)
+            .max_workers(3, Duration::from_millis(10))
+            .on_start(move |w| {
+                h.fetch_max(w, Ordering::SeqCst);
+            })
+            .build();
+        let live = || {
+            let w = crate::sync::lock(&pool.workers);
+            w.threads.iter().filter(|(_, t)| !t.is_finished()).count()
+        };
+        for _ in 0..5 {
+            let barrier = Arc::new(Barrier::new(3));
+            let handles = (0..3)
+                .map(|_| {
+                    let barrier = barrier.clone();
+                    pool.submit(move || {
+                        barrier.wait();
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().for_each(|h| h.join().unwrap());
+            // the extra workers leave between the bursts
+            let started = Instant::now();
+            while live() > 1 {
+                assert!(started.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(5));
+            }
+        }
+        // the threads gone are forgotten, and their numbers reused
+        assert!(crate::sync::lock(&pool.workers).threads.len() <= 3);
+        assert_eq!(2, highest.load(Ordering::SeqCst));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn affinity() {
+        let core = unsafe { libc::sched_getcpu() } as usize;
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .affinity(Affinity::Shared(vec![core]))
+            .build();
+        let cpu = pool.submit(|| unsafe { libc::sched_getcpu() } as usize);
+        assert_eq!(Ok(core), cpu.join().map_err(|_| ()));
+    }
+
+    #[test]
+    fn resize() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let (l, m) = (live.clone(), live.clone());
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .on_start(move |_| {
+                l.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_stop(move |_| {
+                m.fetch_sub(1, Ordering::SeqCst);
+            })
+            .build();
+        pool.resize(6);
+        assert_eq!(6, pool.workers());
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let handles = (0..200)
+            .map(|_| {
+                let threads = threads.clone();
+                pool.submit(move || {
+                    threads.lock().unwrap().insert(thread::current().id());
+                    thread::sleep(Duration::from_millis(1));
+                })
+            })
+            .collect::<Vec<_>>();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        assert!(threads.lock().unwrap().len() > 2);
+
+        pool.resize(1);
+        assert_eq!(1, pool.workers());
+        // the idle workers leave
+        for _ in 0..100 {
+            if live.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(1, live.load(Ordering::SeqCst));
+        assert_eq!(Ok(5), pool.submit(|| 5).join().map_err(|_| ()));
+        pool.resize(3);
+        drop(pool);
+        assert_eq!(0, live.load(Ordering::SeqCst));
+    }
+}
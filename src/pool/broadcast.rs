@@ -0,0 +1,235 @@
+use std::panic;
+use std::sync::Arc;
+use std::thread;
+
+use super::{run_task, with_worker, Job, Shared, ThreadPool, DROPPED};
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{self, Mutex};
+use crate::trace::{self, Parent, Stamp};
+use crate::Semaphore;
+
+/// Closure being run on every worker
+struct Broadcast<F, R> {
+    f: F,
+    /// Number of jobs of the broadcast
+    workers: usize,
+    /// Number of jobs taken by a worker, or dropped
+    arrived: AtomicUsize,
+    /// Holds the jobs of the broadcast until each one has a worker of its
+    /// own
+    go: Semaphore,
+    results: Mutex<Vec<thread::Result<R>>>,
+    /// Number of jobs still running
+    left: AtomicUsize,
+    /// Released once every job completed
+    done: Semaphore,
+    /// Span the broadcast was made in
+    parent: Parent,
+}
+
+/// Job running a broadcast on the worker that takes it
+struct Visit<F, R>
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    shared: Arc<Shared>,
+    broadcast: Option<Arc<Broadcast<F, R>>>,
+    queued: Stamp,
+}
+
+/// Broadcasts
+impl ThreadPool {
+    /// Runs `f` once on every worker thread, and returns the results once
+    /// all the runs completed, e.g. to flush buffers local to the workers,
+    /// or to set up their thread-local state anew.
+    ///
+    /// The runs go ahead of the queued tasks, but a worker busy with a task
+    /// first completes it: as every worker holds on to its run until all of
+    /// them started, the broadcast waits for the longest task running. A
+    /// panic in `f` is resumed once all the runs completed, as is a panic
+    /// with [`DROPPED`] for the runs dropped by a
+    /// [`shutdown_now`](Self::shutdown_now) meanwhile. Broadcasts run
+    /// one after the other; called from a task running on the pool, the
+    /// calling worker takes its part in the broadcast, and runs the queued
+    /// tasks while waiting, as [`join`](super::TaskHandle::join) does.
+    /// Once the pool is [shut down](Self::shutdown), no run is left to make.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::cell::Cell;
+    /// thread_local!(static LEVEL: Cell<u8> = Cell::new(0));
+    ///
+    /// let pool = ThreadPool::new(4);
+    /// // on reload
+    /// let set = pool.broadcast(|| LEVEL.with(|level| level.set(3)));
+    /// assert_eq!(4, set.len());
+    /// assert_eq!(3, pool.submit(|| LEVEL.with(Cell::get)).join().unwrap());
+    /// ```
+    pub fn broadcast<F, R>(&self, f: F) -> Vec<R>
+    where
+        F: Fn() -> R + Send + Sync + 'static,
+        R: Send + 'static,
+    {
+        let shared = &self.shared;
+        if shared.is_shut_down() {
+            return Vec::new();
+        }
+        wait(&shared.broadcasting);
+        let workers = self.workers();
+        let broadcast = Arc::new(Broadcast {
+            f,
+            workers,
+            arrived: AtomicUsize::new(0),
+            go: Semaphore::new(0),
+            results: Mutex::new(Vec::with_capacity(workers)),
+            left: AtomicUsize::new(workers),
+            done: Semaphore::new(0),
+            parent: Parent::current(),
+        });
+        for _ in 0..workers {
+            Visit {
+                shared: shared.clone(),
+                broadcast: Some(broadcast.clone()),
+                queued: Stamp::now(),
+            }
+            .queue();
+        }
+        wait(&broadcast.done);
+        shared.broadcasting.release();
+        let results = std::mem::take(&mut *sync::lock(&broadcast.results));
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect()
+    }
+}
+
+/// Takes `sem`, running the queued jobs meanwhile on a worker
+fn wait(sem: &Semaphore) {
+    if with_worker(|shared, slot| shared.help(slot, sem)).is_none() {
+        // the semaphores are private to the pool and never closed
+        sem.wait().unwrap();
+    }
+}
+
+impl<F, R> Visit<F, R>
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    /// Queues the job ahead of the others
+    fn queue(self) {
+        let shared = self.shared.clone();
+        let slot = shared.slot_for_submit(shared.snapshot().len());
+        let job: Job = Box::new(move || self.run());
+        shared.queue_over(slot, i32::MAX, vec![job]);
+    }
+
+    fn run(mut self) {
+        let broadcast = self.broadcast.take().expect("visits run once");
+        broadcast.arrive();
+        // the semaphore is private to the broadcast and never closed
+        broadcast.go.wait().unwrap();
+        let (f, id) = (&broadcast.f, trace::next_id());
+        let result = run_task(|| broadcast.parent.run("task", id, &self.queued, f));
+        broadcast.complete(result);
+    }
+}
+
+impl<F, R> Broadcast<F, R> {
+    /// Counts a job taken, or dropped, letting all the jobs go once each one
+    /// has a worker
+    fn arrive(&self) {
+        if self.arrived.fetch_add(1, Ordering::SeqCst) + 1 == self.workers {
+            self.go.release_many(self.workers);
+        }
+    }
+
+    fn complete(&self, result: thread::Result<R>) {
+        sync::lock(&self.results).push(result);
+        if self.left.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.done.release();
+        }
+    }
+}
+
+impl<F, R> Drop for Visit<F, R>
+where
+    F: Fn() -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    fn drop(&mut self) {
+        let broadcast = match self.broadcast.take() {
+            Some(broadcast) => broadcast,
+            None => return,
+        };
+        if self.shared.is_shut_down() {
+            // dropped along with the queue
+            broadcast.arrive();
+            broadcast.complete(Err(Box::new(DROPPED)));
+        } else {
+            // dropped from a full queue: the broadcast needs it all the same
+            Visit {
+                shared: self.shared.clone(),
+                broadcast: Some(broadcast),
+                queued: Stamp::now(),
+            }
+            .queue();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    use crate::pool::{self, Overflow, ThreadPool};
+
+    #[test]
+    fn every_worker() {
+        let pool = ThreadPool::bounded(4, 1, Overflow::DropOldest);
+        // a busy worker takes its part once its task completes
+        let (release, rx) = mpsc::channel::<()>();
+        let busy = pool.submit(move || {
+            let _ = rx.recv();
+        });
+        let ids = thread::scope(|s| {
+            let ids = s.spawn(|| pool.broadcast(|| thread::current().id()));
+            drop(release);
+            ids.join().unwrap()
+        });
+        busy.join().unwrap();
+        assert_eq!(4, ids.iter().collect::<HashSet<_>>().len());
+
+        pool.resize(2);
+        assert_eq!(2, pool.broadcast(|| ()).len());
+    }
+
+    #[test]
+    fn from_a_task() {
+        let pool = Arc::new(ThreadPool::new(3));
+        let p = pool.clone();
+        let runs = pool.submit(move || p.broadcast(|| 1).into_iter().sum::<i32>());
+        assert_eq!(3, runs.join().unwrap());
+        let p = pool.clone();
+        let nested = pool.submit(move || pool::spawn(move || p.broadcast(|| ()).len()));
+        assert_eq!(3, nested.join().unwrap().unwrap().join().unwrap());
+    }
+
+    #[test]
+    fn panics() {
+        let pool = ThreadPool::new(2);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.broadcast(|| panic!("flush failed"));
+        }));
+        let e = result.unwrap_err();
+        assert_eq!(Some(&"flush failed"), e.downcast_ref::<&str>());
+        assert_eq!(2, pool.broadcast(|| ()).len());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::Arc;
+
+use super::{job, Job, Shared, TaskHandle, ThreadPool};
+use crate::sync::{self, Mutex};
+
+/// Pool running its tasks on the workers of a [`ThreadPool`], at most a
+/// given number of them at once, created with [`ThreadPool::child`]
+pub struct ChildPool<'a> {
+    pool: &'a ThreadPool,
+    quota: Arc<Quota>,
+}
+
+/// Number of tasks of a child pool that may run at once, and the tasks
+/// waiting for one of them to complete
+struct Quota {
+    limit: usize,
+    state: Mutex<QuotaState>,
+    /// Quota of the parent of a child pool created by another one
+    parent: Option<Arc<Quota>>,
+}
+
+#[derive(Default)]
+struct QuotaState {
+    /// Number of runners queued or running
+    running: usize,
+    waiting: VecDeque<Job>,
+}
+
+/// Job running the jobs of a child pool one after the other, holding one of
+/// the slots of its quota meanwhile
+struct Runner {
+    shared: Arc<Shared>,
+    quota: Arc<Quota>,
+    /// Next job to run
+    job: Option<Job>,
+}
+
+impl ThreadPool {
+    /// Creates a child pool, running its tasks on the workers of this one but
+    /// at most `limit` of them at once, e.g. for a tenant to use no more than
+    /// 4 of the 16 workers, without a set of threads of its own.
+    ///
+    /// The tasks over the limit wait apart, and do not count in the capacity
+    /// of a bounded queue. They run in submission order, each on the worker
+    /// that ran the previous one, right after it, as
+    /// [keyed](Self::submit_keyed) tasks do.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// let pool = ThreadPool::new(16);
+    /// let indexing = pool.child(4);
+    /// let handles = (0..100)
+    ///     .map(|i| indexing.submit(move || i * 2))
+    ///     .collect::<Vec<_>>();
+    /// // the other workers are free for the tasks of the pool
+    /// assert_eq!(1, pool.submit(|| 1).join().unwrap());
+    /// let sum = handles.into_iter().map(|h| h.join().unwrap()).sum::<i32>();
+    /// assert_eq!(9900, sum);
+    /// ```
+    pub fn child(&self, limit: usize) -> ChildPool<'_> {
+        ChildPool::new(self, limit, None)
+    }
+}
+
+impl<'a> ChildPool<'a> {
+    fn new(pool: &'a ThreadPool, limit: usize, parent: Option<Arc<Quota>>) -> Self {
+        assert!(limit > 0, "a child pool must run some tasks");
+        let quota = Quota {
+            limit,
+            state: Mutex::new(QuotaState::default()),
+            parent,
+        };
+        Self {
+            pool,
+            quota: Arc::new(quota),
+        }
+    }
+
+    /// Creates a child of this pool, running at most `limit` tasks at once
+    /// within the quota of this one, as [`ThreadPool::child`] does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn child(&self, limit: usize) -> ChildPool<'a> {
+        ChildPool::new(self.pool, limit, Some(self.quota.clone()))
+    }
+
+    /// Number of tasks of the pool that may run at once
+    pub fn limit(&self) -> usize {
+        self.quota.limit
+    }
+
+    /// Queues `f` to run on one of the workers of the parent pool once fewer
+    /// than [`limit`](Self::limit) tasks of this one are running, as
+    /// [`ThreadPool::submit`] does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue of the parent pool is full and it rejects tasks
+    /// on overflow.
+    pub fn submit<F, R>(&self, f: F) -> TaskHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = job(f);
+        let shared = &self.pool.shared;
+        if shared.is_shut_down() {
+            return handle;
+        }
+        if !self.quota.submit(shared, job) {
+            panic!("the queue of the thread pool is full");
+        }
+        self.pool.grow();
+        handle
+    }
+}
+
+impl Quota {
+    /// Runs `job` once one of the slots of the quota is free. Returns
+    /// `false` if the job was dropped from a full queue.
+    fn submit(self: &Arc<Self>, shared: &Arc<Shared>, job: Job) -> bool {
+        {
+            let mut state = sync::lock(&self.state);
+            if state.running == self.limit {
+                state.waiting.push_back(job);
+                return true;
+            }
+            state.running += 1;
+        }
+        self.start(shared, job)
+    }
+
+    /// Queues a runner for `job`, which took a slot of the quota already
+    fn start(self: &Arc<Self>, shared: &Arc<Shared>, job: Job) -> bool {
+        let runner = Runner {
+            shared: shared.clone(),
+            quota: self.clone(),
+            job: Some(job),
+        };
+        match &self.parent {
+            // the runner holds a slot of the parent quota too
+            Some(parent) => parent.submit(shared, runner.job()),
+            None => shared.submit(0, runner, Runner::job).is_ok(),
+        }
+    }
+
+    /// Takes the next job waiting for a slot, or gives back the slot of the
+    /// runner if there is none, or if `drain` says to drop the waiting jobs
+    fn next(&self, drain: bool) -> Option<Job> {
+        let mut state = sync::lock(&self.state);
+        let job = if drain {
+            None
+        } else {
+            state.waiting.pop_front()
+        };
+        if job.is_none() {
+            state.running -= 1;
+            let dropped = mem::take(&mut state.waiting);
+            drop(state);
+            // out of the lock, as their handles see them dropped
+            drop(dropped);
+        }
+        job
+    }
+}
+
+impl Runner {
+    fn job(self) -> Job {
+        Box::new(move || self.run())
+    }
+
+    fn run(mut self) {
+        while let Some(job) = self.job.take() {
+            // jobs catch their panics
+            job();
+            self.job = self.quota.next(self.shared.token.is_cancelled());
+        }
+    }
+}
+
+impl Drop for Runner {
+    fn drop(&mut self) {
+        // dropped from a full queue or by a shutdown along with its job: the
+        // next job gets a runner of its own, unless the pool takes no job
+        // anymore
+        if self.job.take().is_some() {
+            if let Some(job) = self.quota.next(self.shared.is_shut_down()) {
+                self.quota.start(&self.shared, job);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::pool::{TaskError, ThreadPool};
+
+    /// Number of tasks running at once, and the most of them seen
+    #[derive(Default)]
+    struct Peak {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    impl Peak {
+        fn track(&self) {
+            let now = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+        }
+
+        fn untrack(&self) {
+            self.running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn quota() {
+        let pool = ThreadPool::new(8);
+        let child = pool.child(3);
+        let grandchild = child.child(2);
+        assert_eq!(3, child.limit());
+        let (outer, inner) = (Arc::new(Peak::default()), Arc::new(Peak::default()));
+        let mut handles = vec![];
+        for i in 0..60 {
+            let (outer, inner) = (outer.clone(), inner.clone());
+            let nested = i % 2 == 0;
+            let task = move || {
+                // the tasks of the grandchild count in the quota of the child
+                outer.track();
+                if nested {
+                    inner.track();
+                }
+                thread::sleep(Duration::from_millis(2));
+                if nested {
+                    inner.untrack();
+                }
+                outer.untrack();
+            };
+            handles.push(if nested {
+                grandchild.submit(task)
+            } else {
+                child.submit(task)
+            });
+        }
+        handles.into_iter().for_each(|h| h.join().unwrap());
+        let peak = |p: &Peak| p.peak.load(Ordering::SeqCst);
+        assert!((1..=3).contains(&peak(&outer)), "{}", peak(&outer));
+        assert!((1..=2).contains(&peak(&inner)), "{}", peak(&inner));
+        assert_eq!(1, pool.child(1).submit(|| 1).join().unwrap());
+    }
+
+    #[test]
+    fn shutdown_drops_waiting() {
+        let pool = ThreadPool::new(2);
+        let child = pool.child(1);
+        let (started_tx, started) = std::sync::mpsc::channel();
+        let running = child.submit(move || {
+            started_tx.send(()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+        });
+        started.recv().unwrap();
+        let waiting = child.submit(|| 1);
+        pool.shutdown_now();
+        running.join().unwrap();
+        assert!(matches!(waiting.join(), Err(TaskError::Dropped)));
+        assert!(matches!(child.submit(|| 2).join(), Err(TaskError::Dropped)));
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+use super::{PoolBuilder, ThreadPool};
+use crate::sync::{self, Mutex};
+
+/// Pool shared by the whole process, built on first use
+static GLOBAL: Mutex<Option<&'static ThreadPool>> = Mutex::new(None);
+
+/// Error of [`PoolBuilder::build_global`] once the global pool was built
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyBuilt;
+
+/// Returns the pool shared by the whole process, building it on the first
+/// call with one worker per available CPU, unless it was configured with
+/// [`PoolBuilder::build_global`] before.
+///
+/// Libraries running their tasks on the global pool share its threads
+/// rather than each spawning their own. The global pool lives as long as
+/// the process: it is never dropped, and the tasks still queued when the
+/// process exits do not run.
+///
+/// # Panics
+///
+/// Panics if a thread cannot be spawned.
+///
+/// # Examples
+///
+/// ```
+/// let squares = (0..4)
+///     .map(|i| esync::pool::global().submit(move || i * i))
+///     .collect::<Vec<_>>();
+/// let sum = squares.into_iter().map(|h| h.join().unwrap()).sum::<i32>();
+/// assert_eq!(14, sum);
+/// ```
+pub fn global() -> &'static ThreadPool {
+    let mut global = sync::lock(&GLOBAL);
+    global.get_or_insert_with(|| Box::leak(Box::new(ThreadPool::new(0))))
+}
+
+impl PoolBuilder {
+    /// Builds the pool returned by [`global`], which fails if it was built
+    /// already, by this or by a call to [`global`].
+    ///
+    /// Applications call it early on, before the libraries they use get to
+    /// the global pool.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a thread cannot be spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// ThreadPool::builder()
+    ///     .workers(2)
+    ///     .thread_name("global")
+    ///     .build_global()
+    ///     .unwrap();
+    /// assert_eq!(2, esync::pool::global().workers());
+    /// assert!(ThreadPool::builder().build_global().is_err());
+    /// ```
+    pub fn build_global(self) -> Result<&'static ThreadPool, AlreadyBuilt> {
+        let mut global = sync::lock(&GLOBAL);
+        if global.is_some() {
+            return Err(AlreadyBuilt);
+        }
+        Ok(*global.insert(Box::leak(Box::new(self.build()))))
+    }
+}
+
+impl fmt::Display for AlreadyBuilt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the global thread pool is already built")
+    }
+}
+
+impl std::error::Error for AlreadyBuilt {}
+
+#[cfg(test)]
+mod test {
+    use super::global;
+    use crate::pool::ThreadPool;
+
+    #[test]
+    fn built_once() {
+        let pool = global();
+        assert!(std::ptr::eq(pool, global()));
+        assert_eq!(Ok(2), pool.submit(|| 2).join().map_err(|_| ()));
+        assert!(ThreadPool::builder().workers(1).build_global().is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+
+use super::{run_task, with_worker, Job, Shared, ThreadPool, DROPPED};
+use crate::sync::atomic::Ordering;
+use crate::sync::{self, Mutex};
+use crate::trace::{self, Parent, Stamp};
+use crate::Semaphore;
+
+/// Panic payload reported for a task of a [`TaskGraph`] that did not run
+/// because one of its dependencies panicked or was dropped
+pub const SKIPPED: &str = "a dependency of the task panicked";
+
+/// Closure of a graph node
+type Task<R> = Box<dyn FnOnce() -> R + Send + 'static>;
+
+/// Set of tasks depending on each other, run on a pool with
+/// [`ThreadPool::run_graph`]
+///
+/// A task runs once all the tasks it depends on completed, and at the same
+/// time as every other task whose dependencies completed, as far as the
+/// workers of the pool allow. Tasks may only depend on tasks added before
+/// them, so that a graph has no cycle.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::{TaskGraph, ThreadPool};
+/// let mut graph = TaskGraph::new();
+/// let fetch = graph.add(|| "sources", &[]);
+/// let lib = graph.add(|| "lib", &[fetch]);
+/// let bin = graph.add(|| "bin", &[fetch]);
+/// let link = graph.add(|| "linked", &[lib, bin]);
+///
+/// let results = ThreadPool::new(2).run_graph(graph);
+/// assert_eq!(Some(&"linked"), results[link.index()].as_ref().ok());
+/// ```
+pub struct TaskGraph<R> {
+    tasks: Vec<Task<R>>,
+    deps: Vec<Vec<usize>>,
+}
+
+/// Task of a [`TaskGraph`], returned by [`TaskGraph::add`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId(usize);
+
+/// Graph being run on a pool
+struct Run<R> {
+    shared: Arc<Shared>,
+    state: Mutex<State<R>>,
+    /// Released once every task has a result
+    finished: Semaphore,
+    /// Span the graph was run in
+    parent: Parent,
+}
+
+/// Task of a run, queued on the pool
+struct Step<R: Send + 'static> {
+    run: Arc<Run<R>>,
+    i: usize,
+    done: bool,
+    queued: Stamp,
+}
+
+struct State<R> {
+    /// Tasks that did not start yet
+    tasks: Vec<Option<Task<R>>>,
+    /// Number of dependencies of every task that did not complete yet
+    pending: Vec<usize>,
+    dependents: Vec<Vec<usize>>,
+    results: Vec<Option<thread::Result<R>>>,
+    /// Number of tasks without a result
+    left: usize,
+}
+
+impl<R> TaskGraph<R> {
+    /// Creates an empty graph
+    pub fn new() -> Self {
+        Self {
+            tasks: Vec::new(),
+            deps: Vec::new(),
+        }
+    }
+
+    /// Adds a task running `f` once the tasks of `deps` completed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a task of `deps` is not in the graph.
+    pub fn add<F>(&mut self, f: F, deps: &[NodeId]) -> NodeId
+    where
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let id = self.tasks.len();
+        assert!(
+            deps.iter().all(|dep| dep.0 < id),
+            "tasks depend on tasks of their graph"
+        );
+        self.tasks.push(Box::new(f));
+        self.deps.push(deps.iter().map(|dep| dep.0).collect());
+        NodeId(id)
+    }
+
+    /// Number of tasks of the graph
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    /// Returns `true` if the graph has no task.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+}
+
+impl<R> Default for TaskGraph<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R> fmt::Debug for TaskGraph<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskGraph")
+            .field("deps", &self.deps)
+            .finish()
+    }
+}
+
+impl NodeId {
+    /// Position of the task in its graph, and of its result in those of
+    /// [`ThreadPool::run_graph`]
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Dependency graphs
+impl ThreadPool {
+    /// Runs the tasks of `graph` on the pool, each one once its dependencies
+    /// completed, and returns their results in the order they were added.
+    ///
+    /// A panic of a task is reported in its result, and the tasks depending
+    /// on it, directly or not, do not run: their result is a panic with
+    /// [`SKIPPED`]. Likewise, a task dropped from a full queue with
+    /// [`Overflow::DropOldest`](super::Overflow::DropOldest) reports a panic
+    /// with [`DROPPED`], and the tasks depending on it are skipped.
+    ///
+    /// Called from a task running on a pool, the calling worker runs the
+    /// queued tasks while waiting, as [`join`](super::TaskHandle::join) does.
+    ///
+    /// The tasks without dependencies are queued as
+    /// [`submit`](Self::submit) does; the others are queued once their
+    /// dependencies completed, even over the capacity of a bounded queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue is full for the tasks without dependencies and
+    /// the pool rejects tasks on overflow.
+    pub fn run_graph<R>(&self, graph: TaskGraph<R>) -> Vec<thread::Result<R>>
+    where
+        R: Send + 'static,
+    {
+        let len = graph.len();
+        let mut dependents = vec![Vec::new(); len];
+        for (i, deps) in graph.deps.iter().enumerate() {
+            for &dep in deps {
+                dependents[dep].push(i);
+            }
+        }
+        let pending: Vec<_> = graph.deps.iter().map(Vec::len).collect();
+        let roots: Vec<_> = (0..len).filter(|&i| pending[i] == 0).collect();
+        let run = Arc::new(Run {
+            shared: self.shared.clone(),
+            state: Mutex::new(State {
+                tasks: graph.tasks.into_iter().map(Some).collect(),
+                pending,
+                dependents,
+                results: (0..len).map(|_| None).collect(),
+                left: len,
+            }),
+            finished: Semaphore::new(0),
+            parent: Parent::current(),
+        });
+        if len == 0 {
+            return Vec::new();
+        }
+        for i in roots {
+            run.queue(i);
+        }
+        let helped = with_worker(|shared, slot| shared.help(slot, &run.finished));
+        if helped.is_none() {
+            // the semaphore is private to the run and never closed
+            run.finished.wait().unwrap();
+        }
+        let mut state = sync::lock(&run.state);
+        state
+            .results
+            .iter_mut()
+            .map(|r| r.take().expect("completed tasks have a result"))
+            .collect()
+    }
+}
+
+impl<R: Send + 'static> Run<R> {
+    /// Task `i` of the run, to be queued
+    fn step(self: &Arc<Self>, i: usize) -> Step<R> {
+        Step {
+            run: self.clone(),
+            i,
+            done: false,
+            queued: Stamp::now(),
+        }
+    }
+
+    /// Queues the task `i`, which has no dependency
+    fn queue(self: &Arc<Self>, i: usize) {
+        let queued = self.shared.submit(0, self.step(i), |step| -> Job {
+            Box::new(move || step.execute())
+        });
+        if queued.is_err() {
+            panic!("the queue of the thread pool is full");
+        }
+    }
+
+    /// Queues the task `i`, whose dependencies completed, even over the
+    /// capacity of a bounded queue: the run started already, and a worker
+    /// may not wait for room nor give up on it
+    fn queue_ready(self: &Arc<Self>, i: usize) {
+        let step = self.step(i);
+        let shared = &self.shared;
+        if shared.is_shut_down() {
+            // never runs, as the queued tasks are dropped
+            drop(step);
+            return;
+        }
+        let slot = shared.slot_for_submit(shared.snapshot().len());
+        shared.queue_over(slot, 0, vec![Box::new(move || step.execute())]);
+        if shared.is_shut_down() && shared.live.load(Ordering::SeqCst) == 0 {
+            // queued as the last worker stopped, after a shutdown
+            shared.drop_queued();
+        }
+    }
+
+    /// Records the result of the task `i`, then queues the tasks it was the
+    /// last dependency of
+    fn finish(self: &Arc<Self>, i: usize, result: thread::Result<R>) {
+        let ready = {
+            let mut state = sync::lock(&self.state);
+            let ok = result.is_ok();
+            state.complete(i, result);
+            let mut ready = vec![];
+            for d in state.dependents[i].clone() {
+                if !ok {
+                    state.skip(d);
+                    continue;
+                }
+                state.pending[d] -= 1;
+                if state.pending[d] == 0 && state.results[d].is_none() {
+                    ready.push(d);
+                }
+            }
+            if state.left == 0 {
+                self.finished.release();
+            }
+            ready
+        };
+        for d in ready {
+            self.queue_ready(d);
+        }
+    }
+}
+
+impl<R: Send + 'static> Step<R> {
+    fn execute(mut self) {
+        self.done = true;
+        let task = sync::lock(&self.run.state).tasks[self.i]
+            .take()
+            .expect("tasks run once");
+        let parent = &self.run.parent;
+        let result = run_task(|| parent.run("task", trace::next_id(), &self.queued, task));
+        self.run.finish(self.i, result);
+    }
+}
+
+impl<R: Send + 'static> Drop for Step<R> {
+    fn drop(&mut self) {
+        // dropped from a full queue: the task never runs
+        if !self.done {
+            sync::lock(&self.run.state).tasks[self.i] = None;
+            self.run.finish(self.i, Err(Box::new(DROPPED)));
+        }
+    }
+}
+
+impl<R> State<R> {
+    fn complete(&mut self, i: usize, result: thread::Result<R>) {
+        self.results[i] = Some(result);
+        self.left -= 1;
+    }
+
+    /// Gives up on the task `i` and the tasks depending on it, one of their
+    /// dependencies having panicked
+    fn skip(&mut self, i: usize) {
+        if self.results[i].is_some() {
+            return;
+        }
+        self.tasks[i] = None;
+        self.complete(i, Err(Box::new(SKIPPED)));
+        for d in self.dependents[i].clone() {
+            self.skip(d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{TaskGraph, SKIPPED};
+    use crate::pool::{Overflow, ThreadPool, DROPPED};
+
+    #[test]
+    fn dependencies() {
+        let pool = ThreadPool::new(3);
+        let done = Arc::new(AtomicUsize::new(0));
+        // the two middle tasks wait for each other, so they run at once
+        let barrier = Arc::new(Barrier::new(2));
+        let mut graph = TaskGraph::new();
+        let d = done.clone();
+        let first = graph.add(move || d.fetch_add(1, Ordering::SeqCst), &[]);
+        let middle = (0..2)
+            .map(|_| {
+                let (d, barrier) = (done.clone(), barrier.clone());
+                graph.add(
+                    move || {
+                        barrier.wait();
+                        d.fetch_add(1, Ordering::SeqCst)
+                    },
+                    &[first],
+                )
+            })
+            .collect::<Vec<_>>();
+        let d = done.clone();
+        let last = graph.add(move || d.fetch_add(1, Ordering::SeqCst), &middle);
+        assert_eq!(4, graph.len());
+
+        let results = pool.run_graph(graph);
+        let order = results.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(0, order[first.index()]);
+        assert_eq!(3, order[last.index()]);
+        assert!(pool.run_graph(TaskGraph::<()>::new()).is_empty());
+    }
+
+    #[test]
+    fn panics_skip_dependents() {
+        let pool = ThreadPool::new(2);
+        let mut graph = TaskGraph::new();
+        let ok = graph.add(|| 1, &[]);
+        let failed = graph.add(|| panic!("build failure"), &[]);
+        let dependent = graph.add(|| 2, &[ok, failed]);
+        let transitive = graph.add(|| 3, &[dependent]);
+        let unrelated = graph.add(|| 4, &[ok]);
+        let results = pool.run_graph(graph);
+        let payload = |i: usize| {
+            let e = results[i].as_ref().unwrap_err();
+            e.downcast_ref::<&str>().copied()
+        };
+        assert_eq!(Some("build failure"), payload(failed.index()));
+        assert_eq!(Some(SKIPPED), payload(dependent.index()));
+        assert_eq!(Some(SKIPPED), payload(transitive.index()));
+        assert_eq!(Some(&4), results[unrelated.index()].as_ref().ok());
+    }
+
+    #[test]
+    fn dropped_from_a_full_queue() {
+        let pool = ThreadPool::bounded(1, 1, Overflow::DropOldest);
+        let (started_tx, started) = mpsc::channel();
+        let (release, rx) = mpsc::channel::<()>();
+        drop(pool.submit(move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        }));
+        started.recv().unwrap();
+        let mut graph = TaskGraph::new();
+        // queued behind the busy worker, the second root pushes the first out
+        let dropped = graph.add(|| (), &[]);
+        let kept = graph.add(|| (), &[]);
+        let dependent = graph.add(|| (), &[dropped, kept]);
+        thread::scope(|s| {
+            let run = s.spawn(|| pool.run_graph(graph));
+            thread::sleep(Duration::from_millis(50));
+            drop(release);
+            let results = run.join().unwrap();
+            let payload = |i: usize| {
+                let e = results[i].as_ref().unwrap_err();
+                e.downcast_ref::<&str>().copied()
+            };
+            assert_eq!(Some(DROPPED), payload(dropped.index()));
+            assert!(results[kept.index()].is_ok());
+            assert_eq!(Some(SKIPPED), payload(dependent.index()));
+        });
+    }
+
+    #[test]
+    fn dependents_over_a_full_queue() {
+        let pool = ThreadPool::bounded(1, 1, Overflow::Reject);
+        let mut graph = TaskGraph::new();
+        let root = graph.add(|| 1, &[]);
+        // ready at once, the two go over the capacity of the queue
+        graph.add(|| 2, &[root]);
+        graph.add(|| 3, &[root]);
+        let results = pool.run_graph(graph);
+        let results = results.into_iter().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(vec![1, 2, 3], results);
+        assert_eq!(1, pool.submit(|| 1).join().unwrap());
+    }
+
+    #[test]
+    fn from_a_task() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let p = pool.clone();
+        let sum = pool.submit(move || {
+            let mut graph = TaskGraph::new();
+            let a = graph.add(|| 1, &[]);
+            graph.add(|| 2, &[a]);
+            p.run_graph(graph)
+                .into_iter()
+                .map(|r| r.unwrap())
+                .sum::<i32>()
+        });
+        assert_eq!(3, sum.join().unwrap());
+    }
+}
@@ -0,0 +1,147 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use super::{with_worker, PanicPolicy, ThreadPool, PANICKED};
+use crate::sync;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counters of a pool, behind [`ThreadPool::metrics`]
+#[derive(Default)]
+pub(super) struct Counters {
+    /// Number of workers running a job
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    panicked: AtomicUsize,
+}
+
+/// Snapshot of the activity of a pool, returned by [`ThreadPool::metrics`]
+///
+/// Counters are read one at a time while the workers run, so a snapshot
+/// taken under load may be slightly inconsistent, e.g. count a task as
+/// completed that is still counted as queued.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// Number of tasks queued, waiting for a worker. Delayed tasks not due
+    /// yet, and keyed tasks waiting for the previous one of their key, are
+    /// not queued yet.
+    pub queued: usize,
+    /// Number of workers running a task
+    pub active: usize,
+    /// Number of tasks that ran to completion
+    pub completed: u64,
+    /// Number of tasks that panicked
+    pub panicked: u64,
+    /// Average time the tasks taken by the workers waited in the queue,
+    /// zero until one was taken
+    pub average_wait: Duration,
+}
+
+/// Activity of the pool
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::ThreadPool;
+/// let pool = ThreadPool::new(2);
+/// pool.submit(|| ()).join().unwrap();
+/// assert!(pool.submit(|| panic!()).join().is_err());
+/// let metrics = pool.metrics();
+/// assert_eq!(1, metrics.completed);
+/// assert_eq!(1, metrics.panicked);
+/// ```
+impl ThreadPool {
+    /// Get a snapshot of the queue and of the tasks run so far.
+    pub fn metrics(&self) -> PoolMetrics {
+        let shared = &self.shared;
+        let counters = &shared.counters;
+        let (mut waits, mut waited) = (0u64, Duration::ZERO);
+        for deque in shared.snapshot().iter() {
+            let jobs = sync::lock(deque);
+            waits += jobs.waits;
+            waited += jobs.waited;
+        }
+        let average_wait = match u32::try_from(waits) {
+            Ok(0) => Duration::ZERO,
+            Ok(n) => waited / n,
+            Err(_) => Duration::from_secs_f64(waited.as_secs_f64() / waits as f64),
+        };
+        PoolMetrics {
+            queued: shared.queued.load(Ordering::Relaxed),
+            active: counters.active.load(Ordering::Relaxed),
+            completed: counters.completed.load(Ordering::Relaxed) as u64,
+            panicked: counters.panicked.load(Ordering::Relaxed) as u64,
+            average_wait,
+        }
+    }
+}
+
+impl Counters {
+    /// Counts a worker as active while it runs `job`
+    pub(super) fn active<T>(&self, job: impl FnOnce() -> T) -> T {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        let out = job();
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        out
+    }
+}
+
+/// Runs the task `f`, catching its panic, counts its outcome in the metrics
+/// of the pool running it, and applies the panic policy of the pool
+pub(super) fn run_task<R>(f: impl FnOnce() -> R) -> thread::Result<R> {
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    with_worker(|shared, _| {
+        if result.is_ok() {
+            shared.counters.completed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        shared.counters.panicked.fetch_add(1, Ordering::Relaxed);
+        match shared.panic_policy {
+            PanicPolicy::Propagate => (),
+            PanicPolicy::Restart => PANICKED.with(|panicked| panicked.set(true)),
+            PanicPolicy::Abort => process::abort(),
+        }
+    });
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::pool::{TaskGraph, ThreadPool};
+
+    #[test]
+    fn counters() {
+        let pool = ThreadPool::new(1);
+        assert_eq!(Duration::ZERO, pool.metrics().average_wait);
+        let (started_tx, started) = mpsc::channel();
+        let (release, rx) = mpsc::channel::<()>();
+        let busy = pool.submit(move || {
+            started_tx.send(()).unwrap();
+            let _ = rx.recv();
+        });
+        started.recv().unwrap();
+        let queued = (0..3).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+        let metrics = pool.metrics();
+        assert_eq!(3, metrics.queued);
+        assert_eq!(1, metrics.active);
+        thread::sleep(Duration::from_millis(20));
+        drop(release);
+        busy.join().unwrap();
+        queued.into_iter().for_each(|h| drop(h.join()));
+
+        let mut graph = TaskGraph::new();
+        graph.add(|| panic!("failed"), &[]);
+        drop(pool.run_graph(graph));
+        let metrics = pool.metrics();
+        assert_eq!(0, metrics.queued);
+        assert_eq!(4, metrics.completed);
+        assert_eq!(1, metrics.panicked);
+        // three of the five tasks waited behind the busy one
+        assert!(metrics.average_wait >= Duration::from_millis(10));
+    }
+}
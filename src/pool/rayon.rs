@@ -0,0 +1,74 @@
+use std::io;
+
+use super::ThreadPool;
+
+/// Interoperability with rayon
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::ThreadPool;
+/// let pool = ThreadPool::new(4);
+/// let rayon = pool.rayon_pool(2).unwrap();
+/// // two workers run rayon, the other two the tasks of the pool
+/// assert_eq!(3, pool.submit(|| 3).join().unwrap());
+/// assert_eq!(5050, rayon.install(|| (1..=100).sum::<i32>()));
+/// drop(rayon);
+/// ```
+impl ThreadPool {
+    /// Builds a [rayon](https://docs.rs/rayon) pool of `threads` threads
+    /// taken from the workers of this pool, zero meaning all of them, so
+    /// that code written against rayon runs without spawning a second set
+    /// of threads.
+    ///
+    /// Every thread of the rayon pool is a task of this pool, which holds its
+    /// worker until the rayon pool is dropped: `threads` should leave enough
+    /// workers to run the other tasks of the pool. The rayon pool is to be
+    /// dropped first, as dropping this one waits for its tasks.
+    ///
+    /// Fails if this pool is [shut down](ThreadPool::shutdown), or if rayon
+    /// fails to build its pool.
+    pub fn rayon_pool(
+        &self,
+        threads: usize,
+    ) -> Result<::rayon::ThreadPool, ::rayon::ThreadPoolBuildError> {
+        let threads = if threads == 0 {
+            self.workers()
+        } else {
+            threads
+        };
+        ::rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .spawn_handler(|thread| {
+                if self.is_shutdown() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "the thread pool is shut down",
+                    ));
+                }
+                drop(self.submit(move || thread.run()));
+                Ok(())
+            })
+            .build()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pool::ThreadPool;
+
+    #[test]
+    fn rayon_on_the_workers() {
+        let pool = ThreadPool::builder()
+            .workers(2)
+            .thread_name("esync-worker")
+            .build();
+        let rayon = pool.rayon_pool(1).unwrap();
+        let name = rayon.install(|| std::thread::current().name().map(String::from));
+        assert!(name.unwrap().starts_with("esync-worker-"));
+        assert_eq!(1, rayon.current_num_threads());
+        drop(rayon);
+        pool.shutdown();
+        assert!(pool.rayon_pool(1).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+
+use super::{job, with_worker, Job, TaskError, TaskHandle, ThreadPool};
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::Semaphore;
+
+/// Scope of tasks borrowing from the stack of the caller of
+/// [`ThreadPool::scope`]
+///
+/// The tasks submitted to the scope run on the workers of the pool, and all
+/// of them complete before [`ThreadPool::scope`] returns.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<State>,
+    /// Invariant in both lifetimes, as [`std::thread::Scope`] is
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Handle on the result of a task submitted to a [`Scope`]
+///
+/// Dropping the handle does not cancel the task, only discards its result;
+/// should it panic, the scope does.
+#[must_use = "dropping the handle discards the result of the task"]
+pub struct ScopedTaskHandle<'scope, R> {
+    handle: TaskHandle<R>,
+    state: Arc<State>,
+    scope: PhantomData<&'scope ()>,
+}
+
+/// Tasks of a scope, kept apart from the scope so that the last one to
+/// complete may release the caller without touching the scope afterwards
+struct State {
+    /// Tasks not completed or dropped yet, plus one for the caller until it
+    /// is done submitting
+    pending: AtomicUsize,
+    /// Released once nothing is pending anymore
+    done: Semaphore,
+    /// Tasks that panicked or were dropped, and whose handle did not report
+    /// it
+    failed: AtomicUsize,
+}
+
+/// Task of a scope, leaving the scope once run or dropped
+struct Member<'scope> {
+    job: Option<Box<dyn FnOnce() + Send + 'scope>>,
+    state: Arc<State>,
+}
+
+/// Counts the task as failed unless defused, once it returned
+struct Failure(Option<Arc<State>>);
+
+/// Scoped tasks
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::ThreadPool;
+/// let pool = ThreadPool::new(2);
+/// let words = vec!["scoped", "tasks", "borrow"];
+/// let mut total = 0;
+/// pool.scope(|s| {
+///     let lengths = words
+///         .iter()
+///         .map(|word| s.submit(move || word.len()))
+///         .collect::<Vec<_>>();
+///     total = lengths.into_iter().map(|h| h.join().unwrap()).sum();
+/// });
+/// assert_eq!(17, total);
+/// ```
+impl ThreadPool {
+    /// Calls `f` with a scope whose tasks may borrow what outlives the call,
+    /// and waits for all of them before returning, like
+    /// [`std::thread::scope`] but on the workers of the pool.
+    ///
+    /// Called from a task running on a pool, it runs the other tasks queued
+    /// on the pool while waiting, as [`TaskHandle::join`] does.
+    ///
+    /// # Panics
+    ///
+    /// Panics once the tasks completed if `f` panicked, or if a task panicked
+    /// or was dropped and its handle was not joined.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(State {
+                pending: AtomicUsize::new(1),
+                done: Semaphore::new(0),
+                failed: AtomicUsize::new(0),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.state.leave();
+        let done = &scope.state.done;
+        if with_worker(|shared, slot| shared.help(slot, done)).is_none() {
+            // the semaphore is private to the scope and never closed
+            done.wait().unwrap();
+        }
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.failed.load(Ordering::SeqCst) > 0 => {
+                panic!("a task of the scope panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Queues `f` to run on one of the workers of the pool, as
+    /// [`ThreadPool::submit`] does, and returns a handle on its result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the queue is full and the pool rejects tasks on overflow.
+    pub fn submit<F, R>(&'scope self, f: F) -> ScopedTaskHandle<'scope, R>
+    where
+        F: FnOnce() -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let failure = Failure(Some(self.state.clone()));
+        let (job, handle) = job(move || {
+            let mut failure = failure;
+            let result = f();
+            failure.0 = None;
+            result
+        });
+        self.state.pending.fetch_add(1, Ordering::SeqCst);
+        let member = Member {
+            job: Some(job),
+            state: self.state.clone(),
+        };
+        let run: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let mut member = member;
+            if let Some(job) = member.job.take() {
+                job();
+            }
+        });
+        // SAFETY: the scope waits for the job to leave it, which it does last
+        // once run or dropped, so that what it borrows outlives it
+        let run = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(run) };
+        if self.pool.shared.submit(0, run, |run| run).is_err() {
+            panic!("the queue of the thread pool is full");
+        }
+        self.pool.grow();
+        ScopedTaskHandle {
+            handle,
+            state: self.state.clone(),
+            scope: PhantomData,
+        }
+    }
+}
+
+impl<R> ScopedTaskHandle<'_, R> {
+    /// Waits for the task to complete and returns its result, or why it has
+    /// none, as [`TaskHandle::join`] does.
+    ///
+    /// A panic or a drop reported here does not make the scope panic.
+    pub fn join(self) -> Result<R, TaskError> {
+        let result = self.handle.join();
+        if result.is_err() {
+            self.state.failed.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Id of the task, as [`TaskHandle::id`] tells it.
+    pub fn id(&self) -> usize {
+        self.handle.id()
+    }
+}
+
+impl State {
+    fn leave(&self) {
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.done.release();
+        }
+    }
+}
+
+impl Drop for Member<'_> {
+    fn drop(&mut self) {
+        if let Some(job) = self.job.take() {
+            // dropped from the queue, the failure of the task counts it and
+            // the handle reports it
+            drop(job);
+        }
+        self.state.leave();
+    }
+}
+
+impl Drop for Failure {
+    fn drop(&mut self) {
+        if let Some(state) = &self.0 {
+            state.failed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::pool::{TaskError, ThreadPool};
+
+    #[test]
+    fn borrows() {
+        let pool = ThreadPool::new(3);
+        let count = AtomicUsize::new(0);
+        let mut items = vec![1, 2, 3, 4];
+        pool.scope(|s| {
+            for item in &mut items {
+                let count = &count;
+                // not joined, the scope waits anyway
+                drop(s.submit(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    *item *= 10;
+                    count.fetch_add(1, Ordering::SeqCst);
+                }));
+            }
+        });
+        assert_eq!(4, count.load(Ordering::SeqCst));
+        assert_eq!(vec![10, 20, 30, 40], items);
+    }
+
+    #[test]
+    fn panics() {
+        let pool = ThreadPool::new(2);
+        // a panic joined does not take the scope down
+        let joined = pool.scope(|s| s.submit(|| panic!("task failure")).join().is_err());
+        assert!(joined);
+        let unjoined = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| drop(s.submit(|| panic!("task failure"))))
+        }));
+        assert!(unjoined.is_err());
+        // the tasks complete before the panic of the caller goes on
+        let ran = AtomicUsize::new(0);
+        let failed = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                drop(s.submit(|| {
+                    thread::sleep(Duration::from_millis(20));
+                    ran.fetch_add(1, Ordering::SeqCst);
+                }));
+                panic!("caller failure");
+            })
+        }));
+        let payload = failed.unwrap_err();
+        assert_eq!(Some(&"caller failure"), payload.downcast_ref::<&str>());
+        assert_eq!(1, ran.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn dropped() {
+        let pool = ThreadPool::new(1);
+        pool.shutdown();
+        // a dropped task joined does not take the scope down either
+        let r = pool.scope(|s| s.submit(|| 1).join());
+        assert!(matches!(r, Err(TaskError::Dropped)));
+        let unjoined = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| drop(s.submit(|| 1)));
+        }));
+        assert!(unjoined.is_err());
+    }
+
+    #[test]
+    fn from_a_task() {
+        let pool = Arc::new(ThreadPool::new(1));
+        let p = pool.clone();
+        let sum = pool.submit(move || {
+            let data = [1, 2, 3];
+            let sum = AtomicUsize::new(0);
+            // the single worker runs the tasks of the scope as it waits
+            p.scope(|s| {
+                for &i in &data {
+                    let sum = &sum;
+                    drop(s.submit(move || sum.fetch_add(i, Ordering::SeqCst)));
+                }
+            });
+            sum.into_inner()
+        });
+        assert_eq!(6, sum.join().unwrap());
+    }
+}
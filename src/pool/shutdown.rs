@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use super::{Shared, ThreadPool};
+use crate::sync;
+use crate::sync::atomic::Ordering;
+use crate::CancellationToken;
+
+/// Shutdown
+///
+/// A pool shut down takes no task anymore: the tasks submitted then are
+/// dropped at once, their handles reporting them as
+/// [dropped](super::TaskError::Dropped), and so are the delayed tasks not
+/// due yet.
+/// The workers stop once they have nothing left to run, and
+/// [`await_termination`](ThreadPool::await_termination) waits for them.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::pool::ThreadPool;
+/// # use std::time::Duration;
+/// let pool = ThreadPool::new(2);
+/// let queued = (0..4).map(|i| pool.submit(move || i)).collect::<Vec<_>>();
+/// pool.shutdown();
+/// assert!(pool.submit(|| 4).join().is_err());
+/// assert!(pool.await_termination(Duration::from_secs(10)));
+/// let sum = queued.into_iter().map(|h| h.join().unwrap()).sum::<i32>();
+/// assert_eq!(6, sum);
+/// ```
+impl ThreadPool {
+    /// Stops taking tasks, and lets the workers run the ones queued before
+    /// they stop.
+    pub fn shutdown(&self) {
+        let mut w = sync::lock(&self.workers);
+        self.shared.shut.store(true, Ordering::SeqCst);
+        self.shared.drop_timers();
+        self.shared.retire_all(w.target);
+        w.target = 0;
+    }
+
+    /// Stops taking tasks, drops the ones queued, and cancels the
+    /// [token](Self::shutdown_token) of the pool, for the running tasks to
+    /// stop early.
+    ///
+    /// The tasks dropped include those waiting for the previous one of their
+    /// [key](Self::submit_keyed). The running tasks are not interrupted:
+    /// stopping is up to them.
+    pub fn shutdown_now(&self) {
+        let mut w = sync::lock(&self.workers);
+        self.shared.shut.store(true, Ordering::SeqCst);
+        self.shared.token.cancel();
+        self.shared.drop_timers();
+        self.shared.drop_queued();
+        self.shared.retire_all(w.target);
+        w.target = 0;
+    }
+
+    /// Returns `true` once the pool is shut down, gracefully or not.
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.is_shut_down()
+    }
+
+    /// Waits for all the workers to stop after a shutdown, for at most
+    /// `timeout`. Returns `false` if some are still running tasks then.
+    pub fn await_termination(&self, timeout: Duration) -> bool {
+        let terminated = &self.shared.terminated;
+        if terminated.wait_timeout(timeout).is_err() {
+            return false;
+        }
+        // for the next calls
+        terminated.release();
+        true
+    }
+
+    /// Token cancelled by [`shutdown_now`](Self::shutdown_now), which tasks
+    /// check, or give to their waits, to stop early when the pool is shut
+    /// down.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use esync::{AcquireError, Semaphore};
+    /// # use std::sync::mpsc;
+    /// # use std::time::Duration;
+    /// let pool = ThreadPool::new(1);
+    /// let (token, (started, rx)) = (pool.shutdown_token(), mpsc::channel());
+    /// let waiting = pool.submit(move || {
+    ///     started.send(()).unwrap();
+    ///     Semaphore::new(0).wait_cancellable(&token)
+    /// });
+    /// rx.recv().unwrap();
+    /// pool.shutdown_now();
+    /// assert!(pool.await_termination(Duration::from_secs(10)));
+    /// assert_eq!(Err(AcquireError::Cancelled), waiting.join().unwrap());
+    /// ```
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shared.token.clone()
+    }
+}
+
+impl Shared {
+    pub(super) fn is_shut_down(&self) -> bool {
+        self.shut.load(Ordering::SeqCst)
+    }
+
+    /// Drops the queued jobs, including those waiting for their key
+    pub(super) fn drop_queued(&self) {
+        let deques = self.snapshot();
+        let mut n = 0;
+        while self.claim() {
+            drop(Self::find_lowest(&deques, 0));
+            n += 1;
+        }
+        // the wakeups of the jobs dropped find nothing to claim
+        self.wakeups.fetch_add(n, Ordering::SeqCst);
+        let waiting: Vec<_> = sync::lock(&self.keyed)
+            .values_mut()
+            .flat_map(|jobs| jobs.drain(..))
+            .collect();
+        drop(waiting);
+    }
+
+    /// Counts a worker stopping, the last one after a shutdown ending the
+    /// pool
+    pub(super) fn stopped(&self) {
+        if self.live.fetch_sub(1, Ordering::SeqCst) == 1 && self.is_shut_down() {
+            // jobs queued while the last workers stopped
+            self.drop_queued();
+            self.terminated.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    use crate::pool::{TaskError, ThreadPool};
+
+    fn dropped<R>(r: Result<R, TaskError>) -> bool {
+        matches!(r, Err(TaskError::Dropped))
+    }
+
+    #[test]
+    fn graceful() {
+        let pool = ThreadPool::new(1);
+        let (release, rx) = mpsc::channel::<()>();
+        let busy = pool.submit(move || {
+            let _ = rx.recv();
+        });
+        let queued = pool.submit(|| 1);
+        let delayed = pool.submit_after(Duration::from_secs(3600), || 2);
+        assert!(!pool.is_shutdown());
+        pool.shutdown();
+        assert!(pool.is_shutdown());
+        assert_eq!(0, pool.workers());
+        assert!(!pool.await_termination(Duration::from_millis(20)));
+        drop(release);
+        assert!(pool.await_termination(Duration::from_secs(10)));
+        assert!(pool.await_termination(Duration::ZERO));
+        busy.join().unwrap();
+        assert_eq!(Ok(1), queued.join().map_err(|_| ()));
+        assert!(dropped(delayed.join()));
+        assert!(dropped(pool.submit(|| 3).join()));
+        assert!(dropped(pool.submit_keyed(1, || 4).join()));
+        assert!(pool.broadcast(|| ()).is_empty());
+        assert!(!pool.shutdown_token().is_cancelled());
+        pool.resize(2);
+        assert_eq!(0, pool.workers());
+    }
+
+    #[test]
+    fn now() {
+        let pool = ThreadPool::new(1);
+        let (started_tx, started) = mpsc::channel();
+        let token = pool.shutdown_token();
+        let busy = pool.submit(move || {
+            started_tx.send(()).unwrap();
+            while !token.is_cancelled() {
+                std::thread::yield_now();
+            }
+            "stopped"
+        });
+        started.recv().unwrap();
+        let ran = Arc::new(AtomicUsize::new(0));
+        let (r, k) = (ran.clone(), ran.clone());
+        let queued = pool.submit(move || r.fetch_add(1, Ordering::SeqCst));
+        let keyed = (0..2)
+            .map(|_| {
+                let k = k.clone();
+                pool.submit_keyed("key", move || k.fetch_add(1, Ordering::SeqCst))
+            })
+            .collect::<Vec<_>>();
+        pool.shutdown_now();
+        assert!(pool.await_termination(Duration::from_secs(10)));
+        assert_eq!(Ok("stopped"), busy.join().map_err(|_| ()));
+        assert!(dropped(queued.join()));
+        for h in keyed {
+            assert!(dropped(h.join()));
+        }
+        assert_eq!(0, ran.load(Ordering::SeqCst));
+    }
+}
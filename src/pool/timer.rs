@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use super::{job, run_task, Job, Shared, TaskHandle, ThreadPool};
+use crate::sync;
+use crate::sync::atomic::Ordering;
+use crate::trace::{self, Parent, Stamp};
+use crate::CancellationToken;
+
+/// Jobs waiting for their time to be queued, by due time then submission
+/// order
+#[derive(Default)]
+pub(super) struct Timers {
+    jobs: BTreeMap<(Instant, u64), Job>,
+    /// Number of the next job scheduled, telling apart those due at once
+    seq: u64,
+}
+
+/// Task run over and over, rescheduled by each run
+struct Recurring<F> {
+    /// Pool of the task, not kept alive by it
+    shared: Weak<Shared>,
+    f: F,
+    /// Time the run was due
+    due: Instant,
+    every: Duration,
+    /// Whether runs are `every` apart from their start rather than from the
+    /// end of the previous one
+    fixed_rate: bool,
+    token: CancellationToken,
+    /// Span the task was submitted in, and its number in the traces
+    parent: Parent,
+    id: usize,
+}
+
+/// Delayed and recurring tasks
+impl ThreadPool {
+    /// Queues `f` to run on one of the workers once `delay` elapsed, and
+    /// returns a handle on its result.
+    ///
+    /// The task waits apart until it is due, then runs as if
+    /// [submitted](Self::submit) at that time, as soon as a worker is free.
+    /// The idle workers keep track of the next task due, so that no thread
+    /// is spent waiting for delayed tasks. A due task is queued even over
+    /// the capacity of a bounded queue, and waiting tasks do not count in
+    /// it. Tasks still waiting when the pool is dropped do not run: their
+    /// handles report them as [dropped](super::TaskError::Dropped).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::time::{Duration, Instant};
+    /// let pool = ThreadPool::new(1);
+    /// let start = Instant::now();
+    /// let later = pool.submit_after(Duration::from_millis(20), move || start.elapsed());
+    /// let now = pool.submit(|| "now");
+    /// assert_eq!("now", now.join().unwrap());
+    /// assert!(later.join().unwrap() >= Duration::from_millis(20));
+    /// ```
+    pub fn submit_after<F, R>(&self, delay: Duration, f: F) -> TaskHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = job(f);
+        match Instant::now().checked_add(delay) {
+            Some(at) => self.shared.schedule(at, job),
+            // a delay too long to represent never ends in practice
+            None => drop(job),
+        }
+        handle
+    }
+
+    /// Runs `f` on the workers every `period`, until the returned token is
+    /// cancelled.
+    ///
+    /// The first run is due once `period` elapsed, and every next one a
+    /// `period` after it was due, whatever the runs last, e.g. to flush
+    /// metrics every second. A run is never started while the previous one
+    /// is running: the runs that fall due meanwhile are skipped, so that the
+    /// task does not run in bursts after a long one.
+    ///
+    /// A panic in `f` stops the task, cancelling the token. Cancelling the
+    /// token does not interrupt a run in progress, but no run starts after
+    /// it. The task does not keep the pool alive, and stops when it is
+    /// dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `period` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::pool::ThreadPool;
+    /// # use std::sync::mpsc;
+    /// # use std::time::Duration;
+    /// let pool = ThreadPool::new(2);
+    /// let (tx, rx) = mpsc::channel();
+    /// let flushes = pool.submit_at_fixed_rate(Duration::from_millis(10), move || {
+    ///     let _ = tx.send("flushed");
+    /// });
+    /// assert_eq!(Ok("flushed"), rx.recv());
+    /// assert_eq!(Ok("flushed"), rx.recv());
+    /// flushes.cancel();
+    /// ```
+    pub fn submit_at_fixed_rate<F>(&self, period: Duration, f: F) -> CancellationToken
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.recurring(period, true, f)
+    }
+
+    /// Runs `f` on the workers over and over, `delay` after the previous run
+    /// completed, until the returned token is cancelled.
+    ///
+    /// The first run is due once `delay` elapsed, e.g. to refresh a cache
+    /// that takes a varying time to load. See
+    /// [`submit_at_fixed_rate`](Self::submit_at_fixed_rate) for panics and
+    /// cancellation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `delay` is zero.
+    pub fn submit_with_fixed_delay<F>(&self, delay: Duration, f: F) -> CancellationToken
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.recurring(delay, false, f)
+    }
+
+    fn recurring<F>(&self, every: Duration, fixed_rate: bool, f: F) -> CancellationToken
+    where
+        F: FnMut() + Send + 'static,
+    {
+        assert!(
+            every > Duration::ZERO,
+            "recurring tasks need time between runs"
+        );
+        let token = CancellationToken::new();
+        let task = Recurring {
+            shared: Arc::downgrade(&self.shared),
+            f,
+            due: Instant::now(),
+            every,
+            fixed_rate,
+            token: token.clone(),
+            parent: Parent::current(),
+            id: trace::next_id(),
+        };
+        task.reschedule(&self.shared);
+        token
+    }
+}
+
+impl<F: FnMut() + Send + 'static> Recurring<F> {
+    fn run(mut self) {
+        if self.token.is_cancelled() {
+            return;
+        }
+        let (parent, f) = (&self.parent, &mut self.f);
+        let run = || parent.run("task", self.id, &Stamp::at(self.due), f);
+        if run_task(run).is_err() {
+            self.token.cancel();
+            return;
+        }
+        if self.token.is_cancelled() {
+            return;
+        }
+        // the worker running the task keeps the pool alive
+        if let Some(shared) = self.shared.upgrade() {
+            self.reschedule(&shared);
+        }
+    }
+
+    /// Schedules the next run
+    fn reschedule(mut self, shared: &Shared) {
+        let now = Instant::now();
+        let next = if self.fixed_rate {
+            // the first beat still to come
+            let late = now.saturating_duration_since(self.due).as_nanos();
+            let beats = late / self.every.as_nanos() + 1;
+            let ahead = self.every.as_nanos().saturating_mul(beats);
+            u64::try_from(ahead)
+                .ok()
+                .and_then(|ahead| self.due.checked_add(Duration::from_nanos(ahead)))
+        } else {
+            now.checked_add(self.every)
+        };
+        // a period too long to represent never ends in practice
+        if let Some(next) = next {
+            self.due = next;
+            shared.schedule(next, Box::new(move || self.run()));
+        }
+    }
+}
+
+impl Shared {
+    /// Queues `job` once `at` is past, waking up a worker to keep track of
+    /// it if it is the next one due
+    fn schedule(&self, at: Instant, job: Job) {
+        if self.is_shut_down() {
+            return;
+        }
+        let mut timers = sync::lock(&self.timers);
+        let seq = timers.seq;
+        timers.seq += 1;
+        let first = timers
+            .jobs
+            .keys()
+            .next()
+            .map_or(true, |&(next, _)| at < next);
+        timers.jobs.insert((at, seq), job);
+        drop(timers);
+        if self.is_shut_down() {
+            // shut down meanwhile
+            self.drop_timers();
+            return;
+        }
+        if first {
+            self.wakeups.fetch_add(1, Ordering::SeqCst);
+            self.ready.release();
+        }
+    }
+
+    /// Drops the delayed jobs, the pool being shut down
+    pub(super) fn drop_timers(&self) {
+        let jobs = std::mem::take(&mut sync::lock(&self.timers).jobs);
+        drop(jobs);
+    }
+
+    /// Queues the jobs due on the deque of `slot`, and returns when the next
+    /// one is due
+    pub(super) fn fire_timers(&self, slot: usize) -> Option<Instant> {
+        let mut timers = sync::lock(&self.timers);
+        let now = Instant::now();
+        let mut due = Vec::new();
+        while let Some(&key) = timers.jobs.keys().next() {
+            if key.0 > now {
+                break;
+            }
+            due.extend(timers.jobs.remove(&key));
+        }
+        let next = timers.jobs.keys().next().map(|&(at, _)| at);
+        drop(timers);
+        if !due.is_empty() {
+            self.queue_over(slot, 0, due);
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::pool::{Overflow, TaskError, ThreadPool};
+
+    #[test]
+    fn delayed() {
+        let pool = ThreadPool::new(2);
+        let start = Instant::now();
+        let (tx, rx) = mpsc::channel();
+        // scheduled out of order, and the earlier one wakes up a worker
+        // already waiting for the later one
+        let handles = [60, 20, 40]
+            .iter()
+            .map(|&ms| {
+                let tx = tx.clone();
+                pool.submit_after(Duration::from_millis(ms), move || {
+                    tx.send(ms).unwrap();
+                    start.elapsed()
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+        assert_eq!(vec![20, 40, 60], rx.iter().collect::<Vec<_>>());
+        for (h, ms) in handles.into_iter().zip([60, 20, 40]) {
+            assert!(h.join().unwrap() >= Duration::from_millis(ms));
+        }
+    }
+
+    #[test]
+    fn recurring() {
+        let pool = ThreadPool::new(2);
+        let (tx, rx) = mpsc::channel();
+        let start = Instant::now();
+        let beats = pool.submit_at_fixed_rate(Duration::from_millis(10), move || {
+            let _ = tx.send(start.elapsed());
+        });
+        let times = rx.iter().take(3).collect::<Vec<_>>();
+        beats.cancel();
+        for (i, t) in times.iter().enumerate() {
+            assert!(*t >= Duration::from_millis(10 * (i as u64 + 1)));
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let ends = Arc::new(Mutex::new(vec![]));
+        let e = ends.clone();
+        let token = pool.submit_with_fixed_delay(Duration::from_millis(10), move || {
+            let _ = tx.send(Instant::now());
+            thread::sleep(Duration::from_millis(10));
+            e.lock().unwrap().push(Instant::now());
+        });
+        let starts = rx.iter().take(3).collect::<Vec<_>>();
+        token.cancel();
+        let ends = ends.lock().unwrap().clone();
+        // runs start a delay after the previous one ended
+        for (start, end) in starts[1..].iter().zip(&ends) {
+            assert!(*start >= *end + Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn recurring_panic() {
+        let pool = ThreadPool::new(1);
+        let (tx, rx) = mpsc::channel();
+        let mut runs = 0;
+        let token = pool.submit_with_fixed_delay(Duration::from_millis(1), move || {
+            runs += 1;
+            tx.send(runs).unwrap();
+            assert!(runs < 2, "failed run");
+        });
+        assert_eq!(vec![1, 2], rx.iter().collect::<Vec<_>>());
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn bounded() {
+        let pool = ThreadPool::bounded(1, 1, Overflow::Reject);
+        let delayed = pool.submit_after(Duration::from_millis(10), || 1);
+        assert_eq!(Ok(1), delayed.join().map_err(|_| ()));
+        // the delayed task gave back no room it did not take
+        let (release, rx) = mpsc::channel::<()>();
+        let busy = pool.submit(move || {
+            let _ = rx.recv();
+        });
+        let started = Instant::now();
+        while pool.try_submit(|| ()).is_err() {
+            // the busy task is still queued
+            assert!(started.elapsed() < Duration::from_secs(10));
+        }
+        assert!(pool.try_submit(|| ()).is_err());
+        drop(release);
+        busy.join().unwrap();
+
+        let pool = ThreadPool::new(1);
+        let never = pool.submit_after(Duration::from_secs(3600), || ());
+        drop(pool);
+        assert!(matches!(never.join(), Err(TaskError::Dropped)));
+    }
+}
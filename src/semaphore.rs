@@ -1,105 +1,1849 @@
-use std::sync::{Condvar, Mutex};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "async")]
+use core::task::Waker;
+
+use crate::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+#[cfg(not(feature = "std"))]
+use crate::sync::Instant;
+use crate::sync::{self, Arc, Condvar, Mutex, MutexGuard};
+use crate::CancellationToken;
+
+#[cfg(feature = "async")]
+mod future;
+#[cfg(feature = "async")]
+pub use future::Acquire;
+
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::{Next, OwnedPermit, Permits};
+
+#[cfg(feature = "std")]
+mod lease;
+#[cfg(feature = "std")]
+pub use lease::Lease;
+
+#[cfg(feature = "std")]
+mod wait_set;
+#[cfg(feature = "std")]
+pub use wait_set::WaitSet;
+
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+pub use stats::{SemaphoreStats, WAIT_BUCKETS};
+
+/// Error returned by the blocking acquisition methods of [`Semaphore`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AcquireError {
+    /// The semaphore has been closed
+    Closed,
+    /// The timeout or deadline expired before the resources could be taken
+    TimedOut,
+    /// The cancellation token given to the wait was cancelled
+    Cancelled,
+}
+
+impl fmt::Display for AcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcquireError::Closed => write!(f, "semaphore closed"),
+            AcquireError::TimedOut => write!(f, "timed out waiting for the semaphore"),
+            AcquireError::Cancelled => write!(f, "wait for the semaphore cancelled"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AcquireError {}
+
+/// Error returned by [`Semaphore::try_acquire_many`] when the requested
+/// resources could not be taken all at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// The semaphore has been closed
+    Closed,
+    /// Not enough resources were available
+    NoPermits {
+        /// Number of resources requested
+        requested: usize,
+        /// Number of resources available at the time of the call
+        available: usize,
+    },
+}
+
+impl fmt::Display for TryAcquireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryAcquireError::Closed => write!(f, "semaphore closed"),
+            TryAcquireError::NoPermits {
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} resources but only {} available",
+                requested, available
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TryAcquireError {}
+
+/// Error returned when releasing a bounded semaphore would push its value
+/// above the maximum it was created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReleaseError {
+    /// Number of resources that were being released
+    pub released: usize,
+    /// Value of the semaphore at the time of the call
+    pub current: usize,
+    /// Maximum value of the semaphore
+    pub max: usize,
+}
+
+impl fmt::Display for ReleaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "releasing {} resources would exceed the semaphore maximum of {} (current value {})",
+            self.released, self.max, self.current
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReleaseError {}
+
+/// Order in which waiting consumers are granted resources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FairnessPolicy {
+    /// Consumers are served in strict arrival order. Newcomers never take
+    /// resources while others are waiting.
+    #[default]
+    Fifo,
+    /// The most recently arrived consumer is served first. This favors
+    /// threads whose caches are still warm, at the expense of latency for
+    /// the ones waiting the longest.
+    Lifo,
+    /// Whichever consumer finds enough resources first takes them, including
+    /// newcomers that never had to wait. This is the fastest mode but it can
+    /// starve waiters under load.
+    Unfair,
+}
+
+/// Spinning done by blocking consumers before they park, set with
+/// [`Semaphore::spin`]
+///
+/// A consumer that finds no resource makes up to `rounds` more attempts,
+/// busy-waiting in between for twice as long as the previous time, up to
+/// `max_backoff` spin-loop hints. Spinning pays off when resources are held
+/// for a few microseconds, less than parking and waking a thread up takes;
+/// otherwise it only burns CPU time. Spinning consumers are not queued yet,
+/// so with the FIFO policy they do not take resources ahead of the parked
+/// ones.
+///
+/// ```
+/// # use esync::{Semaphore, SpinConfig};
+/// static SEM: Semaphore = Semaphore::new(1).spin(SpinConfig::new(10));
+/// assert_eq!(SpinConfig::new(10), SEM.get_spin());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinConfig {
+    /// Attempts made before parking; zero parks right away
+    pub rounds: u32,
+    /// Upper bound of the busy-wait between two attempts, in spin-loop hints
+    pub max_backoff: u32,
+}
+
+impl SpinConfig {
+    /// No spinning: consumers park as soon as no resource is available
+    pub const DISABLED: Self = Self::new(0);
+
+    /// Spins for up to `rounds` attempts, backing off to at most 64 spin-loop
+    /// hints between them.
+    pub const fn new(rounds: u32) -> Self {
+        Self {
+            rounds,
+            max_backoff: 64,
+        }
+    }
+}
+
+impl Default for SpinConfig {
+    fn default() -> Self {
+        Self::DISABLED
+    }
+}
+
+/// A consumer parked on the semaphore
+struct Waiter {
+    /// Number of resources requested
+    n: usize,
+    /// Waiters with a higher priority are served first
+    priority: i32,
+    /// When the waiter started waiting
+    #[cfg(feature = "std")]
+    since: Instant,
+    /// Set, under the semaphore lock, once the resources were handed over
+    granted: AtomicBool,
+    /// Used to wake this waiter up, and only this one
+    cv: Condvar,
+    /// Waker of the task polling for the resources, for async waiters
+    #[cfg(feature = "async")]
+    waker: Mutex<Option<Waker>>,
+    /// Whether the waiter only takes a resource if its predicate holds, see
+    /// [`Semaphore::wait_while`]
+    conditional: bool,
+    /// Set, under the semaphore lock, once the predicate of a conditional
+    /// waiter failed on the value `declined_at`
+    declined: AtomicBool,
+    declined_at: AtomicUsize,
+}
+
+impl Waiter {
+    fn new(n: usize, priority: i32) -> Self {
+        Self {
+            n,
+            priority,
+            #[cfg(feature = "std")]
+            since: Instant::now(),
+            granted: AtomicBool::new(false),
+            cv: Condvar::new(),
+            #[cfg(feature = "async")]
+            waker: Mutex::new(None),
+            conditional: false,
+            declined: AtomicBool::new(false),
+            declined_at: AtomicUsize::new(0),
+        }
+    }
+
+    /// Records that the predicate of a conditional waiter failed on `value`
+    #[cfg(feature = "std")]
+    fn decline(&self, value: usize) {
+        self.declined_at.store(value, Ordering::Relaxed);
+        self.declined.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the waiter lets the others through while the semaphore holds
+    /// `value`, its predicate having failed on it
+    fn declined(&self, value: usize) -> bool {
+        self.declined.load(Ordering::Relaxed) && self.declined_at.load(Ordering::Relaxed) == value
+    }
+
+    /// Wakes the thread or the task waiting on this
+    fn notify(&self) {
+        self.cv.notify_one();
+        #[cfg(feature = "async")]
+        {
+            let waker = sync::lock(&self.waker).take();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// State protected by the semaphore mutex
+struct State {
+    /// Parked waiters, in arrival order
+    queue: Vec<Arc<Waiter>>,
+    /// Outstanding leases, in no particular order
+    #[cfg(feature = "std")]
+    leases: Vec<lease::LeaseEntry>,
+    /// Identifier of the next lease handed out
+    #[cfg(feature = "std")]
+    next_lease: u64,
+    /// Wait sets to signal when resources become available
+    #[cfg(feature = "std")]
+    watchers: Vec<Arc<wait_set::Signal>>,
+    /// Number of conditional waiters in the queue
+    #[cfg(feature = "std")]
+    conditional: usize,
+}
 
 pub struct Semaphore {
-    _mutex: Mutex<u32>,
-    _cv: Condvar,
+    /// Number of available resources
+    _permits: AtomicUsize,
+    /// Number of parked waiters, mirroring the length of the queue without
+    /// the conditional waiters, which do not hold back the other consumers
+    _waiters: AtomicUsize,
+    /// Number of outstanding leases, mirroring the lease list
+    #[cfg(feature = "std")]
+    _leases: AtomicUsize,
+    /// Number of watching wait sets, mirroring the watcher list
+    #[cfg(feature = "std")]
+    _watchers: AtomicUsize,
+    /// Number of conditional waiters, mirroring the count in the queue
+    #[cfg(feature = "std")]
+    _conditional: AtomicUsize,
+    _closed: AtomicBool,
+    _mutex: Mutex<State>,
+    _max: AtomicUsize,
+    /// Resources to absorb from future releases, after a shrink
+    _debt: AtomicUsize,
+    _fairness: FairnessPolicy,
+    _spin: SpinConfig,
+    /// Contention counters, if enabled
+    #[cfg(feature = "std")]
+    _stats: Option<stats::Counters>,
 }
 
-/// Basic semaphore implementation
-///
-/// # Examples
-///
-/// Create a semaphore with initial value 1, that is taken and then released.
-/// ```
-/// # use esync::Semaphore;
-/// let sem = Semaphore::new(1);
-/// sem.wait();
-/// assert_eq!(0, sem.get_current_value());
-/// sem.release();
-/// ```
-impl Semaphore {
-    /// Instanties a semaphore with a given initial value
-    pub const fn new(initial_value: u32) -> Self {
-        Self {
-            _mutex: Mutex::new(initial_value),
-            _cv: Condvar::new(),
-        }
+/// Basic semaphore implementation
+///
+/// By default resources are granted in strict arrival order: a consumer that
+/// has to wait is queued behind the consumers already waiting, and no later
+/// caller can take resources ahead of it. See [`FairnessPolicy`] for the
+/// alternatives.
+///
+/// The resource counter is an atomic, so acquiring or releasing an
+/// uncontended semaphore is a single compare-and-swap. The internal lock is
+/// only taken when a consumer has to park, or to wake parked consumers up.
+/// Released resources are handed over directly to the waiters they are
+/// meant for, and only those waiters are woken up.
+///
+/// # Examples
+///
+/// Create a semaphore with initial value 1, that is taken and then released.
+/// ```
+/// # use esync::Semaphore;
+/// let sem = Semaphore::new(1);
+/// sem.wait().unwrap();
+/// assert_eq!(0, sem.get_current_value());
+/// sem.release();
+/// ```
+impl Semaphore {
+    const_fn! {
+        /// Instanties a semaphore with a given initial value
+        ///
+        /// Construction is `const`, so a semaphore can be placed in a `static`
+        /// without any lazy initialization.
+        ///
+        /// ```
+        /// # use esync::Semaphore;
+        /// static LIMIT: Semaphore = Semaphore::new(4);
+        ///
+        /// LIMIT.wait().unwrap();
+        /// assert_eq!(3, LIMIT.get_current_value());
+        /// LIMIT.release();
+        /// ```
+        pub fn new(initial_value: usize) -> Self {
+            Self::with_max(initial_value, usize::MAX)
+        }
+    }
+
+    const_fn! {
+        /// Instanties a bounded semaphore whose value can never exceed `max`.
+        ///
+        /// Releasing a bounded semaphore past its maximum is treated as a bug:
+        /// [`release`](Self::release) panics, while
+        /// [`try_release`](Self::try_release) reports it as an error.
+        ///
+        /// # Panics
+        ///
+        /// Panics if `initial_value` is greater than `max`.
+        pub fn with_max(initial_value: usize, max: usize) -> Self {
+            assert!(initial_value <= max, "initial value exceeds the maximum");
+            Self {
+                _permits: AtomicUsize::new(initial_value),
+                _waiters: AtomicUsize::new(0),
+                #[cfg(feature = "std")]
+                _leases: AtomicUsize::new(0),
+                #[cfg(feature = "std")]
+                _watchers: AtomicUsize::new(0),
+                #[cfg(feature = "std")]
+                _conditional: AtomicUsize::new(0),
+                _closed: AtomicBool::new(false),
+                _mutex: Mutex::new(State {
+                    queue: Vec::new(),
+                    #[cfg(feature = "std")]
+                    leases: Vec::new(),
+                    #[cfg(feature = "std")]
+                    next_lease: 0,
+                    #[cfg(feature = "std")]
+                    watchers: Vec::new(),
+                    #[cfg(feature = "std")]
+                    conditional: 0,
+                }),
+                _max: AtomicUsize::new(max),
+                _debt: AtomicUsize::new(0),
+                _fairness: FairnessPolicy::Fifo,
+                _spin: SpinConfig::DISABLED,
+                #[cfg(feature = "std")]
+                _stats: None,
+            }
+        }
+    }
+
+    /// Sets the order in which waiting consumers are granted resources.
+    ///
+    /// ```
+    /// # use esync::{FairnessPolicy, Semaphore};
+    /// static SEM: Semaphore = Semaphore::new(4).fairness(FairnessPolicy::Lifo);
+    /// assert_eq!(FairnessPolicy::Lifo, SEM.get_fairness());
+    /// ```
+    pub const fn fairness(mut self, policy: FairnessPolicy) -> Self {
+        self._fairness = policy;
+        self
+    }
+
+    /// Makes blocking consumers spin for resources before they park, as
+    /// configured by `config`. Async consumers never spin.
+    pub const fn spin(mut self, config: SpinConfig) -> Self {
+        self._spin = config;
+        self
+    }
+
+    /// Acquires the semaphore or waits in order to do so until another consumer
+    /// releases the resource.
+    ///
+    /// Fails only if the semaphore is closed, either before or while waiting.
+    pub fn wait(&self) -> Result<(), AcquireError> {
+        self.acquire_blocking(1, 0, None, None)
+    }
+
+    /// Acquires the semaphore, waiting ahead of all the consumers with a
+    /// lower `priority`.
+    ///
+    /// When resources are released, the waiters with the highest priority
+    /// are served first, and the fairness policy only orders waiters of equal
+    /// priority. Plain [`wait`](Self::wait) uses priority 0. Priorities only
+    /// order the queue: with [`FairnessPolicy::Unfair`] a newcomer of any
+    /// priority may still take resources that are available right away.
+    ///
+    /// ```
+    /// # use esync::Semaphore;
+    /// let sem = Semaphore::new(1);
+    /// sem.wait_with_priority(10).unwrap();
+    /// sem.release();
+    /// ```
+    pub fn wait_with_priority(&self, priority: i32) -> Result<(), AcquireError> {
+        self.acquire_blocking(1, priority, None, None)
+    }
+
+    /// Attempts to acquire the semaphore without blocking.
+    ///
+    /// Returns `true` if the semaphore was acquired, `false` if no resource
+    /// was available at the time of the call or, with the FIFO policy, other
+    /// consumers are already waiting for it.
+    pub fn try_wait(&self) -> bool {
+        self.try_acquire_many(1).is_ok()
+    }
+
+    /// Acquires the semaphore, waiting at most `timeout` for a resource to
+    /// become available.
+    ///
+    /// Fails with [`AcquireError::TimedOut`] if the timeout expired first.
+    #[cfg(feature = "std")]
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wait_deadline(deadline),
+            None => self.wait(),
+        }
+    }
+
+    /// Acquires the semaphore, waiting until `deadline` at most for a
+    /// resource to become available.
+    ///
+    /// Spurious wakeups and lost races against other consumers do not extend
+    /// the wait: the remaining time is always computed against `deadline`.
+    /// Fails with [`AcquireError::TimedOut`] if the deadline passed first.
+    #[cfg(feature = "std")]
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), AcquireError> {
+        self.acquire_blocking(1, 0, Some(deadline), None)
+    }
+
+    /// Releases once the semaphore
+    ///
+    /// # Panics
+    ///
+    /// Panics if the release would exceed the maximum value of the semaphore.
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// Releases once the semaphore, failing if that would exceed its maximum
+    /// value.
+    pub fn try_release(&self) -> Result<(), ReleaseError> {
+        self.try_release_many(1)
+    }
+
+    /// Acquires `n` resources at once, waiting until all of them are
+    /// available.
+    ///
+    /// The resources are taken in a single step: the semaphore is never
+    /// partially acquired, so a waiter asking for many resources does not sit
+    /// on a subset of them while other consumers are blocked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than the maximum value of the semaphore, as
+    /// such a request could never be satisfied.
+    pub fn acquire_many(&self, n: usize) -> Result<(), AcquireError> {
+        self.acquire_blocking(n, 0, None, None)
+    }
+
+    /// Attempts to acquire `n` resources at once without blocking.
+    ///
+    /// Either all `n` resources are taken or none is. With the FIFO policy the
+    /// call fails if other consumers are already waiting, even if enough
+    /// resources are available, so that it never overtakes them. On failure
+    /// the error reports how many resources were available.
+    pub fn try_acquire_many(&self, n: usize) -> Result<(), TryAcquireError> {
+        if self.is_closed() {
+            Err(TryAcquireError::Closed)
+        } else if self.barge(n)
+            // resources held past their lease may be taken back
+            || self.reclaim_leases() && self.barge(n)
+        {
+            Ok(())
+        } else {
+            Err(TryAcquireError::NoPermits {
+                requested: n,
+                available: self.get_current_value(),
+            })
+        }
+    }
+
+    /// Releases `n` resources at once
+    ///
+    /// # Panics
+    ///
+    /// Panics if the release would exceed the maximum value of the semaphore.
+    pub fn release_many(&self, n: usize) {
+        if let Err(e) = self.try_release_many(n) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Releases `n` resources at once, failing without releasing anything if
+    /// that would exceed the maximum value of the semaphore.
+    pub fn try_release_many(&self, n: usize) -> Result<(), ReleaseError> {
+        if self._debt.load(Ordering::SeqCst) > 0 || self.add(n).is_err() {
+            // the semaphore shrank, and the release may have to pay for it
+            let mut guard = sync::lock(&self._mutex);
+            let debt = self._debt.load(Ordering::SeqCst);
+            let paid = debt.min(n);
+            self.add(n - paid)?;
+            self._debt.store(debt - paid, Ordering::SeqCst);
+            self.grant(&mut guard);
+            return Ok(());
+        }
+        // a shrink racing with the release may have missed the resources
+        if self._debt.load(Ordering::SeqCst) > 0 {
+            let _guard = sync::lock(&self._mutex);
+            self.absorb();
+        }
+        // A waiter registers itself before its last look at the counter, so
+        // either it saw the released resources or it is visible here. The
+        // same goes for wait sets.
+        atomic::store_load_fence();
+        if self._waiters.load(Ordering::SeqCst) > 0 || self.watched() {
+            let mut guard = sync::lock(&self._mutex);
+            self.grant(&mut guard);
+        }
+        Ok(())
+    }
+
+    /// Acquires a weight worth of resources, for semaphores used as a budget
+    /// (e.g. bytes of memory) rather than a number of slots.
+    ///
+    /// Unlike [`acquire_many`](Self::acquire_many), a weight above the
+    /// maximum value of the semaphore is clamped to it: the consumer then
+    /// waits for the whole budget and runs alone, instead of never running.
+    ///
+    /// Returns the weight taken, which is what has to be given back with
+    /// [`release_weighted`](Self::release_weighted): the maximum may change
+    /// in the meantime.
+    pub fn wait_weighted(&self, weight: usize) -> Result<usize, AcquireError> {
+        let weight = weight.min(self.get_max_value());
+        self.acquire_blocking(weight, 0, None, None)?;
+        Ok(weight)
+    }
+
+    /// Releases the weight taken by [`wait_weighted`](Self::wait_weighted),
+    /// as it returned it
+    ///
+    /// # Panics
+    ///
+    /// Panics if the release would exceed the maximum value of the semaphore.
+    pub fn release_weighted(&self, weight: usize) {
+        self.release_many(weight);
+    }
+
+    /// Acquires all the resources currently available, without blocking, and
+    /// returns how many were taken.
+    ///
+    /// The resources are taken as if by [`acquire_many`](Self::acquire_many)
+    /// and have to be released as usual. Nothing is taken from a closed
+    /// semaphore, nor, with the FIFO policy, while other consumers are
+    /// waiting, as with [`try_acquire_many`](Self::try_acquire_many).
+    pub fn drain_permits(&self) -> usize {
+        let mut guard = sync::lock(&self._mutex);
+        if self.is_closed() || !self.may_barge() {
+            return 0;
+        }
+        let drained = self._permits.swap(0, Ordering::SeqCst);
+        if drained > 0 {
+            self.acquired();
+            // the conditional waiters look at the value again
+            self.grant(&mut guard);
+        }
+        drained
+    }
+
+    /// Permanently removes up to `n` of the available resources, without
+    /// blocking, and returns how many were removed.
+    ///
+    /// This shrinks the effective capacity of the semaphore: the forgotten
+    /// resources are never handed out again, unless the owner releases new
+    /// ones. Parked waiters are unaffected and keep waiting for resources to
+    /// be released. The maximum of a bounded semaphore is left unchanged: see
+    /// [`set_max_value`](Self::set_max_value) to lower it.
+    pub fn forget(&self, n: usize) -> usize {
+        let forgotten = self.remove(n);
+        self.taken();
+        forgotten
+    }
+
+    /// Removes up to `n` of the available resources, as
+    /// [`forget`](Self::forget) does
+    fn remove(&self, n: usize) -> usize {
+        let mut current = self._permits.load(Ordering::Relaxed);
+        loop {
+            let forgotten = current.min(n);
+            match self._permits.compare_exchange_weak(
+                current,
+                current - forgotten,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return forgotten,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Adds `n` resources to the semaphore, for good.
+    ///
+    /// Unlike [`release_many`](Self::release_many), this grows the capacity
+    /// of the semaphore: the maximum of a bounded semaphore is raised by `n`
+    /// as well. Parked waiters are served from the new resources.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value or the maximum of the semaphore would overflow.
+    pub fn add_permits(&self, n: usize) {
+        let mut guard = sync::lock(&self._mutex);
+        let max = self.get_max_value();
+        if max != usize::MAX {
+            let max = max
+                .checked_add(n)
+                .expect("semaphore maximum overflow on add_permits");
+            self._max.store(max, Ordering::SeqCst);
+        }
+        self.grow(&mut guard, n);
+    }
+
+    /// Changes the maximum value of a bounded semaphore, which is then its
+    /// capacity: the number of resources it holds when none is handed out.
+    ///
+    /// Raising the maximum adds resources to the semaphore and serves the
+    /// parked waiters. Lowering it removes available resources first, and
+    /// absorbs the ones still held as they are released, until the semaphore
+    /// is down to the new capacity. The holders are not disturbed, and may
+    /// outnumber the new capacity in the meantime.
+    ///
+    /// Waiters asking for more resources than the new maximum keep waiting,
+    /// until it is raised again.
+    ///
+    /// ```
+    /// # use esync::Semaphore;
+    /// let sem = Semaphore::with_max(4, 4);
+    /// sem.acquire_many(3).unwrap();
+    /// sem.set_max_value(2);
+    /// assert_eq!(0, sem.get_current_value());
+    /// sem.release_many(3);
+    /// assert_eq!(2, sem.get_current_value());
+    /// sem.set_max_value(8);
+    /// assert_eq!(8, sem.get_current_value());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the semaphore is unbounded, as it does not know how many
+    /// resources it has overall.
+    pub fn set_max_value(&self, max: usize) {
+        let mut guard = sync::lock(&self._mutex);
+        let old = self.get_max_value();
+        assert!(old != usize::MAX, "resizing an unbounded semaphore");
+        self._max.store(max, Ordering::SeqCst);
+        if max >= old {
+            self.grow(&mut guard, max - old);
+        } else {
+            // recorded before looking at the counter, so that a concurrent
+            // release either finds its resources here or sees the debt
+            self._debt.fetch_add(old - max, Ordering::SeqCst);
+            self.absorb();
+            self.grant(&mut guard);
+        }
+    }
+
+    /// Acquires the semaphore, giving up if `token` gets cancelled first.
+    ///
+    /// Fails with [`AcquireError::Cancelled`] if the token was already
+    /// cancelled or got cancelled while waiting, leaving the semaphore as if
+    /// the call never happened. Fails with [`AcquireError::Closed`] if the
+    /// semaphore is closed.
+    pub fn wait_cancellable(&self, token: &CancellationToken) -> Result<(), AcquireError> {
+        self.acquire_blocking(1, 0, None, Some(token))
+    }
+
+    /// Closes the semaphore.
+    ///
+    /// All the consumers currently waiting are woken up and fail with
+    /// [`AcquireError::Closed`], as do all later acquisition attempts.
+    /// Releasing a closed semaphore is still allowed, so that consumers
+    /// holding resources can give them back as usual.
+    pub fn close(&self) {
+        let guard = sync::lock(&self._mutex);
+        self._closed.store(true, Ordering::SeqCst);
+        for waiter in &guard.queue {
+            waiter.notify();
+        }
+        self.notify_watchers(&guard);
+    }
+
+    /// Returns `true` if the semaphore has been closed.
+    pub fn is_closed(&self) -> bool {
+        self._closed.load(Ordering::SeqCst)
+    }
+
+    /// Get the fairness policy of the semaphore.
+    pub fn get_fairness(&self) -> FairnessPolicy {
+        self._fairness
+    }
+
+    /// Get the spinning done by the semaphore before parking.
+    pub fn get_spin(&self) -> SpinConfig {
+        self._spin
+    }
+
+    /// Get the maximum value of the semaphore.
+    ///
+    /// Unbounded semaphores report `usize::MAX`.
+    pub fn get_max_value(&self) -> usize {
+        self._max.load(Ordering::SeqCst)
+    }
+
+    /// Get the current value of the semaphore.
+    ///
+    /// The semaphore starts with an initial value, that is decremented until
+    /// zero every time a wait() call is completed. On the other hand, the
+    /// semaphore value increments every time a release() call is completed.
+    pub fn get_current_value(&self) -> usize {
+        self._permits.load(Ordering::SeqCst)
+    }
+
+    /// Get the number of consumers currently parked waiting for resources.
+    ///
+    /// Like [`get_current_value`](Self::get_current_value), this is a
+    /// snapshot meant for monitoring: it may be stale by the time it is
+    /// looked at.
+    pub fn waiters(&self) -> usize {
+        self._waiters.load(Ordering::SeqCst) + self.conditional()
+    }
+
+    /// Adds `n` resources to the counter, unless that would exceed the
+    /// maximum
+    fn add(&self, n: usize) -> Result<(), ReleaseError> {
+        let max = self.get_max_value();
+        let mut current = self._permits.load(Ordering::Relaxed);
+        loop {
+            let new = match current.checked_add(n).filter(|v| *v <= max) {
+                Some(v) => v,
+                None => {
+                    return Err(ReleaseError {
+                        released: n,
+                        current,
+                        max,
+                    })
+                }
+            };
+            match self._permits.compare_exchange_weak(
+                current,
+                new,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Adds `n` resources to the counter, paying off the debt of a shrink
+    /// first, and serves the waiters. Called with the lock held.
+    fn grow(&self, state: &mut State, n: usize) {
+        let debt = self._debt.load(Ordering::SeqCst);
+        let paid = debt.min(n);
+        self._debt.store(debt - paid, Ordering::SeqCst);
+        if let Err(e) = self.add(n - paid) {
+            panic!("{}", e);
+        }
+        self.grant(state);
+    }
+
+    /// Pays off the debt of a shrink with the available resources. Called
+    /// with the lock held.
+    fn absorb(&self) {
+        let debt = self._debt.load(Ordering::SeqCst);
+        let paid = self.remove(debt);
+        self._debt.store(debt - paid, Ordering::SeqCst);
+    }
+
+    /// Takes `n` resources from the counter if that many are available
+    fn take(&self, n: usize) -> bool {
+        // sequentially consistent, so that a waiter that just registered
+        // sees the resources of a release that missed it
+        let mut current = self._permits.load(Ordering::SeqCst);
+        while current >= n {
+            match self._permits.compare_exchange_weak(
+                current,
+                current - n,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.acquired();
+                    return true;
+                }
+                Err(actual) => current = actual,
+            }
+        }
+        false
+    }
+
+    /// Retries to take `n` resources as configured, with exponential backoff
+    fn spin_take(&self, n: usize) -> bool {
+        let mut backoff = 1;
+        for _ in 0..self._spin.rounds {
+            for _ in 0..backoff {
+                core::hint::spin_loop();
+            }
+            backoff = (backoff * 2).min(self._spin.max_backoff.max(1));
+            if self.is_closed() {
+                // the caller reports it
+                return false;
+            }
+            if self.barge(n) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Takes `n` resources ahead of the queue, if the fairness policy allows
+    /// it
+    fn barge(&self, n: usize) -> bool {
+        if self.may_barge() && self.take(n) {
+            self.taken();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether a consumer that is not queued may take resources right away
+    fn may_barge(&self) -> bool {
+        self._fairness != FairnessPolicy::Fifo || self._waiters.load(Ordering::SeqCst) == 0
+    }
+
+    /// Index in the queue of the waiter to be served next, if any can be.
+    ///
+    /// Only the waiters with the highest priority are considered, and the
+    /// fairness policy picks among them.
+    fn next_waiter(&self, state: &State) -> Option<usize> {
+        let available = self.get_current_value();
+        // conditional waiters whose predicate failed on the value let the
+        // others through, until it changes
+        let eligible = |w: &&Arc<Waiter>| !w.declined(available);
+        let candidates = |top: i32| {
+            state
+                .queue
+                .iter()
+                .enumerate()
+                .filter(move |(_, w)| w.priority == top && eligible(w))
+                .map(|(i, _)| i)
+        };
+        match self._fairness {
+            FairnessPolicy::Fifo => {
+                let top = state
+                    .queue
+                    .iter()
+                    .filter(eligible)
+                    .map(|w| w.priority)
+                    .max()?;
+                candidates(top).next()
+            }
+            FairnessPolicy::Lifo => {
+                let top = state
+                    .queue
+                    .iter()
+                    .filter(eligible)
+                    .map(|w| w.priority)
+                    .max()?;
+                candidates(top).next_back()
+            }
+            FairnessPolicy::Unfair => {
+                let fits = |w: &&Arc<Waiter>| w.n <= available && eligible(w);
+                let top = state.queue.iter().filter(fits).map(|w| w.priority).max()?;
+                candidates(top).find(|i| state.queue[*i].n <= available)
+            }
+        }
+    }
+
+    /// Hands the available resources over to the waiters entitled to them
+    /// and wakes those waiters up. A conditional waiter is woken up to take a
+    /// resource itself, if its predicate holds. The wait sets watching the
+    /// semaphore are told about whatever is left.
+    fn grant(&self, state: &mut State) {
+        while let Some(pos) = self.next_waiter(state) {
+            if state.queue[pos].conditional {
+                if self.get_current_value() > 0 {
+                    state.queue[pos].notify();
+                }
+                break;
+            }
+            if !self.take(state.queue[pos].n) {
+                break;
+            }
+            let waiter = state.queue.remove(pos);
+            self.queued(state);
+            waiter.granted.store(true, Ordering::Relaxed);
+            waiter.notify();
+        }
+        if self.get_current_value() > 0 {
+            self.notify_watchers(state);
+        }
+    }
+
+    /// Removes `waiter` from the queue of waiters
+    fn leave(&self, state: &mut State, waiter: &Arc<Waiter>) {
+        if let Some(pos) = state.queue.iter().position(|w| Arc::ptr_eq(w, waiter)) {
+            state.queue.remove(pos);
+            self.queued(state);
+        }
+    }
+
+    /// Takes `n` resources, queueing according to the fairness policy when
+    /// they are not readily available.
+    ///
+    /// Fails if `deadline` passed or the semaphore got closed before the
+    /// resources could be taken, in which case the caller leaves the queue
+    /// empty handed.
+    pub(crate) fn acquire_blocking(
+        &self,
+        n: usize,
+        priority: i32,
+        deadline: Option<Instant>,
+        token: Option<&CancellationToken>,
+    ) -> Result<(), AcquireError> {
+        assert!(
+            n <= self.get_max_value(),
+            "requested more than the semaphore maximum"
+        );
+        let cancelled = || token.map_or(false, |t| t.is_cancelled());
+        if self.is_closed() {
+            return Err(AcquireError::Closed);
+        }
+        if cancelled() {
+            return Err(AcquireError::Cancelled);
+        }
+        if self.barge(n) || self.spin_take(n) {
+            return Ok(());
+        }
+
+        let waiter = Arc::new(Waiter::new(n, priority));
+        // taking the lock orders the wakeup after our last look at the token
+        let wake = || {
+            let _guard = sync::lock(&self._mutex);
+            waiter.notify();
+        };
+        // SAFETY: the registration is dropped on return, after the guard,
+        // which the listener needs to take
+        let _registration = token.map(|t| unsafe { t.register(&wake) });
+        let mut guard = sync::lock(&self._mutex);
+        guard.queue.push(waiter.clone());
+        self.queued(&guard);
+        atomic::store_load_fence();
+        self.parked();
+        // resources released before we were visible in the queue are ours
+        // to take, if the policy allows it
+        self.grant(&mut guard);
+        loop {
+            if waiter.granted.load(Ordering::Relaxed) {
+                self.served(&waiter);
+                return Ok(());
+            }
+            if self.is_closed() {
+                self.leave(&mut guard, &waiter);
+                return Err(AcquireError::Closed);
+            }
+            if cancelled() {
+                self.leave(&mut guard, &waiter);
+                self.grant(&mut guard);
+                return Err(AcquireError::Cancelled);
+            }
+            guard = match self.park(&waiter, guard, deadline) {
+                Ok(guard) => guard,
+                Err(mut guard) => {
+                    self.leave(&mut guard, &waiter);
+                    // the waiters queued behind us might be served now
+                    self.grant(&mut guard);
+                    return Err(AcquireError::TimedOut);
+                }
+            };
+        }
+    }
+}
+
+/// Hooks of the features that need the standard library: statistics, wait
+/// sets, leases and timed waits
+#[cfg(feature = "std")]
+impl Semaphore {
+    /// Accounts for a successful acquisition
+    fn acquired(&self) {
+        if let Some(stats) = &self._stats {
+            stats.acquired();
+        }
+    }
+
+    /// Accounts for a consumer that had to park
+    fn parked(&self) {
+        if let Some(stats) = &self._stats {
+            stats.parked();
+        }
+    }
+
+    /// Accounts for a parked consumer that got its resources
+    fn served(&self, waiter: &Waiter) {
+        if let Some(stats) = &self._stats {
+            stats.waited(waiter.since.elapsed());
+        }
+    }
+
+    /// Whether wait sets or conditional waiters are watching the semaphore
+    fn watched(&self) -> bool {
+        self._watchers.load(Ordering::SeqCst) > 0 || self.conditional() > 0
+    }
+
+    /// Number of conditional waiters in the queue
+    fn conditional(&self) -> usize {
+        self._conditional.load(Ordering::SeqCst)
+    }
+
+    /// Publishes the number of queued waiters, not counting the conditional
+    /// ones. Called with the lock held.
+    fn queued(&self, state: &State) {
+        let n = state.queue.len() - state.conditional;
+        self._waiters.store(n, Ordering::SeqCst);
+        self._conditional.store(state.conditional, Ordering::SeqCst);
+    }
+
+    /// Lets the conditional waiters look at the value again, after
+    /// resources were taken ahead of the queue
+    fn taken(&self) {
+        atomic::store_load_fence();
+        if self.conditional() > 0 {
+            let mut guard = sync::lock(&self._mutex);
+            self.grant(&mut guard);
+        }
+    }
+
+    /// Wakes up the wait sets watching the semaphore
+    fn notify_watchers(&self, state: &State) {
+        for watcher in &state.watchers {
+            watcher.notify();
+        }
+    }
+
+    /// Takes back the resources of expired leases, returning `true` if there
+    /// were any
+    fn reclaim_leases(&self) -> bool {
+        self._leases.load(Ordering::SeqCst) > 0 && self.reclaim_expired() > 0
+    }
+
+    /// Parks a queued waiter until it is notified, or it is time to take
+    /// back the resources of expired leases. Hands the guard back as an
+    /// error if `deadline` passed.
+    fn park<'a>(
+        &'a self,
+        waiter: &Waiter,
+        mut guard: MutexGuard<'a, State>,
+        deadline: Option<Instant>,
+    ) -> Result<MutexGuard<'a, State>, MutexGuard<'a, State>> {
+        let expired = self.expire_leases(&mut guard);
+        if !expired.is_empty() {
+            // the callbacks may use the semaphore
+            drop(guard);
+            lease::run_expired(expired);
+            return Ok(sync::lock(&self._mutex));
+        }
+        let now = Instant::now();
+        if deadline.map_or(false, |d| now >= d) {
+            return Err(guard);
+        }
+        // wake up in time to take back the resources of expired leases
+        let wake = match (deadline, self.next_expiry(&guard)) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Ok(match wake {
+            None => sync::wait(&waiter.cv, guard),
+            Some(wake) => sync::wait_timeout(&waiter.cv, guard, wake - now),
+        })
+    }
+}
+
+/// Without the standard library there is no clock, so waits never time out,
+/// and none of the features built on it
+#[cfg(not(feature = "std"))]
+impl Semaphore {
+    fn acquired(&self) {}
+
+    fn parked(&self) {}
+
+    fn served(&self, _waiter: &Waiter) {}
+
+    fn watched(&self) -> bool {
+        false
+    }
+
+    fn conditional(&self) -> usize {
+        0
+    }
+
+    fn queued(&self, state: &State) {
+        self._waiters.store(state.queue.len(), Ordering::SeqCst);
+    }
+
+    fn taken(&self) {}
+
+    fn notify_watchers(&self, _state: &State) {}
+
+    fn reclaim_leases(&self) -> bool {
+        false
+    }
+
+    fn park<'a>(
+        &'a self,
+        waiter: &Waiter,
+        guard: MutexGuard<'a, State>,
+        deadline: Option<Instant>,
+    ) -> Result<MutexGuard<'a, State>, MutexGuard<'a, State>> {
+        match deadline {
+            Some(never) => match never {},
+            None => Ok(sync::wait(&waiter.cv, guard)),
+        }
+    }
+}
+
+/// Shows a snapshot of the state of the semaphore
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.get_current_value())
+            .field("max", &self.get_max_value())
+            .field("fairness", &self._fairness)
+            .field("waiters", &self.waiters())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+/// Shows the available resources and the parked waiters, e.g.
+/// `2/4 permits, 1 waiter`. Unbounded semaphores only show the available
+/// resources.
+impl fmt::Display for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let permits = self.get_current_value();
+        if self.get_max_value() == usize::MAX {
+            write!(f, "{} permits", permits)?;
+        } else {
+            write!(f, "{}/{} permits", permits, self.get_max_value())?;
+        }
+        let waiters = self.waiters();
+        write!(
+            f,
+            ", {} waiter{}",
+            waiters,
+            if waiters == 1 { "" } else { "s" }
+        )?;
+        if self.is_closed() {
+            write!(f, ", closed")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use rand::Rng;
+
+    use super::{
+        AcquireError, FairnessPolicy, ReleaseError, Semaphore, SpinConfig, TryAcquireError,
+    };
+    use crate::CancellationToken;
+
+    #[test]
+    fn wait_and_release() {
+        let s = Semaphore::new(1);
+        s.wait().unwrap();
+        s.release();
+    }
+
+    #[test]
+    fn release_while_wait() {
+        let sem = Semaphore::new(1);
+        sem.wait().unwrap();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                sem.wait().unwrap();
+            });
+            thread::sleep(Duration::from_millis(100));
+            // let's first make sure that the thread is waiting
+            assert!(!waiter.is_finished());
+            // and now release the lock and make sure the thread died
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(waiter.is_finished());
+        });
+        // we should be here still holding the semaphore
+        assert_eq!(0, sem.get_current_value());
+        // so release it
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn try_wait() {
+        let sem = Semaphore::new(1);
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+        assert_eq!(0, sem.get_current_value());
+        sem.release();
+        assert!(sem.try_wait());
+        sem.release();
+    }
+
+    #[test]
+    fn wait_timeout_expires() {
+        let sem = Semaphore::new(0);
+        let start = Instant::now();
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(100))
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn wait_timeout_acquires() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                sem.release();
+            });
+            assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        });
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn wait_deadline() {
+        let sem = Semaphore::new(1);
+        let deadline = Instant::now() + Duration::from_millis(100);
+        assert_eq!(Ok(()), sem.wait_deadline(deadline));
+        assert_eq!(Err(AcquireError::TimedOut), sem.wait_deadline(deadline));
+        assert!(Instant::now() >= deadline);
+        // a deadline in the past still takes an available resource
+        sem.release();
+        assert_eq!(Ok(()), sem.wait_deadline(deadline));
     }
 
-    /// Acquires the semaphore or waits in order to do so until another consumer
-    /// releases the resource.
-    pub fn wait(&self) {
-        loop {
-            let mut guard = self._mutex.lock().unwrap();
-            if *guard > 0 {
-                *guard -= 1;
-                {
-                    return;
-                }
+    #[test]
+    fn acquire_many() {
+        let sem = Semaphore::new(2);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| {
+                sem.acquire_many(4).unwrap();
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            // the waiter must not grab a subset of the permits
+            assert_eq!(2, sem.get_current_value());
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(!waiter.is_finished());
+            sem.release_many(2);
+            thread::sleep(Duration::from_millis(100));
+            assert!(waiter.is_finished());
+        });
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn try_acquire_many() {
+        let sem = Semaphore::new(3);
+        assert_eq!(
+            Err(TryAcquireError::NoPermits {
+                requested: 4,
+                available: 3
+            }),
+            sem.try_acquire_many(4)
+        );
+        assert_eq!(3, sem.get_current_value());
+        assert_eq!(Ok(()), sem.try_acquire_many(2));
+        assert_eq!(1, sem.get_current_value());
+        sem.release_many(2);
+    }
+
+    #[test]
+    fn bounded_release() {
+        let sem = Semaphore::with_max(1, 2);
+        assert_eq!(2, sem.get_max_value());
+        sem.release();
+        assert_eq!(
+            Err(ReleaseError {
+                released: 1,
+                current: 2,
+                max: 2
+            }),
+            sem.try_release()
+        );
+        assert_eq!(2, sem.get_current_value());
+        sem.acquire_many(2).unwrap();
+        assert_eq!(Ok(()), sem.try_release_many(2));
+        assert!(sem.try_release_many(1).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn bounded_release_panics() {
+        let sem = Semaphore::with_max(1, 1);
+        sem.release();
+    }
+
+    #[test]
+    fn unbounded_release_overflow() {
+        let sem = Semaphore::new(usize::MAX - 1);
+        sem.release();
+        assert_eq!(
+            Err(ReleaseError {
+                released: 1,
+                current: usize::MAX,
+                max: usize::MAX
+            }),
+            sem.try_release()
+        );
+        assert_eq!(usize::MAX, sem.get_current_value());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn wide_weights() {
+        // a byte budget of 16 GiB, taken in 5 GiB chunks
+        let budget = 16 << 30;
+        let sem = Semaphore::with_max(budget, budget);
+        sem.wait_weighted(5 << 30).unwrap();
+        sem.wait_weighted(5 << 30).unwrap();
+        sem.wait_weighted(5 << 30).unwrap();
+        assert_eq!(1 << 30, sem.get_current_value());
+        assert!(sem.try_acquire_many(5 << 30).is_err());
+        sem.release_weighted(15 << 30);
+        assert_eq!(budget, sem.get_current_value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn bounded_initial_value() {
+        Semaphore::with_max(2, 1);
+    }
+
+    #[test]
+    fn fifo_order() {
+        let sem = Semaphore::new(0);
+        let order = Mutex::new(vec![]);
+        thread::scope(|s| {
+            for i in 0..5 {
+                let (sem, order) = (&sem, &order);
+                s.spawn(move || {
+                    sem.wait().unwrap();
+                    order.lock().unwrap().push(i);
+                    sem.release();
+                });
+                // make sure the waiters arrive in order
+                thread::sleep(Duration::from_millis(50));
             }
-            while *guard == 0 {
-                guard = self._cv.wait(guard).unwrap();
+            // a newcomer must not overtake the queued waiters
+            assert!(!sem.try_wait());
+            sem.release();
+        });
+        assert_eq!(vec![0, 1, 2, 3, 4], *order.lock().unwrap());
+    }
+
+    #[test]
+    fn no_barging_past_large_waiter() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let large = s.spawn(|| sem.acquire_many(2).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            let small = s.spawn(|| sem.wait().unwrap());
+            thread::sleep(Duration::from_millis(50));
+            // one permit fits the small waiter, but the large one came first
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(!large.is_finished());
+            assert!(!small.is_finished());
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(large.is_finished());
+            assert!(!small.is_finished());
+            sem.release();
+        });
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn timed_out_head_hands_over() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let head = s.spawn(|| sem.wait_timeout(Duration::from_millis(100)));
+            thread::sleep(Duration::from_millis(20));
+            let next = s.spawn(|| sem.acquire_many(1).unwrap());
+            thread::sleep(Duration::from_millis(20));
+            assert!(head.join().unwrap().is_err());
+            sem.release();
+            thread::sleep(Duration::from_millis(100));
+            assert!(next.is_finished());
+        });
+    }
+
+    fn arrival_order(policy: FairnessPolicy) -> Vec<i32> {
+        let sem = Semaphore::new(0).fairness(policy);
+        let order = Mutex::new(vec![]);
+        thread::scope(|s| {
+            for i in 0..5 {
+                let (sem, order) = (&sem, &order);
+                s.spawn(move || {
+                    sem.wait().unwrap();
+                    order.lock().unwrap().push(i);
+                    sem.release();
+                });
+                thread::sleep(Duration::from_millis(50));
             }
-        }
+            sem.release();
+        });
+        order.into_inner().unwrap()
     }
 
-    /// Releases once the semaphore
-    pub fn release(&self) {
-        let mut guard = self._mutex.lock().unwrap();
-        *guard += 1;
-        self._cv.notify_all();
+    #[test]
+    fn lifo_order() {
+        assert_eq!(vec![4, 3, 2, 1, 0], arrival_order(FairnessPolicy::Lifo));
     }
 
-    /// Get the current value of the semaphore.
-    ///
-    /// The semaphore starts with an initial value, that is decremented until
-    /// zero every time a wait() call is completed. On the other hand, the
-    /// semaphore value increments every time a release() call is completed.
-    pub fn get_current_value(&self) -> u32 {
-        *self._mutex.lock().unwrap()
+    #[test]
+    fn unfair_barging() {
+        let sem = Semaphore::new(0).fairness(FairnessPolicy::Unfair);
+        thread::scope(|s| {
+            let large = s.spawn(|| sem.acquire_many(2).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            // a newcomer takes what is available even though someone waits
+            sem.release();
+            assert!(sem.try_wait());
+            assert!(!large.is_finished());
+            sem.release_many(2);
+        });
+        assert_eq!(0, sem.get_current_value());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use std::{thread, time::Duration};
+    fn priority_order(policy: FairnessPolicy) -> Vec<usize> {
+        let sem = Semaphore::new(0).fairness(policy);
+        let order = Mutex::new(vec![]);
+        thread::scope(|s| {
+            for (i, priority) in [0, 5, -1, 5, 1].into_iter().enumerate() {
+                let (sem, order) = (&sem, &order);
+                s.spawn(move || {
+                    sem.wait_with_priority(priority).unwrap();
+                    order.lock().unwrap().push(i);
+                    sem.release();
+                });
+                thread::sleep(Duration::from_millis(50));
+            }
+            sem.release();
+        });
+        order.into_inner().unwrap()
+    }
 
-    use rand::Rng;
+    #[test]
+    fn priority() {
+        // equal priorities are ordered by the fairness policy
+        assert_eq!(vec![1, 3, 4, 0, 2], priority_order(FairnessPolicy::Fifo));
+        assert_eq!(vec![3, 1, 4, 0, 2], priority_order(FairnessPolicy::Lifo));
+        assert_eq!(vec![1, 3, 4, 0, 2], priority_order(FairnessPolicy::Unfair));
+    }
 
-    use super::Semaphore;
+    #[test]
+    fn wait_cancellable() {
+        let sem = Semaphore::new(1);
+        let token = CancellationToken::new();
+        assert_eq!(Ok(()), sem.wait_cancellable(&token));
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_cancellable(&token));
+            let other = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+            assert_eq!(Err(AcquireError::Cancelled), waiter.join().unwrap());
+            assert_eq!(1, sem.waiters());
+            sem.release();
+            other.join().unwrap().unwrap();
+        });
+        // a cancelled token fails even when resources are available
+        sem.release();
+        assert_eq!(Err(AcquireError::Cancelled), sem.wait_cancellable(&token));
+        assert_eq!(1, sem.get_current_value());
+    }
 
     #[test]
-    fn wait_and_release() {
-        let s = Semaphore::new(1);
-        s.wait();
-        s.release();
+    fn close_fails_waiters() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let waiters = (0..3).map(|_| s.spawn(|| sem.wait())).collect::<Vec<_>>();
+            thread::sleep(Duration::from_millis(100));
+            assert!(!sem.is_closed());
+            sem.close();
+            for w in waiters {
+                assert_eq!(Err(AcquireError::Closed), w.join().unwrap());
+            }
+        });
+        assert!(sem.is_closed());
+        // later acquisitions fail even if resources are available
+        sem.release();
+        assert_eq!(Err(AcquireError::Closed), sem.wait());
+        assert_eq!(
+            Err(AcquireError::Closed),
+            sem.wait_timeout(Duration::from_millis(10))
+        );
+        assert_eq!(Err(TryAcquireError::Closed), sem.try_acquire_many(1));
+        assert!(!sem.try_wait());
+        assert_eq!(1, sem.get_current_value());
     }
 
     #[test]
-    fn release_while_wait() {
+    fn weighted() {
+        let sem = Semaphore::with_max(10, 10);
+        sem.wait_weighted(4).unwrap();
+        assert_eq!(6, sem.get_current_value());
+        // an oversized weight takes the whole budget
+        thread::scope(|s| {
+            let large = s.spawn(|| sem.wait_weighted(100).unwrap());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!large.is_finished());
+            sem.release_weighted(4);
+            assert_eq!(10, large.join().unwrap());
+        });
+        assert_eq!(0, sem.get_current_value());
+        sem.release_weighted(10);
+        assert_eq!(10, sem.get_current_value());
+
+        // the weight given back is the one taken, whatever the maximum is now
+        let sem = Semaphore::with_max(4, 4);
+        let taken = sem.wait_weighted(10).unwrap();
+        assert_eq!(4, taken);
+        sem.set_max_value(8);
+        sem.release_weighted(taken);
+        assert_eq!(8, sem.get_current_value());
+    }
+
+    static STATIC_SEM: Semaphore = Semaphore::with_max(2, 2);
+
+    #[test]
+    fn static_semaphore() {
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        STATIC_SEM.wait().unwrap();
+                        assert!(STATIC_SEM.get_current_value() < 2);
+                        STATIC_SEM.release();
+                    }
+                });
+            }
+        });
+        assert_eq!(2, STATIC_SEM.get_current_value());
+    }
+
+    #[test]
+    fn waiters() {
+        let sem = Semaphore::new(0);
+        assert_eq!(0, sem.waiters());
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| sem.wait().unwrap());
+            }
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(3, sem.waiters());
+            sem.release();
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(2, sem.waiters());
+            sem.release_many(2);
+        });
+        assert_eq!(0, sem.waiters());
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(10))
+        );
+        assert_eq!(0, sem.waiters());
+    }
+
+    #[test]
+    fn debug_and_display() {
+        let sem = Semaphore::with_max(2, 4).fairness(FairnessPolicy::Lifo);
+        assert_eq!(
+            "Semaphore { permits: 2, max: 4, fairness: Lifo, waiters: 0, closed: false }",
+            format!("{:?}", sem)
+        );
+        assert_eq!("2/4 permits, 0 waiters", sem.to_string());
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_many(3));
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!("2/4 permits, 1 waiter", sem.to_string());
+            sem.close();
+            assert!(waiter.join().unwrap().is_err());
+        });
+        assert_eq!("2/4 permits, 0 waiters, closed", sem.to_string());
+        assert_eq!("5 permits, 0 waiters", Semaphore::new(5).to_string());
+    }
+
+    #[test]
+    fn add_permits() {
+        let sem = Semaphore::with_max(0, 2);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_many(2));
+            thread::sleep(Duration::from_millis(50));
+            sem.add_permits(2);
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert_eq!(4, sem.get_max_value());
+        assert_eq!(0, sem.get_current_value());
+        sem.release_many(4);
+        assert!(sem.try_release().is_err());
+
+        let unbounded = Semaphore::new(1);
+        unbounded.add_permits(2);
+        assert_eq!(3, unbounded.get_current_value());
+        assert_eq!(usize::MAX, unbounded.get_max_value());
+    }
+
+    #[test]
+    fn set_max_value() {
+        let sem = Semaphore::with_max(4, 4);
+        sem.acquire_many(3).unwrap();
+        // the available resource goes right away, two more on release
+        sem.set_max_value(1);
+        assert_eq!(0, sem.get_current_value());
+        sem.release();
+        sem.release();
+        assert_eq!(0, sem.get_current_value());
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+        assert!(sem.try_release().is_err());
+
+        // raising while in debt cancels the debt first
+        sem.acquire_many(1).unwrap();
+        sem.set_max_value(0);
+        sem.set_max_value(2);
+        assert_eq!(1, sem.get_current_value());
+        sem.release();
+        assert_eq!(2, sem.get_current_value());
+    }
+
+    #[test]
+    fn set_max_value_under_load() {
+        let sem = Semaphore::with_max(4, 4);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        sem.wait().unwrap();
+                        sem.release();
+                    }
+                });
+            }
+            for max in [1, 6, 2, 8, 3] {
+                thread::sleep(Duration::from_millis(1));
+                sem.set_max_value(max);
+            }
+        });
+        assert_eq!(3, sem.get_current_value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn set_max_value_unbounded() {
+        Semaphore::new(1).set_max_value(2);
+    }
+
+    #[test]
+    fn drain_permits() {
+        let sem = Semaphore::new(5);
+        sem.wait().unwrap();
+        assert_eq!(4, sem.drain_permits());
+        assert_eq!(0, sem.get_current_value());
+        assert_eq!(0, sem.drain_permits());
+        sem.release_many(5);
+        sem.close();
+        assert_eq!(0, sem.drain_permits());
+        assert_eq!(5, sem.get_current_value());
+
+        // counted as an acquisition
+        let sem = Semaphore::new(3).with_stats();
+        assert_eq!(3, sem.drain_permits());
+        assert_eq!(1, sem.stats().unwrap().acquisitions);
+    }
+
+    #[test]
+    fn drain_permits_behind_waiters() {
         let sem = Semaphore::new(1);
-        sem.wait();
         thread::scope(|s| {
-            let waiter = s.spawn(|| {
-                sem.wait();
-            });
+            let waiter = s.spawn(|| sem.acquire_many(2));
+            thread::sleep(Duration::from_millis(50));
+            // not ahead of the queued consumer
+            assert_eq!(0, sem.drain_permits());
+            assert_eq!(1, sem.get_current_value());
+            sem.release();
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        // other policies let it through
+        let sem = Semaphore::new(1).fairness(FairnessPolicy::Unfair);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.acquire_many(2));
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(1, sem.drain_permits());
+            sem.release_many(2);
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn forget() {
+        let sem = Semaphore::new(3);
+        assert_eq!(2, sem.forget(2));
+        assert_eq!(1, sem.get_current_value());
+        assert_eq!(1, sem.forget(5));
+        assert_eq!(0, sem.forget(1));
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait().unwrap());
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(0, sem.forget(1));
+            sem.release();
+            waiter.join().unwrap();
+        });
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn release_wakes_a_single_waiter() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let waiters = (0..3)
+                .map(|_| s.spawn(|| sem.wait().unwrap()))
+                .collect::<Vec<_>>();
             thread::sleep(Duration::from_millis(100));
-            // let's first make sure that the thread is waiting
-            assert!(!waiter.is_finished());
-            // and now release the lock and make sure the thread died
             sem.release();
             thread::sleep(Duration::from_millis(100));
-            assert!(waiter.is_finished());
+            assert_eq!(1, waiters.iter().filter(|w| w.is_finished()).count());
+            sem.release_many(2);
         });
-        // we should be here still holding the semaphore
         assert_eq!(0, sem.get_current_value());
-        // so release it
+    }
+
+    #[test]
+    fn no_waiter_is_lost() {
+        for policy in [
+            FairnessPolicy::Fifo,
+            FairnessPolicy::Lifo,
+            FairnessPolicy::Unfair,
+        ] {
+            let sem = Semaphore::new(0).fairness(policy);
+            thread::scope(|s| {
+                for _ in 0..64 {
+                    s.spawn(|| sem.wait().unwrap());
+                }
+                // releases race with the waiters parking
+                for _ in 0..64 {
+                    sem.release();
+                }
+            });
+            assert_eq!(0, sem.get_current_value());
+        }
+    }
+
+    #[test]
+    fn uncontended_path_is_lock_free() {
+        let sem = Semaphore::new(1);
+        // none of these may touch the internal lock, or they would deadlock
+        let _guard = crate::sync::lock(&sem._mutex);
+        sem.wait().unwrap();
+        sem.release();
+        assert!(sem.try_wait());
+        assert_eq!(0, sem.get_current_value());
+        sem.release();
+    }
+
+    #[test]
+    #[cfg(not(feature = "parking_lot"))]
+    fn poisoned_lock_is_recovered() {
+        let sem = Semaphore::new(1);
+        thread::scope(|s| {
+            let poisoner = s.spawn(|| {
+                let _guard = sem._mutex.lock();
+                panic!("poisoning the semaphore");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(sem._mutex.is_poisoned());
+        assert_eq!(Ok(()), sem.wait_timeout(Duration::from_millis(10)),);
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            sem.wait_timeout(Duration::from_millis(10))
+        );
+        assert_eq!(0, sem.get_current_value());
         sem.release();
         assert_eq!(1, sem.get_current_value());
     }
 
-    fn stress(initial_count: u32) {
-        let sem = Semaphore::new(initial_count);
+    fn stress(initial_count: usize) {
+        stress_with(Semaphore::new(initial_count));
+    }
+
+    fn stress_with(sem: Semaphore) {
+        let initial_count = sem.get_current_value();
         thread::scope(|scope| {
             for _ in 0..initial_count * 4 {
                 scope.spawn(|| {
@@ -108,12 +1852,12 @@ mod test {
                     (0..10000)
                         .map(|_| (rng.gen::<f64>() * 20.0) as u64 + 1)
                         // transform that into a vec of microsecond Durations
-                        .map(|f| Duration::from_micros(f))
+                        .map(Duration::from_micros)
                         .collect::<Vec<_>>()
                         // and now use them to hold the semaphore for a given duration
                         .into_iter()
                         .for_each(|d| {
-                            sem.wait();
+                            sem.wait().unwrap();
                             thread::sleep(d);
                             sem.release();
                         });
@@ -140,4 +1884,33 @@ mod test {
     fn stress8() {
         stress(8);
     }
+    #[test]
+    fn stress_spinning() {
+        stress_with(Semaphore::new(2).spin(SpinConfig::new(20)));
+    }
+
+    #[test]
+    fn spin() {
+        // spins until the resource shows up, never parking
+        let config = SpinConfig {
+            rounds: u32::MAX,
+            max_backoff: 1024,
+        };
+        let sem = Semaphore::new(0).spin(config).with_stats();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(20));
+            sem.release();
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert_eq!(0, sem.stats().unwrap().parks);
+
+        // a closed semaphore stops the spinning
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(20));
+            sem.close();
+            assert_eq!(Err(AcquireError::Closed), waiter.join().unwrap());
+        });
+    }
 }
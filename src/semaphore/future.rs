@@ -0,0 +1,238 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{AcquireError, Semaphore, Waiter};
+use crate::sync::atomic::Ordering;
+use crate::sync::{self, Arc};
+
+/// Future returned by [`Semaphore::acquire`] and
+/// [`Semaphore::acquire_many_async`]
+///
+/// Async waiters share the queue and the fairness policy of the blocking
+/// ones. Dropping the future before it completes gives up its place in the
+/// queue, and returns the resources if they were already handed over.
+#[must_use = "futures do nothing unless polled"]
+pub struct Acquire<'a> {
+    sem: &'a Semaphore,
+    n: usize,
+    /// Queue entry, once the future had to park
+    waiter: Option<Arc<Waiter>>,
+}
+
+/// Async acquisition of the semaphore
+///
+/// # Examples
+///
+/// ```
+/// # use esync::Semaphore;
+/// # async fn handle(sem: &Semaphore) {
+/// sem.acquire().await.unwrap();
+/// // do important things here
+/// sem.release();
+/// # }
+/// ```
+impl Semaphore {
+    /// Acquires the semaphore without blocking the thread, resolving once a
+    /// resource is available.
+    ///
+    /// Fails only if the semaphore is closed, either before or while waiting.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many_async(1)
+    }
+
+    /// Acquires `n` resources at once without blocking the thread.
+    ///
+    /// This is the async counterpart of
+    /// [`acquire_many`](Self::acquire_many).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than the maximum value of the semaphore.
+    pub fn acquire_many_async(&self, n: usize) -> Acquire<'_> {
+        assert!(
+            n <= self.get_max_value(),
+            "requested more than the semaphore maximum"
+        );
+        Acquire {
+            sem: self,
+            n,
+            waiter: None,
+        }
+    }
+
+    /// Polls an async acquisition of `n` resources, queueing `waiter` the
+    /// first time it has to wait
+    pub(super) fn poll_acquire(
+        &self,
+        n: usize,
+        waiter: &mut Option<Arc<Waiter>>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), AcquireError>> {
+        let queued = match waiter {
+            Some(queued) => queued.clone(),
+            None => {
+                if self.is_closed() {
+                    return Poll::Ready(Err(AcquireError::Closed));
+                }
+                if self.barge(n) {
+                    return Poll::Ready(Ok(()));
+                }
+                let queued = Arc::new(Waiter::new(n, 0));
+                *sync::lock(&queued.waker) = Some(cx.waker().clone());
+                let mut guard = sync::lock(&self._mutex);
+                guard.queue.push(queued.clone());
+                self.queued(&guard);
+                self.parked();
+                self.grant(&mut guard);
+                drop(guard);
+                *waiter = Some(queued.clone());
+                queued
+            }
+        };
+
+        let mut guard = sync::lock(&self._mutex);
+        if queued.granted.load(Ordering::Relaxed) {
+            self.served(&queued);
+            *waiter = None;
+            return Poll::Ready(Ok(()));
+        }
+        if self.is_closed() {
+            self.leave(&mut guard, &queued);
+            *waiter = None;
+            return Poll::Ready(Err(AcquireError::Closed));
+        }
+        *sync::lock(&queued.waker) = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Gives up an async acquisition that may still be queued
+    pub(super) fn abandon(&self, waiter: &mut Option<Arc<Waiter>>) {
+        if let Some(waiter) = waiter.take() {
+            let mut guard = sync::lock(&self._mutex);
+            if waiter.granted.load(Ordering::Relaxed) {
+                // the resources were handed over but nobody will use them
+                self._permits.fetch_add(waiter.n, Ordering::SeqCst);
+            } else {
+                self.leave(&mut guard, &waiter);
+            }
+            self.grant(&mut guard);
+        }
+    }
+}
+
+impl Future for Acquire<'_> {
+    type Output = Result<(), AcquireError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.sem.poll_acquire(this.n, &mut this.waiter, cx)
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        self.sem.abandon(&mut self.waiter);
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    use crate::{AcquireError, Semaphore};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    pub(in crate::semaphore) fn waker() -> Waker {
+        Arc::new(ThreadWaker(thread::current())).into()
+    }
+
+    pub(in crate::semaphore) fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut f = Box::pin(f);
+        loop {
+            if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+                return r;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn acquire_available() {
+        let sem = Semaphore::new(2);
+        block_on(sem.acquire()).unwrap();
+        assert_eq!(1, sem.get_current_value());
+        block_on(sem.acquire_many_async(1)).unwrap();
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn acquire_shares_the_queue() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let task = s.spawn(|| block_on(sem.acquire_many_async(2)));
+            thread::sleep(Duration::from_millis(50));
+            let blocking = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(50));
+            sem.release();
+            thread::sleep(Duration::from_millis(50));
+            // the task came first and needs both resources
+            assert!(!task.is_finished());
+            assert!(!blocking.is_finished());
+            sem.release();
+            assert_eq!(Ok(()), task.join().unwrap());
+            sem.release();
+            assert_eq!(Ok(()), blocking.join().unwrap());
+        });
+        assert_eq!(0, sem.get_current_value());
+    }
+
+    #[test]
+    fn dropped_future_leaves_the_queue() {
+        let sem = Semaphore::new(0);
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut first = Box::pin(sem.acquire());
+        assert!(first.as_mut().poll(&mut cx).is_pending());
+        let mut second = Box::pin(sem.acquire());
+        assert!(second.as_mut().poll(&mut cx).is_pending());
+        // hand the resource to the first future, which never observes it
+        sem.release();
+        assert_eq!(0, sem.get_current_value());
+        drop(first);
+        assert!(second.as_mut().poll(&mut cx).is_ready());
+        drop(second);
+        assert_eq!(0, sem.get_current_value());
+
+        let mut third = Box::pin(sem.acquire());
+        assert!(third.as_mut().poll(&mut cx).is_pending());
+        drop(third);
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn close_wakes_tasks() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let task = s.spawn(|| block_on(sem.acquire()));
+            thread::sleep(Duration::from_millis(50));
+            sem.close();
+            assert_eq!(Err(AcquireError::Closed), task.join().unwrap());
+        });
+        assert_eq!(Err(AcquireError::Closed), block_on(sem.acquire()));
+    }
+}
@@ -0,0 +1,277 @@
+use std::time::{Duration, Instant};
+
+use super::{AcquireError, Semaphore, State};
+use crate::sync;
+use crate::sync::atomic::Ordering;
+
+/// Callback run when a lease expires
+type OnExpire = Box<dyn FnOnce() + Send>;
+
+/// Resources handed out under a lease, as tracked by the semaphore
+pub(super) struct LeaseEntry {
+    id: u64,
+    n: usize,
+    deadline: Instant,
+    on_expire: OnExpire,
+}
+
+/// Resources acquired for a limited time, returned by
+/// [`Semaphore::wait_leased`] and [`Semaphore::try_wait_leased`]
+///
+/// The resources go back to the semaphore when the lease is dropped or
+/// [released](Self::release), or when it expires, whichever comes first.
+/// Once expired, the lease no longer holds anything and releasing it is a
+/// no-op.
+#[must_use = "if unused the lease is immediately released"]
+pub struct Lease<'a> {
+    sem: &'a Semaphore,
+    id: u64,
+}
+
+/// Leased acquisition of the semaphore
+///
+/// A leased resource is given back automatically if its holder does not
+/// release it within the lease period, so that a consumer that hangs cannot
+/// keep it forever. The holder is told through a callback, which runs on
+/// whichever thread notices the expiry: a consumer waiting on the semaphore,
+/// one failing to acquire it without blocking, or a call to
+/// [`reclaim_expired`](Semaphore::reclaim_expired). Async waiters do not
+/// notice expired leases by themselves.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::Semaphore;
+/// # use std::time::Duration;
+/// let sem = Semaphore::new(1);
+/// let lease = sem
+///     .wait_leased(Duration::from_millis(10), || println!("lease expired"))
+///     .unwrap();
+/// // the holder hangs past the lease period
+/// std::thread::sleep(Duration::from_millis(20));
+/// assert!(sem.try_wait());
+/// assert!(!lease.release());
+/// ```
+impl Semaphore {
+    /// Acquires the semaphore for at most `ttl`, after which the resource is
+    /// taken back and `on_expire` is called.
+    ///
+    /// Fails only if the semaphore is closed, either before or while waiting.
+    pub fn wait_leased<F>(&self, ttl: Duration, on_expire: F) -> Result<Lease<'_>, AcquireError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.wait()?;
+        Ok(self.lease(1, ttl, Box::new(on_expire)))
+    }
+
+    /// Attempts to acquire the semaphore for at most `ttl` without blocking.
+    ///
+    /// See [`wait_leased`](Self::wait_leased) and
+    /// [`try_wait`](Self::try_wait).
+    pub fn try_wait_leased<F>(&self, ttl: Duration, on_expire: F) -> Option<Lease<'_>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if self.try_wait() {
+            Some(self.lease(1, ttl, Box::new(on_expire)))
+        } else {
+            None
+        }
+    }
+
+    /// Takes back the resources of all the expired leases, runs their
+    /// callbacks and returns how many resources were reclaimed.
+    pub fn reclaim_expired(&self) -> usize {
+        let mut guard = sync::lock(&self._mutex);
+        let expired = self.expire_leases(&mut guard);
+        drop(guard);
+        run_expired(expired)
+    }
+
+    /// Earliest deadline among the outstanding leases
+    pub(super) fn next_expiry(&self, state: &State) -> Option<Instant> {
+        state.leases.iter().map(|l| l.deadline).min()
+    }
+
+    /// Removes the expired leases and hands their resources over to the
+    /// waiters. The callbacks are left to the caller, to be run off the lock.
+    pub(super) fn expire_leases(&self, state: &mut State) -> Vec<LeaseEntry> {
+        if state.leases.is_empty() {
+            return Vec::new();
+        }
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut i = 0;
+        while i < state.leases.len() {
+            if state.leases[i].deadline <= now {
+                expired.push(state.leases.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        if !expired.is_empty() {
+            self._leases.store(state.leases.len(), Ordering::SeqCst);
+            let n = expired.iter().map(|l| l.n).sum();
+            self.grow(state, n);
+        }
+        expired
+    }
+
+    /// Registers a lease on `n` resources already taken
+    fn lease(&self, n: usize, ttl: Duration, on_expire: OnExpire) -> Lease<'_> {
+        let mut guard = sync::lock(&self._mutex);
+        let id = guard.next_lease;
+        guard.next_lease += 1;
+        let deadline = Instant::now()
+            .checked_add(ttl)
+            // a lease too long to represent never expires in practice
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(u32::MAX.into()));
+        guard.leases.push(LeaseEntry {
+            id,
+            n,
+            deadline,
+            on_expire,
+        });
+        self._leases.store(guard.leases.len(), Ordering::SeqCst);
+        Lease { sem: self, id }
+    }
+}
+
+/// Runs the callbacks of expired leases and returns how many resources they
+/// held
+pub(super) fn run_expired(expired: Vec<LeaseEntry>) -> usize {
+    let mut n = 0;
+    for lease in expired {
+        n += lease.n;
+        (lease.on_expire)();
+    }
+    n
+}
+
+impl Lease<'_> {
+    /// Releases the leased resources ahead of the expiry.
+    ///
+    /// Returns `false` if the lease had already expired, in which case the
+    /// resources were already given back.
+    pub fn release(self) -> bool {
+        self.end()
+    }
+
+    /// Extends the lease to `ttl` from now.
+    ///
+    /// Returns `false` if the lease had already expired.
+    pub fn renew(&self, ttl: Duration) -> bool {
+        let mut guard = sync::lock(&self.sem._mutex);
+        match guard.leases.iter_mut().find(|l| l.id == self.id) {
+            Some(lease) => {
+                if let Some(deadline) = Instant::now().checked_add(ttl) {
+                    lease.deadline = deadline;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns `true` if the lease expired and no longer holds resources.
+    pub fn is_expired(&self) -> bool {
+        let guard = sync::lock(&self.sem._mutex);
+        !guard.leases.iter().any(|l| l.id == self.id)
+    }
+
+    fn end(&self) -> bool {
+        let mut guard = sync::lock(&self.sem._mutex);
+        let pos = match guard.leases.iter().position(|l| l.id == self.id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let lease = guard.leases.swap_remove(pos);
+        self.sem._leases.store(guard.leases.len(), Ordering::SeqCst);
+        drop(guard);
+        self.sem.release_many(lease.n);
+        true
+    }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::Semaphore;
+
+    fn counter() -> (Arc<AtomicUsize>, impl FnOnce() + Send + 'static) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let c = count.clone();
+        (count, move || {
+            c.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    #[test]
+    fn release_before_expiry() {
+        let sem = Semaphore::new(1);
+        let (expired, on_expire) = counter();
+        let lease = sem.wait_leased(Duration::from_secs(60), on_expire).unwrap();
+        assert_eq!(0, sem.get_current_value());
+        assert!(!lease.is_expired());
+        assert!(lease.release());
+        assert_eq!(1, sem.get_current_value());
+        assert_eq!(0, sem.reclaim_expired());
+        assert_eq!(0, expired.load(Ordering::SeqCst));
+
+        // dropping releases too
+        drop(sem.try_wait_leased(Duration::from_secs(60), || ()).unwrap());
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn expiry_wakes_a_waiter() {
+        let sem = Semaphore::new(1);
+        let (expired, on_expire) = counter();
+        let lease = sem
+            .wait_leased(Duration::from_millis(50), on_expire)
+            .unwrap();
+        // the holder hangs, the waiter gets the resource once the lease ends
+        assert_eq!(Ok(()), sem.wait_timeout(Duration::from_secs(10)));
+        assert_eq!(1, expired.load(Ordering::SeqCst));
+        assert!(lease.is_expired());
+        assert!(!lease.renew(Duration::from_secs(1)));
+        assert!(!lease.release());
+        assert_eq!(0, sem.get_current_value());
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn expiry_on_try_wait() {
+        let sem = Semaphore::new(1);
+        let (expired, on_expire) = counter();
+        let _lease = sem.try_wait_leased(Duration::from_millis(20), on_expire);
+        assert!(!sem.try_wait());
+        thread::sleep(Duration::from_millis(40));
+        assert!(sem.try_wait());
+        assert_eq!(1, expired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn renew() {
+        let sem = Semaphore::new(1);
+        let lease = sem.wait_leased(Duration::from_millis(50), || ()).unwrap();
+        assert!(lease.renew(Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(0, sem.reclaim_expired());
+        assert!(!lease.is_expired());
+        drop(lease);
+        assert_eq!(1, sem.get_current_value());
+    }
+}
@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+use super::Semaphore;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of buckets of the wait time histogram
+pub const WAIT_BUCKETS: usize = 24;
+
+/// Counters of a semaphore created with [`Semaphore::with_stats`]
+pub(super) struct Counters {
+    acquisitions: AtomicUsize,
+    parks: AtomicUsize,
+    waits: [AtomicUsize; WAIT_BUCKETS],
+}
+
+impl Counters {
+    #[cfg(not(loom))]
+    pub(super) const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+        Self {
+            acquisitions: ZERO,
+            parks: ZERO,
+            waits: [ZERO; WAIT_BUCKETS],
+        }
+    }
+
+    /// Loom atomics are built at run time, and one by one
+    #[cfg(loom)]
+    pub(super) fn new() -> Self {
+        Self {
+            acquisitions: AtomicUsize::new(0),
+            parks: AtomicUsize::new(0),
+            waits: core::array::from_fn(|_| AtomicUsize::new(0)),
+        }
+    }
+
+    pub(super) fn acquired(&self) {
+        self.acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn parked(&self) {
+        self.parks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a consumer that waited `wait` before getting its resources
+    pub(super) fn waited(&self, wait: Duration) {
+        self.waits[SemaphoreStats::bucket(wait)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of the contention counters of a semaphore, returned by
+/// [`Semaphore::stats`]
+///
+/// Counters are updated with relaxed atomics and read one at a time, so a
+/// snapshot taken under load may be slightly inconsistent, e.g. count a park
+/// whose wait is not in the histogram yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemaphoreStats {
+    /// Number of successful acquisitions, whether they had to wait or not
+    pub acquisitions: u64,
+    /// Number of acquisitions that had to park, waiting for resources
+    pub parks: u64,
+    /// Wait times of the parked acquisitions that got their resources.
+    /// Bucket `i` counts the waits shorter than
+    /// [`bucket_limit(i)`](Self::bucket_limit), and not counted by the
+    /// previous buckets.
+    pub wait_histogram: [u64; WAIT_BUCKETS],
+}
+
+impl SemaphoreStats {
+    /// Upper bound of the bucket `i` of the wait histogram: 2^i
+    /// microseconds, or `None` for the last bucket, which counts all the
+    /// longer waits.
+    pub fn bucket_limit(i: usize) -> Option<Duration> {
+        if i + 1 < WAIT_BUCKETS {
+            Some(Duration::from_micros(1 << i))
+        } else {
+            None
+        }
+    }
+
+    fn bucket(wait: Duration) -> usize {
+        let micros = wait.as_micros();
+        let bits = (u128::BITS - micros.leading_zeros()) as usize;
+        bits.min(WAIT_BUCKETS - 1)
+    }
+}
+
+/// Contention statistics
+///
+/// # Examples
+///
+/// ```
+/// # use esync::Semaphore;
+/// let sem = Semaphore::new(1).with_stats();
+/// sem.wait().unwrap();
+/// sem.release();
+/// let stats = sem.stats().unwrap();
+/// assert_eq!(1, stats.acquisitions);
+/// assert_eq!(0, stats.parks);
+/// ```
+impl Semaphore {
+    const_fn! {
+        /// Enables the collection of contention statistics, retrieved with
+        /// [`stats`](Self::stats).
+        ///
+        /// Statistics cost an atomic increment per acquisition, and a clock
+        /// reading per park. Semaphores created without them pay nothing.
+        pub fn with_stats(mut self) -> Self {
+            self._stats = Some(Counters::new());
+            self
+        }
+    }
+
+    /// Get the contention statistics of the semaphore, if it was created
+    /// [`with_stats`](Self::with_stats).
+    pub fn stats(&self) -> Option<SemaphoreStats> {
+        let counters = self._stats.as_ref()?;
+        let mut wait_histogram = [0; WAIT_BUCKETS];
+        for (bucket, count) in wait_histogram.iter_mut().zip(&counters.waits) {
+            *bucket = count.load(Ordering::Relaxed) as u64;
+        }
+        Some(SemaphoreStats {
+            acquisitions: counters.acquisitions.load(Ordering::Relaxed) as u64,
+            parks: counters.parks.load(Ordering::Relaxed) as u64,
+            wait_histogram,
+        })
+    }
+
+    /// Sets all the contention statistics back to zero.
+    pub fn reset_stats(&self) {
+        if let Some(counters) = &self._stats {
+            counters.acquisitions.store(0, Ordering::Relaxed);
+            counters.parks.store(0, Ordering::Relaxed);
+            for count in &counters.waits {
+                count.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{SemaphoreStats, WAIT_BUCKETS};
+    use crate::Semaphore;
+
+    #[test]
+    fn disabled() {
+        let sem = Semaphore::new(1);
+        sem.wait().unwrap();
+        assert_eq!(None, sem.stats());
+    }
+
+    #[test]
+    fn counters() {
+        let sem = Semaphore::new(1).with_stats();
+        assert!(sem.try_wait());
+        assert!(!sem.try_wait());
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait().unwrap());
+            thread::sleep(Duration::from_millis(50));
+            sem.release();
+            waiter.join().unwrap();
+        });
+        let stats = sem.stats().unwrap();
+        assert_eq!(2, stats.acquisitions);
+        assert_eq!(1, stats.parks);
+        // the waiter waited about 50ms, in the [2^15, 2^16) microseconds bucket
+        // or a later one
+        assert_eq!(1, stats.wait_histogram[16..].iter().sum::<u64>());
+        sem.reset_stats();
+        assert_eq!(0, sem.stats().unwrap().acquisitions);
+    }
+
+    #[test]
+    fn buckets() {
+        assert_eq!(0, SemaphoreStats::bucket(Duration::from_nanos(500)));
+        assert_eq!(1, SemaphoreStats::bucket(Duration::from_micros(1)));
+        assert_eq!(4, SemaphoreStats::bucket(Duration::from_micros(10)));
+        assert_eq!(
+            Some(Duration::from_micros(16)),
+            SemaphoreStats::bucket_limit(4)
+        );
+        assert_eq!(
+            WAIT_BUCKETS - 1,
+            SemaphoreStats::bucket(Duration::from_secs(3600))
+        );
+        assert_eq!(None, SemaphoreStats::bucket_limit(WAIT_BUCKETS - 1));
+    }
+}
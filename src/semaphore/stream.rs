@@ -0,0 +1,218 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use super::{Semaphore, Waiter};
+
+/// Resource of a shared semaphore, released when dropped
+///
+/// The permit owns a reference to the semaphore, so it is `'static` and can
+/// be moved into a spawned task.
+#[must_use = "if unused the permit is immediately released"]
+pub struct OwnedPermit {
+    sem: Arc<Semaphore>,
+    /// Resources to release on drop
+    n: usize,
+}
+
+/// Endless stream of the resources of a shared semaphore, returned by
+/// [`Semaphore::permits`]
+///
+/// Each item waits for a resource like [`Semaphore::acquire`] would, sharing
+/// the queue with the other consumers, and the stream ends once the semaphore
+/// is closed. It implements [`Stream`], so the combinators of `futures`
+/// apply, and can also be driven without them with [`next`](Self::next).
+pub struct Permits {
+    sem: Arc<Semaphore>,
+    /// Queue entry of the next permit, once it had to park
+    waiter: Option<Arc<Waiter>>,
+}
+
+/// Future returned by [`Permits::next`]
+#[must_use = "futures do nothing unless polled"]
+pub struct Next<'a> {
+    permits: &'a mut Permits,
+}
+
+/// Stream of owned permits
+///
+/// # Examples
+///
+/// ```
+/// # use esync::Semaphore;
+/// # use std::sync::Arc;
+/// # async fn pipeline(sem: Arc<Semaphore>) {
+/// let mut permits = sem.permits();
+/// while let Some(permit) = permits.next().await {
+///     std::thread::spawn(move || {
+///         // do important things here, then release
+///         drop(permit);
+///     });
+/// }
+/// # }
+/// ```
+impl Semaphore {
+    /// Returns a stream yielding a resource of the semaphore each time one is
+    /// available, until the semaphore is closed.
+    pub fn permits(self: &Arc<Self>) -> Permits {
+        Permits {
+            sem: self.clone(),
+            waiter: None,
+        }
+    }
+}
+
+impl Permits {
+    /// Waits for the next permit, or `None` once the semaphore is closed.
+    // named after `StreamExt::next`, which it stands for
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Next<'_> {
+        Next { permits: self }
+    }
+
+    /// Attempts to pull out the next permit, registering the task for a
+    /// wakeup if none is available yet.
+    pub fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OwnedPermit>> {
+        let this = &mut *self;
+        match this.sem.poll_acquire(1, &mut this.waiter, cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Some(OwnedPermit {
+                sem: this.sem.clone(),
+                n: 1,
+            })),
+            Poll::Ready(Err(_)) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Permits {
+    fn drop(&mut self) {
+        self.sem.abandon(&mut self.waiter);
+    }
+}
+
+impl Stream for Permits {
+    type Item = OwnedPermit;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<OwnedPermit>> {
+        Permits::poll_next(self, cx)
+    }
+}
+
+impl Future for Next<'_> {
+    type Output = Option<OwnedPermit>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.permits).poll_next(cx)
+    }
+}
+
+impl OwnedPermit {
+    /// The semaphore the permit belongs to
+    pub fn semaphore(&self) -> &Arc<Semaphore> {
+        &self.sem
+    }
+
+    /// Consumes the permit without releasing its resource, as if it were
+    /// [forgotten](Semaphore::forget).
+    pub fn forget(mut self) {
+        self.n = 0;
+    }
+}
+
+impl Drop for OwnedPermit {
+    fn drop(&mut self) {
+        if self.n > 0 {
+            self.sem.release_many(self.n);
+        }
+    }
+}
+
+impl fmt::Debug for OwnedPermit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OwnedPermit").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures_core::Stream;
+
+    use crate::semaphore::future::test::{block_on, waker};
+    use crate::Semaphore;
+
+    #[test]
+    fn permits() {
+        let sem = Arc::new(Semaphore::new(2));
+        let mut permits = sem.permits();
+        let first = block_on(permits.next()).unwrap();
+        let second = block_on(permits.next()).unwrap();
+        assert_eq!(0, sem.get_current_value());
+        thread::scope(|s| {
+            let task = s.spawn(|| block_on(permits.next()));
+            thread::sleep(Duration::from_millis(50));
+            let worker = thread::spawn(move || drop(first));
+            worker.join().unwrap();
+            assert!(task.join().unwrap().is_some());
+        });
+        second.forget();
+        assert_eq!(1, sem.get_current_value());
+    }
+
+    #[test]
+    fn ends_when_closed() {
+        let sem = Arc::new(Semaphore::new(0));
+        let mut permits = sem.permits();
+        thread::scope(|s| {
+            let task = s.spawn(|| block_on(permits.next()));
+            thread::sleep(Duration::from_millis(50));
+            sem.close();
+            assert!(task.join().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn stream() {
+        fn poll<S: Stream + Unpin>(s: &mut S, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+            Stream::poll_next(Pin::new(s), cx)
+        }
+        let sem = Arc::new(Semaphore::new(1));
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut permits = sem.permits();
+        let first = match poll(&mut permits, &mut cx) {
+            Poll::Ready(Some(permit)) => permit,
+            _ => panic!("a resource is available"),
+        };
+        assert!(poll(&mut permits, &mut cx).is_pending());
+        drop(first);
+        assert!(matches!(poll(&mut permits, &mut cx), Poll::Ready(Some(_))));
+        sem.close();
+        assert!(matches!(poll(&mut permits, &mut cx), Poll::Ready(None)));
+    }
+
+    #[test]
+    fn dropped_stream_leaves_the_queue() {
+        let sem = Arc::new(Semaphore::new(0));
+        let waker = waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut permits = sem.permits();
+        let mut next = Box::pin(permits.next());
+        assert!(next.as_mut().poll(&mut cx).is_pending());
+        drop(next);
+        drop(permits);
+        sem.release();
+        assert_eq!(1, sem.get_current_value());
+    }
+}
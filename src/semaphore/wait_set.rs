@@ -0,0 +1,520 @@
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use super::{AcquireError, Semaphore, Waiter};
+use crate::sync::atomic::{self, Ordering};
+use crate::sync::{self, Arc, Condvar, Mutex};
+
+/// Queue entry of a [`wait_while`](Semaphore::wait_while) caller, which
+/// leaves the queue when dropped, even if the predicate panicked
+struct Conditional<'a> {
+    sem: &'a Semaphore,
+    waiter: Arc<Waiter>,
+}
+
+/// Wakes up a wait set when one of its semaphores may have resources
+pub(super) struct Signal {
+    fired: Mutex<bool>,
+    cv: Condvar,
+    /// Thread waiting on the signal
+    owner: ThreadId,
+}
+
+impl Signal {
+    fn new() -> Self {
+        Self {
+            fired: Mutex::new(false),
+            cv: Condvar::new(),
+            owner: thread::current().id(),
+        }
+    }
+
+    pub(super) fn notify(&self) {
+        // giving back resources it could not use is no news to the waiter
+        if thread::current().id() == self.owner {
+            return;
+        }
+        *sync::lock(&self.fired) = true;
+        self.cv.notify_one();
+    }
+
+    /// Waits for a notification since the last call, until `deadline` at
+    /// most. Returns `false` if the deadline passed first.
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut fired = sync::lock(&self.fired);
+        while !*fired {
+            fired = match deadline {
+                None => sync::wait(&self.cv, fired),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    sync::wait_timeout(&self.cv, fired, deadline - now)
+                }
+            };
+        }
+        *fired = false;
+        true
+    }
+}
+
+/// Set of semaphores to acquire together
+///
+/// [`wait_any`](Self::wait_any) blocks until one of the semaphores of the set
+/// can be acquired, and [`wait_all`](Self::wait_all) until all of them can.
+/// The set parks on a single signal every semaphore of the set notifies on
+/// release, so waiting costs nothing while no resource is released.
+///
+/// A wait set acquires the semaphores like [`Semaphore::try_wait`] would: with
+/// the FIFO policy, it does not take resources ahead of the consumers already
+/// queued on a semaphore.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::{Semaphore, WaitSet};
+/// let fast = Semaphore::new(0);
+/// let slow = Semaphore::new(1);
+/// let mut set = WaitSet::new();
+/// set.add(&fast);
+/// let slow_index = set.add(&slow);
+/// assert_eq!(Ok(slow_index), set.wait_any());
+/// slow.release();
+///
+/// fast.release();
+/// set.wait_all().unwrap();
+/// set.release_all();
+/// ```
+#[derive(Default)]
+pub struct WaitSet<'a> {
+    sems: Vec<&'a Semaphore>,
+}
+
+/// Registration of a signal on a group of semaphores
+struct Watch<'s, 'a> {
+    sems: &'s [&'a Semaphore],
+    signal: Arc<Signal>,
+}
+
+impl<'a> WaitSet<'a> {
+    /// Creates an empty wait set
+    pub fn new() -> Self {
+        Self { sems: Vec::new() }
+    }
+
+    /// Adds a semaphore to the set and returns its index, as reported by
+    /// [`wait_any`](Self::wait_any).
+    pub fn add(&mut self, sem: &'a Semaphore) -> usize {
+        self.sems.push(sem);
+        self.sems.len() - 1
+    }
+
+    /// Number of semaphores in the set
+    pub fn len(&self) -> usize {
+        self.sems.len()
+    }
+
+    /// Returns `true` if the set holds no semaphore.
+    pub fn is_empty(&self) -> bool {
+        self.sems.is_empty()
+    }
+
+    /// Acquires one of the semaphores of the set, waiting until any of them
+    /// has a resource available, and returns its index.
+    ///
+    /// When several semaphores are available, the one added first is taken.
+    /// Fails if one of the semaphores is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the set is empty, as the call could never return.
+    pub fn wait_any(&self) -> Result<usize, AcquireError> {
+        self.any(None)
+    }
+
+    /// Like [`wait_any`](Self::wait_any), waiting at most `timeout`.
+    pub fn wait_any_timeout(&self, timeout: Duration) -> Result<usize, AcquireError> {
+        self.any(Instant::now().checked_add(timeout))
+    }
+
+    /// Acquires all the semaphores of the set, waiting until every one of
+    /// them has a resource available.
+    ///
+    /// The semaphores are taken all at once or not at all: the set never
+    /// holds on to some of them while waiting for the others, so wait sets
+    /// sharing semaphores cannot deadlock each other. Fails if one of the
+    /// semaphores is closed.
+    pub fn wait_all(&self) -> Result<(), AcquireError> {
+        self.all(None)
+    }
+
+    /// Like [`wait_all`](Self::wait_all), waiting at most `timeout`.
+    pub fn wait_all_timeout(&self, timeout: Duration) -> Result<(), AcquireError> {
+        self.all(Instant::now().checked_add(timeout))
+    }
+
+    /// Releases once every semaphore of the set, as acquired by
+    /// [`wait_all`](Self::wait_all)
+    pub fn release_all(&self) {
+        for sem in &self.sems {
+            sem.release();
+        }
+    }
+
+    fn any(&self, deadline: Option<Instant>) -> Result<usize, AcquireError> {
+        assert!(
+            !self.is_empty(),
+            "waiting for any semaphore of an empty set"
+        );
+        let watch = Watch::new(&self.sems);
+        loop {
+            for (i, sem) in self.sems.iter().enumerate() {
+                if sem.is_closed() {
+                    return Err(AcquireError::Closed);
+                }
+                if sem.try_wait() {
+                    return Ok(i);
+                }
+            }
+            if !watch.signal.wait(deadline) {
+                return Err(AcquireError::TimedOut);
+            }
+        }
+    }
+
+    fn all(&self, deadline: Option<Instant>) -> Result<(), AcquireError> {
+        // a global order keeps competing sets from taking turns failing on
+        // each other's semaphores
+        let mut order = self.sems.clone();
+        order.sort_by_key(|sem| *sem as *const Semaphore as usize);
+        let watch = Watch::new(&order);
+        loop {
+            if order.iter().any(|sem| sem.is_closed()) {
+                return Err(AcquireError::Closed);
+            }
+            let taken = order.iter().take_while(|sem| sem.try_wait()).count();
+            if taken == order.len() {
+                return Ok(());
+            }
+            for sem in order[..taken].iter().rev() {
+                sem.release();
+            }
+            if !watch.signal.wait(deadline) {
+                return Err(AcquireError::TimedOut);
+            }
+        }
+    }
+}
+
+/// Conditional acquisition
+///
+/// # Examples
+///
+/// ```
+/// # use esync::Semaphore;
+/// let sem = Semaphore::new(4);
+/// // keep a reserve of two resources
+/// sem.wait_while(|available| available > 2).unwrap();
+/// assert_eq!(3, sem.get_current_value());
+/// ```
+impl Semaphore {
+    /// Acquires the semaphore once `pred` returns `true` for the number of
+    /// available resources, waiting for the value to change until it does.
+    ///
+    /// The predicate is evaluated on the value the resource is taken from, so
+    /// the acquisition only happens if it holds at that point. It is called
+    /// on the waiting thread every time the value changed, through releases
+    /// as well as acquisitions, so that both high and low watermarks are
+    /// seen, and should be cheap.
+    ///
+    /// The caller is queued and served in turn like any other waiter, under
+    /// the fairness policy of the semaphore. While its predicate fails, the
+    /// consumers queued behind it are served past it, and it does not keep
+    /// [`try_wait`](Self::try_wait) from taking resources either.
+    ///
+    /// Fails if the semaphore is closed, either before or while waiting.
+    pub fn wait_while<F>(&self, mut pred: F) -> Result<(), AcquireError>
+    where
+        F: FnMut(usize) -> bool,
+    {
+        if self.is_closed() {
+            return Err(AcquireError::Closed);
+        }
+        if self.may_barge() && self.take_if(&mut pred) {
+            self.acquired();
+            self.taken();
+            return Ok(());
+        }
+        // dropped after the guard, which it takes again
+        let entry = Conditional::new(self);
+        let waiter = &entry.waiter;
+        let mut guard = sync::lock(&self._mutex);
+        loop {
+            if self.is_closed() {
+                return Err(AcquireError::Closed);
+            }
+            let next = self.next_waiter(&guard);
+            if next.map_or(false, |i| Arc::ptr_eq(&guard.queue[i], waiter)) {
+                let value = self.get_current_value();
+                if value > 0 {
+                    // the predicate is user code, run off the lock
+                    drop(guard);
+                    let holds = pred(value);
+                    guard = sync::lock(&self._mutex);
+                    if holds {
+                        // taken from the value the predicate held for, or
+                        // looked at again
+                        let taken = self._permits.compare_exchange(
+                            value,
+                            value - 1,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        );
+                        if taken.is_ok() {
+                            self.acquired();
+                            self.served(waiter);
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                }
+                waiter.decline(value);
+                // serving the waiters behind may change the value, and make
+                // it our turn again
+                self.grant(&mut guard);
+                continue;
+            }
+            guard = sync::wait(&waiter.cv, guard);
+        }
+    }
+
+    /// Takes one resource if `pred` holds for the available resources
+    fn take_if<F: FnMut(usize) -> bool>(&self, pred: &mut F) -> bool {
+        let mut current = self._permits.load(Ordering::SeqCst);
+        loop {
+            if current == 0 || !pred(current) {
+                return false;
+            }
+            match self._permits.compare_exchange_weak(
+                current,
+                current - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl<'a> Conditional<'a> {
+    /// Queues a conditional waiter for one resource of `sem`
+    fn new(sem: &'a Semaphore) -> Self {
+        let waiter = Arc::new(Waiter {
+            conditional: true,
+            ..Waiter::new(1, 0)
+        });
+        let mut guard = sync::lock(&sem._mutex);
+        guard.queue.push(waiter.clone());
+        guard.conditional += 1;
+        sem.queued(&guard);
+        // the releases and acquisitions after this look for the waiter
+        atomic::store_load_fence();
+        sem.parked();
+        Conditional { sem, waiter }
+    }
+}
+
+impl Drop for Conditional<'_> {
+    fn drop(&mut self) {
+        let mut guard = sync::lock(&self.sem._mutex);
+        guard.conditional -= 1;
+        self.sem.leave(&mut guard, &self.waiter);
+        // the waiters queued behind might be served now
+        self.sem.grant(&mut guard);
+    }
+}
+
+impl<'s, 'a> Watch<'s, 'a> {
+    /// Registers a new signal on all the semaphores, before the first
+    /// attempt to take them, so that no release is missed
+    fn new(sems: &'s [&'a Semaphore]) -> Self {
+        let signal = Arc::new(Signal::new());
+        for sem in sems {
+            let mut guard = sync::lock(&sem._mutex);
+            guard.watchers.push(signal.clone());
+            sem._watchers.store(guard.watchers.len(), Ordering::SeqCst);
+        }
+        Watch { sems, signal }
+    }
+}
+
+impl Drop for Watch<'_, '_> {
+    fn drop(&mut self) {
+        for sem in self.sems {
+            let mut guard = sync::lock(&sem._mutex);
+            if let Some(pos) = guard
+                .watchers
+                .iter()
+                .position(|w| Arc::ptr_eq(w, &self.signal))
+            {
+                guard.watchers.swap_remove(pos);
+                sem._watchers.store(guard.watchers.len(), Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::WaitSet;
+    use crate::{AcquireError, Semaphore};
+
+    #[test]
+    fn wait_any() {
+        let a = Semaphore::new(0);
+        let b = Semaphore::new(0);
+        let mut set = WaitSet::new();
+        set.add(&a);
+        set.add(&b);
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            set.wait_any_timeout(Duration::from_millis(20))
+        );
+        thread::scope(|s| {
+            let waiter = s.spawn(|| set.wait_any());
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            b.release();
+            assert_eq!(Ok(1), waiter.join().unwrap());
+        });
+        assert_eq!(0, b.get_current_value());
+        assert_eq!(0, a._watchers.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn wait_all() {
+        let a = Semaphore::new(1);
+        let b = Semaphore::new(0);
+        let mut set = WaitSet::new();
+        set.add(&a);
+        set.add(&b);
+        assert_eq!(
+            Err(AcquireError::TimedOut),
+            set.wait_all_timeout(Duration::from_millis(20))
+        );
+        // nothing is held while waiting
+        assert_eq!(1, a.get_current_value());
+        thread::scope(|s| {
+            let waiter = s.spawn(|| set.wait_all());
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(1, a.get_current_value());
+            b.release();
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert_eq!(0, a.get_current_value());
+        assert_eq!(0, b.get_current_value());
+        set.release_all();
+        assert_eq!(1, b.get_current_value());
+    }
+
+    #[test]
+    fn overlapping_sets() {
+        let sems = [Semaphore::new(1), Semaphore::new(1), Semaphore::new(1)];
+        let mut first = WaitSet::new();
+        first.add(&sems[0]);
+        first.add(&sems[1]);
+        let mut second = WaitSet::new();
+        second.add(&sems[2]);
+        second.add(&sems[1]);
+        second.add(&sems[0]);
+        thread::scope(|s| {
+            for set in [&first, &second, &first, &second] {
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        set.wait_all().unwrap();
+                        set.release_all();
+                    }
+                });
+            }
+        });
+        assert!(sems.iter().all(|s| s.get_current_value() == 1));
+    }
+
+    #[test]
+    fn wait_while() {
+        let sem = Semaphore::new(1);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_while(|available| available >= 3));
+            thread::sleep(Duration::from_millis(50));
+            sem.release();
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            sem.release();
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert_eq!(2, sem.get_current_value());
+        assert_eq!(0, sem.waiters());
+
+        // an empty semaphore has nothing to take, whatever the predicate
+        sem.drain_permits();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_while(|_| true));
+            thread::sleep(Duration::from_millis(50));
+            sem.close();
+            assert_eq!(Err(AcquireError::Closed), waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn wait_while_low_watermark() {
+        let sem = Semaphore::new(4);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_while(|available| available <= 2));
+            thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            // the waiter does not hold the other consumers back
+            assert!(sem.try_wait());
+            assert!(sem.try_wait());
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert_eq!(1, sem.get_current_value());
+        assert_eq!(0, sem.waiters());
+    }
+
+    #[test]
+    fn wait_while_in_turn() {
+        let sem = Semaphore::new(0);
+        thread::scope(|s| {
+            let first = s.spawn(|| sem.wait_while(|_| true));
+            thread::sleep(Duration::from_millis(50));
+            let second = s.spawn(|| sem.wait());
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(2, sem.waiters());
+            sem.release();
+            assert_eq!(Ok(()), first.join().unwrap());
+            assert!(!second.is_finished());
+            sem.release();
+            assert_eq!(Ok(()), second.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn closed() {
+        let a = Semaphore::new(0);
+        let mut set = WaitSet::new();
+        set.add(&a);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| set.wait_any());
+            thread::sleep(Duration::from_millis(50));
+            a.close();
+            assert_eq!(Err(AcquireError::Closed), waiter.join().unwrap());
+        });
+        assert_eq!(Err(AcquireError::Closed), set.wait_all());
+    }
+}
@@ -0,0 +1,83 @@
+//! Parallel processing of slices.
+
+use crate::worker_threads::process;
+
+/// Process the consecutive chunks of `chunk_len` elements of `data` on a
+/// given number of threads
+///
+/// The chunks are dispatched as the items of [`process`] are, and `f` gets
+/// every one as a slice of `data`. The last chunk may be shorter. Results
+/// are returned per chunk, in the order of the chunks. Zero `workers` means
+/// one per CPU.
+///
+/// # Panics
+///
+/// Panics if `chunk_len` is zero.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::slice::par_chunks;
+/// let data = (1..=10).collect::<Vec<u32>>();
+/// let sums = par_chunks(&data, 4, 2, |chunk| chunk.iter().sum::<u32>());
+/// assert_eq!(vec![10, 26, 19], sums);
+/// ```
+pub fn par_chunks<T, F, R>(data: &[T], chunk_len: usize, workers: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    F: Fn(&[T]) -> R + Sync,
+    R: Send,
+{
+    process(data.chunks(chunk_len), f, workers)
+}
+
+/// Process the consecutive chunks of `chunk_len` elements of `data` on a
+/// given number of threads, updating them in place
+///
+/// This is [`par_chunks`] with a mutable slice of `data` for every chunk.
+///
+/// # Panics
+///
+/// Panics if `chunk_len` is zero.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::slice::par_chunks_mut;
+/// let mut data = vec![1u32; 10];
+/// let lengths = par_chunks_mut(&mut data, 4, 2, |chunk| {
+///     chunk.iter_mut().for_each(|x| *x *= 2);
+///     chunk.len()
+/// });
+/// assert_eq!(vec![4, 4, 2], lengths);
+/// assert_eq!(vec![2; 10], data);
+/// ```
+pub fn par_chunks_mut<T, F, R>(data: &mut [T], chunk_len: usize, workers: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    F: Fn(&mut [T]) -> R + Sync,
+    R: Send,
+{
+    process(data.chunks_mut(chunk_len), f, workers)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{par_chunks, par_chunks_mut};
+
+    #[test]
+    fn chunks() {
+        let mut data = (0..1000).collect::<Vec<u64>>();
+        let firsts = par_chunks(&data, 100, 4, |chunk| chunk[0]);
+        assert_eq!((0..10).map(|i| i * 100).collect::<Vec<_>>(), firsts);
+        par_chunks_mut(&mut data, 7, 4, |chunk| chunk.reverse());
+        let expected = (0..1000)
+            .collect::<Vec<u64>>()
+            .chunks(7)
+            .flat_map(|chunk| chunk.iter().rev().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(expected, data);
+        assert!(par_chunks(&[0u8; 0], 3, 2, |chunk| chunk.len()).is_empty());
+        assert!(std::panic::catch_unwind(|| par_chunks(&[1, 2], 0, 2, |_| ())).is_err());
+    }
+}
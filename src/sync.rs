@@ -0,0 +1,263 @@
+//! Locking layer shared by the primitives of this crate.
+//!
+//! The primitives are written against the types and helpers of this module,
+//! atomics and internal reference counting included, so that the whole
+//! concurrency layer can be swapped in one place. The locks map either to the
+//! standard library or, with the `parking_lot` feature, to the `parking_lot`
+//! crate. Without the `std` feature they are spin locks taken in a critical
+//! section. Built with `--cfg loom`, the atomics, locks and reference counts
+//! are those of the `loom` crate, which model checks the tests under
+//! `tests/loom.rs`. The backend is chosen at compile time and the rest of
+//! the crate does not see the difference.
+//!
+//! The primitives never run user code while holding their internal locks, so
+//! the state behind those locks is consistent even if a thread panicked while
+//! holding one. The crate policy is therefore to recover poisoned locks
+//! rather than propagate the panic to every other user of the primitive.
+
+pub(crate) use imp::*;
+
+/// Defines a `const` function, except under loom, whose atomics and locks
+/// are built at run time
+macro_rules! const_fn {
+    ($(#[$attr:meta])* $vis:vis fn $($rest:tt)*) => {
+        #[cfg(not(loom))]
+        $(#[$attr])*
+        $vis const fn $($rest)*
+
+        #[cfg(loom)]
+        $(#[$attr])*
+        $vis fn $($rest)*
+    };
+}
+
+#[cfg(not(loom))]
+pub(crate) use alloc::sync::Arc;
+#[cfg(loom)]
+pub(crate) use loom::sync::Arc;
+
+/// Atomic types of the primitives
+pub(crate) mod atomic {
+    #[cfg(not(loom))]
+    pub(crate) use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    #[cfg(loom)]
+    pub(crate) use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    /// Orders a `SeqCst` store before a later `SeqCst` load, as the accesses
+    /// do on their own, but under loom: it models them as `AcqRel`, and only
+    /// fences as sequentially consistent
+    #[inline]
+    pub(crate) fn store_load_fence() {
+        #[cfg(loom)]
+        loom::sync::atomic::fence(Ordering::SeqCst);
+    }
+}
+
+#[cfg(all(feature = "std", not(feature = "parking_lot"), not(loom)))]
+mod imp {
+    use std::sync::PoisonError;
+    use std::time::Duration;
+
+    pub(crate) use std::sync::{Condvar, Mutex, MutexGuard};
+
+    /// Locks `mutex`, recovering it if it was poisoned
+    pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits on `cv`, recovering the lock if it was poisoned
+    pub(crate) fn wait<'a, T>(cv: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        cv.wait(guard).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits on `cv` for at most `timeout`, recovering the lock if it was
+    /// poisoned
+    pub(crate) fn wait_timeout<'a, T>(
+        cv: &Condvar,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, T> {
+        cv.wait_timeout(guard, timeout)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+}
+
+#[cfg(all(feature = "parking_lot", not(loom)))]
+mod imp {
+    use std::time::Duration;
+
+    pub(crate) use parking_lot::{Condvar, Mutex, MutexGuard};
+
+    /// Locks `mutex`; `parking_lot` locks are never poisoned
+    pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock()
+    }
+
+    /// Waits on `cv`
+    pub(crate) fn wait<'a, T>(cv: &Condvar, mut guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        cv.wait(&mut guard);
+        guard
+    }
+
+    /// Waits on `cv` for at most `timeout`
+    pub(crate) fn wait_timeout<'a, T>(
+        cv: &Condvar,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, T> {
+        cv.wait_for(&mut guard, timeout);
+        guard
+    }
+}
+
+#[cfg(all(feature = "std", loom))]
+mod imp {
+    use std::sync::PoisonError;
+    use std::time::Duration;
+
+    pub(crate) use loom::sync::{Condvar, Mutex, MutexGuard};
+
+    /// Locks `mutex`, recovering it if it was poisoned
+    pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits on `cv`, recovering the lock if it was poisoned
+    pub(crate) fn wait<'a, T>(cv: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        cv.wait(guard).unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits on `cv` for a notification: loom does not model time, so the
+    /// wait never times out and the callers check their deadline before
+    pub(crate) fn wait_timeout<'a, T>(
+        cv: &Condvar,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> MutexGuard<'a, T> {
+        cv.wait_timeout(guard, timeout)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod imp {
+    use super::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::cell::UnsafeCell;
+    use core::hint;
+    use core::ops::{Deref, DerefMut};
+
+    use critical_section::RestoreState;
+
+    /// Clocks need the standard library, so no deadline can be given
+    #[derive(Clone, Copy)]
+    pub(crate) enum Instant {}
+
+    /// Spin lock held in a critical section
+    ///
+    /// On a single core, interrupts are masked while the lock is held, so an
+    /// interrupt handler never spins against the code it interrupted. The
+    /// crate never holds its locks across user code, and releases them in
+    /// the reverse order it took them, as critical sections require.
+    pub(crate) struct Mutex<T: ?Sized> {
+        locked: AtomicBool,
+        data: UnsafeCell<T>,
+    }
+
+    // SAFETY: the flag gives access to the data to a single guard at a time
+    unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+    unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+    impl<T> Mutex<T> {
+        pub(crate) const fn new(data: T) -> Self {
+            Self {
+                locked: AtomicBool::new(false),
+                data: UnsafeCell::new(data),
+            }
+        }
+    }
+
+    pub(crate) struct MutexGuard<'a, T: ?Sized> {
+        mutex: &'a Mutex<T>,
+        restore: RestoreState,
+    }
+
+    impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // SAFETY: the guard holds the lock
+            unsafe { &*self.mutex.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            // SAFETY: the guard holds the lock
+            unsafe { &mut *self.mutex.data.get() }
+        }
+    }
+
+    impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+        fn drop(&mut self) {
+            self.mutex.locked.store(false, Ordering::Release);
+            // SAFETY: restores the state saved when the guard was created
+            unsafe { critical_section::release(self.restore) };
+        }
+    }
+
+    /// Locks `mutex`, spinning outside of the critical section while it is
+    /// held elsewhere
+    pub(crate) fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        loop {
+            // SAFETY: released by the guard, or right below
+            let restore = unsafe { critical_section::acquire() };
+            if mutex
+                .locked
+                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                return MutexGuard { mutex, restore };
+            }
+            // SAFETY: restores the state saved just above
+            unsafe { critical_section::release(restore) };
+            while mutex.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    /// Condition variable waiters spin on
+    pub(crate) struct Condvar {
+        /// Bumped by every notification
+        epoch: AtomicUsize,
+    }
+
+    impl Condvar {
+        pub(crate) const fn new() -> Self {
+            Self {
+                epoch: AtomicUsize::new(0),
+            }
+        }
+
+        /// Wakes up the waiters; they all see the notification, which callers
+        /// treat as spurious wakeups
+        pub(crate) fn notify_one(&self) {
+            self.epoch.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Waits on `cv`, spinning with the lock released
+    pub(crate) fn wait<'a, T>(cv: &Condvar, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        // read under the lock, so that a notification sent after the caller
+        // checked its condition is not missed
+        let epoch = cv.epoch.load(Ordering::Acquire);
+        let mutex = guard.mutex;
+        drop(guard);
+        while cv.epoch.load(Ordering::Acquire) == epoch {
+            hint::spin_loop();
+        }
+        lock(mutex)
+    }
+}
@@ -0,0 +1,126 @@
+//! Spans around the tasks and items run by the crate, with the `tracing`
+//! feature. Without it, the types below hold nothing and the work runs as
+//! is.
+
+use crate::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(feature = "tracing")]
+mod imp {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use tracing::{field, Span};
+
+    /// Span work was submitted in
+    #[derive(Clone)]
+    pub(crate) struct Parent(Span);
+
+    /// Time work was queued at
+    pub(crate) struct Stamp(Instant);
+
+    impl Parent {
+        /// Span the calling thread is in
+        pub(crate) fn current() -> Self {
+            Self(Span::current())
+        }
+
+        /// Runs `f`, the work `id` of `kind` queued at `queued`, in a span
+        /// child of this one
+        pub(crate) fn run<R>(
+            &self,
+            kind: &'static str,
+            id: usize,
+            queued: &Stamp,
+            f: impl FnOnce() -> R,
+        ) -> R {
+            let start = Instant::now();
+            let span = self.span(kind, id, start.saturating_duration_since(queued.0));
+            let r = {
+                let _entered = span.enter();
+                f()
+            };
+            span.record("busy_us", start.elapsed().as_micros() as u64);
+            r
+        }
+
+        /// Span of the work `id` of `kind`, which waited `wait` to start.
+        ///
+        /// Kept out of [`run`](Self::run), whose frame stays on the stack
+        /// while the work runs, and may be nested deep when tasks run the
+        /// tasks they wait for.
+        #[inline(never)]
+        fn span(&self, kind: &'static str, id: usize, wait: Duration) -> Span {
+            tracing::info_span!(
+                parent: &self.0,
+                "esync",
+                kind,
+                id = id as u64,
+                thread = ?thread::current().id(),
+                wait_us = wait.as_micros() as u64,
+                busy_us = field::Empty
+            )
+        }
+    }
+
+    impl Stamp {
+        pub(crate) fn now() -> Self {
+            Self(Instant::now())
+        }
+
+        pub(crate) fn at(at: Instant) -> Self {
+            Self(at)
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+mod imp {
+    use std::time::Instant;
+
+    /// Span work was submitted in, which is not traced
+    #[derive(Clone)]
+    pub(crate) struct Parent;
+
+    /// Time work was queued at, which is not traced
+    pub(crate) struct Stamp;
+
+    impl Parent {
+        #[inline]
+        pub(crate) fn current() -> Self {
+            Self
+        }
+
+        #[inline]
+        pub(crate) fn run<R>(
+            &self,
+            _kind: &'static str,
+            _id: usize,
+            _queued: &Stamp,
+            f: impl FnOnce() -> R,
+        ) -> R {
+            f()
+        }
+    }
+
+    impl Stamp {
+        #[inline]
+        pub(crate) fn now() -> Self {
+            Self
+        }
+
+        #[inline]
+        pub(crate) fn at(_at: Instant) -> Self {
+            Self
+        }
+    }
+}
+
+pub(crate) use imp::*;
+
+/// Number of the next task
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of a new task, telling it apart in the traces and to its handle
+pub(crate) fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
@@ -1,49 +1,2592 @@
-use std::thread;
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle, ScopedJoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::Semaphore;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{self, Mutex};
+use crate::trace::{Parent, Stamp};
+use crate::{AcquireError, CancellationToken, Semaphore};
+
+#[cfg(feature = "async")]
+mod future;
+#[cfg(feature = "async")]
+pub use future::{process_async, process_stream_async, ProcessAsync, ProcessStream};
+
+#[cfg(feature = "rayon")]
+mod rayon;
+#[cfg(feature = "rayon")]
+pub use self::rayon::process_on_rayon;
+
+/// Most results a run reserves ahead from the size hint of its input
+const RESERVED_RESULTS: usize = 1 << 16;
+
+/// Items waiting for a worker, tagged with their position in the input
+struct Queue<T> {
+    /// Items split between the workers, every worker taking those of a
+    /// single lane
+    lanes: Vec<Lane<T>>,
+    /// Room left for items in the lanes, closed once the processing is
+    /// aborted
+    room: Semaphore,
+    /// Token aborting the processing once cancelled
+    token: Option<CancellationToken>,
+    /// Time at which the processing is aborted
+    deadline: Option<Instant>,
+    /// Bound on the number of items processed at a time, below the number
+    /// of workers
+    limit: Option<ConcurrencyLimit>,
+    /// Bound on the rate at which the items start being processed
+    rate: Option<RateLimit>,
+    /// Position past the last item taken, and whether the processing is
+    /// aborted, after which only the items before it are processed
+    stop: Mutex<(usize, bool)>,
+    /// Span the processing runs in
+    parent: Parent,
+}
+
+/// Items of a [`Queue`] for some of the workers, with the time they were
+/// queued at. `None` tells a worker to stop.
+struct Lane<T> {
+    items: Mutex<VecDeque<Option<(usize, T, Stamp)>>>,
+    /// Number of entries in `items`
+    ready: Semaphore,
+}
+
+/// Resource taken from a semaphore, given back when dropped, even by a
+/// panicking predicate
+struct Permit<'a>(&'a Semaphore);
+
+/// Aborts the processing if the worker holding it panics
+struct PanicGuard<'a>(&'a Semaphore);
+
+/// Results of [`process_iter`], received from the workers
+struct Results<R, H = JoinHandle<()>> {
+    rx: Receiver<R>,
+    /// Workers and feeder, joined once all the results are in
+    threads: Vec<H>,
+}
+
+/// Thread joined by [`Results`], scoped or not
+trait Join {
+    fn join(self) -> thread::Result<()>;
+}
+
+/// Batches of consecutive items of an input
+struct Chunks<I> {
+    it: I,
+    size: usize,
+}
+
+/// Worker of [`process_with_timeout`]
+struct Worker<T> {
+    id: usize,
+    items: Sender<(usize, T)>,
+    /// Position of the item being processed, and when it times out
+    busy: Option<(usize, Instant)>,
+}
+
+/// Weight taken from a semaphore, given back when dropped, even by a
+/// panicking predicate
+struct WeightedPermit<'a> {
+    sem: &'a Semaphore,
+    weight: usize,
+}
+
+/// Panic of the predicate on an item, as reported by [`process_catching`]
+pub struct TaskPanic {
+    index: usize,
+    payload: Box<dyn Any + Send>,
+}
+
+impl TaskPanic {
+    /// Position in the input of the item the predicate panicked on, as
+    /// [`process_with_index`] gives it to the predicate, and as its span
+    /// records it with the `tracing` feature
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The panic message, if the panic was raised with one, as `panic!` does
+    pub fn message(&self) -> Option<&str> {
+        match self.payload.downcast_ref::<&str>() {
+            Some(s) => Some(s),
+            None => self.payload.downcast_ref::<String>().map(String::as_str),
+        }
+    }
+
+    /// The payload of the panic, e.g. to resume it with
+    /// [`std::panic::resume_unwind`]
+    pub fn into_payload(self) -> Box<dyn Any + Send> {
+        self.payload
+    }
+}
+
+impl fmt::Debug for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TaskPanic")
+            .field("index", &self.index)
+            .field("message", &self.message())
+            .finish()
+    }
+}
+
+impl fmt::Display for TaskPanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.message() {
+            Some(message) => write!(f, "task {} panicked: {}", self.index, message),
+            None => write!(f, "task {} panicked", self.index),
+        }
+    }
+}
+
+impl std::error::Error for TaskPanic {}
+
+/// Retries of a failing predicate, for [`try_process_with_retry`]
+///
+/// An item is attempted up to `max_attempts` times, waiting `backoff`
+/// before the second attempt, and twice as long as the previous time before
+/// every later one.
+///
+/// ```
+/// # use esync::worker_threads::RetryPolicy;
+/// # use std::time::Duration;
+/// let policy = RetryPolicy::new(3, Duration::from_millis(10));
+/// assert_eq!(3, policy.max_attempts);
+/// assert_eq!(1, RetryPolicy::default().max_attempts);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts made on an item, the first one included, before its error
+    /// is reported
+    pub max_attempts: u32,
+    /// Wait before the first retry
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// No retry: the first error of an item is reported
+    pub const NONE: Self = Self::new(1, Duration::ZERO);
+
+    /// Attempts an item up to `max_attempts` times, starting with a
+    /// `backoff` wait between attempts.
+    pub const fn new(max_attempts: u32, backoff: Duration) -> Self {
+        Self {
+            max_attempts,
+            backoff,
+        }
+    }
+
+    /// Calls `f` until it succeeds or the attempts run out, returning its
+    /// last result
+    fn run<R, E>(&self, mut f: impl FnMut() -> Result<R, E>) -> Result<R, E> {
+        let mut backoff = self.backoff;
+        let mut attempts = 1;
+        loop {
+            match f() {
+                Err(_) if attempts < self.max_attempts => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempts += 1;
+                }
+                r => return r,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Result of an item on which the predicate took too long, as reported by
+/// [`process_with_timeout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task timed out")
+    }
+}
+
+impl std::error::Error for Timeout {}
+
+/// Channel end the results of [`process_to`] are sent to
+///
+/// Implemented for the senders of `std::sync::mpsc`; every worker sends
+/// through a clone of its own.
+pub trait ResultSender<R>: Clone + Send {
+    /// Sends a result, returning `false` if nobody receives them anymore.
+    fn send_result(&self, result: R) -> bool;
+}
+
+impl<R: Send> ResultSender<R> for Sender<R> {
+    fn send_result(&self, result: R) -> bool {
+        self.send(result).is_ok()
+    }
+}
+
+impl<R: Send> ResultSender<R> for SyncSender<R> {
+    fn send_result(&self, result: R) -> bool {
+        self.send(result).is_ok()
+    }
+}
+
+/// What a [`ProcessBuilder`] run does when the predicate panics on an item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PanicPolicy {
+    /// Stop taking items from the input, and resume the panic in the caller
+    /// once the items in flight complete
+    #[default]
+    Stop,
+    /// Process all the other items, then resume the panic of the first item
+    /// that raised one, in input order
+    Finish,
+}
+
+/// Configuration of a parallel processing, run with
+/// [`run`](ProcessBuilder::run)
+///
+/// [`process`] is a run with the default configuration but the number of
+/// workers.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::ProcessBuilder;
+/// let squares = ProcessBuilder::new()
+///     .workers(4)
+///     .chunk_size(16)
+///     .thread_name("squares")
+///     .run(0..100, |x| x * x);
+/// assert_eq!(81, squares[9]);
+/// ```
+#[derive(Clone)]
+pub struct ProcessBuilder {
+    workers: usize,
+    ordered: bool,
+    chunk_size: usize,
+    threads: ThreadConfig,
+    panic_policy: PanicPolicy,
+    token: Option<CancellationToken>,
+    deadline: Option<Instant>,
+    progress: Option<ProgressHook>,
+    limit: Option<ConcurrencyLimit>,
+    rate: Option<RateLimit>,
+}
+
+/// Bound on the number of items a [`ProcessBuilder`] run processes at a time,
+/// which may be changed while the run goes on
+///
+/// Clones share the same bound. The run still spawns its number of workers,
+/// and the bound only matters below it: lowering it lets the workers finish
+/// their current item before they wait, raising it lets them go on right
+/// away.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::{ConcurrencyLimit, ProcessBuilder};
+/// let limit = ConcurrencyLimit::new(2);
+/// let r = ProcessBuilder::new()
+///     .workers(8)
+///     .limit(limit.clone())
+///     .run(0..100, |i| {
+///         if i == 50 {
+///             // e.g. on a signal to shed load
+///             limit.set(1);
+///         }
+///         i
+///     });
+/// assert_eq!(100, r.len());
+/// assert_eq!(1, limit.get());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimit {
+    sem: Arc<Semaphore>,
+}
+
+/// Bound on the rate at which a [`ProcessBuilder`] run starts processing
+/// items, whatever its number of workers
+///
+/// Items start at regular intervals, `items` every `per`, with bursts of up
+/// to [`with_burst`](Self::with_burst) items when the run was slower for a
+/// while. The workers wait for their turn before calling the predicate.
+/// Clones share the same bound, so that several runs, e.g. calling the same
+/// service, share its rate.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::{ProcessBuilder, RateLimit};
+/// # use std::time::{Duration, Instant};
+/// let start = Instant::now();
+/// let r = ProcessBuilder::new()
+///     .workers(8)
+///     .rate_limit(RateLimit::new(100, Duration::from_secs(1)))
+///     .run(0..11, |i| i);
+/// // the last item started 10 intervals of 10ms after the first one
+/// assert!(start.elapsed() >= Duration::from_millis(100));
+/// assert_eq!(11, r.len());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    rate: Arc<Rate>,
+}
+
+/// State of a [`RateLimit`], a token bucket in the form of the time at
+/// which the next item may start if the bucket is empty
+#[derive(Debug)]
+struct Rate {
+    interval: Duration,
+    /// How early an item may start, to catch up with a slower past
+    burst: Duration,
+    next: Mutex<Option<Instant>>,
+    /// Never released, waited on until the turn of an item comes or the
+    /// processing is aborted
+    sleep: Semaphore,
+}
+
+/// Cores the worker threads of a run or a pool are pinned to
+///
+/// Cores are numbered as the system does, from zero. Pinning is done on
+/// Linux with `sched_setaffinity(2)` and on Windows with an affinity mask,
+/// which only reaches the cores of the processor group of the process.
+/// It is best effort: a worker that cannot be pinned, e.g. to a core that
+/// does not exist or on another system, runs unpinned.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::{Affinity, ProcessBuilder};
+/// let squares = ProcessBuilder::new()
+///     .workers(2)
+///     .affinity(Affinity::PerWorker(vec![0, 1]))
+///     .run(0..100, |x| x * x);
+/// assert_eq!(81, squares[9]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Affinity {
+    /// Pins the worker `w` to the core `cores[w % cores.len()]` alone
+    PerWorker(Vec<usize>),
+    /// Lets every worker run on any of the cores, and on them only
+    Shared(Vec<usize>),
+    /// Lets the worker `w` run on any core of the node
+    /// `nodes[w % nodes.len()]`, a node being a set of cores, e.g. those of
+    /// a [`NumaNode`]
+    PerNode(Vec<Vec<usize>>),
+}
+
+/// NUMA node of the system, as listed by [`numa_nodes`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumaNode {
+    /// Number of the node on the system
+    pub id: usize,
+    /// Cores of the node
+    pub cores: Vec<usize>,
+}
+
+/// Names, stack size and affinity of the threads spawned by a run or a pool
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ThreadConfig {
+    /// Prefix of the thread names, followed by the number of the worker
+    pub(crate) name: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) affinity: Option<Affinity>,
+}
+
+/// Callback told about the progress of a [`ProcessBuilder`] run
+type ProgressHook = Arc<dyn Fn(ProgressEvent) + Send + Sync>;
+
+/// Step of the processing of an item, reported to the callback set with
+/// [`ProcessBuilder::progress`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressStep {
+    /// The predicate is about to be called on the item
+    Started,
+    /// The predicate returned for the item
+    Completed,
+}
+
+/// Progress of a [`ProcessBuilder`] run, as the processing of an item starts
+/// or completes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// What happened to the item
+    pub step: ProgressStep,
+    /// Position of the item in the input
+    pub index: usize,
+    /// Number of items started so far, this one included if it started
+    pub started: usize,
+    /// Number of items completed so far, this one included if it completed
+    pub completed: usize,
+    /// Number of items of the input, if it tells it exactly
+    pub total: Option<usize>,
+}
+
+/// Counters of a run reporting its progress
+struct Progress<'a> {
+    hook: Option<&'a ProgressHook>,
+    started: AtomicUsize,
+    completed: AtomicUsize,
+    total: Option<usize>,
+}
+
+/// Results of a processing that may have stopped early, returned by
+/// [`process_until`] and [`ProcessBuilder::run_partial`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partial<R> {
+    /// Results of the items processed, which come first in the input
+    pub results: Vec<R>,
+    /// Number of items of the input left unprocessed
+    pub unprocessed: usize,
+}
+
+impl<T> Queue<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            lanes: vec![Lane::new(capacity)],
+            room: Semaphore::new(capacity),
+            token: None,
+            deadline: None,
+            limit: None,
+            rate: None,
+            stop: Mutex::new((0, false)),
+            parent: Parent::current(),
+        }
+    }
+
+    /// Splits the items in `lanes` lanes, instead of a single one
+    fn lanes(mut self, lanes: usize) -> Self {
+        let capacity = self.room.get_current_value();
+        self.lanes = (0..lanes).map(|_| Lane::new(capacity)).collect();
+        self
+    }
+
+    /// Processes at most as many items at a time as `limit` allows
+    fn limit(mut self, limit: Option<ConcurrencyLimit>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Starts processing items no faster than `rate` allows
+    fn rate(mut self, rate: Option<RateLimit>) -> Self {
+        self.rate = rate;
+        self
+    }
+
+    /// Aborts the processing once `token` is cancelled, or `deadline`
+    /// passed
+    fn abort_on(mut self, token: Option<CancellationToken>, deadline: Option<Instant>) -> Self {
+        self.token = token;
+        self.deadline = deadline;
+        self
+    }
+
+    /// Queues an item in `lane`, waiting for room. Returns `false` if no
+    /// worker may ever take it.
+    fn push(&self, lane: usize, i: usize, item: T) -> bool {
+        let room = self
+            .room
+            .acquire_blocking(1, 0, self.deadline, self.token.as_ref());
+        if room.is_err() {
+            // the queued items are skipped too
+            self.room.close();
+            return false;
+        }
+        self.lanes[lane].push(Some((i, item, Stamp::now())));
+        true
+    }
+
+    /// Processes items until told to stop, returning the results with the
+    /// position of their item. A result for which `failed` returns `true`
+    /// aborts the processing: no item is taken from the input anymore, and
+    /// the ones still queued are skipped.
+    fn work<R>(
+        &self,
+        predicate: &impl Fn(usize, T) -> R,
+        failed: &impl Fn(&R) -> bool,
+    ) -> Vec<(usize, R)> {
+        let mut done = vec![];
+        self.drain(|i, item| {
+            let r = predicate(i, item);
+            let ok = !failed(&r);
+            done.push((i, r));
+            ok
+        });
+        done
+    }
+
+    /// Processes items until told to stop, sending the results as they
+    /// complete. Nobody receiving them anymore aborts the processing.
+    fn stream<R>(&self, predicate: &impl Fn(T) -> R, tx: &impl ResultSender<R>) {
+        self.drain(|_, item| tx.send_result(predicate(item)));
+    }
+
+    /// Hands the items to `f` with their position, until told to stop. `f`
+    /// returning `false` aborts the processing.
+    fn drain(&self, f: impl FnMut(usize, T) -> bool) {
+        self.drain_lane(0, f);
+    }
+
+    /// Hands the items of `lane` to `f`, as [`drain`](Self::drain) does
+    fn drain_lane(&self, lane: usize, mut f: impl FnMut(usize, T) -> bool) {
+        let _guard = PanicGuard(&self.room);
+        let lane = &self.lanes[lane];
+        loop {
+            // the semaphore is private to the queue and never closed
+            lane.ready.wait().unwrap();
+            let entry = sync::lock(&lane.items).pop_front();
+            let (i, item, queued) = match entry.flatten() {
+                Some(entry) => entry,
+                None => return,
+            };
+            self.room.release();
+            // waited for before the item is taken, so that an abort meanwhile
+            // stops the processing ahead of it
+            let mut permit = self.limit.as_ref().and_then(|limit| {
+                let acquired = limit
+                    .sem
+                    .acquire_blocking(1, 0, self.deadline, self.token.as_ref());
+                acquired.ok().map(|()| Permit(&limit.sem))
+            });
+            if let Some(rate) = &self.rate {
+                // aborted while waiting, the item still goes on at once if
+                // it comes before the stop point
+                rate.wait(self.deadline, self.token.as_ref());
+            }
+            if !self.take(i) {
+                continue;
+            }
+            if let (None, Some(limit)) = (&permit, &self.limit) {
+                // aborted while waiting, but before the stop point: the item
+                // goes on once one of those in flight completes. The
+                // semaphore is private to the limit and never closed.
+                limit.sem.wait().unwrap();
+                permit = Some(Permit(&limit.sem));
+            }
+            let _permit = permit;
+            if !self.parent.run("item", i, &queued, || f(i, item)) {
+                self.room.close();
+            }
+        }
+    }
+
+    /// Returns `true` if the item at `i` is to be processed. Once the
+    /// processing is aborted, the items before the last one taken still are,
+    /// whatever lane they are queued in, so that the items processed are the
+    /// first ones of the input.
+    fn take(&self, i: usize) -> bool {
+        let mut stop = sync::lock(&self.stop);
+        let (next, stopped) = &mut *stop;
+        *stopped = *stopped || self.is_aborted();
+        if *stopped {
+            return i < *next;
+        }
+        *next = (*next).max(i + 1);
+        true
+    }
+
+    /// Returns `true` once the processing is aborted, and the queued items
+    /// past the stop point are to be skipped
+    fn is_aborted(&self) -> bool {
+        self.room.is_closed()
+            || self.token.as_ref().map_or(false, |t| t.is_cancelled())
+            || self.deadline.map_or(false, |d| Instant::now() >= d)
+    }
+
+    /// Queues the items of `it` until the processing is aborted, then tells
+    /// the `workers` to stop. Returns the number of items queued.
+    fn feed(&self, it: impl IntoIterator<Item = T>, workers: usize) -> usize {
+        self.feed_lanes(it, |_| 0, workers)
+    }
+
+    /// Queues every item of `it` in the lane `route` picks for it, as
+    /// [`feed`](Self::feed) does. The worker `w` takes the items of the
+    /// lane `w % lanes`.
+    fn feed_lanes(
+        &self,
+        it: impl IntoIterator<Item = T>,
+        route: impl Fn(&T) -> usize,
+        workers: usize,
+    ) -> usize {
+        let mut count = 0;
+        // the workers have to be stopped even if the input panics
+        let fed = panic::catch_unwind(AssertUnwindSafe(|| {
+            for s in it {
+                let lane = route(&s) % self.lanes.len();
+                if !self.push(lane, count, s) {
+                    // aborted, by a failure or a panic the caller reports
+                    break;
+                }
+                count += 1;
+            }
+        }));
+        for w in 0..workers {
+            // once the items ahead are taken
+            self.lanes[w % self.lanes.len()].push(None);
+        }
+        if let Err(e) = fed {
+            panic::resume_unwind(e);
+        }
+        count
+    }
+}
+
+/// Runs `predicate` on the items of `it` and their position, on `workers`
+/// threads, stopping early if `failed` returns `true` for a result. Returns
+/// the result of every item of the input that was processed, in input order,
+/// `None` for the others.
+fn dispatch<IT, P, F, R>(it: IT, predicate: P, failed: F, workers: usize) -> Vec<Option<R>>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(usize, IT::Item) -> R + Sync,
+    F: Fn(&R) -> bool + Sync,
+    R: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    thread::scope(|sc| {
+        let threads = (0..workers)
+            .map(|_| {
+                let (queue, predicate, failed) = (&queue, &predicate, &failed);
+                sc.spawn(move || queue.work(predicate, failed))
+            })
+            .collect::<Vec<_>>();
+        let count = queue.feed(it, workers);
+        place(threads, count)
+    })
+}
+
+impl ProcessBuilder {
+    /// Creates the default configuration: a worker per available CPU,
+    /// results in input order, items dispatched one at a time to unnamed
+    /// threads, and panics stopping the processing.
+    pub fn new() -> Self {
+        Self {
+            workers: 0,
+            ordered: true,
+            chunk_size: 1,
+            threads: ThreadConfig::default(),
+            panic_policy: PanicPolicy::Stop,
+            token: None,
+            deadline: None,
+            progress: None,
+            limit: None,
+            rate: None,
+        }
+    }
+
+    /// Sets the number of worker threads, zero meaning one per available
+    /// CPU, as for [`process`].
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Returns the results in input order if `ordered`, the default, or in
+    /// completion order otherwise, which spares reordering them.
+    pub fn ordered(mut self, ordered: bool) -> Self {
+        self.ordered = ordered;
+        self
+    }
+
+    /// Hands the items to the workers in batches of `chunk_size`
+    /// consecutive items, as [`process_chunks`] does, while still calling
+    /// the predicate once per item.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunks must hold at least one item");
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Names the worker threads `name-0`, `name-1`, and so on.
+    pub fn thread_name(mut self, name: impl Into<String>) -> Self {
+        self.threads.name = Some(name.into());
+        self
+    }
+
+    /// Sets the stack size of the worker threads, in bytes, instead of the
+    /// default of [`std::thread`].
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.threads.stack_size = Some(size);
+        self
+    }
+
+    /// Pins the worker threads to CPU cores, see [`Affinity`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `affinity` holds no core.
+    pub fn affinity(mut self, affinity: Affinity) -> Self {
+        affinity.check();
+        self.threads.affinity = Some(affinity);
+        self
+    }
+
+    /// Sets what happens when the predicate panics on an item.
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.panic_policy = panic_policy;
+        self
+    }
+
+    /// Stops the processing once `token` is cancelled: no item is taken from
+    /// the input anymore, the queued ones are skipped, and the run returns
+    /// once the items in flight complete. Their predicate may watch the
+    /// token to complete early.
+    ///
+    /// The results returned are then those of the items processed, which
+    /// come first in the input.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::CancellationToken;
+    /// # use esync::worker_threads::ProcessBuilder;
+    /// let token = CancellationToken::new();
+    /// let r = ProcessBuilder::new()
+    ///     .workers(2)
+    ///     .cancel_on(token.clone())
+    ///     .run(0.., |i| {
+    ///         if i == 100 {
+    ///             token.cancel();
+    ///         }
+    ///         i
+    ///     });
+    /// assert!(r.len() > 100);
+    /// assert_eq!(r, (0..r.len()).collect::<Vec<_>>());
+    /// ```
+    pub fn cancel_on(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// Stops the processing at `deadline`, as a cancellation would, see
+    /// [`cancel_on`](Self::cancel_on).
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Calls `progress` as the processing of every item starts and
+    /// completes, e.g. to render a progress bar.
+    ///
+    /// The callback runs on the workers, in the way of the items, and should
+    /// return quickly. Runs without a callback keep no count.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::worker_threads::{ProcessBuilder, ProgressStep};
+    /// let r = ProcessBuilder::new()
+    ///     .workers(2)
+    ///     .progress(|e| {
+    ///         if e.step == ProgressStep::Completed {
+    ///             println!("{}/{}", e.completed, e.total.unwrap());
+    ///         }
+    ///     })
+    ///     .run(vec![1, 2, 3], |x| x + 1);
+    /// assert_eq!(vec![2, 3, 4], r);
+    /// ```
+    pub fn progress<F>(mut self, progress: F) -> Self
+    where
+        F: Fn(ProgressEvent) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Processes at most as many items at a time as `limit` allows, see
+    /// [`ConcurrencyLimit`].
+    pub fn limit(mut self, limit: ConcurrencyLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Starts processing items no faster than `rate` allows, see
+    /// [`RateLimit`].
+    pub fn rate_limit(mut self, rate: RateLimit) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    /// Runs `predicate` on every item of `it`, returning the results.
+    pub fn run<IT, P, R>(&self, it: IT, predicate: P) -> Vec<R>
+    where
+        IT: IntoIterator,
+        IT::Item: Send,
+        P: Fn(IT::Item) -> R + Sync,
+        R: Send,
+    {
+        self.run_routed(it, false, |_| 0, predicate)
+    }
+
+    /// Runs `predicate` on every item of `it` on the workers of the node
+    /// `hint` returns for it, so that the items are processed close to the
+    /// memory holding their data, and returns the results.
+    ///
+    /// Nodes are those of an [`Affinity::PerNode`] set with
+    /// [`affinity`](Self::affinity), numbered from zero in their order. A
+    /// hint past the last node wraps around, and so does a hint past the
+    /// last worker when there are fewer workers than nodes. Without nodes,
+    /// the hint is ignored and the items go to any worker. The items of a
+    /// [chunk](Self::chunk_size) all go to the node of its first item.
+    ///
+    /// The workers of a node only take the items of their node, so that
+    /// uneven hints leave the workers of some nodes idle. A cancellation or the
+    /// deadline stops every node at the same point of the input all the
+    /// same, the results being those of its first items.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use esync::worker_threads::{Affinity, ProcessBuilder};
+    /// let rows: Vec<Vec<u64>> = (0..8).map(|i| vec![i; 1000]).collect();
+    /// let sums = ProcessBuilder::new()
+    ///     .affinity(Affinity::numa())
+    ///     // e.g. the node each row was allocated on
+    ///     .run_with_hint(&rows, |row| row[0] as usize % 2, |row| row.iter().sum::<u64>());
+    /// assert_eq!(7000, sums[7]);
+    /// ```
+    pub fn run_with_hint<IT, H, P, R>(&self, it: IT, hint: H, predicate: P) -> Vec<R>
+    where
+        IT: IntoIterator,
+        IT::Item: Send,
+        H: Fn(&IT::Item) -> usize,
+        P: Fn(IT::Item) -> R + Sync,
+        R: Send,
+    {
+        self.run_routed(it, true, hint, predicate)
+    }
+
+    /// Runs `predicate` on every item of `it`, on the workers of the node
+    /// `hint` returns for it if `routed`
+    fn run_routed<IT, H, P, R>(&self, it: IT, routed: bool, hint: H, predicate: P) -> Vec<R>
+    where
+        IT: IntoIterator,
+        IT::Item: Send,
+        H: Fn(&IT::Item) -> usize,
+        P: Fn(IT::Item) -> R + Sync,
+        R: Send,
+    {
+        let (workers, size) = (worker_count(self.workers), self.chunk_size);
+        let lanes = match &self.threads.affinity {
+            Some(Affinity::PerNode(nodes)) if routed => nodes.len().min(workers),
+            _ => 1,
+        };
+        let queue = Queue::new(workers)
+            .lanes(lanes)
+            .abort_on(self.token.clone(), self.deadline)
+            .limit(self.limit.clone())
+            .rate(self.rate.clone());
+        let it = it.into_iter();
+        let progress = Progress {
+            hook: self.progress.as_ref(),
+            started: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            total: match it.size_hint() {
+                (lower, Some(upper)) if lower == upper => Some(lower),
+                _ => None,
+            },
+        };
+        // the results are reserved for the items the input is sure to have,
+        // up to a bound, unless it may not end or the run may stop early
+        let stops = self.token.is_some() || self.deadline.is_some();
+        let lower = match it.size_hint() {
+            (lower, Some(_)) if !stops => lower.min(RESERVED_RESULTS),
+            _ => 0,
+        };
+        let chunks = Chunks { it, size };
+        let completed = Mutex::new(if self.ordered {
+            vec![]
+        } else {
+            Vec::with_capacity(lower)
+        });
+
+        let (done, panicked) = thread::scope(|sc| {
+            let (queue, predicate, completed) = (&queue, &predicate, &completed);
+            let progress = &progress;
+            let threads = (0..workers)
+                .map(|w| {
+                    self.spawn(sc, w, move || {
+                        let ordered = if self.ordered { lower } else { 0 };
+                        // results of the items, with their position in the input
+                        let mut done = Vec::with_capacity(ordered / workers.max(1));
+                        let mut panicked = None;
+                        queue.drain_lane(w % lanes, |i, chunk: Vec<IT::Item>| {
+                            let mut results = Vec::new();
+                            for (k, item) in chunk.into_iter().enumerate() {
+                                let index = i * size + k;
+                                progress.report(ProgressStep::Started, index);
+                                let r = match self.panic_policy {
+                                    PanicPolicy::Stop => predicate(item),
+                                    PanicPolicy::Finish => {
+                                        let r = panic::catch_unwind(AssertUnwindSafe(|| {
+                                            predicate(item)
+                                        }));
+                                        match r {
+                                            Ok(r) => r,
+                                            // the first panic of the worker
+                                            // is its first in input order
+                                            Err(e) => {
+                                                panicked = panicked.take().or(Some((i, e)));
+                                                continue;
+                                            }
+                                        }
+                                    }
+                                };
+                                if self.ordered {
+                                    done.push((index, r));
+                                } else {
+                                    results.push(r);
+                                }
+                                progress.report(ProgressStep::Completed, index);
+                            }
+                            if !self.ordered {
+                                sync::lock(completed).extend(results);
+                            }
+                            true
+                        });
+                        (done, panicked)
+                    })
+                })
+                .collect::<Vec<_>>();
+            queue.feed_lanes(chunks, |chunk| hint(&chunk[0]), workers);
+            // a slot per item, in input order, as the results are gathered;
+            // the ones of items skipped or failed stay empty
+            let mut slots = Vec::with_capacity(if self.ordered { lower } else { 0 });
+            let mut panicked = None;
+            for (d, p) in threads.into_iter().map(join) {
+                for (i, r) in d {
+                    if i >= slots.len() {
+                        slots.resize_with(i + 1, || None);
+                    }
+                    slots[i] = Some(r);
+                }
+                panicked = match (panicked, p) {
+                    (Some((i, e)), Some((j, _))) if i <= j => Some((i, e)),
+                    (first, None) => first,
+                    (_, p) => p,
+                };
+            }
+            (slots, panicked)
+        });
+
+        if let Some((_, e)) = panicked {
+            panic::resume_unwind(e);
+        }
+        if !self.ordered {
+            return mem::take(&mut *sync::lock(&completed));
+        }
+        let mut results = Vec::with_capacity(done.len());
+        results.extend(done.into_iter().flatten());
+        results
+    }
+
+    /// Runs `predicate` on the items of `it` until the processing is stopped,
+    /// by a cancellation or the deadline, returning the results and the
+    /// number of items left unprocessed.
+    pub fn run_partial<IT, P, R>(&self, it: IT, predicate: P) -> Partial<R>
+    where
+        IT: IntoIterator,
+        IT::IntoIter: ExactSizeIterator,
+        IT::Item: Send,
+        P: Fn(IT::Item) -> R + Sync,
+        R: Send,
+    {
+        let it = it.into_iter();
+        let len = it.len();
+        let results = self.run(it, predicate);
+        Partial {
+            unprocessed: len - results.len(),
+            results,
+        }
+    }
+
+    /// Spawns the worker `w` in the scope
+    fn spawn<'scope, T: Send + 'scope>(
+        &self,
+        sc: &'scope thread::Scope<'scope, '_>,
+        w: usize,
+        f: impl FnOnce() -> T + Send + 'scope,
+    ) -> ScopedJoinHandle<'scope, T> {
+        self.threads
+            .builder(w)
+            .spawn_scoped(sc, self.threads.pinned(w, f))
+            .expect("failed to spawn thread")
+    }
+}
+
+impl Default for ProcessBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ProcessBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProcessBuilder")
+            .field("workers", &self.workers)
+            .field("ordered", &self.ordered)
+            .field("chunk_size", &self.chunk_size)
+            .field("thread_name", &self.threads.name)
+            .field("stack_size", &self.threads.stack_size)
+            .field("affinity", &self.threads.affinity)
+            .field("panic_policy", &self.panic_policy)
+            .field("token", &self.token)
+            .field("deadline", &self.deadline)
+            .field("progress", &self.progress.is_some())
+            .field("limit", &self.limit.as_ref().map(ConcurrencyLimit::get))
+            .field("rate", &self.rate)
+            .finish()
+    }
+}
+
+impl ConcurrencyLimit {
+    /// Creates a bound of `limit` items at a time
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn new(limit: usize) -> Self {
+        assert!(limit > 0, "at least one item must be processed at a time");
+        Self {
+            sem: Arc::new(Semaphore::with_max(limit, limit)),
+        }
+    }
+
+    /// Changes the bound to `limit` items at a time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is zero.
+    pub fn set(&self, limit: usize) {
+        assert!(limit > 0, "at least one item must be processed at a time");
+        self.sem.set_max_value(limit);
+    }
+
+    /// Returns the current bound.
+    pub fn get(&self) -> usize {
+        self.sem.get_max_value()
+    }
+}
+
+impl<T> Lane<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+            ready: Semaphore::new(0),
+        }
+    }
+
+    fn push(&self, entry: Option<(usize, T, Stamp)>) {
+        sync::lock(&self.items).push_back(entry);
+        self.ready.release();
+    }
+}
+
+impl RateLimit {
+    /// Creates a bound of `items` items every `per`, without bursts.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `items` or `per` is zero.
+    pub fn new(items: u32, per: Duration) -> Self {
+        assert!(
+            items > 0 && per > Duration::ZERO,
+            "some items must be processed over some time"
+        );
+        Self {
+            rate: Arc::new(Rate {
+                interval: per / items,
+                burst: Duration::ZERO,
+                next: Mutex::new(None),
+                sleep: Semaphore::new(0),
+            }),
+        }
+    }
+
+    /// Lets up to `burst` items start at once after a slower period, the
+    /// default being 1: items then start at regular intervals.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `burst` is zero, or if the bound is shared already.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        assert!(burst > 0, "at least one item must start at a time");
+        let rate = Arc::get_mut(&mut self.rate).expect("the rate limit is shared already");
+        rate.burst = rate.interval.saturating_mul(burst - 1);
+        self
+    }
+
+    /// Waits for the turn of the next item. Returns `false` if the
+    /// processing is aborted by `deadline` or `token` meanwhile.
+    fn wait(&self, deadline: Option<Instant>, token: Option<&CancellationToken>) -> bool {
+        let rate = &*self.rate;
+        let now = Instant::now();
+        let at = {
+            let mut next = sync::lock(&rate.next);
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + rate.interval);
+            start.checked_sub(rate.burst).unwrap_or(now)
+        };
+        if at <= now {
+            return true;
+        }
+        let until = deadline.map_or(at, |deadline| deadline.min(at));
+        match rate.sleep.acquire_blocking(1, 0, Some(until), token) {
+            Err(AcquireError::TimedOut) => until == at,
+            _ => false,
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl ThreadConfig {
+    /// Builder of the thread of the worker `w`
+    pub(crate) fn builder(&self, w: usize) -> thread::Builder {
+        let mut builder = thread::Builder::new();
+        if let Some(name) = &self.name {
+            builder = builder.name(format!("{}-{}", name, w));
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder
+    }
+
+    /// Wraps the body `f` of the thread of the worker `w`, to pin the thread
+    /// first if so configured
+    pub(crate) fn pinned<T>(&self, w: usize, f: impl FnOnce() -> T) -> impl FnOnce() -> T {
+        let cores = self.affinity.as_ref().map(|affinity| affinity.cores(w));
+        move || {
+            if let Some(cores) = cores {
+                // best effort, the worker runs anyway
+                let _ = crate::affinity::pin_current(&cores);
+            }
+            f()
+        }
+    }
+}
+
+impl Affinity {
+    /// Spreads the workers across the NUMA nodes of the system, as listed
+    /// by [`numa_nodes`], the worker `w` running on the cores of the node
+    /// `w % nodes`.
+    pub fn numa() -> Self {
+        Self::PerNode(numa_nodes().into_iter().map(|node| node.cores).collect())
+    }
+
+    /// Panics if there is no core to pin to
+    pub(crate) fn check(&self) {
+        match self {
+            Self::PerWorker(cores) | Self::Shared(cores) => {
+                assert!(!cores.is_empty(), "workers must be pinned to some core")
+            }
+            Self::PerNode(nodes) => assert!(
+                !nodes.is_empty() && nodes.iter().all(|cores| !cores.is_empty()),
+                "workers must be pinned to some core"
+            ),
+        }
+    }
+
+    /// Cores the worker `w` is pinned to
+    fn cores(&self, w: usize) -> Vec<usize> {
+        match self {
+            Self::PerWorker(cores) => vec![cores[w % cores.len()]],
+            Self::Shared(cores) => cores.clone(),
+            Self::PerNode(nodes) => nodes[w % nodes.len()].clone(),
+        }
+    }
+}
+
+impl Progress<'_> {
+    /// Counts the step of an item and reports it, if anybody listens
+    fn report(&self, step: ProgressStep, index: usize) {
+        let hook = match self.hook {
+            Some(hook) => hook,
+            None => return,
+        };
+        let (started, completed) = match step {
+            ProgressStep::Started => (
+                self.started.fetch_add(1, Ordering::Relaxed) + 1,
+                self.completed.load(Ordering::Relaxed),
+            ),
+            ProgressStep::Completed => (
+                self.started.load(Ordering::Relaxed),
+                self.completed.fetch_add(1, Ordering::Relaxed) + 1,
+            ),
+        };
+        hook(ProgressEvent {
+            step,
+            index,
+            started,
+            completed,
+            total: self.total,
+        });
+    }
+}
+
+/// Lists the NUMA nodes of the system that have cores, by increasing number.
+///
+/// The nodes are read from sysfs on Linux. Elsewhere, or if that fails, the
+/// system is taken as a single node, numbered zero, with as many cores as
+/// there are CPUs available to the process.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::numa_nodes;
+/// let nodes = numa_nodes();
+/// assert!(!nodes.is_empty());
+/// assert!(nodes.iter().all(|node| !node.cores.is_empty()));
+/// ```
+pub fn numa_nodes() -> Vec<NumaNode> {
+    let nodes: Vec<_> = crate::affinity::numa_nodes()
+        .into_iter()
+        .map(|(id, cores)| NumaNode { id, cores })
+        .collect();
+    if !nodes.is_empty() {
+        return nodes;
+    }
+    vec![NumaNode {
+        id: 0,
+        cores: (0..worker_count(0)).collect(),
+    }]
+}
+
+/// Number of workers to spawn when asked for `workers`: zero means one per
+/// CPU available to the process, or a single one if that cannot be told.
+pub(crate) fn worker_count(workers: usize) -> usize {
+    if workers > 0 {
+        return workers;
+    }
+    thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Joins a scoped thread, resuming its panic with its own payload
+fn join<T>(t: ScopedJoinHandle<'_, T>) -> T {
+    t.join().unwrap_or_else(|e| panic::resume_unwind(e))
+}
+
+/// Joins the `threads`, placing the results they return with their
+/// position in the input in a slot per item of the input, `count` of them
+fn place<R>(threads: Vec<ScopedJoinHandle<'_, Vec<(usize, R)>>>, count: usize) -> Vec<Option<R>> {
+    let mut retval = Vec::with_capacity(count);
+    retval.resize_with(count, || None);
+    for t in threads {
+        for (i, r) in join(t) {
+            retval[i] = Some(r);
+        }
+    }
+    retval
+}
+
+impl<I: Iterator> Iterator for Chunks<I> {
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let chunk = self.it.by_ref().take(self.size).collect::<Vec<_>>();
+        if chunk.is_empty() {
+            None
+        } else {
+            Some(chunk)
+        }
+    }
+}
+
+impl<R, H: Join> Iterator for Results<R, H> {
+    type Item = R;
+
+    fn next(&mut self) -> Option<R> {
+        match self.rx.recv() {
+            Ok(r) => Some(r),
+            Err(_) => {
+                // every worker is gone: the input is exhausted, or one of
+                // them panicked
+                for t in self.threads.drain(..) {
+                    if let Err(e) = Join::join(t) {
+                        panic::resume_unwind(e);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Join for JoinHandle<()> {
+    fn join(self) -> thread::Result<()> {
+        JoinHandle::join(self)
+    }
+}
+
+impl Join for ScopedJoinHandle<'_, ()> {
+    fn join(self) -> thread::Result<()> {
+        ScopedJoinHandle::join(self)
+    }
+}
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.0.close();
+        }
+    }
+}
+
+impl Drop for WeightedPermit<'_> {
+    fn drop(&mut self) {
+        self.sem.release_weighted(self.weight);
+    }
+}
 
 /// Process some iterable workload on a given number of threads
 ///
+/// Exactly `workers` threads are spawned, and they take the items from an
+/// internal queue as they become idle. The queue holds at most `workers`
+/// items, so the input is consumed as fast as it is processed, not ahead.
+///
+/// Results are returned in the order of the input: `result[i]` is the
+/// outcome of the `i`-th item, whichever order the items complete in.
+///
+/// Passing zero `workers` spawns one per CPU available to the process, as
+/// reported by [`std::thread::available_parallelism`], which accounts for
+/// the CPUs the process is restricted to. This holds for every function of
+/// this module.
+///
+/// This is a shorthand for a [`ProcessBuilder`] run with `workers` threads;
+/// the builder configures the rest.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process;
+/// let vec = vec![1, 2, 3, 4, 5];
+/// let result = process(&vec, |x| x * x, 2);
+/// assert_eq!(vec![1, 4, 9, 16, 25], result);
+///
+/// // as many workers as CPUs
+/// assert_eq!(result, process(1..=5, |x| x * x, 0));
+///
+/// // results may borrow from the items, or from the caller
+/// let text = String::from("alpha beta gamma");
+/// assert_eq!(vec!["al", "be", "ga"], process(text.split(' '), |w| &w[..2], 2));
+/// ```
+pub fn process<IT, P, R>(it: IT, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    ProcessBuilder::new().workers(workers).run(it, predicate)
+}
+
+/// Process some workload of known length on a given number of threads,
+/// writing the results into `out`
+///
+/// Items are dispatched as with [`process`], and the result of the `i`-th
+/// item is written straight into `out[i]`, replacing what was there, so that
+/// nothing is collected on the way and a buffer can be reused from one call
+/// to the next.
+///
+/// # Panics
+///
+/// Panics if `out` is not as long as the input.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_into;
+/// let mut out = vec![0; 5];
+/// process_into(1..6, |x| x * x, 2, &mut out);
+/// assert_eq!(vec![1, 4, 9, 16, 25], out);
+/// ```
+pub fn process_into<IT, P, R>(it: IT, predicate: P, workers: usize, out: &mut [R])
+where
+    IT: IntoIterator,
+    IT::IntoIter: ExactSizeIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    let it = it.into_iter();
+    assert_eq!(
+        it.len(),
+        out.len(),
+        "the output is not as long as the input"
+    );
+    for_each(it.zip(out), |(item, slot)| *slot = predicate(item), workers);
+}
+
+/// Run a closure on every item of an iterable workload, on a given number of
+/// threads, for its side effects
+///
+/// Items are dispatched as with [`process`], but nothing is collected.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::for_each;
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// let total = AtomicUsize::new(0);
+/// for_each(1..=10, |x| { total.fetch_add(x, Ordering::Relaxed); }, 4);
+/// assert_eq!(55, total.into_inner());
+/// ```
+pub fn for_each<IT, F>(it: IT, f: F, workers: usize)
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    F: Fn(IT::Item) + Sync,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    thread::scope(|sc| {
+        let (queue, f) = (&queue, &f);
+        let threads = (0..workers)
+            .map(|_| {
+                sc.spawn(move || {
+                    queue.drain(|_, item| {
+                        f(item);
+                        true
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        queue.feed(it, workers);
+        threads.into_iter().for_each(join);
+    });
+}
+
+/// Process some iterable workload on a given number of threads, keeping
+/// only the results the predicate returns `Some` for
+///
+/// The results kept are returned in the order of the input, as with
+/// [`process`], and no room is taken by the items filtered out.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::filter_map;
+/// let numbers = filter_map(["1", "x", "3"].iter(), |s| s.parse::<i32>().ok(), 2);
+/// assert_eq!(vec![1, 3], numbers);
+/// ```
+pub fn filter_map<IT, P, R>(it: IT, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> Option<R> + Sync,
+    R: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    let mut kept = thread::scope(|sc| {
+        let (queue, predicate) = (&queue, &predicate);
+        let threads = (0..workers)
+            .map(|_| {
+                sc.spawn(move || {
+                    let mut kept = vec![];
+                    queue.drain(|i, item| {
+                        if let Some(r) = predicate(item) {
+                            kept.push((i, r));
+                        }
+                        true
+                    });
+                    kept
+                })
+            })
+            .collect::<Vec<_>>();
+        queue.feed(it, workers);
+        threads.into_iter().flat_map(join).collect::<Vec<_>>()
+    });
+    kept.sort_unstable_by_key(|(i, _)| *i);
+    kept.into_iter().map(|(_, r)| r).collect()
+}
+
+/// Map the items of an iterable workload and fold the results, on a given
+/// number of threads
+///
+/// Every worker folds the results of the items it processes into a partial
+/// result of its own, starting from `identity()`, and the partial results
+/// are folded together once the input is exhausted. Nothing is collected on
+/// the way. Which items end up in which partial result is not specified, so
+/// `fold` should be associative and commutative, and `identity()` neutral
+/// for it.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::reduce;
+/// let sum_of_squares = reduce(1..=10u64, |x| x * x, |a, b| a + b, || 0, 4);
+/// assert_eq!(385, sum_of_squares);
+/// ```
+pub fn reduce<IT, M, F, I, R>(it: IT, map: M, fold: F, identity: I, workers: usize) -> R
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    M: Fn(IT::Item) -> R + Sync,
+    F: Fn(R, R) -> R + Sync,
+    I: Fn() -> R + Sync,
+    R: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    thread::scope(|sc| {
+        let (queue, map, fold, identity) = (&queue, &map, &fold, &identity);
+        let threads = (0..workers)
+            .map(|_| {
+                sc.spawn(move || {
+                    let mut acc = Some(identity());
+                    queue.drain(|_, item| {
+                        let r = map(item);
+                        acc = acc.take().map(|acc| fold(acc, r));
+                        true
+                    });
+                    acc.expect("the accumulator is put back after every item")
+                })
+            })
+            .collect::<Vec<_>>();
+        queue.feed(it, workers);
+        threads.into_iter().map(join).fold(identity(), fold)
+    })
+}
+
+/// Aggregate an iterable workload on a given number of threads, in
+/// accumulators private to every worker
+///
+/// Every worker creates an accumulator with `init`, and `map` adds each item
+/// it processes to it, with no synchronization. Once the input is exhausted,
+/// the accumulators are merged with `merge`, in an unspecified order. This is
+/// [`reduce`] for aggregates built in place rather than folded by value.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::map_reduce;
+/// # use std::collections::HashMap;
+/// let words = "a b a c b a".split(' ');
+/// let counts = map_reduce(
+///     words,
+///     HashMap::new,
+///     |counts, w| *counts.entry(w).or_insert(0) += 1,
+///     |mut a, b| {
+///         for (w, n) in b {
+///             *a.entry(w).or_insert(0) += n;
+///         }
+///         a
+///     },
+///     2,
+/// );
+/// assert_eq!(3, counts["a"]);
+/// ```
+pub fn map_reduce<IT, I, M, G, A>(it: IT, init: I, map: M, merge: G, workers: usize) -> A
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    I: Fn() -> A + Sync,
+    M: Fn(&mut A, IT::Item) + Sync,
+    G: Fn(A, A) -> A,
+    A: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    let accumulators = thread::scope(|sc| {
+        let (queue, init, map) = (&queue, &init, &map);
+        let threads = (0..workers)
+            .map(|_| {
+                sc.spawn(move || {
+                    let mut acc = init();
+                    queue.drain(|_, item| {
+                        map(&mut acc, item);
+                        true
+                    });
+                    acc
+                })
+            })
+            .collect::<Vec<_>>();
+        queue.feed(it, workers);
+        threads.into_iter().map(join).collect::<Vec<_>>()
+    });
+    accumulators.into_iter().fold(init(), merge)
+}
+
+/// Process some iterable workload on a given number of threads, in batches
+/// of `chunk_size` items
+///
+/// The predicate is called once per batch of consecutive items, rather than
+/// once per item, which amortizes the cost of dispatching an item to a
+/// worker when the work done on every item is tiny. The last batch may be
+/// shorter. Results are returned per batch, in the order of the input.
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is zero.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_chunks;
+/// let sums = process_chunks(1..=10, 4, |chunk| chunk.iter().sum::<i32>(), 2);
+/// assert_eq!(vec![10, 26, 19], sums);
+/// ```
+pub fn process_chunks<IT, P, R>(it: IT, chunk_size: usize, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(Vec<IT::Item>) -> R + Sync,
+    R: Send,
+{
+    assert!(chunk_size > 0, "chunks must hold at least one item");
+    let chunks = Chunks {
+        it: it.into_iter(),
+        size: chunk_size,
+    };
+    dispatch(chunks, |_, chunk| predicate(chunk), |_| false, workers)
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Process some iterable workload on a given number of threads, giving the
+/// predicate the position of every item in the input
+///
+/// This is [`process`] with a predicate called as `predicate(i, item)` for
+/// the `i`-th item, without enumerating the input by hand.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_with_index;
+/// let result = process_with_index(["a", "b"].iter(), |i, s| format!("{}{}", s, i), 2);
+/// assert_eq!(vec!["a0", "b1"], result);
+/// ```
+pub fn process_with_index<IT, P, R>(it: IT, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(usize, IT::Item) -> R + Sync,
+    R: Send,
+{
+    dispatch(it, predicate, |_| false, workers)
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Process some iterable workload on a given number of threads, with state
+/// private to every worker
+///
+/// Every worker creates its state once, with `init`, and hands it to the
+/// predicate for each item it processes: a connection, a scratch buffer or a
+/// random number generator is then set up once per worker rather than once
+/// per item, and used without synchronization. The state is dropped when the
+/// worker stops, once the input is exhausted. Results are returned in the
+/// order of the input, as with [`process`].
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_with_state;
+/// let r = process_with_state(
+///     ["a", "bb", "ccc"],
+///     String::new,
+///     |buf, s| {
+///         // the buffer is reused from one item to the next
+///         buf.clear();
+///         buf.push_str(s);
+///         buf.push('!');
+///         buf.len()
+///     },
+///     2,
+/// );
+/// assert_eq!(vec![2, 3, 4], r);
+/// ```
+pub fn process_with_state<IT, I, S, P, R>(it: IT, init: I, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    I: Fn() -> S + Sync,
+    P: Fn(&mut S, IT::Item) -> R + Sync,
+    R: Send,
+{
+    let (init, predicate) = (&init, &predicate);
+    // the state is made on the worker, so that it need not be `Send`
+    let worker = || {
+        move || {
+            let mut state = init();
+            move |item| predicate(&mut state, item)
+        }
+    };
+    process_per_worker(it, worker, workers)
+}
+
+/// Process some iterable workload on a given number of threads, with a
+/// predicate that mutates what it captures
+///
+/// Every worker gets its own clone of `predicate`, and calls it as
+/// [`FnMut`] for each item it processes: counters, random number generators
+/// or scratch buffers are captured by the closure rather than shared behind
+/// a lock. Which items go to which clone is up to the scheduling. Results
+/// are returned in the order of the input, as with [`process`];
+/// [`process_with_state`] is the one to use for state that is not [`Clone`]
+/// or [`Send`].
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_mut;
+/// let mut scratch = Vec::new();
+/// let lengths = process_mut(
+///     ["a", "bb", "ccc"],
+///     move |s: &str| {
+///         // every worker reuses its own buffer
+///         scratch.clear();
+///         scratch.extend(s.bytes());
+///         scratch.len()
+///     },
+///     2,
+/// );
+/// assert_eq!(vec![1, 2, 3], lengths);
+/// ```
+pub fn process_mut<IT, P, R>(it: IT, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: FnMut(IT::Item) -> R + Clone + Send,
+    R: Send,
+{
+    let worker = || {
+        let predicate = predicate.clone();
+        move || predicate
+    };
+    process_per_worker(it, worker, workers)
+}
+
+/// Processes the items of `it` on `workers` threads, and returns the
+/// results in input order. `worker` is called on the caller's thread once
+/// for each worker, and what it returns is called on that worker to make
+/// the closure it calls the items with
+fn process_per_worker<IT, W, M, F, R>(it: IT, mut worker: W, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    W: FnMut() -> M,
+    M: FnOnce() -> F + Send,
+    F: FnMut(IT::Item) -> R,
+    R: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    let retval = thread::scope(|sc| {
+        let queue = &queue;
+        let threads = (0..workers)
+            .map(|_| {
+                let make = worker();
+                sc.spawn(move || {
+                    let mut f = make();
+                    let mut done = vec![];
+                    queue.drain(|i, item| {
+                        done.push((i, f(item)));
+                        true
+                    });
+                    done
+                })
+            })
+            .collect::<Vec<_>>();
+        let count = queue.feed(it, workers);
+        place(threads, count)
+    });
+
+    retval.into_iter().map(Option::unwrap).collect()
+}
+
+/// Process some iterable workload on a given number of threads, the items
+/// with the same key one after the other
+///
+/// The items for which `key` returns the same key are processed in input
+/// order, each one once the previous one completed, e.g. the events of a
+/// user; items with different keys are processed in parallel. Results are
+/// returned in the order of the input, as with [`process`].
+///
+/// Every item goes to the worker its key is assigned to, so that a key
+/// holding most of the items keeps a single worker busy with them while the
+/// others idle.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_keyed;
+/// # use std::collections::HashMap;
+/// # use std::sync::Mutex;
+/// let balances = Mutex::new(HashMap::new());
+/// let events = [("alice", 10), ("bob", 5), ("alice", -3), ("bob", -5)];
+/// let after = process_keyed(
+///     events,
+///     |(user, _)| *user,
+///     |(user, amount)| {
+///         let mut balances = balances.lock().unwrap();
+///         let balance = balances.entry(user).or_insert(0);
+///         *balance += amount;
+///         *balance
+///     },
+///     4,
+/// );
+/// assert_eq!(vec![10, 5, 7, 0], after);
+/// ```
+pub fn process_keyed<IT, K, Q, P, R>(it: IT, key: K, predicate: P, workers: usize) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    K: Fn(&IT::Item) -> Q,
+    Q: Hash,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers).lanes(workers);
+
+    let retval = thread::scope(|sc| {
+        let (queue, predicate) = (&queue, &predicate);
+        let threads = (0..workers)
+            .map(|w| {
+                sc.spawn(move || {
+                    let mut done = vec![];
+                    queue.drain_lane(w, |i, item| {
+                        done.push((i, predicate(item)));
+                        true
+                    });
+                    done
+                })
+            })
+            .collect::<Vec<_>>();
+        let route = |item: &IT::Item| {
+            let mut hasher = DefaultHasher::new();
+            key(item).hash(&mut hasher);
+            hasher.finish() as usize
+        };
+        let count = queue.feed_lanes(it, route, workers);
+        place(threads, count)
+    });
+
+    retval.into_iter().map(Option::unwrap).collect()
+}
+
+/// Process some iterable workload on a given number of threads, until
+/// `deadline`
+///
+/// Items are processed as with [`process`], until the deadline passes: no
+/// item is taken from the input anymore, the queued ones are skipped, and the
+/// call returns once the items in flight complete, with their results and
+/// the number of items left unprocessed.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_until;
+/// # use std::time::{Duration, Instant};
+/// let deadline = Instant::now() + Duration::from_secs(60);
+/// let r = process_until(vec![1, 2, 3], |x| x * 2, deadline, 2);
+/// assert_eq!(vec![2, 4, 6], r.results);
+/// assert_eq!(0, r.unprocessed);
+/// ```
+pub fn process_until<IT, P, R>(
+    it: IT,
+    predicate: P,
+    deadline: Instant,
+    workers: usize,
+) -> Partial<R>
+where
+    IT: IntoIterator,
+    IT::IntoIter: ExactSizeIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    ProcessBuilder::new()
+        .workers(workers)
+        .deadline(deadline)
+        .run_partial(it, predicate)
+}
+
+/// Process some iterable workload on a given number of threads, reporting
+/// the panics of the predicate rather than propagating them
+///
+/// Items are processed as with [`process`], and `result[i]` is either the
+/// outcome of the `i`-th item or the panic it raised. A panicking item does
+/// not prevent the others from being processed, whereas [`process`] stops
+/// and resumes the panic in the caller.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_catching;
+/// let result = process_catching(0..3, |i| 10 / (i - 1), 2);
+/// assert_eq!(-10, *result[0].as_ref().unwrap());
+/// assert!(result[1].is_err());
+/// assert_eq!(10, *result[2].as_ref().unwrap());
+/// ```
+pub fn process_catching<IT, P, R>(it: IT, predicate: P, workers: usize) -> Vec<Result<R, TaskPanic>>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    let catching = |index, item| {
+        panic::catch_unwind(AssertUnwindSafe(|| predicate(item)))
+            .map_err(|payload| TaskPanic { index, payload })
+    };
+    dispatch(it, catching, |_| false, workers)
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Process some iterable workload on a given number of threads, yielding
+/// the results lazily, as they complete
+///
+/// Items are taken from the input as the workers become idle, like
+/// [`process`] does, but the results are not collected: the returned
+/// iterator yields them in completion order. At most `workers` results are
+/// buffered, so the workers only get ahead of the consumer of the results by
+/// that much, and an arbitrarily long input is processed in bounded memory.
+///
+/// The input is driven from a thread of its own, hence the `'static`
+/// bounds, which [`process_iter_scoped`] lifts. Dropping the iterator stops
+/// the processing once the items in flight complete. A panic of the
+/// predicate or of the input is resumed by the iterator once the results
+/// processed before it are consumed.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_iter;
+/// let mut squares = process_iter(1..=1000, |x: u64| x * x, 4).collect::<Vec<_>>();
+/// squares.sort();
+/// assert_eq!(vec![1, 4, 9], squares[..3]);
+///
+/// // the input does not have to end
+/// assert_eq!(5, process_iter(0.., |x: u64| x, 4).take(5).count());
+/// ```
+pub fn process_iter<IT, P, R>(it: IT, predicate: P, workers: usize) -> impl Iterator<Item = R>
+where
+    IT: IntoIterator + Send + 'static,
+    IT::Item: Send + 'static,
+    P: Fn(IT::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    stream(it, predicate, workers, thread::spawn)
+}
+
+/// Process some iterable workload on threads of `scope`, yielding the
+/// results lazily, as they complete
+///
+/// This is [`process_iter`] for items, predicates and results that borrow
+/// from outside `scope`, e.g. slices of a string the input splits: the
+/// input is driven from a thread of the scope, and the workers are threads
+/// of the scope too. Dropping the iterator stops the processing once the
+/// items in flight complete, and the scope waits for them; a panic is then
+/// resumed by the scope.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_iter_scoped;
+/// let text = String::from("the quick brown fox");
+/// let longest = std::thread::scope(|s| {
+///     let words = process_iter_scoped(s, text.split(' '), |w| (w.len(), w), 2);
+///     words.max().map(|(_, w)| w)
+/// });
+/// assert_eq!(Some("quick"), longest);
+/// ```
+pub fn process_iter_scoped<'scope, 'env, IT, P, R>(
+    scope: &'scope thread::Scope<'scope, 'env>,
+    it: IT,
+    predicate: P,
+    workers: usize,
+) -> impl Iterator<Item = R> + 'scope
+where
+    IT: IntoIterator + Send + 'scope,
+    IT::Item: Send + 'scope,
+    P: Fn(IT::Item) -> R + Send + Sync + 'scope,
+    R: Send + 'scope,
+{
+    stream(it, predicate, workers, |f| scope.spawn(f))
+}
+
+/// Processes `it` as [`process_iter`] does, each worker and the feeder a
+/// thread spawned with `spawn`
+fn stream<'a, IT, P, R, H>(
+    it: IT,
+    predicate: P,
+    workers: usize,
+    mut spawn: impl FnMut(Box<dyn FnOnce() + Send + 'a>) -> H,
+) -> Results<R, H>
+where
+    IT: IntoIterator + Send + 'a,
+    IT::Item: Send + 'a,
+    P: Fn(IT::Item) -> R + Send + Sync + 'a,
+    R: Send + 'a,
+{
+    let workers = worker_count(workers);
+    let queue = Arc::new(Queue::new(workers));
+    let predicate = Arc::new(predicate);
+    let (tx, rx) = mpsc::sync_channel(workers);
+    let mut threads = (0..workers)
+        .map(|_| {
+            let (queue, predicate, tx) = (queue.clone(), predicate.clone(), tx.clone());
+            spawn(Box::new(move || queue.stream(&*predicate, &tx)))
+        })
+        .collect::<Vec<_>>();
+    threads.push(spawn(Box::new(move || {
+        queue.feed(it, workers);
+    })));
+    Results { rx, threads }
+}
+
+/// Process some iterable workload on a given number of threads, giving up on
+/// the items that take longer than `timeout`
+///
+/// Every item is handed to an idle worker, and its result is reported as
+/// [`Timeout`] if the predicate does not return within `timeout`. The worker
+/// is then left to complete the item on its own, its result discarded, and
+/// another one is spawned in its place, so that a hanging item does not keep
+/// the others from being processed. Results are returned in the order of the
+/// input, as with [`process`].
+///
+/// As a worker may outlive the call, the predicate, the items and the
+/// results have `'static` bounds. A panic of the predicate stops the
+/// processing, and is resumed once the items in flight complete or time out.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::{process_with_timeout, Timeout};
+/// # use std::thread;
+/// # use std::time::Duration;
+/// let r = process_with_timeout(
+///     [1, 500, 2],
+///     |ms| {
+///         thread::sleep(Duration::from_millis(ms));
+///         ms
+///     },
+///     Duration::from_millis(100),
+///     2,
+/// );
+/// assert_eq!(vec![Ok(1), Err(Timeout), Ok(2)], r);
+/// ```
+pub fn process_with_timeout<IT, P, R>(
+    it: IT,
+    predicate: P,
+    timeout: Duration,
+    workers: usize,
+) -> Vec<Result<R, Timeout>>
+where
+    IT: IntoIterator,
+    IT::Item: Send + 'static,
+    P: Fn(IT::Item) -> R + Send + Sync + 'static,
+    R: Send + 'static,
+{
+    let predicate = Arc::new(predicate);
+    let (tx, rx) = mpsc::channel();
+    let parent = Parent::current();
+    let mut next_id = 0;
+    let mut spawn = || {
+        let (id, predicate, results) = (next_id, predicate.clone(), tx.clone());
+        let parent = parent.clone();
+        next_id += 1;
+        let (items, inbox) = mpsc::channel::<(usize, IT::Item)>();
+        thread::spawn(move || {
+            for (i, item) in inbox {
+                let run = || parent.run("item", i, &Stamp::now(), || predicate(item));
+                let r = panic::catch_unwind(AssertUnwindSafe(run));
+                if results.send((id, i, r)).is_err() {
+                    return;
+                }
+            }
+        });
+        Worker {
+            id,
+            items,
+            busy: None,
+        }
+    };
+    let mut workers = (0..worker_count(workers))
+        .map(|_| spawn())
+        .collect::<Vec<_>>();
+    let mut it = it.into_iter();
+    let mut results = vec![];
+    let mut exhausted = false;
+    let mut panicked = None;
+
+    loop {
+        while !exhausted && panicked.is_none() {
+            let worker = match workers.iter_mut().find(|w| w.busy.is_none()) {
+                Some(worker) => worker,
+                None => break,
+            };
+            match it.next() {
+                Some(item) => {
+                    let deadline = Instant::now()
+                        .checked_add(timeout)
+                        // a timeout too long to represent never expires in
+                        // practice
+                        .unwrap_or_else(|| Instant::now() + Duration::from_secs(u32::MAX.into()));
+                    worker.busy = Some((results.len(), deadline));
+                    results.push(None);
+                    // idle workers wait for items until their sender is dropped
+                    worker.items.send((results.len() - 1, item)).unwrap();
+                }
+                None => exhausted = true,
+            }
+        }
+        let deadline = match workers.iter().filter_map(|w| w.busy).map(|b| b.1).min() {
+            Some(deadline) => deadline,
+            None => break,
+        };
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((id, i, r)) => {
+                // the results of the workers replaced are discarded
+                if let Some(worker) = workers.iter_mut().find(|w| w.id == id) {
+                    worker.busy = None;
+                    match r {
+                        Ok(r) => results[i] = Some(Ok(r)),
+                        Err(e) => panicked = panicked.or(Some(e)),
+                    }
+                }
+            }
+            Err(_) => {
+                // a sender is kept to spawn workers, so the wait timed out
+                let now = Instant::now();
+                for worker in workers.iter_mut() {
+                    if let Some((i, deadline)) = worker.busy {
+                        if deadline <= now {
+                            results[i] = Some(Err(Timeout));
+                            *worker = spawn();
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(e) = panicked {
+        panic::resume_unwind(e);
+    }
+    results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Process some iterable workload on a given number of threads, sending the
+/// results to `sender` as they complete
+///
+/// This lets a consumer, typically on another thread, handle the results
+/// while the workload is being processed. Results are sent in completion
+/// order. With a bounded channel, the workers wait for the consumer when it
+/// falls behind, and the processing stops early if the receiving end is
+/// dropped. The call returns once all the items are processed, dropping the
+/// senders, so that the consumer sees the end of the results.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_to;
+/// # use std::sync::mpsc;
+/// # use std::thread;
+/// let (tx, rx) = mpsc::sync_channel(4);
+/// let consumer = thread::spawn(move || rx.into_iter().sum::<u64>());
+/// process_to(1..=100, |x: u64| x * 2, 4, tx);
+/// assert_eq!(10100, consumer.join().unwrap());
+/// ```
+pub fn process_to<IT, P, R, S>(it: IT, predicate: P, workers: usize, sender: S)
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    S: ResultSender<R>,
+{
+    let workers = worker_count(workers);
+    let queue = Queue::new(workers);
+
+    thread::scope(|sc| {
+        let (queue, predicate) = (&queue, &predicate);
+        let threads = (0..workers)
+            .map(|_| {
+                let tx = sender.clone();
+                sc.spawn(move || queue.stream(predicate, &tx))
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+        queue.feed(it, workers);
+        threads.into_iter().for_each(join);
+    });
+}
+
+/// Process some iterable workload on a given number of threads, handing
+/// the results to `consume` as they complete
+///
+/// Unlike [`process_iter`], the input and the predicate may borrow from the
+/// caller. `consume` runs on a thread of its own, while the calling thread
+/// feeds the workers, and gets the results in completion order: a slow item
+/// does not hold back the ones completing after it. At most `workers`
+/// results wait for `consume`, and a panic in it stops the processing and
+/// is resumed in the caller.
+///
 /// # Examples
 ///
 /// ```
-/// # use esync::worker_threads::process;
-/// let vec = vec![1, 2, 3, 4, 5];
-/// let result = process(vec.iter(), |x| x * x, 2);
-/// assert_eq!(1 + 4 + 9 + 16 + 25, result.into_iter().sum());
+/// # use esync::worker_threads::process_unordered;
+/// let words = ["a", "bb", "ccc"];
+/// let mut lengths = vec![];
+/// process_unordered(words.iter(), |w| w.len(), 2, |l| lengths.push(l));
+/// lengths.sort();
+/// assert_eq!(vec![1, 2, 3], lengths);
+/// ```
+pub fn process_unordered<IT, P, R, C>(it: IT, predicate: P, workers: usize, mut consume: C)
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+    C: FnMut(R) + Send,
+{
+    let (tx, rx) = mpsc::sync_channel(worker_count(workers));
+    thread::scope(|sc| {
+        let consumer = sc.spawn(move || rx.into_iter().for_each(&mut consume));
+        process_to(it, predicate, workers, tx);
+        join(consumer);
+    });
+}
+
+/// Process some fallible workload on a given number of threads, stopping at
+/// the first error
+///
+/// Items are processed as with [`process`], until the predicate fails on
+/// one of them: no new item is then taken from the input, the ones already
+/// queued are skipped, and the error is returned once the items still being
+/// processed complete. If several items fail, the error of the first one in
+/// input order is returned.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::try_process;
+/// let parsed = try_process(["1", "2", "3"].iter(), |s| s.parse::<i32>(), 2);
+/// assert_eq!(Ok(vec![1, 2, 3]), parsed);
+/// assert!(try_process(["1", "x", "3"].iter(), |s| s.parse::<i32>(), 2).is_err());
+/// ```
+pub fn try_process<IT, P, R, E>(it: IT, predicate: P, workers: usize) -> Result<Vec<R>, E>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> Result<R, E> + Sync,
+    R: Send,
+    E: Send,
+{
+    // the items skipped after a failure come after it in input order
+    dispatch(it, |_, item| predicate(item), Result::is_err, workers)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Process some fallible workload on a given number of threads, going on
+/// past the errors
+///
+/// Every item is processed as with [`process`], whether the predicate
+/// failed on others or not. The results of the items that succeeded are
+/// returned in input order, along with the error of every item that failed
+/// and its position in the input, in input order too, so that all the
+/// failures of e.g. a validation are reported at once.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::try_process_all;
+/// let (parsed, errors) = try_process_all(["1", "x", "3", "y"].iter(), |s| s.parse::<i32>(), 2);
+/// assert_eq!(vec![1, 3], parsed);
+/// assert_eq!(vec![1, 3], errors.iter().map(|(i, _)| *i).collect::<Vec<_>>());
+/// ```
+pub fn try_process_all<IT, P, R, E>(
+    it: IT,
+    predicate: P,
+    workers: usize,
+) -> (Vec<R>, Vec<(usize, E)>)
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> Result<R, E> + Sync,
+    R: Send,
+    E: Send,
+{
+    let mut errors = vec![];
+    let results = dispatch(it, |_, item| predicate(item), |_| false, workers)
+        .into_iter()
+        .enumerate()
+        // nothing stops the processing, so every item has a result
+        .filter_map(|(i, r)| match r.expect("every item is processed") {
+            Ok(r) => Some(r),
+            Err(e) => {
+                errors.push((i, e));
+                None
+            }
+        })
+        .collect();
+    (results, errors)
+}
+
+/// Process some fallible workload on a given number of threads, retrying
+/// the items that fail
+///
+/// This is [`try_process`], but for the predicate being attempted again on
+/// an item that fails, on the same worker, according to `retry`. Only the
+/// error of the last attempt is reported, and it stops the processing. The
+/// predicate takes the items by reference, so that it may be called on them
+/// several times.
+///
+/// # Examples
+///
 /// ```
-pub fn process<IT, P, R>(it: IT, predicate: P, workers: u32) -> Vec<R>
+/// # use esync::worker_threads::{try_process_with_retry, RetryPolicy};
+/// # use std::sync::atomic::{AtomicUsize, Ordering};
+/// # use std::time::Duration;
+/// let calls = AtomicUsize::new(0);
+/// let flaky = |x: &u32| {
+///     // every other call fails
+///     if calls.fetch_add(1, Ordering::SeqCst) % 2 == 0 {
+///         Err("transient failure")
+///     } else {
+///         Ok(x * 2)
+///     }
+/// };
+/// let retry = RetryPolicy::new(2, Duration::from_millis(1));
+/// assert_eq!(Ok(vec![2, 4, 6]), try_process_with_retry([1, 2, 3], flaky, retry, 1));
+/// ```
+pub fn try_process_with_retry<IT, P, R, E>(
+    it: IT,
+    predicate: P,
+    retry: RetryPolicy,
+    workers: usize,
+) -> Result<Vec<R>, E>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(&IT::Item) -> Result<R, E> + Sync,
+    R: Send,
+    E: Send,
+{
+    let attempt = |_, item| retry.run(|| predicate(&item));
+    dispatch(it, attempt, Result::is_err, workers)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Process some iterable workload, bounding the total weight of the items
+/// being processed at any time rather than their number
+///
+/// `weight` gives the cost of every item (e.g. its size in bytes), and items
+/// are dispatched as long as the sum of the costs in flight stays within
+/// `budget`. An item heavier than the whole budget is processed alone, and
+/// one weighing nothing counts as weighing 1, so that the number of items in
+/// flight stays bounded too. Results are returned in the order of the input,
+/// as with [`process`]. The weight of an item whose predicate panics is
+/// given back all the same, and the panic is resumed in the caller once the
+/// other items complete.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_weighted;
+/// let vec = vec![vec![1u8; 10], vec![2u8; 20], vec![3u8; 30]];
+/// let result = process_weighted(vec.iter(), |v| v.len(), |v| v.len(), 32);
+/// assert_eq!(60, result.into_iter().sum::<usize>());
+/// ```
+///
+/// # Panics
+///
+/// Panics if `budget` is zero, as no item could ever be processed.
+pub fn process_weighted<IT, P, W, R>(it: IT, predicate: P, weight: W, budget: usize) -> Vec<R>
 where
-    IT: Iterator,
+    IT: IntoIterator,
     IT::Item: Send,
-    P: Send + Fn(IT::Item) -> R,
-    for<'a> &'a P: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    W: Fn(&IT::Item) -> usize,
     R: Send,
 {
-    let mut retval = vec![];
-    let sem = Semaphore::new(workers);
+    assert!(budget > 0, "processing within a zero budget");
+    let sem = Semaphore::with_max(budget, budget);
+    let (tx, rx) = mpsc::channel();
+    let mut done = vec![];
 
+    // the threads are not joined one by one: every item sends its outcome as
+    // it completes, and the outcomes are gathered while the input is fed
     thread::scope(|sc| {
-        let mut threads = vec![];
-        for s in it {
-            sem.wait();
-            threads.push(sc.spawn(|| {
-                let r = predicate(s);
-                sem.release();
-                r
-            }));
-        }
-        while let Some(t) = threads.pop() {
-            retval.push(t.join().unwrap());
+        for (i, s) in it.into_iter().enumerate() {
+            // the semaphore is private to this call and never closed
+            let w = sem.wait_weighted(weight(&s).max(1)).unwrap();
+            let permit = WeightedPermit {
+                sem: &sem,
+                weight: w,
+            };
+            let (predicate, tx) = (&predicate, tx.clone());
+            sc.spawn(move || {
+                let _permit = permit;
+                let r = panic::catch_unwind(AssertUnwindSafe(|| predicate(s)));
+                // the receiver outlives the scope
+                tx.send((i, r)).unwrap();
+            });
+            done.extend(rx.try_iter());
         }
     });
+    drop(tx);
+    done.extend(rx);
 
-    retval
+    done.sort_unstable_by_key(|(i, _)| *i);
+    done.into_iter()
+        .map(|(_, r)| r.unwrap_or_else(|e| panic::resume_unwind(e)))
+        .collect()
 }
 
+/// Runs every closure of `fs` on a thread of its own, all at once, and
+/// returns their results in order
+///
+/// This is the counterpart of [`gather!`](crate::gather) for closures of a
+/// single type, e.g. boxed ones. A panic in a closure is resumed once the
+/// others complete.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::gather;
+/// let backends = ["eu", "us", "ap"];
+/// let replies = gather(backends.iter().map(|b| move || format!("{}: ok", b)));
+/// assert_eq!("us: ok", replies[1]);
+/// ```
+pub fn gather<I, F, R>(fs: I) -> Vec<R>
+where
+    I: IntoIterator<Item = F>,
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    thread::scope(|sc| {
+        let threads = fs.into_iter().map(|f| sc.spawn(f)).collect::<Vec<_>>();
+        threads.into_iter().map(join).collect()
+    })
+}
+
+/// Runs closures of any types on a thread of their own, all at once, and
+/// returns their results as a tuple
+///
+/// The closures may borrow from the caller, as they complete before the
+/// macro returns. A panic in a closure is resumed once the others complete.
+/// Up to 12 closures are taken; [`gather`] takes any
+/// number of closures of a single type.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::gather;
+/// let user = "alice";
+/// let (profile, orders, score) = gather!(
+///     || format!("profile of {}", user),
+///     || vec![1, 2, 3],
+///     || 4.5,
+/// );
+/// assert_eq!("profile of alice", profile);
+/// assert_eq!(3, orders.len());
+/// assert_eq!(4.5, score);
+/// ```
+///
+/// [`gather`]: crate::worker_threads::gather
+#[macro_export]
+macro_rules! gather {
+    ($($f:expr),+ $(,)?) => {
+        ::std::thread::scope(|scope| {
+            $crate::worker_threads::JoinAll::join_all(($(scope.spawn($f),)+))
+        })
+    };
+}
+
+/// Joins a tuple of scoped threads, for [`gather!`](crate::gather)
+#[doc(hidden)]
+pub trait JoinAll {
+    type Output;
+
+    fn join_all(self) -> Self::Output;
+}
+
+macro_rules! join_all {
+    ($($t:ident),+) => {
+        impl<'scope, $($t),+> JoinAll for ($(ScopedJoinHandle<'scope, $t>,)+) {
+            type Output = ($($t,)+);
+
+            #[allow(non_snake_case)]
+            fn join_all(self) -> Self::Output {
+                let ($($t,)+) = self;
+                ($(join($t),)+)
+            }
+        }
+    };
+}
+
+join_all!(A);
+join_all!(A, B);
+join_all!(A, B, C);
+join_all!(A, B, C, D);
+join_all!(A, B, C, D, E);
+join_all!(A, B, C, D, E, F);
+join_all!(A, B, C, D, E, F, G);
+join_all!(A, B, C, D, E, F, G, H);
+join_all!(A, B, C, D, E, F, G, H, I);
+join_all!(A, B, C, D, E, F, G, H, I, J);
+join_all!(A, B, C, D, E, F, G, H, I, J, K);
+join_all!(A, B, C, D, E, F, G, H, I, J, K, L);
+
 #[cfg(test)]
 mod test {
-    use crate::worker_threads::process;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::worker_threads::{
+        filter_map, for_each, gather, map_reduce, process, process_catching, process_chunks,
+        process_into, process_iter, process_iter_scoped, process_keyed, process_mut, process_to,
+        process_unordered, process_until, process_weighted, process_with_index, process_with_state,
+        process_with_timeout, reduce, try_process, try_process_all, try_process_with_retry,
+        worker_count, ConcurrencyLimit, PanicPolicy, ProcessBuilder, ProgressEvent, ProgressStep,
+        RateLimit, RetryPolicy, Timeout,
+    };
+    use crate::CancellationToken;
 
     #[test]
     fn process_string() {
@@ -67,4 +2610,856 @@ mod test {
         let r = process(s, |p| p.matches("a").count(), 2);
         assert_eq!(42, r.into_iter().reduce(|acc, e| acc + e).unwrap());
     }
+
+    #[test]
+    fn process_into_iterator() {
+        let vec = vec![1, 2, 3];
+        assert_eq!(vec![2, 4, 6], process(&vec, |x| x * 2, 2));
+        assert_eq!(vec![2, 4, 6], process(vec, |x| x * 2, 2));
+
+        // the predicate only has to be shared, not sent: a guard cannot be
+        let data = Mutex::new(vec![10, 20, 30]);
+        let guard = data.lock().unwrap();
+        let r = process(0..3, move |i| guard[i], 2);
+        assert_eq!(vec![10, 20, 30], r);
+    }
+
+    #[test]
+    fn process_preserves_order() {
+        // later items complete first
+        let r = process(
+            (0..8u64).rev(),
+            |i| {
+                thread::sleep(Duration::from_millis(i * 5));
+                i
+            },
+            8,
+        );
+        assert_eq!((0..8).rev().collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn process_reuses_threads() {
+        let threads = Mutex::new(HashSet::new());
+        let r = process(
+            0..1000,
+            |i| {
+                threads.lock().unwrap().insert(thread::current().id());
+                i * 2
+            },
+            4,
+        );
+        assert_eq!((0..1000).map(|i| i * 2).collect::<Vec<_>>(), r);
+        assert!(threads.into_inner().unwrap().len() <= 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "item 50")]
+    fn process_propagates_panics() {
+        process(0..100, |i| assert!(i != 50, "item {}", i), 2);
+    }
+
+    #[test]
+    fn process_catching_panics() {
+        let r = process_catching(
+            0..100,
+            |i| {
+                if i % 10 == 3 {
+                    panic!("item {}", i);
+                }
+                i
+            },
+            2,
+        );
+        assert_eq!(100, r.len());
+        for (i, r) in r.into_iter().enumerate() {
+            match r {
+                Ok(v) => assert_eq!(i, v),
+                Err(e) => {
+                    assert_eq!(3, i % 10);
+                    assert_eq!(Some(format!("item {}", i).as_str()), e.message());
+                    assert_eq!(i, e.index());
+                    assert_eq!(format!("task {} panicked: item {}", i, i), e.to_string());
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "broken input")]
+    fn process_input_panic() {
+        process((0..10).map(|i| assert!(i != 5, "broken input")), |_| (), 2);
+    }
+
+    #[test]
+    fn default_workers() {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        assert_eq!(cpus, worker_count(0));
+        assert_eq!(3, worker_count(3));
+        let threads = Mutex::new(HashSet::new());
+        let r = process(
+            0..1000,
+            |i| {
+                threads.lock().unwrap().insert(thread::current().id());
+                i
+            },
+            0,
+        );
+        assert_eq!((0..1000).collect::<Vec<_>>(), r);
+        assert!(threads.into_inner().unwrap().len() <= cpus);
+    }
+
+    #[test]
+    fn builder_chunks_and_names() {
+        let names = Mutex::new(HashSet::new());
+        let r = ProcessBuilder::new()
+            .workers(3)
+            .chunk_size(7)
+            .thread_name("builder")
+            .stack_size(1 << 20)
+            .run(0..100, |i| {
+                let name = thread::current().name().map(String::from);
+                names.lock().unwrap().insert(name.unwrap());
+                i * 2
+            });
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<_>>(), r);
+        let names = names.into_inner().unwrap();
+        assert!(names.iter().all(|n| n.starts_with("builder-")));
+        assert!(ProcessBuilder::new().run(0..0, |i: i32| i).is_empty());
+    }
+
+    #[test]
+    fn builder_cancellation() {
+        let token = CancellationToken::new();
+        let calls = AtomicUsize::new(0);
+        let r = ProcessBuilder::new()
+            .workers(3)
+            .cancel_on(token.clone())
+            .run(0..10_000, |i| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                if i == 10 {
+                    token.cancel();
+                }
+                i
+            });
+        // the items in flight and queued when cancelled, at most, went on
+        assert!(calls.load(Ordering::SeqCst) <= 17);
+        assert_eq!((0..r.len()).collect::<Vec<_>>(), r);
+        assert!(r.len() > 10);
+
+        // cancelled before it starts, nothing is processed
+        let r = ProcessBuilder::new()
+            .cancel_on(token.clone())
+            .run(0..10, |i| i);
+        assert!(r.is_empty());
+
+        // nor is anything reserved for an input too large to hold
+        for ordered in [true, false] {
+            let r = ProcessBuilder::new()
+                .ordered(ordered)
+                .cancel_on(token.clone())
+                .run(0..usize::MAX, |i| i);
+            assert!(r.is_empty());
+        }
+    }
+
+    #[test]
+    fn process_until_deadline() {
+        let start = Instant::now();
+        let r = process_until(
+            0..1000u32,
+            |i| {
+                thread::sleep(Duration::from_millis(10));
+                i
+            },
+            start + Duration::from_millis(200),
+            2,
+        );
+        // the items in flight at the deadline complete
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(!r.results.is_empty());
+        assert_eq!((0..r.results.len() as u32).collect::<Vec<_>>(), r.results);
+        assert_eq!(1000, r.results.len() + r.unprocessed);
+
+        // a deadline already passed processes nothing
+        let r = process_until([1, 2, 3], |x| x, start, 2);
+        assert_eq!(3, r.unprocessed);
+    }
+
+    #[test]
+    fn builder_progress() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let e = events.clone();
+        let r = ProcessBuilder::new()
+            .workers(3)
+            .chunk_size(4)
+            .progress(move |event| e.lock().unwrap().push(event))
+            .run(0..50, |i| i);
+        assert_eq!((0..50).collect::<Vec<_>>(), r);
+        let events = events.lock().unwrap();
+        assert_eq!(100, events.len());
+        let completed = events
+            .iter()
+            .filter(|e| e.step == ProgressStep::Completed)
+            .collect::<Vec<&ProgressEvent>>();
+        let mut indexes = completed.iter().map(|e| e.index).collect::<Vec<_>>();
+        indexes.sort();
+        assert_eq!((0..50).collect::<Vec<_>>(), indexes);
+        assert!(events.iter().all(|e| e.total == Some(50)));
+        assert_eq!(50, completed.iter().map(|e| e.completed).max().unwrap());
+    }
+
+    #[test]
+    fn builder_limit() {
+        let limit = ConcurrencyLimit::new(4);
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let r = ProcessBuilder::new()
+            .workers(8)
+            .limit(limit.clone())
+            .run(0..200, |i| {
+                if i == 100 {
+                    limit.set(2);
+                    peak.store(0, Ordering::SeqCst);
+                }
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(1));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            });
+        assert_eq!((0..200).collect::<Vec<_>>(), r);
+        // the items started before the change may still have been running
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+    }
+
+    #[test]
+    fn wrong_size_hint() {
+        /// Range claiming to hold two items
+        struct Lying(std::ops::Range<usize>);
+
+        impl Iterator for Lying {
+            type Item = usize;
+
+            fn next(&mut self) -> Option<usize> {
+                self.0.next()
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                (2, Some(2))
+            }
+        }
+
+        // the results past the slots reserved are placed all the same
+        let r = ProcessBuilder::new()
+            .workers(3)
+            .run(Lying(0..100), |i| i * 2);
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn builder_limit_cancelled() {
+        for _ in 0..20 {
+            let token = CancellationToken::new();
+            let r = ProcessBuilder::new()
+                .workers(8)
+                .limit(ConcurrencyLimit::new(2))
+                .cancel_on(token.clone())
+                .run(0..1000, |i| {
+                    thread::sleep(Duration::from_micros(100 * (i as u64 % 3)));
+                    if i == 50 {
+                        token.cancel();
+                    }
+                    i
+                });
+            assert!(r.len() > 50);
+            assert_eq!((0..r.len()).collect::<Vec<_>>(), r);
+        }
+    }
+
+    #[test]
+    fn builder_unordered() {
+        let r = ProcessBuilder::new()
+            .workers(4)
+            .ordered(false)
+            .run(0..4u64, |i| {
+                // the first item is the slowest
+                thread::sleep(Duration::from_millis(if i == 0 { 200 } else { 10 }));
+                i
+            });
+        assert_eq!(4, r.len());
+        assert_eq!(Some(&0), r.last());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn builder_affinity() {
+        use crate::worker_threads::Affinity;
+
+        // the core the test runs on is one the workers may be pinned to
+        let core = unsafe { libc::sched_getcpu() } as usize;
+        let cores = ProcessBuilder::new()
+            .workers(3)
+            .affinity(Affinity::PerWorker(vec![core]))
+            .run(0..30, |_| unsafe { libc::sched_getcpu() } as usize);
+        assert!(cores.iter().all(|&c| c == core));
+        // pinning to a core that does not exist leaves the workers unpinned
+        let r = ProcessBuilder::new()
+            .workers(2)
+            .affinity(Affinity::Shared(vec![usize::MAX]))
+            .run(0..10, |x| x);
+        assert_eq!((0..10).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn keyed() {
+        let running = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        let last = Mutex::new([None, None]);
+        let r = process_keyed(
+            0..100,
+            |i| i % 2,
+            |i| {
+                let key = i % 2;
+                assert_eq!(0, running[key].fetch_add(1, Ordering::SeqCst));
+                let mut last = last.lock().unwrap();
+                assert!(last[key] < Some(i));
+                last[key] = Some(i);
+                drop(last);
+                thread::sleep(Duration::from_micros(100));
+                running[key].fetch_sub(1, Ordering::SeqCst);
+                i * 2
+            },
+            4,
+        );
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn builder_rate_limit() {
+        let start = Instant::now();
+        let rate = RateLimit::new(50, Duration::from_secs(1));
+        let r = ProcessBuilder::new()
+            .workers(4)
+            .rate_limit(rate.clone())
+            .run(0..6, |i| i);
+        assert_eq!(6, r.len());
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // a burst starts right away
+        let start = Instant::now();
+        let burst = RateLimit::new(1, Duration::from_secs(60)).with_burst(5);
+        let r = ProcessBuilder::new()
+            .workers(5)
+            .rate_limit(burst.clone())
+            .run(0..5, |i| i);
+        assert_eq!(5, r.len());
+        assert!(start.elapsed() < Duration::from_secs(30));
+        // and the next item waits for a minute, unless cancelled
+        let token = CancellationToken::new();
+        let r = thread::scope(|s| {
+            let run = s.spawn(|| {
+                ProcessBuilder::new()
+                    .rate_limit(burst)
+                    .cancel_on(token.clone())
+                    .run_partial(0..3, |i| i)
+            });
+            thread::sleep(Duration::from_millis(20));
+            token.cancel();
+            run.join().unwrap()
+        });
+        assert_eq!(0, r.results.len());
+        assert_eq!(3, r.unprocessed);
+    }
+
+    #[test]
+    fn scatter_gather() {
+        let barrier = std::sync::Barrier::new(3);
+        // the closures wait for each other, so they run at once
+        let (a, b, c) = crate::gather!(
+            || {
+                barrier.wait();
+                'a'
+            },
+            || {
+                barrier.wait();
+                "b"
+            },
+            || {
+                barrier.wait();
+                3
+            }
+        );
+        assert_eq!(('a', "b", 3), (a, b, c));
+        let (single,) = crate::gather!(|| 1);
+        assert_eq!(1, single);
+
+        let r = gather((0..4).map(|i| {
+            let barrier = &barrier;
+            move || {
+                if i < 3 {
+                    barrier.wait();
+                }
+                i * 10
+            }
+        }));
+        assert_eq!(vec![0, 10, 20, 30], r);
+    }
+
+    #[test]
+    fn builder_hint() {
+        use crate::worker_threads::Affinity;
+
+        // the node of a worker is told by its name
+        let builder = ProcessBuilder::new()
+            .workers(4)
+            .thread_name("node")
+            .affinity(Affinity::PerNode(vec![vec![0], vec![0]]));
+        let r = builder.run_with_hint(
+            0..40,
+            |i| *i,
+            |i| {
+                let name = thread::current().name().unwrap().to_owned();
+                let w: usize = name.strip_prefix("node-").unwrap().parse().unwrap();
+                (i, w % 2)
+            },
+        );
+        assert_eq!(40, r.len());
+        for (i, node) in r {
+            assert_eq!(i % 2, node);
+        }
+        // without nodes the hint is ignored
+        let r = ProcessBuilder::new()
+            .workers(3)
+            .run_with_hint(0..10, |_| 7, |x| x);
+        assert_eq!((0..10).collect::<Vec<_>>(), r);
+
+        // a cancellation stops all the nodes at the same point, the items of
+        // the slower one behind it processed all the same
+        let token = CancellationToken::new();
+        let r = builder.cancel_on(token.clone()).run_with_hint(
+            0..10_000,
+            |i| *i,
+            |i| {
+                if i % 2 == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                if i == 40 {
+                    token.cancel();
+                }
+                i
+            },
+        );
+        assert!(r.len() > 40);
+        assert_eq!((0..r.len()).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn builder_finishes_despite_panics() {
+        let calls = AtomicUsize::new(0);
+        let r = std::panic::catch_unwind(|| {
+            ProcessBuilder::new()
+                .workers(2)
+                .chunk_size(3)
+                .panic_policy(PanicPolicy::Finish)
+                .run(0..100, |i| {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    assert!(i % 40 != 30, "item {}", i);
+                })
+        });
+        assert_eq!(100, calls.load(Ordering::SeqCst));
+        let e = r.unwrap_err();
+        assert_eq!(Some(&"item 30".to_string()), e.downcast_ref::<String>());
+    }
+
+    #[test]
+    fn process_with_index_positions() {
+        let r = process_with_index((0..100).rev(), |i, item| (i, item), 3);
+        assert_eq!((0..100).zip((0..100).rev()).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn process_with_state_per_worker() {
+        struct State<'a> {
+            items: usize,
+            dropped: &'a AtomicUsize,
+        }
+        impl Drop for State<'_> {
+            fn drop(&mut self) {
+                self.dropped.fetch_add(self.items, Ordering::SeqCst);
+            }
+        }
+
+        let (inits, dropped) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        let r = process_with_state(
+            0..100,
+            || {
+                inits.fetch_add(1, Ordering::SeqCst);
+                State {
+                    items: 0,
+                    dropped: &dropped,
+                }
+            },
+            |state, i| {
+                state.items += 1;
+                i * 2
+            },
+            3,
+        );
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<_>>(), r);
+        assert_eq!(3, inits.load(Ordering::SeqCst));
+        // every state was torn down, after counting its items
+        assert_eq!(100, dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn borrowed_results() {
+        let lines = vec![
+            String::from("a=1"),
+            String::from("b=2"),
+            String::from("c=3"),
+        ];
+        let pairs = process(&lines, |l| l.split_once('=').unwrap(), 2);
+        assert_eq!(vec![("a", "1"), ("b", "2"), ("c", "3")], pairs);
+        let keys = filter_map(&lines, |l| l.get(..1), 2);
+        assert_eq!(vec!["a", "b", "c"], keys);
+        let mut unordered = ProcessBuilder::new()
+            .ordered(false)
+            .run(&lines, |l| &l[2..]);
+        unordered.sort_unstable();
+        assert_eq!(vec!["1", "2", "3"], unordered);
+        let stateful = process_mut(&lines, |l| l.as_str(), 2);
+        assert_eq!(vec!["a=1", "b=2", "c=3"], stateful);
+        let mut lazy =
+            thread::scope(|s| process_iter_scoped(s, &lines, |l| &l[..1], 2).collect::<Vec<_>>());
+        lazy.sort_unstable();
+        assert_eq!(vec!["a", "b", "c"], lazy);
+    }
+
+    #[test]
+    fn process_mut_per_worker() {
+        let mut seen = 0;
+        let r = process_mut(
+            0..100,
+            move |i| {
+                seen += 1;
+                (i, seen)
+            },
+            3,
+        );
+        assert_eq!(
+            (0..100).collect::<Vec<_>>(),
+            r.iter().map(|r| r.0).collect::<Vec<_>>()
+        );
+        // every clone counted its own items from one
+        let clones = r.iter().filter(|r| r.1 == 1).count();
+        assert!((1..=3).contains(&clones), "{} clones", clones);
+    }
+
+    #[test]
+    fn process_chunks_batches() {
+        let r = process_chunks(0..1000, 64, |chunk| (chunk[0], chunk.len()), 4);
+        assert_eq!(16, r.len());
+        for (i, (first, len)) in r.into_iter().enumerate() {
+            assert_eq!(i * 64, first);
+            assert_eq!(if i == 15 { 1000 - 15 * 64 } else { 64 }, len);
+        }
+        assert!(process_chunks(0..0, 4, |c| c.len(), 2).is_empty());
+    }
+
+    #[test]
+    fn for_each_items() {
+        let seen = Mutex::new(vec![]);
+        for_each(0..100, |i| seen.lock().unwrap().push(i), 3);
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+        assert_eq!((0..100).collect::<Vec<_>>(), seen);
+    }
+
+    #[test]
+    fn process_into_slots() {
+        let mut out = vec![0; 1000];
+        process_into(0..1000, |i| i * 2, 4, &mut out);
+        assert_eq!((0..1000).map(|i| i * 2).collect::<Vec<_>>(), out);
+        let short = std::panic::catch_unwind(|| process_into(0..3, |i| i, 2, &mut [0; 2]));
+        assert!(short.is_err());
+    }
+
+    #[test]
+    fn filter_map_keeps_order() {
+        let r = filter_map(0..1000, |i| if i % 3 == 0 { Some(i * 2) } else { None }, 4);
+        assert_eq!(
+            (0..1000)
+                .filter(|i| i % 3 == 0)
+                .map(|i| i * 2)
+                .collect::<Vec<_>>(),
+            r
+        );
+    }
+
+    #[test]
+    fn reduce_partials() {
+        let longest = reduce(
+            ["a", "abc", "ab", "abcd", "b"].iter(),
+            |s| s.len(),
+            usize::max,
+            || 0,
+            2,
+        );
+        assert_eq!(4, longest);
+        assert_eq!(0, reduce(0..0, |x| x, |a, b| a + b, || 0, 3));
+        assert_eq!(4950, reduce(0..100, |x| x, |a, b| a + b, || 0, 3));
+    }
+
+    #[test]
+    fn map_reduce_accumulators() {
+        let (evens, odds) = map_reduce(
+            0..100,
+            || (vec![], vec![]),
+            |(evens, odds): &mut (Vec<_>, Vec<_>), i| {
+                if i % 2 == 0 {
+                    evens.push(i)
+                } else {
+                    odds.push(i)
+                }
+            },
+            |mut a, b| {
+                a.0.extend(b.0);
+                a.1.extend(b.1);
+                a
+            },
+            3,
+        );
+        assert_eq!(50, evens.len());
+        assert!(evens.iter().all(|i| i % 2 == 0));
+        assert_eq!(2500, odds.iter().sum::<i32>());
+    }
+
+    #[test]
+    fn process_iter_results() {
+        let mut r = process_iter((0..1000).map(|i| i * 3), |i| i + 1, 4).collect::<Vec<_>>();
+        r.sort();
+        assert_eq!((0..1000).map(|i| i * 3 + 1).collect::<Vec<_>>(), r);
+    }
+
+    #[test]
+    fn process_iter_is_lazy() {
+        let taken = Arc::new(AtomicUsize::new(0));
+        let counter = taken.clone();
+        let input = (0..).inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut r = process_iter(input, |i: u64| i, 2);
+        assert!(r.next().is_some());
+        thread::sleep(Duration::from_millis(50));
+        // the results buffered, in flight and queued, and a pending push
+        assert!(taken.load(Ordering::SeqCst) <= 8);
+        drop(r);
+    }
+
+    #[test]
+    #[should_panic(expected = "item 7")]
+    fn process_iter_panics() {
+        process_iter(0..100, |i| assert!(i != 7, "item {}", i), 2).for_each(drop);
+    }
+
+    #[test]
+    fn process_with_timeout_recycles_workers() {
+        let start = Instant::now();
+        let r = process_with_timeout(
+            0..20u64,
+            |i| {
+                // two items hang, taking both the initial workers
+                thread::sleep(Duration::from_millis(if i % 10 == 0 { 2000 } else { 5 }));
+                i
+            },
+            Duration::from_millis(100),
+            2,
+        );
+        assert!(start.elapsed() < Duration::from_millis(1500));
+        for (i, r) in r.into_iter().enumerate() {
+            if i % 10 == 0 {
+                assert_eq!(Err(Timeout), r);
+            } else {
+                assert_eq!(Ok(i as u64), r);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "item 3")]
+    fn process_with_timeout_panics() {
+        let timeout = Duration::from_secs(10);
+        process_with_timeout(0..10, |i| assert!(i != 3, "item {}", i), timeout, 2);
+    }
+
+    #[test]
+    fn process_to_channel() {
+        let (tx, rx) = mpsc::channel();
+        process_to(0..100, |i| i * 2, 3, tx);
+        let mut r = rx.into_iter().collect::<Vec<_>>();
+        r.sort();
+        assert_eq!((0..100).map(|i| i * 2).collect::<Vec<_>>(), r);
+
+        // a consumer going away stops the processing
+        let calls = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::sync_channel(1);
+        let consumer = thread::spawn(move || rx.iter().take(3).count());
+        process_to(
+            0..10_000,
+            |i| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                i
+            },
+            2,
+            tx,
+        );
+        assert_eq!(3, consumer.join().unwrap());
+        assert!(calls.load(Ordering::SeqCst) < 100);
+    }
+
+    #[test]
+    fn process_unordered_completion_order() {
+        let mut r = vec![];
+        process_unordered(
+            0..4u64,
+            |i| {
+                // the first item is the slowest
+                thread::sleep(Duration::from_millis(if i == 0 { 200 } else { 10 }));
+                i
+            },
+            4,
+            |i| r.push(i),
+        );
+        assert_eq!(4, r.len());
+        assert_eq!(Some(&0), r.last());
+    }
+
+    #[test]
+    fn try_process_stops_early() {
+        let prefix = AtomicUsize::new(0);
+        let r = try_process(
+            0..10_000,
+            |i| {
+                if i <= 10 {
+                    prefix.fetch_add(1, Ordering::SeqCst);
+                }
+                if i == 10 {
+                    Err(i)
+                } else {
+                    Ok(i)
+                }
+            },
+            2,
+        );
+        // how far the other worker went on meanwhile depends on scheduling,
+        // but the items up to the failure all ran
+        assert_eq!(Err(10), r);
+        assert_eq!(11, prefix.load(Ordering::SeqCst));
+
+        let r = try_process(0..100, |i| Ok::<_, ()>(i + 1), 3);
+        assert_eq!(Ok((1..=100).collect::<Vec<_>>()), r);
+    }
+
+    #[test]
+    fn try_process_retries() {
+        let attempts = Mutex::new(vec![0; 20]);
+        let retry = RetryPolicy::new(3, Duration::from_millis(1));
+        let r = try_process_with_retry(
+            0..20,
+            |i| {
+                let mut attempts = attempts.lock().unwrap();
+                attempts[*i] += 1;
+                // every item fails twice before going through
+                if attempts[*i] < 3 {
+                    Err(*i)
+                } else {
+                    Ok(*i)
+                }
+            },
+            retry,
+            3,
+        );
+        assert_eq!(Ok((0..20).collect::<Vec<_>>()), r);
+        assert!(attempts.into_inner().unwrap().iter().all(|&n| n == 3));
+
+        // the error of the last attempt is reported
+        let calls = AtomicUsize::new(0);
+        let r = try_process_with_retry(
+            [1],
+            |_| Err::<(), _>(calls.fetch_add(1, Ordering::SeqCst)),
+            retry,
+            1,
+        );
+        assert_eq!(Err(2), r);
+        assert_eq!(
+            Err(0),
+            try_process_with_retry([1], |_| Err::<(), _>(0), RetryPolicy::NONE, 1)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "heavy item")]
+    fn process_weighted_panic_releases_budget() {
+        // every item takes the whole budget, which a panic must not keep
+        process_weighted(0..4, |i| assert!(i != 0, "heavy item"), |_| 10, 10);
+    }
+
+    #[test]
+    fn process_weighted_budget() {
+        let in_flight = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let weights = [3, 5, 2, 8, 1, 4, 6, 7];
+        let r = process_weighted(
+            weights.iter(),
+            |w| {
+                let now = in_flight.fetch_add(*w, Ordering::SeqCst) + *w;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                in_flight.fetch_sub(*w, Ordering::SeqCst);
+                *w
+            },
+            |w| **w,
+            10,
+        );
+        assert_eq!(weights.to_vec(), r);
+        assert!(peak.load(Ordering::SeqCst) <= 10);
+
+        // weightless items count as weighing 1
+        let (in_flight, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        process_weighted(
+            0..16,
+            |_| {
+                peak.fetch_max(
+                    in_flight.fetch_add(1, Ordering::SeqCst) + 1,
+                    Ordering::SeqCst,
+                );
+                thread::sleep(Duration::from_millis(10));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+            },
+            |_| 0,
+            3,
+        );
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "zero budget")]
+    fn process_weighted_zero_budget() {
+        process_weighted(0..4, |i| i, |_| 1, 0);
+    }
+
+    #[test]
+    fn try_process_all_errors() {
+        let (evens, odds) =
+            try_process_all(0..100, |i| if i % 2 == 0 { Ok(i) } else { Err(i * 10) }, 4);
+        assert_eq!((0..100).step_by(2).collect::<Vec<_>>(), evens);
+        let expected = (1..100).step_by(2).map(|i| (i, i * 10)).collect::<Vec<_>>();
+        assert_eq!(expected, odds);
+    }
+
+    #[test]
+    fn process_weighted_long_input() {
+        // outcomes are gathered on the way, in any order, and sorted back
+        let r = process_weighted(0..2000, |i| i * 2, |i| i % 3, 4);
+        assert_eq!((0..2000).map(|i| i * 2).collect::<Vec<_>>(), r);
+    }
 }
@@ -0,0 +1,354 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+
+use super::worker_count;
+use crate::{OwnedPermit, Permits, Semaphore};
+
+/// Future returned by [`process_async`], resolving to the results of the
+/// items in the order of the input
+#[must_use = "futures do nothing unless polled"]
+pub struct ProcessAsync<I, P, F: Future> {
+    /// Items not started yet, `None` once they all were
+    items: Option<I>,
+    run: Run<P, F>,
+}
+
+/// Future returned by [`process_stream_async`], resolving to the results of
+/// the items in the order of the stream
+#[must_use = "futures do nothing unless polled"]
+pub struct ProcessStream<S, P, F: Future> {
+    /// Items not started yet, `None` once the stream ended
+    items: Option<Pin<Box<S>>>,
+    run: Run<P, F>,
+}
+
+/// Items in flight of a [`ProcessAsync`] or a [`ProcessStream`]
+struct Run<P, F: Future> {
+    predicate: P,
+    /// Permits of the items in flight
+    permits: Permits,
+    /// Permit taken for the next item, while the input has none ready
+    permit: Option<OwnedPermit>,
+    /// Futures of the items in flight, with their position in the input
+    running: Vec<(usize, OwnedPermit, Pin<Box<F>>)>,
+    results: Vec<Option<F::Output>>,
+}
+
+/// Process some iterable workload asynchronously, with at most `concurrency`
+/// items in flight
+///
+/// The future returned calls `predicate` on every item as a permit of a
+/// semaphore of `concurrency` resources is available, and polls the futures
+/// it returns until they complete, in the task awaiting it. Results come in
+/// the order of the input, as with [`process`](super::process). Zero
+/// `concurrency` means one per CPU.
+///
+/// Nothing blocks: while all the permits are taken, the future returns
+/// pending until an item completes, so that it does not hold up a thread of
+/// the async runtime, whichever runtime it is. Items produced
+/// asynchronously are taken by [`process_stream_async`].
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_async;
+/// async fn fetch(id: u32) -> String {
+///     // some I/O here
+///     format!("item {}", id)
+/// }
+///
+/// async fn fetch_all() -> Vec<String> {
+///     process_async(1..=100, fetch, 8).await
+/// }
+/// ```
+pub fn process_async<IT, P, F>(
+    it: IT,
+    predicate: P,
+    concurrency: usize,
+) -> ProcessAsync<IT::IntoIter, P, F>
+where
+    IT: IntoIterator,
+    P: FnMut(IT::Item) -> F,
+    F: Future,
+{
+    ProcessAsync {
+        items: Some(it.into_iter()),
+        run: Run::new(predicate, concurrency),
+    }
+}
+
+/// Process the items of a stream asynchronously, with at most `concurrency`
+/// items in flight
+///
+/// This is [`process_async`] over a [`Stream`]: the next item is polled for
+/// once a permit is available, and the items in flight go on while the
+/// stream has none ready. Results come in the order of the stream, once it
+/// ended and all its items completed.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_stream_async;
+/// # use esync::Semaphore;
+/// # use std::sync::Arc;
+/// // a job for every resource of the semaphore until it is closed, each
+/// // releasing its resource once done
+/// async fn serve(sem: Arc<Semaphore>) -> usize {
+///     let jobs = process_stream_async(sem.permits(), |permit| async move { drop(permit) }, 4);
+///     jobs.await.len()
+/// }
+/// ```
+pub fn process_stream_async<S, P, F>(
+    stream: S,
+    predicate: P,
+    concurrency: usize,
+) -> ProcessStream<S, P, F>
+where
+    S: Stream,
+    P: FnMut(S::Item) -> F,
+    F: Future,
+{
+    ProcessStream {
+        items: Some(Box::pin(stream)),
+        run: Run::new(predicate, concurrency),
+    }
+}
+
+// the futures are never pinned in place, only the futures of the items, and
+// the stream, which are boxed
+impl<I, P, F: Future> Unpin for ProcessAsync<I, P, F> {}
+
+impl<S, P, F: Future> Unpin for ProcessStream<S, P, F> {}
+
+impl<I, P, F> Future for ProcessAsync<I, P, F>
+where
+    I: Iterator,
+    P: FnMut(I::Item) -> F,
+    F: Future,
+{
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.run
+            .poll(&mut this.items, cx, |items, _| Poll::Ready(items.next()))
+    }
+}
+
+impl<S, P, F> Future for ProcessStream<S, P, F>
+where
+    S: Stream,
+    P: FnMut(S::Item) -> F,
+    F: Future,
+{
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.run.poll(&mut this.items, cx, |items, cx| {
+            items.as_mut().poll_next(cx)
+        })
+    }
+}
+
+impl<P, F: Future> Run<P, F> {
+    fn new(predicate: P, concurrency: usize) -> Self {
+        let sem = Arc::new(Semaphore::new(worker_count(concurrency)));
+        Run {
+            predicate,
+            permits: sem.permits(),
+            permit: None,
+            running: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Starts the items `next` takes out of `items` while permits are
+    /// available, and polls the ones in flight, until `next` ran out of
+    /// items, which sets `items` to `None`, and they all completed
+    fn poll<I, T>(
+        &mut self,
+        items: &mut Option<I>,
+        cx: &mut Context<'_>,
+        mut next: impl FnMut(&mut I, &mut Context<'_>) -> Poll<Option<T>>,
+    ) -> Poll<Vec<F::Output>>
+    where
+        P: FnMut(T) -> F,
+    {
+        loop {
+            while let Some(input) = items {
+                // the semaphore is private to the processing and never closed
+                let permit = match self.permit.take() {
+                    Some(permit) => permit,
+                    None => match Pin::new(&mut self.permits).poll_next(cx) {
+                        Poll::Ready(Some(permit)) => permit,
+                        _ => break,
+                    },
+                };
+                match next(input, cx) {
+                    Poll::Ready(Some(item)) => {
+                        let i = self.results.len();
+                        self.results.push(None);
+                        let f = Box::pin((self.predicate)(item));
+                        self.running.push((i, permit, f));
+                    }
+                    Poll::Ready(None) => *items = None,
+                    Poll::Pending => {
+                        self.permit = Some(permit);
+                        break;
+                    }
+                }
+            }
+            let before = self.running.len();
+            let results = &mut self.results;
+            // releasing the permit of a future completed lets another item in
+            self.running
+                .retain_mut(|(i, _, f)| match f.as_mut().poll(cx) {
+                    Poll::Ready(result) => {
+                        results[*i] = Some(result);
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+            if self.running.is_empty() && items.is_none() {
+                let results = self.results.drain(..);
+                return Poll::Ready(
+                    results
+                        .map(|r| r.expect("items run to completion"))
+                        .collect(),
+                );
+            }
+            if self.running.len() == before {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    use futures_core::Stream;
+
+    use super::{process_async, process_stream_async};
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut f = Box::pin(f);
+        loop {
+            if let Poll::Ready(r) = f.as_mut().poll(&mut cx) {
+                return r;
+            }
+            thread::park();
+        }
+    }
+
+    /// Future pending for its first `n` polls
+    struct Yield(usize);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn bounded_in_order() {
+        let (running, peak) = (Cell::new(0), Cell::new(0));
+        let result = block_on(process_async(
+            0..20,
+            |i| {
+                let (running, peak) = (&running, &peak);
+                async move {
+                    running.set(running.get() + 1);
+                    peak.set(peak.get().max(running.get()));
+                    // later items complete first
+                    Yield(20 - i).await;
+                    running.set(running.get() - 1);
+                    i * 2
+                }
+            },
+            3,
+        ));
+        assert_eq!((0..20).map(|i| i * 2).collect::<Vec<_>>(), result);
+        assert_eq!(3, peak.get());
+        assert!(block_on(process_async(Vec::<u32>::new(), |i| async move { i }, 3)).is_empty());
+    }
+
+    /// Stream of `0..n`, pending once before each item
+    struct Slow {
+        next: usize,
+        n: usize,
+        ready: bool,
+    }
+
+    impl Stream for Slow {
+        type Item = usize;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<usize>> {
+            if !self.ready {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            if self.next == self.n {
+                return Poll::Ready(None);
+            }
+            self.next += 1;
+            Poll::Ready(Some(self.next - 1))
+        }
+    }
+
+    #[test]
+    fn stream() {
+        let (running, peak) = (Cell::new(0), Cell::new(0));
+        let slow = Slow {
+            next: 0,
+            n: 20,
+            ready: false,
+        };
+        let result = block_on(process_stream_async(
+            slow,
+            |i| {
+                let (running, peak) = (&running, &peak);
+                async move {
+                    running.set(running.get() + 1);
+                    peak.set(peak.get().max(running.get()));
+                    Yield(20 - i).await;
+                    running.set(running.get() - 1);
+                    i * 2
+                }
+            },
+            3,
+        ));
+        assert_eq!((0..20).map(|i| i * 2).collect::<Vec<_>>(), result);
+        assert_eq!(3, peak.get());
+    }
+}
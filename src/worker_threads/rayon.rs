@@ -0,0 +1,69 @@
+/// Process some iterable workload on the threads of an existing
+/// [rayon](https://docs.rs/rayon) pool
+///
+/// This is [`process`](super::process) for an application that has a rayon
+/// pool already, and runs every item as a task of `pool` rather than on
+/// threads spawned for the call. Results are returned in the order of the
+/// input. Unlike [`process`](super::process), the input is collected before
+/// the first item starts, so that it only has to be [`Send`] item by item.
+///
+/// # Panics
+///
+/// Panics once all the items completed if the predicate panicked on one of
+/// them, as [`rayon::ThreadPool::scope`](::rayon::ThreadPool::scope) does.
+///
+/// # Examples
+///
+/// ```
+/// # use esync::worker_threads::process_on_rayon;
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+/// let squares = process_on_rayon(&pool, 1..=5, |x| x * x);
+/// assert_eq!(vec![1, 4, 9, 16, 25], squares);
+/// ```
+pub fn process_on_rayon<IT, P, R>(pool: &::rayon::ThreadPool, it: IT, predicate: P) -> Vec<R>
+where
+    IT: IntoIterator,
+    IT::Item: Send,
+    P: Fn(IT::Item) -> R + Sync,
+    R: Send,
+{
+    let items = it.into_iter().collect::<Vec<_>>();
+    let mut results = items.iter().map(|_| None).collect::<Vec<_>>();
+    let predicate = &predicate;
+    pool.scope(|s| {
+        for (result, item) in results.iter_mut().zip(items) {
+            s.spawn(move |_| *result = Some(predicate(item)));
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.expect("the scope completes every item"))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::process_on_rayon;
+
+    #[test]
+    fn runs_on_the_pool() {
+        let pool = ::rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .thread_name(|i| format!("rayon-{}", i))
+            .build()
+            .unwrap();
+        let names = Mutex::new(HashSet::new());
+        let result = process_on_rayon(&pool, 0..100, |i| {
+            let name = thread::current().name().map(String::from);
+            names.lock().unwrap().insert(name.unwrap());
+            i + 1
+        });
+        assert_eq!((1..=100).collect::<Vec<_>>(), result);
+        let names = names.into_inner().unwrap();
+        assert!(names.iter().all(|name| name.starts_with("rayon-")));
+    }
+}
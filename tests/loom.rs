@@ -0,0 +1,150 @@
+//! Model checking of the semaphore with loom, which runs every test under
+//! all the interleavings of its threads:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//! ```
+#![cfg(loom)]
+
+use std::time::Instant;
+
+use esync::{AcquireError, CancellationToken, FairnessPolicy, Semaphore};
+use loom::sync::Arc;
+use loom::thread;
+
+/// Runs `f` under the interleavings with at most three preemptions, unless
+/// `LOOM_MAX_PREEMPTIONS` says otherwise: that is enough for the bugs of
+/// a few threads, and the models with three threads end in seconds
+fn model(f: impl Fn() + Sync + Send + 'static) {
+    let mut builder = loom::model::Builder::new();
+    if builder.preemption_bound.is_none() {
+        builder.preemption_bound = Some(3);
+    }
+    builder.check(f);
+}
+
+#[test]
+fn release_wakes_a_waiter() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let s = sem.clone();
+        let waiter = thread::spawn(move || s.wait());
+        sem.release();
+        assert_eq!(Ok(()), waiter.join().unwrap());
+        assert_eq!(0, sem.get_current_value());
+        assert_eq!(0, sem.waiters());
+    });
+}
+
+#[test]
+fn releases_reach_every_waiter() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let waiters: Vec<_> = (0..2)
+            .map(|_| {
+                let s = sem.clone();
+                thread::spawn(move || s.wait())
+            })
+            .collect();
+        sem.release();
+        sem.release();
+        for waiter in waiters {
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        }
+        assert_eq!(0, sem.get_current_value());
+    });
+}
+
+#[test]
+fn barging_is_allowed_unless_fifo() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(1).fairness(FairnessPolicy::Unfair));
+        let s = sem.clone();
+        let other = thread::spawn(move || {
+            s.wait().unwrap();
+            s.release();
+        });
+        sem.wait().unwrap();
+        sem.release();
+        other.join().unwrap();
+        assert_eq!(1, sem.get_current_value());
+    });
+}
+
+#[test]
+fn timeout_racing_a_release() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let s = sem.clone();
+        let releaser = thread::spawn(move || s.release());
+        // already passed: the waiter queues, then gives up unless served
+        let r = sem.wait_deadline(Instant::now());
+        releaser.join().unwrap();
+        // the resource is either taken or left, never lost
+        match r {
+            Ok(()) => assert_eq!(0, sem.get_current_value()),
+            Err(e) => {
+                assert_eq!(AcquireError::TimedOut, e);
+                assert_eq!(1, sem.get_current_value());
+            }
+        }
+        assert_eq!(0, sem.waiters());
+    });
+}
+
+#[test]
+fn timeout_leaves_the_resource_to_the_next_waiter() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let s = sem.clone();
+        let waiter = thread::spawn(move || s.wait());
+        let s = sem.clone();
+        let releaser = thread::spawn(move || s.release());
+        // queued ahead of the waiter or not, the timed out waiter does not
+        // keep the resource from it
+        if sem.wait_deadline(Instant::now()).is_ok() {
+            sem.release();
+        }
+        assert_eq!(Ok(()), waiter.join().unwrap());
+        releaser.join().unwrap();
+        assert_eq!(0, sem.get_current_value());
+    });
+}
+
+#[test]
+fn close_wakes_a_waiter() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let s = sem.clone();
+        let waiter = thread::spawn(move || s.wait());
+        sem.close();
+        assert_eq!(Err(AcquireError::Closed), waiter.join().unwrap());
+        assert_eq!(0, sem.waiters());
+    });
+}
+
+#[test]
+fn cancellation_wakes_a_waiter() {
+    model(|| {
+        let sem = Arc::new(Semaphore::new(0));
+        let token = CancellationToken::new();
+        let (s, t) = (sem.clone(), token.clone());
+        let waiter = thread::spawn(move || s.wait_cancellable(&t));
+        token.cancel();
+        assert_eq!(Err(AcquireError::Cancelled), waiter.join().unwrap());
+        assert_eq!(0, sem.waiters());
+    });
+}
+
+#[test]
+fn bounded_releases() {
+    model(|| {
+        let sem = Arc::new(Semaphore::with_max(0, 1));
+        let s = sem.clone();
+        let other = thread::spawn(move || s.try_release().is_ok());
+        let mine = sem.try_release().is_ok();
+        // exactly one of the two fits under the maximum
+        assert!(mine ^ other.join().unwrap());
+        assert_eq!(1, sem.get_current_value());
+    });
+}